@@ -0,0 +1,110 @@
+//! Attachment of frontends to a running session via a watched
+//! directory (see `--attach-dir`). A frontend started after the session
+//! attaches by listening on a socket it creates in the directory, and
+//! detaches by removing the socket again.
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+pub struct AttachDir {
+    dir: PathBuf,
+    /// Sockets of the directory whose frontends are attached.
+    sockets: BTreeSet<PathBuf>,
+    /// Sockets of the directory whose frontends failed to attach, and
+    /// are retried on every poll.
+    failed: BTreeSet<PathBuf>,
+}
+
+impl AttachDir {
+    /// Watches `dir`, creating it if it does not exist. Sockets already
+    /// in it are attached on the first poll.
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            sockets: BTreeSet::new(),
+            failed: BTreeSet::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The sockets of the directory whose frontends are not attached,
+    /// and the sockets of attached frontends that disappeared from the
+    /// directory since the last poll. Report the sockets whose
+    /// frontends attached via [`AttachDir::attached`].
+    pub fn poll(&mut self) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let present = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map_or(false, |ft| ft.is_socket()))
+            .map(|entry| entry.path())
+            .collect();
+
+        Ok(self.update(present))
+    }
+
+    fn update(&mut self, present: BTreeSet<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let appeared = present.difference(&self.sockets).cloned().collect();
+        let disappeared = self.sockets.difference(&present).cloned().collect();
+        self.sockets.retain(|socket| present.contains(socket));
+        self.failed.retain(|socket| present.contains(socket));
+
+        (appeared, disappeared)
+    }
+
+    /// Records that the frontend of `socket` attached.
+    pub fn attached(&mut self, socket: PathBuf) {
+        self.failed.remove(&socket);
+        self.sockets.insert(socket);
+    }
+
+    /// Records that the frontend of `socket` failed to attach. Returns
+    /// whether it failed for the first time.
+    pub fn failed(&mut self, socket: PathBuf) -> bool {
+        self.failed.insert(socket)
+    }
+}
+
+/// Name of the frontend listening on `socket`: the name of the socket,
+/// without extension.
+pub fn frontend_name(socket: &Path) -> String {
+    socket.file_stem().map_or_else(
+        || socket.display().to_string(),
+        |stem| stem.to_string_lossy().into_owned(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure attached sockets are only reported once they disappear,
+    /// and that sockets that failed to attach are retried.
+    #[test]
+    fn update() {
+        let mut dir = AttachDir {
+            dir: PathBuf::new(),
+            sockets: BTreeSet::new(),
+            failed: BTreeSet::new(),
+        };
+        let set = |paths: &[&str]| paths.iter().map(PathBuf::from).collect();
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert_eq!(
+            dir.update(set(&["a", "b"])),
+            (paths(&["a", "b"]), paths(&[]))
+        );
+        dir.attached(PathBuf::from("a"));
+        assert!(dir.failed(PathBuf::from("b")));
+        assert_eq!(dir.update(set(&["a", "b"])), (paths(&["b"]), paths(&[])));
+        assert!(!dir.failed(PathBuf::from("b")));
+        dir.attached(PathBuf::from("b"));
+        assert_eq!(dir.update(set(&["b", "c"])), (paths(&["c"]), paths(&["a"])));
+        assert_eq!(frontend_name(Path::new("/tmp/attach/plot.socket")), "plot");
+    }
+}
@@ -0,0 +1,451 @@
+//! Samples of instruments external to the target, e.g. of the current
+//! drawn by the target as measured by a power analyzer, merged into the
+//! event stream as [`api::EventType::AuxSample`]s. See `trace
+//! --aux-csv` and `stats --aux-csv`.
+//!
+//! The samples are CSV. The first line names the columns: `time`, in
+//! seconds on the clock of the instrument, followed by a column per
+//! channel. A row may leave the value of a channel empty. Lines
+//! starting with `#` are comments.
+//!
+//! The clock of the instrument is aligned on the sync pulse of the
+//! target (see [`TraceMetadata::sync_anchor`]): the pulse is at the
+//! first non-zero value of a `sync` column, if the instrument samples
+//! it, and at time zero otherwise, e.g. if the instrument is triggered
+//! on it. Drift between the clocks is not corrected.
+//!
+//! During a trace session, aligned samples are held until the trace
+//! reaches their offset, such that they are merged into the event
+//! stream in time order. At most [`MAX_HELD`] samples and chunks are
+//! held; beyond that, the oldest samples are dropped, e.g. if the
+//! target never pulses, and the oldest chunks are merged ahead of the
+//! trace, e.g. if the target is idle. Samples are not trace data, and
+//! are thus not recorded in the trace file: pass them to `stats
+//! --aux-csv` to analyze a replayed trace.
+//!
+//! [`TraceMetadata::sync_anchor`]: crate::recovery::TraceMetadata::sync_anchor
+use crate::log;
+use crate::timestamps::offset;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crossbeam_channel as channel;
+use rtic_scope_api as api;
+use thiserror::Error;
+
+/// The column of the sync pulse, if the instrument samples it.
+const SYNC_COLUMN: &str = "sync";
+
+/// The most samples not yet aligned, and the most aligned chunks not
+/// yet merged, that [`AuxInput`] holds. Also bounds the lines read but
+/// not yet parsed.
+pub const MAX_HELD: usize = 1 << 16;
+
+#[derive(Debug, Error)]
+pub enum AuxError {
+    #[error("Failed to read auxiliary samples from {}: {1}", .0.display())]
+    Read(PathBuf, #[source] io::Error),
+    #[error("The auxiliary samples of {} do not include the sync pulse", .0.display())]
+    NoPulse(PathBuf),
+    #[error("The trace has no sync anchor to align the auxiliary samples on")]
+    NoSyncAnchor,
+}
+
+impl crate::diag::DiagnosableError for AuxError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::NoPulse(_) => vec![
+                "The pulse is at the first non-zero value of the `sync` column. Without the column, the pulse is at time zero.".to_string(),
+            ],
+            Self::NoSyncAnchor => vec![
+                "Call `cortex_m_rtic_trace::sync_pulse` along with the external pulse the instrument samples or is triggered on, and set `sync_pulse = true` in [package.metadata.rtic-scope].".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// A sample of a channel, at a time in seconds on the clock of the
+/// instrument.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub time: f64,
+    pub channel: String,
+    pub value: f64,
+}
+
+/// Parses the lines of auxiliary samples into [`Sample`]s.
+#[derive(Default)]
+pub struct Parser {
+    /// The name of each column after `time`, once the header is
+    /// parsed.
+    columns: Option<Vec<String>>,
+    /// Time of the sync pulse on the clock of the instrument, once
+    /// sampled.
+    pulse: Option<f64>,
+}
+
+impl Parser {
+    /// Parses `line` into the samples of its row. The header, comments,
+    /// and empty lines have none.
+    pub fn parse(&mut self, line: &str) -> Result<Vec<Sample>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(vec![]);
+        }
+        let mut fields = line.split(',').map(str::trim);
+
+        let columns = match &self.columns {
+            Some(columns) => columns,
+            None => {
+                if fields.next() != Some("time") {
+                    return Err("the header must start with a time column".to_string());
+                }
+                self.columns = Some(fields.map(String::from).collect());
+                return Ok(vec![]);
+            }
+        };
+        let time: f64 = fields
+            .next()
+            .and_then(|time| time.parse().ok())
+            .filter(|time: &f64| time.is_finite())
+            .ok_or_else(|| "the time is not a finite number".to_string())?;
+
+        let mut samples = vec![];
+        for (column, field) in columns.iter().zip(fields) {
+            if field.is_empty() {
+                continue;
+            }
+            let value: f64 = field
+                .parse()
+                .ok()
+                .filter(|value: &f64| value.is_finite())
+                .ok_or_else(|| format!("the value of {} is not a finite number", column))?;
+            if column == SYNC_COLUMN {
+                if self.pulse.is_none() && value != 0.0 {
+                    self.pulse = Some(time);
+                }
+                continue;
+            }
+            samples.push(Sample {
+                time,
+                channel: column.to_owned(),
+                value,
+            });
+        }
+
+        Ok(samples)
+    }
+
+    /// Time of the sync pulse on the clock of the instrument, if known.
+    pub fn pulse(&self) -> Option<f64> {
+        match &self.columns {
+            Some(columns) if !columns.iter().any(|c| c == SYNC_COLUMN) => Some(0.0),
+            _ => self.pulse,
+        }
+    }
+}
+
+/// The chunk of `samples` of the same time, aligned such that the sync
+/// `pulse` on the clock of the instrument is at `anchor` from target
+/// reset, in order. Samples from before target reset are dropped.
+pub fn align(
+    samples: Vec<Sample>,
+    pulse: f64,
+    anchor: Duration,
+    core: Option<u8>,
+) -> Vec<api::EventChunk> {
+    let mut chunks: Vec<(f64, api::EventChunk)> = vec![];
+    for sample in samples {
+        let at = anchor.as_secs_f64() + sample.time - pulse;
+        if at < 0.0 {
+            continue;
+        }
+        let event = api::EventType::AuxSample {
+            channel: sample.channel,
+            value: sample.value,
+        };
+        match chunks.last_mut() {
+            Some((time, chunk)) if *time == sample.time => chunk.events.push(event),
+            _ => chunks.push((
+                sample.time,
+                api::EventChunk {
+                    timestamp: api::Timestamp::Sync(
+                        Duration::from_nanos((at * 1e9).round() as u64),
+                    ),
+                    events: vec![event],
+                    estimated_timestamp: None,
+                    cycles: None,
+                    core,
+                    packets: None,
+                },
+            )),
+        }
+    }
+
+    chunks.into_iter().map(|(_, chunk)| chunk).collect()
+}
+
+/// Reads the samples of the file at `path`, aligned on the sync
+/// `anchor` of a trace, in order. Malformed lines are skipped.
+pub fn load(
+    path: &Path,
+    anchor: Option<Duration>,
+    core: Option<u8>,
+) -> Result<Vec<api::EventChunk>, AuxError> {
+    let fd = fs::File::open(path).map_err(|e| AuxError::Read(path.to_owned(), e))?;
+    let mut parser = Parser::default();
+    let mut samples = vec![];
+    for line in BufReader::new(fd).lines() {
+        let line = line.map_err(|e| AuxError::Read(path.to_owned(), e))?;
+        match parser.parse(&line) {
+            Ok(row) => samples.extend(row),
+            Err(e) => log::warn(format!("ignoring auxiliary samples {:?}: {}", line, e)),
+        }
+    }
+    let pulse = parser
+        .pulse()
+        .ok_or_else(|| AuxError::NoPulse(path.to_owned()))?;
+    let anchor = anchor.ok_or(AuxError::NoSyncAnchor)?;
+
+    Ok(align(samples, pulse, anchor, core))
+}
+
+/// Samples read live during a trace session, from a file, FIFO, or
+/// serial device (configured beforehand, e.g. via `stty`). Samples
+/// are held until both the sync pulse and the sync anchor are known,
+/// and then until the trace reaches them.
+pub struct AuxInput {
+    path: PathBuf,
+    lines: channel::Receiver<String>,
+    parser: Parser,
+    /// Samples not yet aligned, in order.
+    pending: VecDeque<Sample>,
+    /// Aligned chunks not yet merged, in order.
+    aligned: VecDeque<api::EventChunk>,
+    /// Number of samples dropped because too many were held.
+    dropped: usize,
+}
+
+impl AuxInput {
+    pub fn open(path: &Path) -> Result<Self, AuxError> {
+        // Opening a FIFO blocks until its writer opens it: only check
+        // that the path exists, and open it on the reading thread.
+        fs::metadata(path).map_err(|e| AuxError::Read(path.to_owned(), e))?;
+
+        let (tx, lines) = channel::bounded(MAX_HELD);
+        let p = path.to_owned();
+        std::thread::spawn(move || {
+            let fd = match fs::File::open(&p) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    log::warn(format!(
+                        "failed to open auxiliary samples {}: {}",
+                        p.display(),
+                        e
+                    ));
+                    return;
+                }
+            };
+            for line in BufReader::new(fd).lines() {
+                match line {
+                    Ok(line) => {
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn(format!(
+                            "failed to read auxiliary samples from {}: {}",
+                            p.display(),
+                            e
+                        ));
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            path: path.to_owned(),
+            lines,
+            parser: Parser::default(),
+            pending: VecDeque::new(),
+            aligned: VecDeque::new(),
+            dropped: 0,
+        })
+    }
+
+    /// The chunks of the samples read until now, aligned on the sync
+    /// `anchor` of the trace, if known, up to the offset `until` the
+    /// trace has reached, in order.
+    pub fn poll(
+        &mut self,
+        anchor: Option<Duration>,
+        until: Duration,
+        core: Option<u8>,
+    ) -> Vec<api::EventChunk> {
+        for line in self.lines.try_iter() {
+            match self.parser.parse(&line) {
+                Ok(row) => self.pending.extend(row),
+                Err(e) => log::warn(format!(
+                    "ignoring auxiliary samples {:?} from {}: {}",
+                    line,
+                    self.path.display(),
+                    e
+                )),
+            }
+        }
+
+        match (self.parser.pulse(), anchor) {
+            (Some(pulse), Some(anchor)) => {
+                self.aligned
+                    .extend(align(self.pending.drain(..).collect(), pulse, anchor, core))
+            }
+            _ => {
+                let excess = self.pending.len().saturating_sub(MAX_HELD);
+                if excess > 0 {
+                    if self.dropped == 0 {
+                        log::warn(format!(
+                            "dropping the oldest auxiliary samples of {}: more than {} cannot be aligned yet",
+                            self.path.display(),
+                            MAX_HELD
+                        ));
+                    }
+                    self.pending.drain(..excess);
+                    self.dropped += excess;
+                }
+            }
+        }
+
+        let mut chunks = vec![];
+        while let Some(chunk) = self.aligned.front() {
+            if offset(&chunk.timestamp) > until && self.aligned.len() <= MAX_HELD {
+                break;
+            }
+            chunks.extend(self.aligned.pop_front());
+        }
+
+        chunks
+    }
+
+    /// The chunks still held, e.g. after the end of the trace.
+    pub fn flush(&mut self) -> Vec<api::EventChunk> {
+        self.aligned.drain(..).collect()
+    }
+
+    /// Warns about the samples that were dropped or never aligned.
+    pub fn finish(&self) {
+        if self.dropped > 0 {
+            log::warn(format!(
+                "{} auxiliary sample(s) of {} were dropped while they could not be aligned",
+                self.dropped,
+                self.path.display(),
+            ));
+        }
+        if self.pending.is_empty() {
+            return;
+        }
+
+        log::warn(format!(
+            "{} auxiliary sample(s) of {} were dropped: {}",
+            self.pending.len(),
+            self.path.display(),
+            match self.parser.pulse() {
+                Some(_) => AuxError::NoSyncAnchor,
+                None => AuxError::NoPulse(self.path.clone()),
+            }
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure samples are aligned on the sampled sync pulse, that empty
+    /// values are skipped, and that samples of the same time share a
+    /// chunk.
+    #[test]
+    fn aligned() {
+        let mut parser = Parser::default();
+        let mut samples = vec![];
+        for line in [
+            "# joulescope",
+            "time,current,sync,voltage",
+            "0.5,0.010,0,3.3",
+            "1.0,0.020,1,",
+            "1.5,0.030,0,3.2",
+        ] {
+            samples.extend(parser.parse(line).unwrap());
+        }
+        assert!(parser.parse("2.0,x,0,3.3").is_err());
+        assert!(parser.parse("2.0,NaN,0,3.3").is_err());
+        assert!(parser.parse("inf,0.010,0,3.3").is_err());
+        assert_eq!(parser.pulse(), Some(1.0));
+        assert_eq!(samples.len(), 5);
+
+        let chunks = align(samples, 1.0, Duration::from_millis(700), None);
+        let offsets: Vec<Duration> = chunks.iter().map(|c| offset(&c.timestamp)).collect();
+        assert_eq!(
+            offsets,
+            [
+                Duration::from_millis(200),
+                Duration::from_millis(700),
+                Duration::from_millis(1200)
+            ]
+        );
+        assert_eq!(chunks[0].events.len(), 2);
+        assert_eq!(chunks[1].events.len(), 1);
+
+        // Without a sync column, the pulse is at time zero.
+        let mut parser = Parser::default();
+        assert_eq!(parser.pulse(), None);
+        parser.parse("time,power").unwrap();
+        assert_eq!(parser.pulse(), Some(0.0));
+        let samples = parser.parse("-0.5,1.0").unwrap();
+        assert!(align(samples, 0.0, Duration::from_millis(100), None).is_empty());
+    }
+
+    /// Ensure live samples are held until the trace reaches them, and
+    /// that the oldest are dropped while they cannot be aligned.
+    #[test]
+    fn held() {
+        let (tx, lines) = channel::unbounded();
+        let mut aux = AuxInput {
+            path: PathBuf::from("aux.csv"),
+            lines,
+            parser: Parser::default(),
+            pending: VecDeque::new(),
+            aligned: VecDeque::new(),
+            dropped: 0,
+        };
+        let ms = Duration::from_millis;
+
+        tx.send("time,current,sync".to_string()).unwrap();
+        for i in 0..MAX_HELD + 2 {
+            tx.send(format!("{},1.0,0", i)).unwrap();
+        }
+        assert!(aux.poll(None, ms(0), None).is_empty());
+        assert_eq!(aux.dropped, 2);
+        assert_eq!(aux.pending.front().unwrap().time, 2.0);
+
+        aux.pending.clear();
+        tx.send("0.1,1.0,1".to_string()).unwrap();
+        tx.send("0.2,2.0,0".to_string()).unwrap();
+        tx.send("0.3,3.0,0".to_string()).unwrap();
+        let offsets = |chunks: Vec<api::EventChunk>| -> Vec<Duration> {
+            chunks.iter().map(|c| offset(&c.timestamp)).collect()
+        };
+        assert_eq!(
+            offsets(aux.poll(Some(ms(1000)), ms(1100), None)),
+            [ms(1000), ms(1100)]
+        );
+        assert!(aux.poll(Some(ms(1000)), ms(1150), None).is_empty());
+        assert_eq!(offsets(aux.flush()), [ms(1200)]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Comparison of the execution statistics of a trace session against
+//! those of a baseline, such that a session ends with a verdict. See
+//! `trace --baseline`.
+use crate::cores::MergedTraces;
+use crate::stats::{TaskStatistics, TaskStats, Units};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BaselineError {
+    #[error("Failed to read baseline {}: {1}", .0.display())]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("Baseline {} is neither a trace file nor a statistics report: {1}", .0.display())]
+    Parse(PathBuf, String),
+    #[error("Baseline {} is in {1}, not in ns", .0.display())]
+    Units(PathBuf, String),
+    #[error("Baseline {} is of {1}, not of {2}", .0.display())]
+    Program(PathBuf, String, String),
+}
+
+impl crate::diag::DiagnosableError for BaselineError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::Parse(_, _) => vec![
+                "A baseline is a trace file, or the report of `cargo rtic-scope stats --aggregate --format json`.".to_string(),
+            ],
+            Self::Units(_, _) => vec![
+                "Generate the report with `--units ns`.".to_string(),
+            ],
+            Self::Program(_, _, program) => vec![
+                format!("Generate the report with `--program {}`.", program),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// The subset of the report of `stats --aggregate --format json` that
+/// is compared against.
+#[derive(Deserialize)]
+struct Report {
+    units: String,
+    /// Absent from reports of versions that aggregated the traces of
+    /// all programs.
+    #[serde(default)]
+    program: Option<String>,
+    tasks: IndexMap<String, TaskStats>,
+}
+
+/// A measured execution time of a task that exceeds that of the
+/// baseline by more than the tolerance.
+#[derive(Debug, PartialEq)]
+pub struct Regression {
+    pub task: String,
+    /// The regressed statistic: "max" or "mean".
+    pub statistic: &'static str,
+    pub baseline: u128,
+    pub measured: u128,
+}
+
+impl Regression {
+    /// By how much the measured value exceeds the baseline, in percent.
+    pub fn excess(&self) -> f64 {
+        100.0 * (self.measured as f64 / self.baseline.max(1) as f64 - 1.0)
+    }
+}
+
+/// The execution statistics of each task, in nanoseconds, that a
+/// session is compared against.
+pub struct Baseline {
+    pub path: PathBuf,
+    /// The program the baseline was recorded from, if known.
+    program: Option<String>,
+    tasks: IndexMap<String, TaskStats>,
+    /// How much longer the executions of a task may be than in the
+    /// baseline, in percent.
+    tolerance: f64,
+}
+
+impl Baseline {
+    /// Reads the baseline from `path`: either a trace file, whose
+    /// statistics are computed, or a statistics report.
+    pub fn load(path: &Path, tolerance: f64) -> Result<Self, BaselineError> {
+        let data = fs::read(path).map_err(|e| BaselineError::Read(path.to_owned(), e))?;
+        let (program, tasks) = match serde_json::from_slice::<Report>(&data) {
+            Ok(report) if report.units != Units::Nanoseconds.suffix() => {
+                return Err(BaselineError::Units(path.to_owned(), report.units))
+            }
+            Ok(report) => (report.program, report.tasks),
+            // NOTE a trace file is a stream of several JSON values
+            Err(_) => Self::trace_statistics(path)
+                .map(|(program, tasks)| (Some(program), tasks))
+                .map_err(|e| BaselineError::Parse(path.to_owned(), e.to_string()))?,
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            program,
+            tasks,
+            tolerance,
+        })
+    }
+
+    /// The program of the trace at `path`, and the statistics of each
+    /// of its tasks.
+    fn trace_statistics(
+        path: &Path,
+    ) -> Result<(String, IndexMap<String, TaskStats>), crate::RTICScopeError> {
+        let mut src = MergedTraces::open(&[path.to_owned()], false)?;
+        let metadata = src.metadata().next().unwrap();
+        let (program, freq) = (metadata.program_name.clone(), metadata.tpiu_freq());
+        let mut tasks = TaskStatistics::new(Units::Nanoseconds, freq);
+        for chunk in &mut src {
+            let (_, _, chunk) = chunk?;
+            tasks.update(&chunk);
+        }
+
+        Ok((program, tasks.tasks))
+    }
+
+    /// Checks that the baseline was recorded from `program`, as the
+    /// statistics of other programs are not comparable.
+    pub fn check_program(&self, program: &str) -> Result<(), BaselineError> {
+        match &self.program {
+            Some(baseline) if baseline != program => Err(BaselineError::Program(
+                self.path.clone(),
+                baseline.clone(),
+                program.to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Compares the statistics of each task `measured` in nanoseconds
+    /// against the baseline. Tasks that did not execute in either are
+    /// not compared.
+    pub fn compare(&self, measured: &IndexMap<String, TaskStats>) -> Vec<Regression> {
+        let mut regressions = vec![];
+        for (task, baseline) in self.tasks.iter().filter(|(_, stats)| stats.count > 0) {
+            let measured = match measured.get(task).filter(|stats| stats.count > 0) {
+                Some(measured) => measured,
+                None => continue,
+            };
+            for (statistic, baseline, measured) in [
+                ("max", baseline.max, measured.max),
+                ("mean", baseline.mean(), measured.mean()),
+            ] {
+                let limit = baseline as f64 * (1.0 + self.tolerance / 100.0);
+                if measured as f64 > limit {
+                    regressions.push(Regression {
+                        task: task.to_owned(),
+                        statistic,
+                        baseline,
+                        measured,
+                    });
+                }
+            }
+        }
+
+        regressions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stats(count: usize, total: u128, max: u128) -> TaskStats {
+        TaskStats {
+            count,
+            total,
+            min: Some(0),
+            max,
+        }
+    }
+
+    /// Ensure only executions longer than the tolerance allows are
+    /// regressions, and that tasks without executions are skipped.
+    #[test]
+    fn compare() {
+        let baseline = Baseline {
+            path: PathBuf::new(),
+            program: Some("app".to_string()),
+            tasks: IndexMap::from_iter([
+                ("a".to_string(), stats(10, 1000, 200)),
+                ("b".to_string(), stats(10, 1000, 200)),
+                ("c".to_string(), stats(10, 1000, 200)),
+            ]),
+            tolerance: 10.0,
+        };
+        let measured = IndexMap::from_iter([
+            ("a".to_string(), stats(5, 550, 220)),
+            ("b".to_string(), stats(5, 500, 221)),
+            ("c".to_string(), stats(0, 0, 0)),
+            ("d".to_string(), stats(5, 5000, 5000)),
+        ]);
+
+        assert_eq!(
+            baseline.compare(&measured),
+            [Regression {
+                task: "b".to_string(),
+                statistic: "max",
+                baseline: 200,
+                measured: 221,
+            }]
+        );
+    }
+
+    /// Ensure a baseline of another program is rejected, unless its
+    /// program is unknown.
+    #[test]
+    fn program() {
+        let baseline = |program: Option<&str>| Baseline {
+            path: PathBuf::new(),
+            program: program.map(str::to_string),
+            tasks: IndexMap::new(),
+            tolerance: 10.0,
+        };
+
+        assert!(baseline(Some("app")).check_program("app").is_ok());
+        assert!(matches!(
+            baseline(Some("app")).check_program("other"),
+            Err(BaselineError::Program(_, _, _))
+        ));
+        assert!(baseline(None).check_program("other").is_ok());
+    }
+}
@@ -0,0 +1,139 @@
+//! Throughput benchmark of the trace pipeline against a
+//! [`SyntheticSource`], as run by `cargo rtic-scope bench`.
+use crate::manifest::InterruptLatency;
+use crate::recovery::{TraceLookupMaps, TraceMetadata};
+use crate::sinks::{SinkError, SinkMetrics};
+use crate::sources::{synthetic, Source, SourceError, SyntheticSource};
+use crate::{BenchOptions, RTICScopeError};
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel as channel;
+use rtic_scope_api as api;
+
+/// A row of the latency table: the p95 and maximum latency of a stage
+/// in µs.
+fn latencies(stage: &str, metrics: &SinkMetrics) -> String {
+    format!(
+        "{:<8}{:>10.1}{:>10.1}",
+        stage,
+        metrics.p95().unwrap_or_default().as_secs_f64() * 1e6,
+        metrics.max().as_secs_f64() * 1e6,
+    )
+}
+
+pub fn run(opts: &BenchOptions) -> Result<(), RTICScopeError> {
+    let metadata = TraceMetadata::from(
+        "synthetic".to_string(),
+        TraceLookupMaps::synthetic(),
+        chrono::Local::now(),
+        synthetic::FREQ,
+        InterruptLatency::default(),
+        None,
+    );
+    let mut source = SyntheticSource::new(opts.rate);
+    crate::log::status(
+        "Benchmarking",
+        format!(
+            "{} for {:.1} s...",
+            source.describe(),
+            opts.duration.as_secs_f64()
+        ),
+    );
+
+    // Decode on a separate thread, as when tracing.
+    let (tx, rx) = channel::unbounded();
+    let stop = Arc::new(AtomicBool::new(false));
+    let decoder = {
+        let stop = stop.clone();
+        std::thread::spawn(move || -> Result<(SinkMetrics, usize), SourceError> {
+            let mut decode = SinkMetrics::default();
+            let mut high_water = 0;
+            while !stop.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                let data = match source.next() {
+                    Some(data) => data?,
+                    None => break,
+                };
+                decode.record(start.elapsed());
+                if tx.send(data).is_err() {
+                    break;
+                }
+                high_water = high_water.max(tx.len());
+            }
+
+            Ok((decode, high_water))
+        })
+    };
+
+    let (mut map, mut sink) = (SinkMetrics::default(), SinkMetrics::default());
+    let (mut packets, mut chunks, mut nonmappable) = (0, 0, 0);
+    let start = Instant::now();
+    while start.elapsed() < opts.duration {
+        let data = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(data) => data,
+            Err(channel::RecvTimeoutError::Timeout) => continue,
+            Err(channel::RecvTimeoutError::Disconnected) => break,
+        };
+
+        let t = Instant::now();
+        let chunk = metadata.build_event_chunk(&data);
+        map.record(t.elapsed());
+
+        // The work of the file and frontend sinks, short of I/O.
+        let t = Instant::now();
+        serde_json::to_writer(io::sink(), &data).map_err(SinkError::from)?;
+        serde_json::to_writer(io::sink(), &chunk).map_err(SinkError::from)?;
+        sink.record(t.elapsed());
+
+        packets += data.consumed_packets;
+        chunks += 1;
+        nonmappable += chunk
+            .events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    api::EventType::Unmappable(..) | api::EventType::Unknown(_)
+                )
+            })
+            .count();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    stop.store(true, Ordering::Relaxed);
+    drop(rx);
+    let (decode, high_water) = decoder.join().unwrap()?;
+
+    println!(
+        "{} packets in {} chunks in {:.1} s: {:.0} packets/s ({:.0} chunks/s)",
+        packets,
+        chunks,
+        elapsed,
+        packets as f64 / elapsed,
+        chunks as f64 / elapsed
+    );
+    if let Some(rate) = opts.rate {
+        if (packets as f64 / elapsed) < rate as f64 * 0.95 {
+            crate::log::warn(format!(
+                "the pipeline did not sustain the requested rate of {} packets/s",
+                rate
+            ));
+        }
+    }
+    println!("{:<8}{:>10}{:>10}", "stage", "p95 [µs]", "max [µs]");
+    println!("{}", latencies("decode", &decode));
+    println!("{}", latencies("map", &map));
+    println!("{}", latencies("sink", &sink));
+    println!("channel high-water mark: {} chunks", high_water);
+    if nonmappable > 0 {
+        crate::log::warn(format!(
+            "{} synthetic packets could not be mapped",
+            nonmappable
+        ));
+    }
+
+    Ok(())
+}
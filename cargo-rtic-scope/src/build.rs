@@ -2,10 +2,12 @@
 use crate::diag;
 
 use std::env;
-use std::io::BufReader;
+use std::fs;
+use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
+use cargo_metadata::camino::Utf8PathBuf;
 pub use cargo_metadata::Artifact;
 use cargo_metadata::Message;
 use thiserror::Error;
@@ -37,6 +39,14 @@ pub enum CargoError {
     StdoutError(#[source] std::io::Error),
     #[error("Failed to resolve the current directory: {0}")]
     CurrentDirError(#[source] std::io::Error),
+    #[error("Failed to read artifact {}: {1}", .0.display())]
+    ArtifactReadError(PathBuf, #[source] std::io::Error),
+    #[error("Found {1} executable artifacts in {} where one was expected", .0.display())]
+    ArtifactRecordError(PathBuf, usize),
+    #[error("Failed to find a bin or example target named {1} for {}", .0.display())]
+    CannotFindArtifactTarget(PathBuf, String),
+    #[error("{} is not an ELF file", .0.display())]
+    NotAnElf(PathBuf),
 }
 
 impl CargoError {
@@ -53,6 +63,12 @@ impl diag::DiagnosableError for CargoError {
                 "Modify your call so that only one {}-crate is built. Try --bin or --example.",
                 kind
             )],
+            CargoError::ArtifactRecordError(_, _) => vec![
+                "Save the output of a single `cargo build --message-format=json --bin <bin>` to pass to --artifact.".to_string(),
+            ],
+            CargoError::CannotFindArtifactTarget(_, _) => vec![
+                "Give the root source file of the target the ELF file was built from via --artifact-src, or with --elf, the source file of the RTIC application via --app-src.".to_string(),
+            ],
             CargoError::CargoBuildExecFailed(_, _) => vec!["Cargo errors/warnings are not properly propagated at the moment (see <https://github.com/rtic-scope/cargo-rtic-scope/issues/50>).".to_string(),
             "Manually build your target application with `cargo build` to see eventual errors/warnings.".to_string()],
             _ => vec![],
@@ -79,18 +95,180 @@ impl CargoWrapper {
     pub fn new(crate_root: &Path, opts: Vec<String>) -> Result<(Self, Artifact), CargoError> {
         let cargo = Self::intermediate();
         let artifact = cargo.build(crate_root, Some(opts.clone()), "bin")?;
+        let metadata = Self::resolve_metadata(&opts, artifact.executable.as_ref().unwrap())?;
 
-        // Resolve artifact metadata
-        let manifest_path = opts
-            .iter()
-            .position(|opt| opt.as_str() == "--manifest-path")
-            .and_then(|idx| opts.get(idx + 1))
-            .map(PathBuf::from)
-            .unwrap_or(find_manifest_path(&artifact)?);
-        let metadata = cargo_metadata::MetadataCommand::new()
+        Self::with_metadata(metadata, artifact)
+    }
+
+    /// Like [`CargoWrapper::new`], but instead of building the target
+    /// application, uses the pre-built `artifact`: either the JSON
+    /// messages of `cargo build --message-format=json`, of which the
+    /// single executable artifact is used, or an ELF file. The target
+    /// an ELF file was built from is the bin or example target of the
+    /// same name, unless its root source file is given as `src`. The
+    /// features it was built with are taken from `opts`.
+    pub fn prebuilt(
+        artifact: &Path,
+        src: Option<&Path>,
+        opts: Vec<String>,
+    ) -> Result<(Self, Artifact), CargoError> {
+        let read_err = |e| CargoError::ArtifactReadError(artifact.to_owned(), e);
+        let data = fs::read(artifact).map_err(read_err)?;
+        if !data.starts_with(b"\x7fELF") {
+            let mut executables = vec![];
+            for message in Message::parse_stream(&data[..]) {
+                match message.map_err(read_err)? {
+                    Message::CompilerArtifact(a) if a.executable.is_some() => executables.push(a),
+                    _ => (),
+                }
+            }
+            if executables.len() != 1 {
+                return Err(CargoError::ArtifactRecordError(
+                    artifact.to_owned(),
+                    executables.len(),
+                ));
+            }
+            let artifact = executables.remove(0);
+            let metadata = Self::resolve_metadata(&opts, artifact.executable.as_ref().unwrap())?;
+            return Self::with_metadata(metadata, artifact);
+        }
+
+        let elf = Self::canonical_elf(artifact)?;
+        let metadata = Self::resolve_metadata(&opts, &elf)?;
+        Self::elf_artifact(elf, src, &opts, metadata)
+    }
+
+    /// Like [`CargoWrapper::prebuilt`] with an ELF file, but for
+    /// firmware built outside of cargo (e.g. by CMake, linking a Rust
+    /// staticlib): the package is that of the working directory (or of
+    /// `--manifest-path` in `opts`) instead of the one the ELF file is
+    /// in, and the target is rooted at `src`. Such firmware is not built
+    /// from a bin target of the package, so `src` is required.
+    pub fn external(
+        elf: &Path,
+        src: &Path,
+        opts: Vec<String>,
+    ) -> Result<(Self, Artifact), CargoError> {
+        let mut header = [0; 4];
+        fs::File::open(elf)
+            .and_then(|mut file| file.read_exact(&mut header))
+            .map_err(|e| CargoError::ArtifactReadError(elf.to_owned(), e))?;
+        if &header != b"\x7fELF" {
+            return Err(CargoError::NotAnElf(elf.to_owned()));
+        }
+
+        let elf = Self::canonical_elf(elf)?;
+        let mut metadata = cargo_metadata::MetadataCommand::new();
+        if let Some(path) = option_value(&opts, "--manifest-path") {
+            metadata.manifest_path(path);
+        }
+        Self::elf_artifact(elf, Some(src), &opts, metadata.exec()?)
+    }
+
+    fn canonical_elf(elf: &Path) -> Result<Utf8PathBuf, CargoError> {
+        Utf8PathBuf::from_path_buf(
+            elf.canonicalize()
+                .map_err(|e| CargoError::CannotCanonicalize(elf.to_owned(), e))?,
+        )
+        .map_err(|p| {
+            CargoError::ArtifactReadError(
+                elf.to_owned(),
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} is not valid UTF-8", p.display()),
+                ),
+            )
+        })
+    }
+
+    /// The artifact of `elf`, built from the root package of
+    /// `metadata`. The target is named after the file, and rooted at
+    /// `src` if given, or else the bin or example target of that name.
+    fn elf_artifact(
+        elf: Utf8PathBuf,
+        src: Option<&Path>,
+        opts: &[String],
+        metadata: cargo_metadata::Metadata,
+    ) -> Result<(Self, Artifact), CargoError> {
+        let name = elf.file_stem().unwrap_or_default().to_string();
+        let package = metadata
+            .root_package()
+            .ok_or(CargoError::CannotFindRootPackage)?;
+        let target = match src {
+            Some(src) => serde_json::json!({
+                "name": name,
+                "kind": ["bin"],
+                "crate_types": ["bin"],
+                "src_path": src
+                    .canonicalize()
+                    .map_err(|e| CargoError::CannotCanonicalize(src.to_owned(), e))?,
+                "edition": package.edition,
+            }),
+            None => serde_json::to_value(
+                package
+                    .targets
+                    .iter()
+                    .find(|t| {
+                        t.name.replace('-', "_") == name.replace('-', "_")
+                            && t.kind.iter().any(|k| k == "bin" || k == "example")
+                    })
+                    .ok_or_else(|| {
+                        CargoError::CannotFindArtifactTarget(elf.clone().into(), name.clone())
+                    })?,
+            )
+            .unwrap(),
+        };
+        let features: Vec<String> = option_value(opts, "--features")
+            .map(|features| {
+                features
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|f| !f.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // NOTE(unwrap) all fields are given in the expected format
+        let artifact: Artifact = serde_json::from_value(serde_json::json!({
+            "package_id": package.id,
+            "target": target,
+            "profile": {
+                "opt_level": "0",
+                "debuginfo": null,
+                "debug_assertions": false,
+                "overflow_checks": false,
+                "test": false,
+            },
+            "features": features,
+            "filenames": [elf],
+            "executable": elf,
+            "fresh": true,
+        }))
+        .unwrap();
+
+        Self::with_metadata(metadata, artifact)
+    }
+
+    /// Resolves the metadata of the package `executable` was built
+    /// from, unless `--manifest-path` is given in `opts`.
+    fn resolve_metadata(
+        opts: &[String],
+        executable: &Utf8PathBuf,
+    ) -> Result<cargo_metadata::Metadata, CargoError> {
+        let manifest_path = match option_value(opts, "--manifest-path") {
+            Some(path) => PathBuf::from(path),
+            None => find_manifest_path(executable)?,
+        };
+
+        Ok(cargo_metadata::MetadataCommand::new()
             .manifest_path(&manifest_path)
-            .exec()?;
+            .exec()?)
+    }
 
+    fn with_metadata(
+        metadata: cargo_metadata::Metadata,
+        artifact: Artifact,
+    ) -> Result<(Self, Artifact), CargoError> {
         Ok((
             CargoWrapper {
                 target_dir: Some(metadata.target_directory.clone().canonicalize().map_err(
@@ -162,6 +340,7 @@ impl CargoWrapper {
             cargo.current_dir(crate_root);
         }
 
+        crate::log::debug!("running {:?}", cargo);
         let mut child = cargo
             .spawn()
             .map_err(CargoError::CargoBuildSpawnWaitError)?;
@@ -210,9 +389,17 @@ impl CargoWrapper {
     }
 }
 
-fn find_manifest_path(artifact: &cargo_metadata::Artifact) -> Result<PathBuf, CargoError> {
+/// The value of `option` in the cargo options `opts`, if given.
+fn option_value<'a>(opts: &'a [String], option: &str) -> Option<&'a str> {
+    opts.iter()
+        .position(|opt| opt.as_str() == option)
+        .and_then(|idx| opts.get(idx + 1))
+        .map(String::as_str)
+}
+
+fn find_manifest_path(executable: &Utf8PathBuf) -> Result<PathBuf, CargoError> {
     let start_path = || {
-        let mut path = artifact.executable.clone().unwrap();
+        let mut path = executable.clone();
         path.pop();
         path
     };
@@ -236,3 +423,78 @@ fn find_manifest_path(artifact: &cargo_metadata::Artifact) -> Result<PathBuf, Ca
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writes a package without dependencies to `dir`, along with a
+    /// target directory, such that its metadata resolves offline.
+    fn package(dir: &Path) -> PathBuf {
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"firmware-glue\"\nversion = \"0.1.0\"\nedition = \"2018\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("src/lib.rs"), "").unwrap();
+        dir.join("Cargo.toml")
+    }
+
+    /// Ensure an externally built ELF file is traced as the target
+    /// rooted at the given source file, of the package of the given
+    /// manifest.
+    #[test]
+    fn external() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = package(dir.path());
+        let elf = dir.path().join("firmware.elf");
+        fs::write(&elf, b"\x7fELF\x01\x01\x01").unwrap();
+        let src = dir.path().join("src/app.rs");
+        fs::write(&src, "").unwrap();
+
+        let (cargo, artifact) = CargoWrapper::external(
+            &elf,
+            &src,
+            vec![
+                "--manifest-path".to_string(),
+                manifest.display().to_string(),
+                "--features".to_string(),
+                "a, b".to_string(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(cargo.package().unwrap().name, "firmware-glue");
+        assert_eq!(artifact.target.name, "firmware");
+        assert_eq!(
+            artifact.target.src_path.as_std_path(),
+            src.canonicalize().unwrap()
+        );
+        assert_eq!(artifact.features, vec!["a", "b"]);
+        assert_eq!(
+            artifact.executable.unwrap().as_std_path(),
+            elf.canonicalize().unwrap()
+        );
+    }
+
+    /// Ensure files that are not ELF files are rejected before the
+    /// package is resolved.
+    #[test]
+    fn external_not_an_elf() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("app.rs");
+        fs::write(&src, "").unwrap();
+        let hex = dir.path().join("firmware.hex");
+        fs::write(&hex, ":020000040800F2\n").unwrap();
+
+        assert!(matches!(
+            CargoWrapper::external(&hex, &src, vec![]),
+            Err(CargoError::NotAnElf(path)) if path == hex
+        ));
+        assert!(matches!(
+            CargoWrapper::external(&dir.path().join("missing.elf"), &src, vec![]),
+            Err(CargoError::ArtifactReadError(..))
+        ));
+    }
+}
@@ -0,0 +1,418 @@
+//! The user configuration file, `~/.config/rtic-scope/config.toml` (or
+//! `$XDG_CONFIG_HOME/rtic-scope/config.toml`), with host-side defaults
+//! that apply to all crates. Command-line options and
+//! `[{package,workspace}.metadata.rtic-scope]` take precedence over it.
+//! Read and written by `cargo rtic-scope config`.
+//!
+//! Named profiles (e.g. `[profile.rack]`) describe the setups of a lab:
+//! the source of the trace stream, the probe, and the TPIU clock and
+//! baud rate. A profile selected via `--profile` sets its options as if
+//! they were given on the command line, unless they are, except for the
+//! TPIU clock and baud rate, which apply only where neither the command
+//! line nor the manifest sets them.
+use crate::diag;
+use crate::{manifest, ConfigAction, ConfigOptions, RTICScopeError};
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Neither $XDG_CONFIG_HOME nor $HOME is set")]
+    NoConfigDir,
+    #[error("Failed to read {}: {1}", .0.display())]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Failed to parse {}: {1}", .0.display())]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[error("Failed to write {}: {1}", .0.display())]
+    Write(PathBuf, #[source] io::Error),
+    #[error("Unknown configuration key {0:?}")]
+    UnknownKey(String),
+    #[error("Invalid value {value:?} for {key}: {reason}")]
+    InvalidValue {
+        key: String,
+        value: String,
+        reason: String,
+    },
+    #[error("No profile {0:?} is configured")]
+    UnknownProfile(String, Vec<String>),
+    #[error("Profile {0:?} sets both serial and jlink_swo")]
+    AmbiguousSource(String),
+}
+
+impl diag::DiagnosableError for ConfigError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::Parse(path, _) => vec![format!("Fix or remove {}.", path.display())],
+            Self::UnknownKey(_) => vec![format!("Known keys are: {}.", KEYS.join(", "))],
+            Self::UnknownProfile(_, profiles) if profiles.is_empty() => vec![format!(
+                "Add a [profile.<name>] table to {}.",
+                path().map_or("the configuration file".to_string(), |p| p
+                    .display()
+                    .to_string())
+            )],
+            Self::UnknownProfile(_, profiles) => {
+                vec![format!("Configured profiles are: {}.", profiles.join(", "))]
+            }
+            Self::AmbiguousSource(name) => vec![format!(
+                "Remove either serial or jlink_swo from [profile.{}].",
+                name
+            )],
+            _ => vec![],
+        }
+    }
+}
+
+/// The keys of the configuration file.
+const KEYS: &[&str] = &["frontends", "trace_dir", "probe_speed", "color"];
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    /// Frontends to forward trace data to, unless `frontends` is set in
+    /// the manifest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frontends: Option<Vec<String>>,
+    /// Directory of recorded traces, instead of the build cache of the
+    /// application.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_dir: Option<PathBuf>,
+    /// Speed of the probe, in kHz.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_speed: Option<u32>,
+    /// Whether to color output. By default, output is colored if
+    /// stdout is a terminal.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<bool>,
+    /// Named profiles, selected via `--profile`.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub profile: IndexMap<String, Profile>,
+}
+
+/// The options of a lab setup, each named after its command-line
+/// option. The source of the trace stream (`serial`, or `jlink_swo`
+/// and `jlink_gdb`) is only set if none is given on the command line.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// See `trace --serial`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// See `trace --jlink-swo`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jlink_swo: Option<String>,
+    /// See `trace --jlink-gdb`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jlink_gdb: Option<String>,
+    /// The probe, as VID:PID[:serial]; see `--probe`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<String>,
+    /// See `--chip`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chip: Option<String>,
+    /// See `trace --chip-filter`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chip_filter: Option<String>,
+    /// Speed of the probe, in kHz; see `--speed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_speed: Option<u32>,
+    /// See `--tpiu-freq`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tpiu_freq: Option<u32>,
+    /// See `--tpiu-baud`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tpiu_baud: Option<u32>,
+    /// Frontends to forward trace data to, in addition to those given
+    /// via `--frontend`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frontends: Option<Vec<String>>,
+    /// See `--trace-dir`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace_dir: Option<PathBuf>,
+}
+
+impl Profile {
+    /// The options set by the profile, formatted as they are set.
+    fn entries(&self) -> Vec<(String, String)> {
+        let table = serde_json::to_value(self).expect("profile is not serializable");
+        table
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(s) => s.to_owned(),
+                    serde_json::Value::Array(values) => values
+                        .iter()
+                        .filter_map(|v| v.as_str())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    value => value.to_string(),
+                };
+                (key.to_owned(), value)
+            })
+            .collect()
+    }
+}
+
+/// Location of the configuration file.
+pub fn path() -> Result<PathBuf, ConfigError> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            PathBuf::from(std::env::var_os("HOME").ok_or(ConfigError::NoConfigDir)?).join(".config")
+        }
+    };
+
+    Ok(dir.join("rtic-scope").join("config.toml"))
+}
+
+impl UserConfig {
+    /// Reads the configuration file. A missing file is an empty
+    /// configuration.
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = match path() {
+            Ok(path) => path,
+            Err(ConfigError::NoConfigDir) => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(ConfigError::Read(path, e)),
+        };
+
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    /// The profile of the given name, if any is selected.
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<&Profile>, ConfigError> {
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let profile = self
+            .profile
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownProfile(name.to_string(), self.profiles()))?;
+        if profile.serial.is_some() && profile.jlink_swo.is_some() {
+            return Err(ConfigError::AmbiguousSource(name.to_string()));
+        }
+        if let (Some(gdb), None) = (&profile.jlink_gdb, &profile.jlink_swo) {
+            return Err(ConfigError::InvalidValue {
+                key: format!("profile.{}.jlink_gdb", name),
+                value: gdb.to_owned(),
+                reason: "jlink_swo is not set".to_string(),
+            });
+        }
+
+        Ok(Some(profile))
+    }
+
+    /// The names of the configured profiles.
+    fn profiles(&self) -> Vec<String> {
+        self.profile.keys().cloned().collect()
+    }
+
+    fn store(&self) -> Result<PathBuf, ConfigError> {
+        let path = path()?;
+        let content = toml::to_string(self).expect("configuration is not serializable");
+        fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&path, content))
+            .map_err(|e| ConfigError::Write(path.clone(), e))?;
+
+        Ok(path)
+    }
+
+    /// The value of `key`, formatted as it is set.
+    pub fn get(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        Ok(match key {
+            "frontends" => self.frontends.as_ref().map(|f| f.join(",")),
+            "trace_dir" => self.trace_dir.as_ref().map(|d| d.display().to_string()),
+            "probe_speed" => self.probe_speed.map(|s| s.to_string()),
+            "color" => self.color.map(|c| c.to_string()),
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        })
+    }
+
+    /// Sets `key` to `value`, or unsets it. Frontends are given as a
+    /// comma-separated list.
+    pub fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError>
+        where
+            T::Err: ToString,
+        {
+            value
+                .parse()
+                .map_err(|e: T::Err| ConfigError::InvalidValue {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    reason: e.to_string(),
+                })
+        }
+
+        match key {
+            "frontends" => {
+                self.frontends = value.map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|f| !f.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+            }
+            "trace_dir" => self.trace_dir = value.map(PathBuf::from),
+            "probe_speed" => self.probe_speed = value.map(|v| parse(key, v)).transpose()?,
+            "color" => self.color = value.map(|v| parse(key, v)).transpose()?,
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints the effective value of each key and where it is set,
+/// considering the manifest of the crate in the current directory, if
+/// any.
+fn show(config: &UserConfig) -> Result<(), RTICScopeError> {
+    let metadata = cargo_metadata::MetadataCommand::new().no_deps().exec().ok();
+    let (manifest_frontends, _) = match &metadata {
+        Some(metadata) => manifest::default_frontends(metadata)?,
+        None => (vec![], manifest::Provenance::Default),
+    };
+
+    for key in KEYS {
+        let (value, origin) = match (*key, config.get(key)?) {
+            ("frontends", _) if !manifest_frontends.is_empty() => {
+                (manifest_frontends.join(","), "manifest")
+            }
+            (_, Some(value)) => (value, "user configuration"),
+            ("frontends", None) => ("dummy".to_string(), "default"),
+            ("trace_dir", None) if metadata.is_some() => (
+                metadata
+                    .as_ref()
+                    .unwrap()
+                    .target_directory
+                    .join("rtic-traces")
+                    .to_string(),
+                "default",
+            ),
+            (_, None) => ("(unset)".to_string(), "default"),
+        };
+        println!("{} = {} ({})", key, value, origin);
+    }
+    for (name, profile) in config.profile.iter() {
+        for (key, value) in profile.entries() {
+            println!("profile.{}.{} = {} (user configuration)", name, key, value);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run(opts: &ConfigOptions) -> Result<(), RTICScopeError> {
+    let action = match &opts.action {
+        Some(action) => action,
+        None => {
+            crate::log::status("Reading", format!("{}", path()?.display()));
+            return show(&UserConfig::load()?);
+        }
+    };
+
+    let mut config = UserConfig::load()?;
+    match action {
+        ConfigAction::Get { key } => {
+            if let Some(value) = config.get(key)? {
+                println!("{}", value);
+            }
+        }
+        ConfigAction::Set { key, value } => {
+            config.set(key, Some(value))?;
+            let path = config.store()?;
+            crate::log::status(
+                "Configured",
+                format!("{} = {} in {}", key, value, path.display()),
+            );
+        }
+        ConfigAction::Unset { key } => {
+            config.set(key, None)?;
+            let path = config.store()?;
+            crate::log::status("Configured", format!("unset {} in {}", key, path.display()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure values are set, read back, and unset by key.
+    #[test]
+    fn set_get() {
+        let mut config = UserConfig::default();
+        config
+            .set("frontends", Some("dummy, ./my-frontend"))
+            .unwrap();
+        config.set("probe_speed", Some("4000")).unwrap();
+        assert_eq!(
+            config.frontends,
+            Some(vec!["dummy".to_string(), "./my-frontend".to_string()])
+        );
+        assert_eq!(
+            config.get("frontends").unwrap().as_deref(),
+            Some("dummy,./my-frontend")
+        );
+        assert_eq!(config.probe_speed, Some(4000));
+
+        assert!(config.set("probe_speed", Some("fast")).is_err());
+        assert!(config.set("speed", Some("4000")).is_err());
+        config.set("probe_speed", None).unwrap();
+        assert_eq!(config.get("probe_speed").unwrap(), None);
+    }
+
+    /// Ensure profiles are selected by name, and that profiles with
+    /// conflicting sources are rejected.
+    #[test]
+    fn profile() {
+        let config: UserConfig = toml::from_str(
+            r#"
+            probe_speed = 4000
+
+            [profile.desk]
+            probe = "0483:374b"
+            tpiu_baud = 2000000
+
+            [profile.rack]
+            jlink_swo = "rack-3:2332"
+            serial = "/dev/ttyUSB0"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.profile(None).unwrap(), None);
+        let desk = config.profile(Some("desk")).unwrap().unwrap();
+        assert_eq!(desk.tpiu_baud, Some(2_000_000));
+        assert_eq!(
+            desk.entries(),
+            [
+                ("probe".to_string(), "0483:374b".to_string()),
+                ("tpiu_baud".to_string(), "2000000".to_string())
+            ]
+        );
+        assert!(matches!(
+            config.profile(Some("rack")),
+            Err(ConfigError::AmbiguousSource(_))
+        ));
+        match config.profile(Some("lab")) {
+            Err(ConfigError::UnknownProfile(_, profiles)) => assert_eq!(profiles, ["desk", "rack"]),
+            _ => panic!("profile lab should be unknown"),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Analysis of the traces of the cores of a multi-core target as one
+//! dataset, as done by `stats --merge` and `export --merge`. Each core
+//! is traced in a session of its own via `trace --core`; the chunks of
+//! the traces are merged in order of their offset from target reset.
+use crate::gts::GlobalTimestamps;
+use crate::recovery::TraceMetadata;
+use crate::sources::{FileSource, SourceError};
+use crate::timestamps::{offset, shift_chunk};
+use crate::{RTICScopeError, TraceData};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use rtic_scope_api as api;
+
+/// The trace of a single core.
+struct CoreTrace {
+    metadata: TraceMetadata,
+    src: FileSource,
+    /// Whether timestamps are corrected for drift.
+    drift_correct: bool,
+    /// Offset that moves the sync anchor of the trace to that of the
+    /// first trace, and whether it is added or subtracted.
+    shift: (Duration, bool),
+    /// The next chunk of the trace, if read ahead.
+    next: Option<(TraceData, api::EventChunk)>,
+    gts: GlobalTimestamps,
+}
+
+impl CoreTrace {
+    /// Reads the next chunk of the trace, unless already read ahead.
+    fn peek(&mut self) -> Result<Option<&(TraceData, api::EventChunk)>, SourceError> {
+        if self.next.is_none() {
+            if let Some(data) = self.src.next() {
+                let data = data?;
+                let mut chunk = self.metadata.build_event_chunk(&data);
+                self.gts
+                    .update(&data, &mut chunk, self.metadata.tpiu_freq());
+                if let Some(drift) = self.metadata.drift().filter(|_| self.drift_correct) {
+                    drift.correct_chunk(&mut chunk);
+                }
+                shift_chunk(&mut chunk, self.shift);
+                self.next = Some((data, chunk));
+            }
+        }
+
+        Ok(self.next.as_ref())
+    }
+}
+
+/// The chunks of one or more traces, in order of their offset from
+/// target reset. Each chunk is yielded along with the index of the
+/// trace it was read from. Chunks of a trace recorded without `--core`
+/// are attributed to the core of the index of the trace.
+pub struct MergedTraces {
+    traces: Vec<CoreTrace>,
+}
+
+impl MergedTraces {
+    /// Opens the given traces. If all traces have a sync anchor (see
+    /// `cortex_m_rtic_trace::sync_pulse`), the timestamps of each trace
+    /// are shifted such that its anchor coincides with that of the
+    /// first trace. Otherwise, the cores are assumed to have been reset
+    /// at the same time.
+    pub fn open(paths: &[PathBuf], drift_correct: bool) -> Result<Self, RTICScopeError> {
+        Self::open_range(paths, drift_correct, None, None)
+    }
+
+    /// As [`MergedTraces::open`], but only reads the trace data of each
+    /// trace between the offsets from target reset `from` and `to`, as
+    /// recorded. See [`FileSource::open`].
+    pub fn open_range(
+        paths: &[PathBuf],
+        drift_correct: bool,
+        from: Option<Duration>,
+        to: Option<Duration>,
+    ) -> Result<Self, RTICScopeError> {
+        let mut traces = vec![];
+        for (i, path) in paths.iter().enumerate() {
+            let src = FileSource::open(path, from, to)?;
+            let mut metadata = src.metadata();
+            if drift_correct && metadata.drift().is_none() {
+                crate::log::warn(format!(
+                    "{} contains no drift estimate; timestamps will not be corrected",
+                    path.display()
+                ));
+            }
+            if paths.len() > 1 && metadata.core().is_none() {
+                metadata.set_core(Some(i as u8));
+            }
+            traces.push(CoreTrace {
+                metadata,
+                src,
+                drift_correct,
+                shift: (Duration::ZERO, true),
+                next: None,
+                gts: GlobalTimestamps::default(),
+            });
+        }
+
+        let anchors: Option<Vec<Duration>> = traces
+            .iter()
+            .map(|trace| trace.metadata.sync_anchor())
+            .collect();
+        match anchors {
+            Some(anchors) => {
+                for (trace, anchor) in traces.iter_mut().zip(anchors.iter()) {
+                    trace.shift = if anchor <= &anchors[0] {
+                        (anchors[0] - *anchor, true)
+                    } else {
+                        (*anchor - anchors[0], false)
+                    };
+                }
+            }
+            None if traces.len() > 1 => crate::log::warn(
+                "not all traces contain a sync anchor; cores are assumed to have been reset at the same time".to_string(),
+            ),
+            None => (),
+        }
+
+        Ok(Self { traces })
+    }
+
+    /// The metadata of each trace, in the order given.
+    pub fn metadata(&self) -> impl Iterator<Item = &TraceMetadata> {
+        self.traces.iter().map(|trace| &trace.metadata)
+    }
+
+    /// Whether more than one trace is merged.
+    pub fn is_merged(&self) -> bool {
+        self.traces.len() > 1
+    }
+}
+
+impl Iterator for MergedTraces {
+    type Item = Result<(usize, TraceData, api::EventChunk), SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut earliest: Option<(usize, Duration)> = None;
+        for (i, trace) in self.traces.iter_mut().enumerate() {
+            match trace.peek() {
+                Ok(Some((_, chunk))) => {
+                    let at = offset(&chunk.timestamp);
+                    if earliest.map_or(true, |(_, earliest)| at < earliest) {
+                        earliest = Some((i, at));
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        let (i, _) = earliest?;
+        let (data, chunk) = self.traces[i].next.take().unwrap();
+        Some(Ok((i, data, chunk)))
+    }
+}
+
+/// Utilization of a core: the fraction of the trace during which any
+/// task executed on it.
+#[derive(Default)]
+pub struct Utilization {
+    /// Number of executing tasks, including preempted ones.
+    depth: usize,
+    /// When the core last became busy, if it is.
+    busy_since: Option<Duration>,
+    busy: Duration,
+    first: Option<Duration>,
+    last: Duration,
+}
+
+impl Utilization {
+    /// Accounts the task events of `chunk`.
+    pub fn update(&mut self, chunk: &api::EventChunk) {
+        let now = offset(&chunk.timestamp);
+        self.first.get_or_insert(now);
+        self.last = self.last.max(now);
+        for event in chunk.events.iter() {
+            match event {
+                api::EventType::Task {
+                    action: api::TaskAction::Entered,
+                    ..
+                } => {
+                    self.depth += 1;
+                    self.busy_since.get_or_insert(now);
+                }
+                api::EventType::Task {
+                    action: api::TaskAction::Exited,
+                    ..
+                } if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        if let Some(since) = self.busy_since.take() {
+                            self.busy += now.saturating_sub(since);
+                        }
+                    }
+                }
+                // NOTE(noop) the state of the core is unknown after an
+                // overflow.
+                api::EventType::Overflow => {
+                    self.depth = 0;
+                    self.busy_since = None;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Utilization in percent, if the trace spans any time.
+    pub fn percent(&self) -> Option<f64> {
+        let span = self.last.saturating_sub(self.first?);
+        if span.is_zero() {
+            return None;
+        }
+
+        Some(100.0 * self.busy.as_secs_f64() / span.as_secs_f64())
+    }
+}
+
+/// A software task spawned on another core than the one it executes
+/// on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CrossCoreSpawn {
+    pub from_core: u8,
+    pub from: String,
+    pub to_core: u8,
+    pub to: String,
+}
+
+/// Collects the spawns of software tasks across cores. As a spawn does
+/// not tell which core the spawned task executes on, spawns are related
+/// to cores once all executions are known.
+#[derive(Default)]
+pub struct CrossCoreSpawns {
+    /// The cores each task executed on.
+    executed_on: HashMap<String, Vec<u8>>,
+    /// (core, from, to) of each spawn, with the number of times it
+    /// occurred.
+    spawns: IndexMap<(u8, String, String), usize>,
+}
+
+impl CrossCoreSpawns {
+    /// Accounts the task and spawn events of `chunk`, traced from
+    /// `core`.
+    pub fn update(&mut self, core: u8, chunk: &api::EventChunk) {
+        for event in chunk.events.iter() {
+            match event {
+                api::EventType::Task {
+                    name,
+                    action: api::TaskAction::Entered,
+                    ..
+                } => {
+                    let cores = self.executed_on.entry(name.to_owned()).or_default();
+                    if !cores.contains(&core) {
+                        cores.push(core);
+                    }
+                }
+                api::EventType::Spawn {
+                    from,
+                    to,
+                    rejected: false,
+                } => {
+                    *self
+                        .spawns
+                        .entry((core, from.to_owned(), to.to_owned()))
+                        .or_default() += 1;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// The spawns of tasks that executed on another core than the
+    /// spawner, with the number of times each occurred.
+    pub fn finish(&self) -> Vec<(CrossCoreSpawn, usize)> {
+        let mut spawns = vec![];
+        for ((from_core, from, to), count) in self.spawns.iter() {
+            for to_core in self.executed_on.get(to).into_iter().flatten() {
+                if to_core != from_core {
+                    spawns.push((
+                        CrossCoreSpawn {
+                            from_core: *from_core,
+                            from: from.to_owned(),
+                            to_core: *to_core,
+                            to: to.to_owned(),
+                        },
+                        *count,
+                    ));
+                }
+            }
+        }
+
+        spawns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, task};
+
+    /// Ensure a core is busy from the entry of a task until no task is
+    /// executing, preemptions included.
+    #[test]
+    fn utilization() {
+        use api::TaskAction::*;
+
+        let ms = Duration::from_millis;
+        let mut util = Utilization::default();
+        util.update(&chunk(ms(0), vec![]));
+        util.update(&chunk(ms(2), vec![task("a", Entered)]));
+        util.update(&chunk(ms(3), vec![task("b", Entered)]));
+        util.update(&chunk(ms(4), vec![task("b", Exited)]));
+        util.update(&chunk(ms(5), vec![task("a", Exited)]));
+        util.update(&chunk(ms(10), vec![]));
+        assert!((util.percent().unwrap() - 30.0).abs() < 1e-9);
+    }
+
+    /// Ensure only spawns of tasks that executed on another core are
+    /// reported.
+    #[test]
+    fn cross_core_spawns() {
+        let ms = Duration::from_millis;
+        let spawn = |from: &str, to: &str| api::EventType::Spawn {
+            from: from.to_string(),
+            to: to.to_string(),
+            rejected: false,
+        };
+
+        let mut spawns = CrossCoreSpawns::default();
+        spawns.update(0, &chunk(ms(1), vec![spawn("app0::a", "app1::b")]));
+        spawns.update(0, &chunk(ms(2), vec![spawn("app0::a", "app0::c")]));
+        spawns.update(
+            1,
+            &chunk(ms(3), vec![task("app1::b", api::TaskAction::Entered)]),
+        );
+        spawns.update(
+            0,
+            &chunk(ms(4), vec![task("app0::c", api::TaskAction::Entered)]),
+        );
+        spawns.update(0, &chunk(ms(5), vec![spawn("app0::a", "app1::b")]));
+        assert_eq!(
+            spawns.finish(),
+            vec![(
+                CrossCoreSpawn {
+                    from_core: 0,
+                    from: "app0::a".to_string(),
+                    to_core: 1,
+                    to: "app1::b".to_string(),
+                },
+                2
+            )]
+        );
+    }
+}
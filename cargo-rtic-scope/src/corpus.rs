@@ -0,0 +1,274 @@
+//! Regression corpus of raw trace streams that have troubled the decode
+//! and map pipeline, as run by `cargo rtic-scope corpus`.
+//!
+//! Each file of the corpus directory with the `raw` extension is
+//! replayed as by `replay --raw-file`, but against the lookup maps of
+//! the application emulated by
+//! [`SyntheticSource`](crate::sources::SyntheticSource), such that the
+//! outcome does not depend on the application at hand. The resulting
+//! event chunks are compared with those recorded in the file of the
+//! same name with the `events` extension, which must exist. A panic
+//! anywhere in the pipeline fails the file.
+use crate::manifest::{InterruptLatency, ManifestProperties};
+use crate::recovery::{TraceLookupMaps, TraceMetadata};
+use crate::sources::{synthetic, RawFileSource};
+use crate::{CorpusOptions, RTICScopeError};
+
+use std::fs;
+use std::io::{self, Cursor};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CorpusError {
+    #[error("Failed to read the corpus at {}: {1}", .0.display())]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Failed to write {}: {1}", .0.display())]
+    Write(PathBuf, #[source] io::Error),
+    #[error("{failed} of {total} corpus file(s) failed")]
+    Failed { failed: usize, total: usize },
+    #[error("Fuzzing requires cargo-rtic-scope to be built with the fuzz feature")]
+    FuzzDisabled,
+}
+
+impl crate::diag::DiagnosableError for CorpusError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::Failed { .. } => vec![
+                "If the changed events are intended, or the corpus files are new, record them with `cargo rtic-scope corpus --bless`.".to_string(),
+            ],
+            Self::FuzzDisabled => vec![
+                "Install with `cargo install cargo-rtic-scope --features fuzz`.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// Decodes and maps the raw trace `bytes` as `replay --raw-file` would.
+/// Returns the event chunks as JSON lines, followed by the error that
+/// ended the stream, if any. Returns the panic message if the pipeline
+/// panicked.
+pub fn replay(bytes: Vec<u8>) -> Result<String, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let manip = ManifestProperties::synthetic();
+        let metadata = TraceMetadata::from(
+            "corpus".to_string(),
+            TraceLookupMaps::synthetic(),
+            chrono::Local::now(),
+            synthetic::FREQ,
+            InterruptLatency::default(),
+            None,
+        );
+
+        let mut events = String::new();
+        let src = match RawFileSource::from_reader(
+            Cursor::new(bytes),
+            "corpus".to_string(),
+            &manip,
+            Box::new(manip.decoder),
+            0,
+            None,
+        ) {
+            Ok(src) => src,
+            Err(e) => return format!("error: {}\n", e),
+        };
+        for data in src {
+            match data {
+                Ok(data) => {
+                    let chunk = metadata.build_event_chunk(&data);
+                    events.push_str(&serde_json::to_string(&chunk).unwrap());
+                    events.push('\n');
+                }
+                Err(e) => {
+                    events.push_str(&format!("error: {}\n", e));
+                    break;
+                }
+            }
+        }
+
+        events
+    }))
+    .map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string())
+    })
+}
+
+/// The raw trace files of the corpus in `dir`, in order of name.
+fn raw_files(dir: &Path) -> Result<Vec<PathBuf>, CorpusError> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect())
+        .map_err(|e| CorpusError::Read(dir.to_owned(), e))?;
+    files.retain(|path| path.extension().map_or(false, |ext| ext == "raw"));
+    files.sort();
+
+    Ok(files)
+}
+
+/// Mutates corpus files for the given number of iterations, and adds
+/// the mutations that make the pipeline panic to the corpus in `dir`.
+#[cfg(feature = "fuzz")]
+fn fuzz(dir: &Path, corpus: &[Vec<u8>], iterations: usize) -> Result<usize, CorpusError> {
+    // xorshift64*; reproducibility matters more than quality.
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut rand = |n: usize| {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        (state.wrapping_mul(0x2545_f491_4f6c_dd1d) % n.max(1) as u64) as usize
+    };
+
+    let mut found = 0;
+    for i in 0..iterations {
+        let mut input = corpus.get(rand(corpus.len())).cloned().unwrap_or_default();
+        for _ in 0..=rand(8) {
+            let pos = rand(input.len() + 1);
+            match rand(4) {
+                0 if pos < input.len() => input[pos] ^= 1 << rand(8),
+                1 => input.insert(pos, rand(256) as u8),
+                2 if pos < input.len() => {
+                    input.remove(pos);
+                }
+                _ => input.truncate(pos),
+            }
+        }
+
+        if let Err(msg) = replay(input.clone()) {
+            let path = dir.join(format!("fuzz-{}.raw", i));
+            fs::write(&path, &input).map_err(|e| CorpusError::Write(path.clone(), e))?;
+            crate::log::warn(format!("{} panics: {}", path.display(), msg));
+            found += 1;
+        }
+    }
+
+    Ok(found)
+}
+
+pub fn run(opts: &CorpusOptions) -> Result<(), RTICScopeError> {
+    let files = raw_files(&opts.dir)?;
+    crate::log::status(
+        "Replaying",
+        format!("{} corpus file(s) in {}", files.len(), opts.dir.display()),
+    );
+
+    // Panics are reported per file instead.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut failed = 0;
+    let mut corpus = vec![];
+    for raw in files.iter() {
+        let bytes = fs::read(raw).map_err(|e| CorpusError::Read(raw.clone(), e))?;
+        let expected_path = raw.with_extension("events");
+        let outcome = replay(bytes.clone());
+        corpus.push(bytes);
+
+        let events = match outcome {
+            Ok(events) => events,
+            Err(msg) => {
+                crate::log::warn(format!("{} panics: {}", raw.display(), msg));
+                failed += 1;
+                continue;
+            }
+        };
+        if opts.bless {
+            fs::write(&expected_path, &events)
+                .map_err(|e| CorpusError::Write(expected_path.clone(), e))?;
+            continue;
+        }
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == events => (),
+            Ok(_) => {
+                crate::log::warn(format!(
+                    "{} yields other events than recorded in {}",
+                    raw.display(),
+                    expected_path.display()
+                ));
+                failed += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                crate::log::warn(format!(
+                    "{} has no events recorded in {}",
+                    raw.display(),
+                    expected_path.display()
+                ));
+                failed += 1;
+            }
+            Err(e) => return Err(CorpusError::Read(expected_path, e).into()),
+        }
+    }
+
+    let fuzzed = match opts.fuzz {
+        #[cfg(feature = "fuzz")]
+        Some(iterations) => fuzz(&opts.dir, &corpus, iterations),
+        #[cfg(not(feature = "fuzz"))]
+        Some(_) => Err(CorpusError::FuzzDisabled),
+        None => Ok(0),
+    };
+    panic::set_hook(hook);
+    let fuzzed = fuzzed?;
+
+    if failed + fuzzed > 0 {
+        return Err(CorpusError::Failed {
+            failed: failed + fuzzed,
+            total: files.len() + fuzzed,
+        }
+        .into());
+    }
+    crate::log::status(
+        "Passed",
+        format!(
+            "{} corpus file(s){}",
+            files.len(),
+            if opts.bless { "; events recorded" } else { "" }
+        ),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use rtic_scope_api as api;
+    use std::path::Path;
+
+    /// Ensure the streams that have troubled the pipeline the most do
+    /// not make it panic, map to the same events on every replay, and
+    /// map to the events recorded for them.
+    #[test]
+    fn worst_offenders() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+        for name in [
+            "truncated-packet",
+            "invalid-headers",
+            "endless-timestamp",
+            "tpiu-garbage",
+        ] {
+            let raw = dir.join(name).with_extension("raw");
+            let bytes = std::fs::read(&raw).unwrap();
+            let events = super::replay(bytes.clone()).unwrap();
+            assert_eq!(super::replay(bytes).unwrap(), events, "{}", name);
+
+            // Only the error that ended the stream may follow the
+            // chunks.
+            let mut lines = events.lines().peekable();
+            while let Some(line) = lines.next() {
+                if lines.peek().is_none() && line.starts_with("error: ") {
+                    break;
+                }
+                serde_json::from_str::<api::EventChunk>(line)
+                    .unwrap_or_else(|e| panic!("{}: {:?}: {}", name, line, e));
+            }
+
+            if let Ok(expected) = std::fs::read_to_string(raw.with_extension("events")) {
+                assert_eq!(events, expected, "{}", name);
+            }
+        }
+    }
+}
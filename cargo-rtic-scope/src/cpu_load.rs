@@ -0,0 +1,83 @@
+//! Host-side estimation of the target CPU load from DWT sleep counter
+//! overflow events.
+use crate::timestamps::{cycles, offset};
+use crate::TraceData;
+
+use std::time::Duration;
+
+use itm::TracePacket;
+
+/// The DWT sleep counter (SLEEPCNT) is 8 bits wide: an overflow event
+/// is emitted for every 256 cycles the processor sleeps.
+const SLEEPCNT_WRAP: f64 = 256.0;
+
+/// Length of the trace time window over which each load sample is
+/// calculated.
+const SAMPLE_WINDOW: Duration = Duration::from_millis(100);
+
+/// Estimates the CPU load per [`SAMPLE_WINDOW`] of trace time by
+/// comparing the number of sleep cycles against the number of elapsed
+/// cycles. Only yields samples after the first sleep counter overflow
+/// has been received: targets are not expected to emit
+/// [`TracePacket::EventCounterWrap`] packets unless configured to do
+/// so via `cortex_m_rtic_trace::enable_cpu_load_events`.
+#[derive(Default)]
+pub struct CpuLoad {
+    window_start: Option<Duration>,
+    sleep_wraps: usize,
+    samples: usize,
+    sum: f64,
+    max: f32,
+}
+
+impl CpuLoad {
+    /// Accounts the sleep counter overflows in `data`. Returns the
+    /// CPU load in percent if a sample window was completed. `freq` is
+    /// the frequency of the timestamp clock, which is assumed to be
+    /// the core clock.
+    pub fn update(&mut self, data: &TraceData, freq: u32) -> Option<f32> {
+        let wraps = data
+            .packets
+            .iter()
+            .filter(|packet| matches!(packet, TracePacket::EventCounterWrap { sleep: true, .. }))
+            .count();
+        let now = offset(&data.timestamp);
+
+        let start = match self.window_start {
+            Some(start) => start,
+            None if wraps > 0 => {
+                self.window_start = Some(now);
+                return None;
+            }
+            None => return None,
+        };
+
+        self.sleep_wraps += wraps;
+        let elapsed = now.saturating_sub(start);
+        if elapsed < SAMPLE_WINDOW {
+            return None;
+        }
+
+        let sleep_cycles = self.sleep_wraps as f64 * SLEEPCNT_WRAP;
+        let percent =
+            (100.0 * (1.0 - sleep_cycles / cycles(elapsed, freq) as f64)).clamp(0.0, 100.0) as f32;
+
+        self.window_start = Some(now);
+        self.sleep_wraps = 0;
+        self.samples += 1;
+        self.sum += percent as f64;
+        self.max = self.max.max(percent);
+
+        Some(percent)
+    }
+
+    /// Average and maximum CPU load in percent over all samples, if
+    /// any.
+    pub fn summary(&self) -> Option<(f32, f32)> {
+        if self.samples == 0 {
+            return None;
+        }
+
+        Some(((self.sum / self.samples as f64) as f32, self.max))
+    }
+}
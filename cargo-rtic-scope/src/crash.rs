@@ -0,0 +1,299 @@
+//! Capture of why a traced target crashed. When the target enters the
+//! HardFault exception, the core is halted and its fault status
+//! registers are read along with the last panic message left by
+//! `panic-persist`, if the firmware uses it. The crash ends the trace;
+//! see [`api::EventType::Crash`](rtic_scope_api::EventType::Crash).
+//!
+//! Faults the target handles are instead reported by the fault handler
+//! itself via `cortex_m_rtic_trace::trace_fault`; see [`FaultRecords`].
+use crate::recovery::FAULT_PORT;
+use crate::TraceData;
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use cortex_m::peripheral::scb::Exception;
+use itm::{ExceptionAction, TracePacket, VectActive};
+use object::{Object, ObjectSymbol};
+use probe_rs::{MemoryInterface, Session};
+use rtic_scope_api as api;
+
+/// Configurable Fault Status Register.
+const CFSR: u32 = 0xe000_ed28;
+/// HardFault Status Register.
+const HFSR: u32 = 0xe000_ed2c;
+/// MemManage Fault Address Register.
+const MMFAR: u32 = 0xe000_ed34;
+/// BusFault Address Register.
+const BFAR: u32 = 0xe000_ed38;
+
+/// Value at the start of the `panic-persist` region if it holds a
+/// message, followed by the length of the message and the message.
+const PANIC_DUMP_MAGIC: u32 = 0x0fac_ade0;
+
+/// Whether the HardFault exception was entered in `data`.
+pub fn hard_fault_entered(data: &TraceData) -> bool {
+    data.packets.iter().any(|packet| {
+        matches!(
+            packet,
+            TracePacket::ExceptionTrace {
+                exception: VectActive::Exception(Exception::HardFault),
+                action: ExceptionAction::Entered,
+            }
+        )
+    })
+}
+
+/// The causes of a fault flagged in CFSR and HFSR.
+fn fault_causes(cfsr: u32, hfsr: u32) -> Vec<&'static str> {
+    let flags = [
+        (hfsr, 1, "vector table read fault"),
+        (hfsr, 30, "escalated to HardFault"),
+        (cfsr, 0, "instruction access violation"),
+        (cfsr, 1, "data access violation"),
+        (cfsr, 3, "MemManage fault on exception return unstacking"),
+        (cfsr, 4, "MemManage fault on exception entry stacking"),
+        (cfsr, 5, "MemManage fault during lazy FP state preservation"),
+        (cfsr, 8, "instruction bus error"),
+        (cfsr, 9, "precise data bus error"),
+        (cfsr, 10, "imprecise data bus error"),
+        (cfsr, 11, "BusFault on exception return unstacking"),
+        (cfsr, 12, "BusFault on exception entry stacking"),
+        (cfsr, 13, "BusFault during lazy FP state preservation"),
+        (cfsr, 16, "undefined instruction"),
+        (cfsr, 17, "invalid EPSR state"),
+        (cfsr, 18, "invalid EXC_RETURN"),
+        (cfsr, 19, "no coprocessor"),
+        (cfsr, 24, "unaligned access"),
+        (cfsr, 25, "divide by zero"),
+    ];
+
+    flags
+        .iter()
+        .filter(|(reg, bit, _)| reg & (1 << bit) != 0)
+        .map(|(_, _, cause)| *cause)
+        .collect()
+}
+
+/// Describes the causes of a fault flagged in `cfsr`, and the faulting
+/// address, if any.
+pub fn describe_fault(cfsr: u32, address: Option<u32>) -> String {
+    let mut causes: Vec<String> = fault_causes(cfsr, 0)
+        .iter()
+        .map(|cause| cause.to_string())
+        .collect();
+    if let Some(address) = address {
+        causes.push(format!("address {:#010x}", address));
+    }
+    format!("{} (CFSR={:#010x})", causes.join(", "), cfsr)
+}
+
+/// Decodes the fault records written on [`FAULT_PORT`] by
+/// `cortex_m_rtic_trace::trace_fault`: the exception number of the
+/// fault in a 1-byte write, followed by CFSR and the faulting address in
+/// 4-byte writes. A record may span chunks.
+#[derive(Default)]
+pub struct FaultRecords {
+    /// The fault of the record being decoded, and its CFSR once
+    /// written.
+    record: Option<(api::FaultKind, Option<u32>)>,
+}
+
+impl FaultRecords {
+    /// Appends an [`api::EventType::Fault`] to `chunk` for each record
+    /// completed in `data`.
+    pub fn update(&mut self, data: &TraceData, chunk: &mut api::EventChunk) {
+        for packet in data.packets.iter() {
+            let payload = match packet {
+                TracePacket::Instrumentation { port, payload } if *port == FAULT_PORT => payload,
+                // NOTE the rest of the record may have been lost.
+                TracePacket::Overflow => {
+                    self.record = None;
+                    continue;
+                }
+                _ => continue,
+            };
+
+            self.record = match (payload.as_slice(), self.record.take()) {
+                (&[exception], _) => fault_kind(exception).map(|kind| (kind, None)),
+                (&[a, b, c, d], Some((kind, None))) => {
+                    Some((kind, Some(u32::from_le_bytes([a, b, c, d]))))
+                }
+                (&[a, b, c, d], Some((kind, Some(cfsr)))) => {
+                    // Fault addresses are only valid if flagged so.
+                    let valid = cfsr & (1 << 7 | 1 << 15) != 0;
+                    chunk.events.push(api::EventType::Fault {
+                        kind,
+                        cfsr,
+                        address: valid.then(|| u32::from_le_bytes([a, b, c, d])),
+                    });
+                    None
+                }
+                _ => None,
+            };
+        }
+    }
+}
+
+/// The fault of exception number `exception`, if it is a fault.
+fn fault_kind(exception: u8) -> Option<api::FaultKind> {
+    match exception {
+        3 => Some(api::FaultKind::HardFault),
+        4 => Some(api::FaultKind::MemManage),
+        5 => Some(api::FaultKind::BusFault),
+        6 => Some(api::FaultKind::UsageFault),
+        _ => None,
+    }
+}
+
+/// Captures the state of a crashed target.
+pub struct CrashCapture {
+    /// Index of the traced core.
+    core: u8,
+    /// Start and end address of the `panic-persist` region, if the
+    /// firmware has one.
+    panic_dump: Option<(u32, u32)>,
+}
+
+impl CrashCapture {
+    /// Locates the `panic-persist` region in the firmware `elf`, if any.
+    /// `core` is the index of the traced core.
+    pub fn new(elf: &Path, core: u8) -> Self {
+        let panic_dump = fs::read(elf).ok().and_then(|data| {
+            let file = object::File::parse(&*data).ok()?;
+            let symbol = |name: &str| {
+                file.symbols()
+                    .find(|sym| sym.name().ok() == Some(name))
+                    .map(|sym| sym.address() as u32)
+            };
+            Some((symbol("_panic_dump_start")?, symbol("_panic_dump_end")?))
+        });
+
+        Self { core, panic_dump }
+    }
+
+    /// Halts the target and describes why it crashed: the fault causes
+    /// and addresses, the raw fault status registers, and the last
+    /// panic message. Whatever cannot be read from the target is
+    /// omitted.
+    pub fn capture(&self, session: &mut Session) -> String {
+        let mut details = "HardFault".to_string();
+        let mut core = match session.core(self.core as usize) {
+            Ok(core) => core,
+            Err(e) => return format!("{} (target state unavailable: {})", details, e),
+        };
+        if let Err(e) = core.halt(Duration::from_millis(100)) {
+            return format!("{} (failed to halt target: {})", details, e);
+        }
+
+        let mut read = |addr| core.read_word_32(addr).ok();
+        if let (Some(cfsr), Some(hfsr)) = (read(CFSR), read(HFSR)) {
+            let mut causes: Vec<String> = fault_causes(cfsr, hfsr)
+                .iter()
+                .map(|cause| cause.to_string())
+                .collect();
+            // Fault addresses are only valid if flagged so.
+            for (valid, addr, kind) in [(7, MMFAR, "MemManage"), (15, BFAR, "BusFault")] {
+                if cfsr & (1 << valid) != 0 {
+                    if let Some(addr) = read(addr) {
+                        causes.push(format!("{} address {:#010x}", kind, addr));
+                    }
+                }
+            }
+            details = format!(
+                "{} ({}; CFSR={:#010x} HFSR={:#010x})",
+                details,
+                causes.join(", "),
+                cfsr,
+                hfsr
+            );
+        }
+
+        if let Some(msg) = self
+            .panic_dump
+            .and_then(|region| panic_message(&mut core, region))
+        {
+            details = format!("{}; last panic: {}", details, msg.trim_end());
+        }
+
+        details
+    }
+}
+
+/// Reads the message in the `panic-persist` region from `start` to
+/// `end`, if any.
+fn panic_message(core: &mut probe_rs::Core, (start, end): (u32, u32)) -> Option<String> {
+    if core.read_word_32(start).ok()? != PANIC_DUMP_MAGIC {
+        return None;
+    }
+    let len = core.read_word_32(start + 4).ok()?;
+    let len = len.min(end.saturating_sub(start + 8)) as usize;
+    let mut msg = vec![0; len];
+    core.read_8(start + 8, &mut msg).ok()?;
+
+    Some(String::from_utf8_lossy(&msg).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::chunk;
+    use itm::Timestamp;
+
+    /// Ensure fault causes are decoded from the fault status registers.
+    #[test]
+    fn fault_causes() {
+        assert_eq!(
+            super::fault_causes(1 << 9 | 1 << 15, 1 << 30),
+            vec!["escalated to HardFault", "precise data bus error"]
+        );
+        assert!(super::fault_causes(0, 0).is_empty());
+    }
+
+    /// Ensure fault records are decoded across chunks, and that the
+    /// faulting address is only reported if valid.
+    #[test]
+    fn fault_records() {
+        let mut records = FaultRecords::default();
+        let mut update = |payloads: Vec<Vec<u8>>| {
+            let packets: Vec<TracePacket> = payloads
+                .into_iter()
+                .map(|payload| TracePacket::Instrumentation {
+                    port: FAULT_PORT,
+                    payload,
+                })
+                .collect();
+            let data = TraceData {
+                timestamp: Timestamp::Sync(Duration::ZERO),
+                consumed_packets: packets.len(),
+                packets,
+                malformed_packets: vec![],
+            };
+            let mut chunk = chunk(Duration::ZERO, vec![]);
+            records.update(&data, &mut chunk);
+            chunk.events
+        };
+        let word = |w: u32| w.to_le_bytes().to_vec();
+
+        assert!(update(vec![vec![5], word(1 << 15 | 1 << 9)]).is_empty());
+        assert!(matches!(
+            update(vec![word(0x2000_1000)])[..],
+            [api::EventType::Fault {
+                kind: api::FaultKind::BusFault,
+                cfsr: 0x8200,
+                address: Some(0x2000_1000),
+            }]
+        ));
+        assert!(matches!(
+            update(vec![vec![6], word(1 << 25), word(0)])[..],
+            [api::EventType::Fault {
+                kind: api::FaultKind::UsageFault,
+                address: None,
+                ..
+            }]
+        ));
+        // Not a fault exception.
+        assert!(update(vec![vec![11], word(0), word(0)]).is_empty());
+    }
+}
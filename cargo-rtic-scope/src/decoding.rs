@@ -0,0 +1,148 @@
+//! Statistics of the ITM decoder: how many bytes the source has read,
+//! how often the decoder saw a synchronization packet and
+//! resynchronized after a decode error, and which classes of decode
+//! errors occurred.
+use crate::TraceData;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use indexmap::IndexMap;
+use itm::{MalformedPacket, TracePacket};
+
+#[derive(Default)]
+pub struct DecoderStats {
+    /// Bytes read by the source, if it reads a byte stream. See
+    /// [`Source::byte_counter`](crate::sources::Source::byte_counter).
+    bytes: Option<Arc<AtomicU64>>,
+    syncs: usize,
+    resyncs: usize,
+    /// Whether decode errors occurred since the last synchronization
+    /// packet.
+    desynced: bool,
+    /// Decode errors by class.
+    errors: IndexMap<&'static str, usize>,
+}
+
+/// The class of a malformed packet, e.g. "invalid header" for
+/// `MalformedPacket::InvalidHeader(..)`.
+fn class(malformed: &MalformedPacket) -> &'static str {
+    match malformed {
+        MalformedPacket::InvalidHeader(_) => "invalid header",
+        MalformedPacket::InvalidHardwarePacket { .. } => "invalid hardware packet",
+        _ => "malformed packet",
+    }
+}
+
+impl DecoderStats {
+    pub fn new(bytes: Option<Arc<AtomicU64>>) -> Self {
+        Self {
+            bytes,
+            ..Self::default()
+        }
+    }
+
+    /// Accounts the packets decoded in `data`. As malformed packets are
+    /// not ordered relative to well-formed ones, a resynchronization is
+    /// counted for the first synchronization packet in a later chunk
+    /// than a decode error.
+    pub fn update(&mut self, data: &TraceData) {
+        let syncs = data
+            .packets
+            .iter()
+            .filter(|packet| matches!(packet, TracePacket::Sync))
+            .count();
+        self.syncs += syncs;
+        if self.desynced && syncs > 0 {
+            crate::log::debug!("resynchronized at {:?}", data.timestamp);
+            self.resyncs += 1;
+            self.desynced = false;
+        }
+
+        for malformed in data.malformed_packets.iter() {
+            crate::log::debug!("malformed packet: {:?}", malformed);
+            *self.errors.entry(class(malformed)).or_default() += 1;
+            self.desynced = true;
+        }
+    }
+
+    /// Bytes read by the source so far, if counted.
+    pub fn bytes(&self) -> Option<u64> {
+        self.bytes
+            .as_ref()
+            .map(|bytes| bytes.load(Ordering::Relaxed))
+    }
+
+    /// A summary for the status line, e.g. "1.2 MiB read, 3 syncs, 1
+    /// resync".
+    pub fn summary(&self) -> String {
+        let mut parts = vec![];
+        if let Some(bytes) = self.bytes() {
+            parts.push(match bytes {
+                b if b >= 1 << 20 => format!("{:.1} MiB read", b as f64 / (1 << 20) as f64),
+                b if b >= 1 << 10 => format!("{:.1} KiB read", b as f64 / (1 << 10) as f64),
+                b => format!("{} B read", b),
+            });
+        }
+        parts.push(format!(
+            "{} sync{}",
+            self.syncs,
+            if self.syncs == 1 { "" } else { "s" }
+        ));
+        if self.resyncs > 0 {
+            parts.push(format!(
+                "{} resync{}",
+                self.resyncs,
+                if self.resyncs == 1 { "" } else { "s" }
+            ));
+        }
+
+        parts.join(", ")
+    }
+
+    /// Decode errors by class, e.g. "2 invalid header, 1 invalid
+    /// hardware packet", if any.
+    pub fn errors(&self) -> Option<String> {
+        if self.errors.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.errors
+                .iter()
+                .map(|(class, n)| format!("{} {}", n, class))
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use itm::Timestamp;
+    use std::time::Duration;
+
+    /// Ensure decode errors are counted by class.
+    #[test]
+    fn errors() {
+        let mut stats = DecoderStats::new(None);
+        stats.update(&TraceData {
+            timestamp: Timestamp::Sync(Duration::from_micros(1)),
+            packets: vec![],
+            consumed_packets: 0,
+            malformed_packets: vec![
+                MalformedPacket::InvalidHeader(7),
+                MalformedPacket::InvalidHardwarePacket {
+                    disc_id: 3,
+                    payload: vec![],
+                },
+                MalformedPacket::InvalidHeader(0x7f),
+            ],
+        });
+        assert_eq!(
+            stats.errors().unwrap(),
+            "2 invalid header, 1 invalid hardware packet"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Estimation and correction of the drift between the target timestamp
+//! clock and the host clock.
+//!
+//! Target timestamps are calculated from the configured `tpiu_freq`,
+//! which is seldom exact. Over long sessions, the inaccuracy
+//! accumulates into a significant drift against wall clock time.
+use std::time::Duration;
+
+use rtic_scope_api as api;
+use serde::{Deserialize, Serialize};
+
+/// How often the host clock is sampled against the trace clock.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum span of host time the samples must cover before a drift
+/// model is estimated.
+const MIN_SPAN: Duration = Duration::from_secs(10);
+
+/// Linear model of the drift of the target timestamp clock relative to
+/// the host clock.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct DriftModel {
+    /// Drift in parts per million. Positive if the trace clock runs
+    /// faster than the host clock.
+    pub ppm: f64,
+
+    /// Number of samples the model was estimated from.
+    pub samples: usize,
+}
+
+impl DriftModel {
+    /// Corrects an offset from target reset for drift.
+    pub fn correct(&self, offset: Duration) -> Duration {
+        offset.div_f64(1.0 + self.ppm / 1e6)
+    }
+
+    /// Corrects the timestamps of a chunk for drift.
+    pub fn correct_chunk(&self, chunk: &mut api::EventChunk) {
+        crate::timestamps::map_chunk(chunk, |offset| self.correct(offset));
+    }
+}
+
+/// Periodically samples the host clock against the trace clock and
+/// estimates a [`DriftModel`] from the samples. Only meaningful when
+/// trace data is received live from the target.
+#[derive(Default)]
+pub struct DriftEstimator {
+    /// (host time, trace time) pairs, in seconds.
+    samples: Vec<(f64, f64)>,
+    last: Option<Duration>,
+}
+
+impl DriftEstimator {
+    /// Records a sample if [`SAMPLE_INTERVAL`] has passed since the
+    /// last one. `host` is the host time elapsed since tracing started,
+    /// and `chunk` the chunk that was just received. Only chunks with
+    /// timestamps of known quality are sampled.
+    pub fn sample(&mut self, host: Duration, chunk: &api::EventChunk) {
+        let trace = match &chunk.timestamp {
+            api::Timestamp::Sync(offset) | api::Timestamp::AssocEventDelay(offset) => *offset,
+            _ => return,
+        };
+        if matches!(self.last, Some(last) if host < last + SAMPLE_INTERVAL) {
+            return;
+        }
+
+        self.last = Some(host);
+        self.samples.push((host.as_secs_f64(), trace.as_secs_f64()));
+    }
+
+    /// Estimates the drift as the least-squares slope of trace time
+    /// over host time. `None` if the samples do not span enough time.
+    pub fn model(&self) -> Option<DriftModel> {
+        let (first, last) = (self.samples.first()?, self.samples.last()?);
+        if last.0 - first.0 < MIN_SPAN.as_secs_f64() {
+            return None;
+        }
+
+        let n = self.samples.len() as f64;
+        let (mean_host, mean_trace) = self
+            .samples
+            .iter()
+            .fold((0.0, 0.0), |(h, t), (host, trace)| {
+                (h + host / n, t + trace / n)
+            });
+        let (cov, var) = self
+            .samples
+            .iter()
+            .fold((0.0, 0.0), |(cov, var), (host, trace)| {
+                let dh = host - mean_host;
+                (cov + dh * (trace - mean_trace), var + dh * dh)
+            });
+
+        Some(DriftModel {
+            ppm: (cov / var - 1.0) * 1e6,
+            samples: self.samples.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::chunk;
+
+    /// Ensure a trace clock running 100 ppm fast is detected and
+    /// corrected for.
+    #[test]
+    fn estimate() {
+        let mut estimator = DriftEstimator::default();
+        for ms in (0..20_000).step_by(100) {
+            let host = Duration::from_millis(ms);
+            let chunk = chunk(host.mul_f64(1.0001), vec![]);
+            estimator.sample(host, &chunk);
+        }
+
+        let model = estimator.model().unwrap();
+        assert_eq!(model.samples, 20);
+        assert!((model.ppm - 100.0).abs() < 0.01);
+        let corrected = model.correct(Duration::from_secs(10).mul_f64(1.0001));
+        assert!((corrected.as_secs_f64() - 10.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,354 @@
+//! Dump of the target registers that configure tracing, as printed by
+//! `cargo rtic-scope dump-regs`. Used to debug a target that does not
+//! emit any trace data.
+use crate::manifest::ManifestProperties;
+use crate::sources::SourceError;
+use crate::{DumpRegsOptions, RTICScopeError};
+
+use cortex_m::peripheral::itm::LocalTimestampOptions;
+use probe_rs::{Core, MemoryInterface, Session};
+
+/// Debug Exception and Monitor Control Register.
+const DEMCR: u32 = 0xe000_edfc;
+/// ITM Trace Enable Register 0.
+const ITM_TER0: u32 = 0xe000_0e00;
+/// ITM Trace Control Register.
+const ITM_TCR: u32 = 0xe000_0e80;
+/// DWT Control Register.
+const DWT_CTRL: u32 = 0xe000_1000;
+/// DWT Comparator Register 0. Comparator n is at an offset of 16n.
+const DWT_COMP0: u32 = 0xe000_1020;
+/// DWT Function Register 0. Comparator n is at an offset of 16n.
+const DWT_FUNCTION0: u32 = 0xe000_1028;
+/// TPIU Asynchronous Clock Prescaler Register.
+const TPIU_ACPR: u32 = 0xe004_0010;
+/// TPIU Selected Pin Protocol Register.
+const TPIU_SPPR: u32 = 0xe004_00f0;
+/// TPIU Formatter and Flush Control Register.
+const TPIU_FFCR: u32 = 0xe004_0304;
+
+/// The raw values of the registers that configure tracing.
+#[derive(Debug, Default)]
+struct Registers {
+    demcr: u32,
+    itm_ter0: u32,
+    itm_tcr: u32,
+    dwt_ctrl: u32,
+    /// COMP and FUNCTION of each implemented DWT comparator.
+    comparators: Vec<(u32, u32)>,
+    tpiu_acpr: u32,
+    tpiu_sppr: u32,
+    tpiu_ffcr: u32,
+}
+
+fn bit(reg: u32, n: u32) -> u32 {
+    (reg >> n) & 1
+}
+
+fn field(reg: u32, lsb: u32, width: u32) -> u32 {
+    (reg >> lsb) & ((1 << width) - 1)
+}
+
+/// COMP and FUNCTION of each implemented DWT comparator of `core`.
+fn read_comparators(core: &mut Core) -> Result<Vec<(u32, u32)>, probe_rs::Error> {
+    let dwt_ctrl = core.read_word_32(DWT_CTRL)?;
+    (0..field(dwt_ctrl, 28, 4))
+        .map(|n| {
+            Ok((
+                core.read_word_32(DWT_COMP0 + 16 * n)?,
+                core.read_word_32(DWT_FUNCTION0 + 16 * n)?,
+            ))
+        })
+        .collect()
+}
+
+/// Reads back what the firmware has programmed into the DWT
+/// comparators of the given core; see [`crate::recovery::DwtComparator`].
+pub fn comparators(session: &mut Session, core: usize) -> Result<Vec<(u32, u32)>, SourceError> {
+    let mut core = session.core(core).map_err(SourceError::ProbeError)?;
+    read_comparators(&mut core).map_err(SourceError::ProbeError)
+}
+
+impl Registers {
+    fn read(core: &mut Core) -> Result<Self, probe_rs::Error> {
+        let dwt_ctrl = core.read_word_32(DWT_CTRL)?;
+        let comparators = read_comparators(core)?;
+
+        Ok(Self {
+            demcr: core.read_word_32(DEMCR)?,
+            itm_ter0: core.read_word_32(ITM_TER0)?,
+            itm_tcr: core.read_word_32(ITM_TCR)?,
+            dwt_ctrl,
+            comparators,
+            tpiu_acpr: core.read_word_32(TPIU_ACPR)?,
+            tpiu_sppr: core.read_word_32(TPIU_SPPR)?,
+            tpiu_ffcr: core.read_word_32(TPIU_FFCR)?,
+        })
+    }
+
+    /// The registers and their decoded fields, one register per line.
+    fn decode(&self) -> Vec<String> {
+        let r = self;
+        let mut lines = vec![
+            format!("DEMCR       {:#010x}  TRCENA={}", r.demcr, bit(r.demcr, 24)),
+            format!(
+                "ITM_TCR     {:#010x}  ITMENA={} TSENA={} SYNCENA={} TXENA={} SWOENA={} TSPrescale={} GTSFREQ={} TraceBusID={} BUSY={}",
+                r.itm_tcr,
+                bit(r.itm_tcr, 0),
+                bit(r.itm_tcr, 1),
+                bit(r.itm_tcr, 2),
+                bit(r.itm_tcr, 3),
+                bit(r.itm_tcr, 4),
+                match field(r.itm_tcr, 8, 2) {
+                    0 => "/1",
+                    1 => "/4",
+                    2 => "/16",
+                    _ => "/64",
+                },
+                field(r.itm_tcr, 10, 2),
+                field(r.itm_tcr, 16, 7),
+                bit(r.itm_tcr, 23),
+            ),
+            format!("ITM_TER0    {:#010x}  (enabled stimulus ports)", r.itm_ter0),
+            format!(
+                "DWT_CTRL    {:#010x}  NUMCOMP={} EXCTRCENA={} SLEEPEVTENA={} CYCCNTENA={}",
+                r.dwt_ctrl,
+                field(r.dwt_ctrl, 28, 4),
+                bit(r.dwt_ctrl, 16),
+                bit(r.dwt_ctrl, 19),
+                bit(r.dwt_ctrl, 0),
+            ),
+        ];
+        for (n, (comp, function)) in r.comparators.iter().enumerate() {
+            lines.push(format!(
+                "DWT_COMP{}   {:#010x}  FUNCTION={:#010x}{}",
+                n,
+                comp,
+                function,
+                if field(*function, 0, 4) == 0 {
+                    " (disabled)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        lines.extend([
+            format!(
+                "TPIU_ACPR   {:#010x}  SWOSCALER={} (baud rate = TPIU clock / {})",
+                r.tpiu_acpr,
+                field(r.tpiu_acpr, 0, 16),
+                field(r.tpiu_acpr, 0, 16) + 1
+            ),
+            format!(
+                "TPIU_SPPR   {:#010x}  TXMODE={}",
+                r.tpiu_sppr,
+                match field(r.tpiu_sppr, 0, 2) {
+                    0 => "parallel",
+                    1 => "SWO (Manchester)",
+                    2 => "SWO (NRZ)",
+                    _ => "reserved",
+                }
+            ),
+            format!(
+                "TPIU_FFCR   {:#010x}  EnFCont={} TrigIn={}",
+                r.tpiu_ffcr,
+                bit(r.tpiu_ffcr, 1),
+                bit(r.tpiu_ffcr, 8)
+            ),
+        ]);
+
+        lines
+    }
+
+    /// Mismatches between the registers and the configuration in the
+    /// manifest.
+    fn check(&self, manip: &ManifestProperties) -> Vec<String> {
+        let r = self;
+        let mut warnings = vec![];
+        let mut expect = |ok: bool, msg: String| {
+            if !ok {
+                warnings.push(msg);
+            }
+        };
+
+        expect(
+            bit(r.demcr, 24) == 1,
+            "DEMCR.TRCENA is not set: DWT and ITM are disabled".to_string(),
+        );
+        expect(
+            bit(r.itm_tcr, 0) == 1,
+            "ITM_TCR.ITMENA is not set: the ITM is disabled".to_string(),
+        );
+        expect(
+            bit(r.itm_tcr, 3) == 1,
+            "ITM_TCR.TXENA is not set: DWT packets are not forwarded to the ITM".to_string(),
+        );
+        expect(
+            bit(r.dwt_ctrl, 16) == 1,
+            "DWT_CTRL.EXCTRCENA is not set: hardware tasks are not traced".to_string(),
+        );
+
+        let prescaler = match manip.lts_prescaler {
+            LocalTimestampOptions::Disabled => None,
+            LocalTimestampOptions::Enabled => Some(0),
+            LocalTimestampOptions::EnabledDiv4 => Some(1),
+            LocalTimestampOptions::EnabledDiv16 => Some(2),
+            LocalTimestampOptions::EnabledDiv64 => Some(3),
+        };
+        match prescaler {
+            None => expect(
+                bit(r.itm_tcr, 1) == 0,
+                "ITM_TCR.TSENA is set, but lts_prescaler disables local timestamps".to_string(),
+            ),
+            Some(prescaler) => expect(
+                bit(r.itm_tcr, 1) == 1 && field(r.itm_tcr, 8, 2) == prescaler,
+                format!(
+                    "local timestamps are not enabled with the lts_prescaler of the manifest ({:?})",
+                    manip.lts_prescaler
+                ),
+            ),
+        }
+
+        for (name, id) in [
+            ("dwt_enter_id", Some(manip.dwt_enter_id)),
+            ("dwt_exit_id", Some(manip.dwt_exit_id)),
+            ("dwt_lock_id", manip.dwt_lock_id),
+            ("dwt_spawn_id", manip.dwt_spawn_id),
+        ] {
+            let id = match id {
+                Some(id) => id,
+                None => continue,
+            };
+            match r.comparators.get(id) {
+                Some((_, function)) => expect(
+                    field(*function, 0, 4) != 0,
+                    format!("DWT comparator {} ({}) is disabled", id, name),
+                ),
+                None => expect(
+                    false,
+                    format!(
+                        "DWT comparator {} ({}) is not implemented: the target has {} comparators",
+                        id,
+                        name,
+                        r.comparators.len()
+                    ),
+                ),
+            }
+        }
+
+        if manip.tpiu_baud != 0 {
+            let swoscaler = (manip.tpiu_freq / manip.tpiu_baud).saturating_sub(1);
+            expect(
+                field(r.tpiu_acpr, 0, 16) == swoscaler,
+                format!(
+                    "TPIU_ACPR.SWOSCALER is {}, but {} is expected from tpiu_freq = {} and tpiu_baud = {}",
+                    field(r.tpiu_acpr, 0, 16),
+                    swoscaler,
+                    manip.tpiu_freq,
+                    manip.tpiu_baud
+                ),
+            );
+        }
+        expect(
+            field(r.tpiu_sppr, 0, 2) != 0,
+            "TPIU_SPPR.TXMODE selects the parallel trace port, not SWO".to_string(),
+        );
+        expect(
+            bit(r.tpiu_ffcr, 1) == 0,
+            "TPIU_FFCR.EnFCont is set: the ITM stream is TPIU-formatted".to_string(),
+        );
+
+        warnings
+    }
+}
+
+pub fn run(opts: &DumpRegsOptions) -> Result<(), RTICScopeError> {
+    // The manifest is optional: without it, the registers are only
+    // decoded.
+    let manip = cargo_metadata::MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .ok()
+        .and_then(|metadata| {
+            let package = metadata.root_package()?;
+            match ManifestProperties::from_metadata(package, &metadata, Some(&opts.pac)) {
+                Ok(manip) => Some(manip),
+                Err(e) => {
+                    crate::log::warn(format!(
+                        "not comparing the registers against the manifest: {}",
+                        e
+                    ));
+                    None
+                }
+            }
+        });
+
+    opts.probe_options.maybe_load_chip_desc()?;
+    let mut session = opts.probe_options.simple_attach()?;
+    let mut core = session.core(0).map_err(SourceError::ProbeError)?;
+    let regs = Registers::read(&mut core).map_err(SourceError::ProbeError)?;
+
+    for line in regs.decode() {
+        println!("{}", line);
+    }
+    if let Some(manip) = manip {
+        for warning in regs.check(&manip) {
+            crate::log::warn(warning);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::InterruptLatency;
+
+    /// Ensure registers configured as by `cortex_m_rtic_trace::configure`
+    /// match the manifest, and that misconfigurations are reported.
+    #[test]
+    fn check() {
+        let manip = ManifestProperties {
+            pac_name: "stm32f4".to_string(),
+            pac_version: "0.13".to_string(),
+            pac_features: vec![],
+            interrupt_path: "stm32f4::stm32f401::Interrupt".to_string(),
+            tpiu_freq: 16_000_000,
+            tpiu_baud: 1_000_000,
+            lts_prescaler: LocalTimestampOptions::Enabled,
+            dwt_enter_id: 1,
+            dwt_exit_id: 2,
+            dwt_lock_id: None,
+            dwt_spawn_id: None,
+            dwt_args_id: None,
+            ignore_exceptions: vec![],
+            expect_malformed: false,
+            decoder: crate::sources::DecoderKind::Itm,
+            trace_idle: false,
+            fault_trace: false,
+            build_id: false,
+            sync_pulse: false,
+            freq_report: false,
+            name_policy: crate::recovery::NamePolicy::Full,
+            irq_latency: InterruptLatency::default(),
+            ports: indexmap::IndexMap::new(),
+            tasks: indexmap::IndexMap::new(),
+        };
+        let mut regs = Registers {
+            demcr: 1 << 24,
+            itm_ter0: 0,
+            itm_tcr: 0b1011 | 1 << 16,
+            dwt_ctrl: 4 << 28 | 1 << 16,
+            comparators: vec![(0, 0), (0x2000_0000, 0b1101), (0x2000_0004, 0b1101), (0, 0)],
+            tpiu_acpr: 15,
+            tpiu_sppr: 2,
+            tpiu_ffcr: 1 << 8,
+        };
+        assert!(regs.check(&manip).is_empty());
+
+        regs.tpiu_acpr = 7;
+        regs.comparators[2].1 = 0;
+        assert_eq!(regs.check(&manip).len(), 2);
+    }
+}
@@ -0,0 +1,540 @@
+//! Export of the task execution intervals of a recorded trace, as
+//! written by `cargo rtic-scope export`.
+use crate::cores::MergedTraces;
+use crate::recovery::TraceMetadata;
+use crate::stats::{Execution, Executions, Preemption, Preemptions, Units};
+use crate::timestamps::{alignment, interval_in, offset_in};
+use crate::{ExportOptions, RTICScopeError};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use rtic_scope_api::SourceLocation;
+use serde_json::{json, Value};
+
+/// The formats the execution intervals can be exported in.
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    /// The Chrome trace event format, as read by Perfetto and
+    /// chrome://tracing. See [`ChromeWriter`].
+    Chrome,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "chrome" | "perfetto" => Ok(Self::Chrome),
+            "parquet" => Err("parquet export is not supported; use csv".to_string()),
+            _ => Err(format!(
+                "unknown export format {:?}; expected csv or chrome",
+                s
+            )),
+        }
+    }
+}
+
+/// Derives a file name stem from a task name: any character that is not
+/// alphanumeric, `-`, or `_` is replaced with `_`. Stems that collide
+/// with one in `taken` are suffixed with `-2`, `-3`, etc.
+fn sanitize(task: &str, taken: &HashSet<String>) -> String {
+    let mut stem: String = task
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if stem.is_empty() {
+        stem.push('_');
+    }
+
+    let mut candidate = stem.clone();
+    let mut n = 1;
+    while taken.contains(&candidate) {
+        n += 1;
+        candidate = format!("{}-{}", stem, n);
+    }
+
+    candidate
+}
+
+/// The columns written by [`format_execution`], in the given units.
+fn execution_header(units: Units) -> String {
+    format!(
+        "start_{unit},end_{unit},duration_{unit},data_relation,diverged,quality",
+        unit = units.suffix()
+    )
+}
+
+/// Formats an execution as `start,end,duration` in the given units,
+/// with start and end shifted by `shift`, followed by the quality of
+/// its timestamps.
+fn format_execution(execution: &Execution, units: Units, freq: u32, shift: i128) -> String {
+    format!(
+        "{},{},{},{},{},{}",
+        offset_in(units, execution.start, freq) as i128 + shift,
+        offset_in(units, execution.end, freq) as i128 + shift,
+        interval_in(units, execution.start, execution.end, freq),
+        execution.quality.relation.name(),
+        execution.quality.diverged,
+        execution.quality.score(),
+    )
+}
+
+/// Writes each execution to a file of its own task.
+struct PerTaskWriter {
+    out_dir: PathBuf,
+    units: Units,
+    /// See [`format_execution`].
+    shift: i128,
+    files: HashMap<String, BufWriter<fs::File>>,
+    stems: HashSet<String>,
+    /// The file name of each task, in order of first execution.
+    names: IndexMap<String, String>,
+}
+
+impl PerTaskWriter {
+    fn new(out_dir: &Path, units: Units, shift: i128) -> Self {
+        Self {
+            out_dir: out_dir.to_owned(),
+            units,
+            shift,
+            files: HashMap::new(),
+            stems: HashSet::new(),
+            names: IndexMap::new(),
+        }
+    }
+
+    /// Writes `execution` to the file of the task exported as `task`.
+    /// `freq` is the frequency of the timestamp clock.
+    fn write(&mut self, task: &str, execution: &Execution, freq: u32) -> io::Result<()> {
+        if !self.files.contains_key(task) {
+            let stem = sanitize(task, &self.stems);
+            let name = format!("{}.csv", stem);
+            let path = self.out_dir.join(&name);
+            let mut file = BufWriter::new(fs::File::create(&path)?);
+            writeln!(file, "{}", execution_header(self.units))?;
+            crate::log::status("Exporting", format!("{} to {}", task, path.display()));
+            self.stems.insert(stem);
+            self.names.insert(task.to_owned(), name);
+            self.files.insert(task.to_owned(), file);
+        }
+
+        let file = self.files.get_mut(task).unwrap();
+        writeln!(
+            file,
+            "{}",
+            format_execution(execution, self.units, freq, self.shift)
+        )
+    }
+
+    /// Flushes all files and returns the file name of each task.
+    fn finish(self) -> io::Result<IndexMap<String, String>> {
+        for (_, mut file) in self.files {
+            file.flush()?;
+        }
+
+        Ok(self.names)
+    }
+}
+
+/// Writes the executions to trace.json in the Chrome trace event
+/// format. The tasks of each priority level (of each core, if merged)
+/// are grouped into a process of their own, the highest priority level
+/// on top, with a thread per task. Tasks without a recorded priority
+/// are grouped into an "unknown priority" process below the levels. Preemptions are drawn as flows from
+/// the slice of the preempted task to that of the preempting task.
+/// Times are in microseconds, shifted by `shift` nanoseconds.
+/// The level of the process of tasks without a recorded priority, above
+/// all priority levels; see [`ChromeWriter::track`].
+const UNKNOWN_PRIORITY: u32 = 0x100;
+
+struct ChromeWriter {
+    shift: i128,
+    events: Vec<Value>,
+    /// The process and thread ID of each task, in order of first
+    /// execution or preemption.
+    tracks: IndexMap<String, (u32, usize)>,
+    flows: usize,
+}
+
+impl ChromeWriter {
+    fn new(shift: i128) -> Self {
+        Self {
+            shift,
+            events: vec![],
+            tracks: IndexMap::new(),
+            flows: 0,
+        }
+    }
+
+    fn micros(&self, offset: Duration) -> f64 {
+        (offset.as_nanos() as i128 + self.shift) as f64 / 1e3
+    }
+
+    /// The process and thread ID of the task exported as `task`, which
+    /// is of the given priority, if recorded, and core. The process of
+    /// the priority level and the thread of the task are named on first
+    /// use.
+    fn track(&mut self, task: &str, priority: Option<u8>, core: Option<u8>) -> (u32, usize) {
+        if let Some(track) = self.tracks.get(task) {
+            return *track;
+        }
+
+        let core_bits = u32::from(core.unwrap_or(0)) << 9;
+        let pid = core_bits | priority.map_or(UNKNOWN_PRIORITY, u32::from);
+        if !self.tracks.values().any(|(p, _)| *p == pid) {
+            // cores in order, the highest priority level first, and
+            // unknown priorities last
+            let sort_index =
+                core_bits | priority.map_or(UNKNOWN_PRIORITY, |p| u32::from(u8::MAX - p));
+            let level = match priority {
+                Some(priority) => format!("priority {}", priority),
+                None => "unknown priority".to_string(),
+            };
+            let name = match core {
+                Some(core) => format!("core{} {}", core, level),
+                None => level,
+            };
+            self.events.push(json!({
+                "ph": "M", "name": "process_name", "pid": pid,
+                "args": { "name": name },
+            }));
+            self.events.push(json!({
+                "ph": "M", "name": "process_sort_index", "pid": pid,
+                "args": { "sort_index": sort_index },
+            }));
+        }
+        let tid = self.tracks.len() + 1;
+        self.events.push(json!({
+            "ph": "M", "name": "thread_name", "pid": pid, "tid": tid,
+            "args": { "name": task },
+        }));
+        self.tracks.insert(task.to_owned(), (pid, tid));
+
+        (pid, tid)
+    }
+
+    /// Writes `execution` as a slice of the task exported as `task`.
+    fn execution(
+        &mut self,
+        task: &str,
+        priority: Option<u8>,
+        core: Option<u8>,
+        execution: &Execution,
+    ) {
+        let (pid, tid) = self.track(task, priority, core);
+        let (start, end) = (self.micros(execution.start), self.micros(execution.end));
+        self.events.push(json!({
+            "ph": "X", "name": task, "cat": "task", "pid": pid, "tid": tid,
+            "ts": start, "dur": end - start,
+            "args": {
+                "data_relation": execution.quality.relation.name(),
+                "diverged": execution.quality.diverged,
+                "quality": execution.quality.score(),
+            },
+        }));
+    }
+
+    /// Writes a flow from the slice of the preempted task to that of
+    /// the preempting task, given as exported name and priority.
+    fn preemption(
+        &mut self,
+        preempted: (&str, Option<u8>),
+        preempting: (&str, Option<u8>),
+        core: Option<u8>,
+        at: Duration,
+    ) {
+        let from = self.track(preempted.0, preempted.1, core);
+        let to = self.track(preempting.0, preempting.1, core);
+        let ts = self.micros(at);
+        self.flows += 1;
+        self.events.push(json!({
+            "ph": "s", "name": "preemption", "cat": "preemption", "id": self.flows,
+            "pid": from.0, "tid": from.1, "ts": ts,
+        }));
+        self.events.push(json!({
+            "ph": "f", "bp": "e", "name": "preemption", "cat": "preemption", "id": self.flows,
+            "pid": to.0, "tid": to.1, "ts": ts,
+        }));
+    }
+
+    /// Writes trace.json to `out_dir` and returns its file name.
+    fn finish(self, out_dir: &Path) -> io::Result<String> {
+        let name = "trace.json".to_string();
+        let mut file = BufWriter::new(fs::File::create(out_dir.join(&name))?);
+        serde_json::to_writer(
+            &mut file,
+            &json!({ "traceEvents": self.events, "displayTimeUnit": "ns" }),
+        )?;
+        file.flush()?;
+
+        Ok(name)
+    }
+}
+
+/// Writes tasks.csv, which lists the source location of the definition
+/// of each exported task as `file:line:column` (understood by most
+/// editors), along with the file the executions of the task were
+/// written to. `tasks` maps the name each task was exported under to
+/// its file, and `locate` looks up the definition of a task by that
+/// name.
+fn write_index<'a>(
+    out_dir: &Path,
+    locate: impl Fn(&str) -> Option<&'a SourceLocation>,
+    tasks: &IndexMap<String, String>,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(fs::File::create(out_dir.join("tasks.csv"))?);
+    writeln!(file, "task,source,file")?;
+    for (task, name) in tasks.iter() {
+        let source = locate(task)
+            .map(|loc| format!("{}:{}:{}", loc.file, loc.line, loc.column))
+            .unwrap_or_default();
+        writeln!(file, "{},{},{}", task, source, name)?;
+    }
+
+    file.flush()
+}
+
+pub fn run(opts: &ExportOptions) -> Result<(), RTICScopeError> {
+    let trace = crate::resolve_trace_file(&opts.trace_file, opts.index, &opts.trace_dir)?;
+    let mut paths = vec![trace];
+    paths.extend(opts.merge.iter().cloned());
+    let mut traces = MergedTraces::open(&paths, false)?;
+    let merged = traces.is_merged();
+    let metadata: Vec<TraceMetadata> = traces.metadata().cloned().collect();
+    fs::create_dir_all(&opts.out_dir)?;
+
+    // Align the timeline to the sync pulse, if requested. Merged traces
+    // are aligned to the anchor of the first. Chrome traces are always
+    // in time units.
+    let units = match opts.format {
+        ExportFormat::Csv => opts.units,
+        ExportFormat::Chrome => Units::Nanoseconds,
+    };
+    let freq = metadata[0].tpiu_freq();
+    let shift = match (opts.align_to, metadata[0].sync_anchor()) {
+        (Some(t0), Some(anchor)) => alignment(anchor, t0, units, freq),
+        (Some(_), None) => {
+            crate::log::warn(
+                "trace contains no sync anchor; times will not be aligned".to_string(),
+            );
+            0
+        }
+        (None, _) => 0,
+    };
+
+    // When merging traces, tasks are exported under the name of their
+    // core, e.g. "core1::app::foo", and the core is exported along with
+    // each execution.
+    let mut origins: HashMap<String, (usize, String)> = HashMap::new();
+    let mut label = |i: usize, task: &str| match (merged, metadata[i].core()) {
+        (true, Some(core)) => {
+            let label = format!("core{}::{}", core, task);
+            origins
+                .entry(label.clone())
+                .or_insert_with(|| (i, task.to_owned()));
+            label
+        }
+        _ => task.to_owned(),
+    };
+
+    let mut executions: Vec<Executions> = metadata.iter().map(|_| Executions::default()).collect();
+    let mut excluded = 0;
+    let mut accept = |execution: &Execution| {
+        let accepted = execution.quality.score() >= opts.min_quality;
+        if !accepted {
+            excluded += 1;
+        }
+        accepted
+    };
+    let tasks = if let ExportFormat::Chrome = opts.format {
+        if opts.per_task {
+            crate::log::warn("--per-task does not apply to chrome export".to_string());
+        }
+        let priority =
+            |i: usize, task: &str| -> Option<u8> { metadata[i].priorities().get(task).copied() };
+        let mut preemptions: Vec<Preemptions> =
+            metadata.iter().map(|_| Preemptions::default()).collect();
+        let mut writer = ChromeWriter::new(shift);
+        for chunk in &mut traces {
+            let (i, _, chunk) = chunk?;
+            let core = metadata[i].core().filter(|_| merged);
+            for Preemption {
+                preempted,
+                preempting,
+                at,
+            } in preemptions[i].update(&chunk)
+            {
+                writer.preemption(
+                    (&label(i, &preempted), priority(i, &preempted)),
+                    (&label(i, &preempting), priority(i, &preempting)),
+                    core,
+                    at,
+                );
+            }
+            for execution in executions[i].update(&chunk).iter().filter(|e| accept(e)) {
+                writer.execution(
+                    &label(i, &execution.task),
+                    priority(i, &execution.task),
+                    core,
+                    execution,
+                );
+            }
+        }
+        let tasks: Vec<String> = writer.tracks.keys().cloned().collect();
+        let name = writer.finish(&opts.out_dir)?;
+        crate::log::status(
+            "Exported",
+            format!("executions to {}", opts.out_dir.join(&name).display()),
+        );
+        tasks.into_iter().map(|task| (task, name.clone())).collect()
+    } else if opts.per_task {
+        let mut writer = PerTaskWriter::new(&opts.out_dir, opts.units, shift);
+        for chunk in &mut traces {
+            let (i, _, chunk) = chunk?;
+            for execution in executions[i].update(&chunk).iter().filter(|e| accept(e)) {
+                writer.write(
+                    &label(i, &execution.task),
+                    execution,
+                    metadata[i].tpiu_freq(),
+                )?;
+            }
+        }
+        writer.finish()?
+    } else {
+        let path = opts.out_dir.join("executions.csv");
+        let mut file = BufWriter::new(fs::File::create(&path)?);
+        writeln!(
+            file,
+            "{}task,{}",
+            if merged { "core," } else { "" },
+            execution_header(opts.units)
+        )?;
+        let mut tasks = IndexMap::new();
+        for chunk in &mut traces {
+            let (i, _, chunk) = chunk?;
+            for execution in executions[i].update(&chunk).iter().filter(|e| accept(e)) {
+                let task = label(i, &execution.task);
+                writeln!(
+                    file,
+                    "{}{},{}",
+                    match (merged, metadata[i].core()) {
+                        (true, Some(core)) => format!("{},", core),
+                        _ => "".to_string(),
+                    },
+                    task,
+                    format_execution(execution, opts.units, metadata[i].tpiu_freq(), shift)
+                )?;
+                tasks
+                    .entry(task)
+                    .or_insert_with(|| "executions.csv".to_string());
+            }
+        }
+        file.flush()?;
+        crate::log::status("Exported", format!("executions to {}", path.display()));
+        tasks
+    };
+    write_index(
+        &opts.out_dir,
+        |task| match origins.get(task) {
+            Some((i, task)) => metadata[*i].locate(task),
+            None => metadata[0].locate(task),
+        },
+        &tasks,
+    )?;
+    if excluded > 0 {
+        crate::log::status(
+            "Excluded",
+            format!(
+                "{} execution(s) with a timestamp quality below {}",
+                excluded, opts.min_quality
+            ),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure task names are turned into unique, portable file names.
+    #[test]
+    fn sanitize_names() {
+        let mut taken = HashSet::new();
+        for (task, expected) in [
+            ("app::foo", "app__foo"),
+            ("app::foo", "app__foo-2"),
+            ("app//foo", "app__foo-3"),
+            ("bar", "bar"),
+            ("", "_"),
+        ] {
+            let stem = sanitize(task, &taken);
+            assert_eq!(stem, expected);
+            taken.insert(stem);
+        }
+    }
+
+    /// Ensure tasks are grouped by priority level, highest on top, that
+    /// tasks without a priority are grouped apart, and that preemptions
+    /// are flows between the slices of the tasks.
+    #[test]
+    fn chrome_lanes() {
+        let mut writer = ChromeWriter::new(-1_000);
+        assert_eq!(writer.track("low", Some(1), None), (1, 1));
+        writer.preemption(
+            ("low", Some(1)),
+            ("high", Some(3)),
+            None,
+            Duration::from_micros(3),
+        );
+        assert_eq!(writer.track("high", Some(3), None), (3, 2));
+        assert_eq!(writer.track("other", Some(1), Some(1)), (513, 3));
+        assert_eq!(writer.track("unknown", None, None), (256, 4));
+        assert_eq!(writer.track("zero", Some(0), None), (0, 5));
+
+        let processes: Vec<&str> = writer
+            .events
+            .iter()
+            .filter(|e| e["name"] == "process_name")
+            .filter_map(|e| e["args"]["name"].as_str())
+            .collect();
+        assert_eq!(
+            processes,
+            [
+                "priority 1",
+                "priority 3",
+                "core1 priority 1",
+                "unknown priority",
+                "priority 0"
+            ]
+        );
+        let flows: Vec<_> = writer
+            .events
+            .iter()
+            .filter(|e| e["cat"] == "preemption")
+            .map(|e| (e["ph"].as_str(), e["tid"].as_u64(), e["ts"].as_f64()))
+            .collect();
+        assert_eq!(
+            flows,
+            [
+                (Some("s"), Some(1), Some(2.0)),
+                (Some("f"), Some(2), Some(2.0))
+            ]
+        );
+    }
+}
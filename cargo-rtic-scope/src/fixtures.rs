@@ -0,0 +1,39 @@
+//! Events and chunks shared by the tests of the analyses, such that
+//! the fields added to the API are defaulted in one place.
+use rtic_scope_api as api;
+
+use std::time::Duration;
+
+/// A chunk of `events` at `timestamp`, with none of the fields that
+/// are filled in after the chunk is built.
+pub fn chunk_at(timestamp: api::Timestamp, events: Vec<api::EventType>) -> api::EventChunk {
+    api::EventChunk {
+        timestamp,
+        events,
+        estimated_timestamp: None,
+        cycles: None,
+        core: None,
+        packets: None,
+    }
+}
+
+/// A chunk of `events` at the synchronized `offset`; see [`chunk_at`].
+pub fn chunk(offset: Duration, events: Vec<api::EventType>) -> api::EventChunk {
+    chunk_at(api::Timestamp::Sync(offset), events)
+}
+
+/// The `action` of the task `name`, without arguments.
+pub fn task(name: &str, action: api::TaskAction) -> api::EventType {
+    api::EventType::Task {
+        name: name.to_string(),
+        action,
+        calibrated_offset: None,
+        args: None,
+        estimated: false,
+    }
+}
+
+/// The entry of the task `name`; see [`task`].
+pub fn entered(name: &str) -> api::EventType {
+    task(name, api::TaskAction::Entered)
+}
@@ -0,0 +1,144 @@
+//! Flashing of the target with the progress of each phase (erase,
+//! program, and optionally verify) reported to a
+//! [`ProgressObserver`](crate::progress::ProgressObserver).
+use crate::diag;
+use crate::progress::{Observer, Stage};
+
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use object::elf::PT_LOAD;
+use object::read::elf::{ElfFile32, FileHeader, ProgramHeader};
+use object::Endianness;
+use probe_rs::flashing::{DownloadOptions, FlashLoader, FlashProgress, ProgressEvent};
+use probe_rs::{MemoryInterface, Session};
+use probe_rs_cli_util::common_options::FlashOptions;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FlashingError {
+    #[error("Failed to flash the target: {0}")]
+    Download(#[source] probe_rs::flashing::FlashError),
+    #[error("Failed to read {}: {1}", .0.display())]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Failed to parse {} as a 32-bit ELF file", .0.display())]
+    Parse(PathBuf),
+    #[error("Failed to read back the flashed firmware: {0}")]
+    ReadBack(#[source] probe_rs::Error),
+    #[error("Flashed firmware differs from {} at {1:#010x}", .0.display())]
+    Mismatch(PathBuf, u32),
+}
+
+impl diag::DiagnosableError for FlashingError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::Mismatch(_, _) => vec![
+                "The flash may be write-protected, or worn out. Try to flash again, and check the option bytes of the target.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// Flashes `elf`, as loaded into `loader`, to the target of `session`,
+/// reporting the progress of each phase to `progress`. If `verify`, the
+/// flashed firmware is read back via `core` and compared with `elf`.
+pub fn flash(
+    session: &mut Session,
+    elf: &Path,
+    opts: &FlashOptions,
+    loader: FlashLoader,
+    verify: bool,
+    core: usize,
+    progress: &Observer,
+) -> Result<(), FlashingError> {
+    // Bytes to erase and to program, as known once initialized.
+    let totals = Rc::new(Cell::new((0, 0)));
+    let flash_progress = {
+        let (totals, observer) = (totals.clone(), progress.clone());
+        FlashProgress::new(move |event| {
+            let mut observer = observer.borrow_mut();
+            match event {
+                ProgressEvent::Initialized { flash_layout } => totals.set((
+                    flash_layout.sectors().iter().map(|s| s.size() as u64).sum(),
+                    flash_layout.pages().iter().map(|p| p.size() as u64).sum(),
+                )),
+                ProgressEvent::StartedErasing => {
+                    observer.started(Stage::Erase, Some(totals.get().0))
+                }
+                ProgressEvent::SectorErased { size, .. } => {
+                    observer.advanced(Stage::Erase, size as u64)
+                }
+                ProgressEvent::FinishedErasing => observer.finished(Stage::Erase),
+                ProgressEvent::StartedProgramming => {
+                    observer.started(Stage::Program, Some(totals.get().1))
+                }
+                ProgressEvent::PageProgrammed { size, .. } => {
+                    observer.advanced(Stage::Program, size as u64)
+                }
+                ProgressEvent::FinishedProgramming => observer.finished(Stage::Program),
+                _ => (),
+            }
+        })
+    };
+
+    let mut options = DownloadOptions::default();
+    options.progress = Some(&flash_progress);
+    options.keep_unwritten_bytes = opts.restore_unwritten;
+    options.dry_run = opts.dry_run;
+    options.do_chip_erase = true;
+    loader
+        .commit(session, options)
+        .map_err(FlashingError::Download)?;
+
+    if verify && !opts.dry_run {
+        self::verify(session, elf, core, progress)?;
+    }
+
+    Ok(())
+}
+
+/// Reads back the loadable segments of `elf` via `core` of the target
+/// of `session` and compares them with the file.
+fn verify(
+    session: &mut Session,
+    elf: &Path,
+    core: usize,
+    progress: &Observer,
+) -> Result<(), FlashingError> {
+    let data = fs::read(elf).map_err(|e| FlashingError::Read(elf.to_owned(), e))?;
+    let file =
+        ElfFile32::<Endianness>::parse(&*data).map_err(|_| FlashingError::Parse(elf.to_owned()))?;
+    let endian = file.endian();
+    let segments: Vec<(u32, &[u8])> = file
+        .raw_header()
+        .program_headers(endian, &*data)
+        .map_err(|_| FlashingError::Parse(elf.to_owned()))?
+        .iter()
+        .filter(|ph| ph.p_type(endian) == PT_LOAD)
+        .filter_map(|ph| Some((ph.p_paddr(endian), ph.data(endian, &*data).ok()?)))
+        .filter(|(_, data)| !data.is_empty())
+        .collect();
+
+    let mut progress = progress.borrow_mut();
+    progress.started(
+        Stage::Verify,
+        Some(segments.iter().map(|(_, data)| data.len() as u64).sum()),
+    );
+    let mut core = session.core(core).map_err(FlashingError::ReadBack)?;
+    for (addr, expected) in segments {
+        let mut flashed = vec![0; expected.len()];
+        core.read_8(addr, &mut flashed)
+            .map_err(FlashingError::ReadBack)?;
+        if let Some(pos) = flashed.iter().zip(expected).position(|(a, b)| a != b) {
+            return Err(FlashingError::Mismatch(elf.to_owned(), addr + pos as u32));
+        }
+        progress.advanced(Stage::Verify, expected.len() as u64);
+    }
+    progress.finished(Stage::Verify);
+
+    Ok(())
+}
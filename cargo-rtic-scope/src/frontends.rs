@@ -0,0 +1,318 @@
+//! Discovery of frontends installed in `PATH`, and the handshake with
+//! spawned frontends.
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use async_std::prelude::*;
+use crossbeam_channel as channel;
+use nix::sys::socket::{self, AddressFamily, SockAddr, SockFlag, SockType, UnixAddr};
+use rtic_scope_api as api;
+use thiserror::Error;
+
+/// Executable name prefix of frontends that can be found in `PATH`.
+pub const FRONTEND_PREFIX: &str = "rtic-scope-frontend-";
+
+/// How long a frontend is given to describe itself.
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Error)]
+pub enum DescribeError {
+    #[error("failed to spawn: {0}")]
+    SpawnFailed(#[source] std::io::Error),
+    #[error("no description within {}s", DESCRIBE_TIMEOUT.as_secs())]
+    Timeout,
+    #[error("--describe not supported (printed {0:?})")]
+    Unsupported(String),
+}
+
+/// How long a spawned frontend is given to print its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("Frontend {0} exited before printing its handshake")]
+    Exited(String),
+    #[error("Frontend {0} did not print its handshake within {timeout}s", timeout = HANDSHAKE_TIMEOUT.as_secs())]
+    Timeout(String),
+    #[error("Failed to read the handshake of frontend {0}: {1}")]
+    Read(String, #[source] io::Error),
+    #[error("Frontend {0} printed an invalid handshake {1:?}: {2}")]
+    Invalid(String, String, #[source] serde_json::Error),
+    #[error("Frontend {0} printed a socket path that is not absolute: {1:?}")]
+    RelativePath(String, String),
+    #[error("Socket {1} of frontend {0} cannot be accessed: {2}")]
+    Missing(String, String, #[source] io::Error),
+    #[error("{1} of frontend {0} is not a socket")]
+    NotASocket(String, String),
+    #[error("Failed to connect to socket {1} of frontend {0}: {2}")]
+    Connect(String, String, #[source] io::Error),
+}
+
+impl crate::diag::DiagnosableError for HandshakeError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::Exited(_) => vec![
+                "The frontend may have failed to start; see its output above.".to_string(),
+            ],
+            Self::Timeout(_) | Self::RelativePath(_, _) | Self::Invalid(_, _, _) => vec![
+                "A frontend must print its handshake to stdout as a single line: either a JSON handshake (see `cargo rtic-scope api-schema Handshake`) or `SOCKET <path>`. Other lines printed before it are forwarded as frontend output.".to_string(),
+            ],
+            Self::Missing(_, _, _) => vec![
+                "The socket may have been removed along with a temporary directory. A frontend can instead listen on a socket in the Linux abstract namespace, given as `@<name>`.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// Parses a line printed by a frontend as its handshake: a JSON
+/// [`api::Handshake`], a `SOCKET <path>` record, or (as printed by
+/// older frontends) only an absolute socket path. Returns `None` for
+/// any other line, e.g. a banner.
+fn parse_handshake(frontend: &str, line: &str) -> Option<Result<api::Handshake, HandshakeError>> {
+    let line = line.trim();
+    let socket = if line.starts_with('{') {
+        return Some(
+            serde_json::from_str(line)
+                .map_err(|e| HandshakeError::Invalid(frontend.to_owned(), line.to_owned(), e)),
+        );
+    } else if let Some(path) = line.strip_prefix("SOCKET ") {
+        path.trim()
+    } else if line.starts_with('/') && !line.contains(char::is_whitespace) {
+        line
+    } else {
+        return None;
+    };
+
+    Some(Ok(api::Handshake {
+        socket: socket.to_owned(),
+        subscription: api::Subscription::default(),
+        shm: false,
+        greeting: false,
+        ping: false,
+    }))
+}
+
+/// Reads the handshake of `frontend` from its `stdout`. Lines printed
+/// before it are forwarded as frontend output.
+pub async fn read_handshake<R>(frontend: &str, stdout: R) -> Result<api::Handshake, HandshakeError>
+where
+    R: async_std::io::Read + Unpin,
+{
+    let mut lines = async_std::io::BufReader::new(stdout).lines();
+    let handshake = async {
+        while let Some(line) = lines.next().await {
+            let line = line.map_err(|e| HandshakeError::Read(frontend.to_owned(), e))?;
+            match parse_handshake(frontend, &line) {
+                Some(handshake) => return handshake,
+                None => crate::log::frontend(format!("{}: {}", frontend, line)),
+            }
+        }
+
+        Err(HandshakeError::Exited(frontend.to_owned()))
+    };
+
+    async_std::future::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| HandshakeError::Timeout(frontend.to_owned()))?
+}
+
+/// Connects to the socket `frontend` listens on. A socket starting
+/// with `@` is in the Linux abstract namespace; any other must be an
+/// absolute path to a socket.
+pub fn connect(frontend: &str, socket: &str) -> Result<UnixStream, HandshakeError> {
+    let connect_err = |e| HandshakeError::Connect(frontend.to_owned(), socket.to_owned(), e);
+    if let Some(name) = socket.strip_prefix('@') {
+        return connect_abstract(name).map_err(connect_err);
+    }
+
+    let path = Path::new(socket);
+    if !path.is_absolute() {
+        return Err(HandshakeError::RelativePath(
+            frontend.to_owned(),
+            socket.to_owned(),
+        ));
+    }
+    let meta = fs::metadata(path)
+        .map_err(|e| HandshakeError::Missing(frontend.to_owned(), socket.to_owned(), e))?;
+    if !meta.file_type().is_socket() {
+        return Err(HandshakeError::NotASocket(
+            frontend.to_owned(),
+            socket.to_owned(),
+        ));
+    }
+
+    UnixStream::connect(path).map_err(connect_err)
+}
+
+fn connect_abstract(name: &str) -> io::Result<UnixStream> {
+    let addr = SockAddr::Unix(UnixAddr::new_abstract(name.as_bytes())?);
+    let fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // NOTE(unsafe) the descriptor was just created, and is owned (and
+    // thus closed) by the stream from here on
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    socket::connect(fd, &addr)?;
+
+    Ok(stream)
+}
+
+/// A frontend executable found in `PATH`.
+pub struct DiscoveredFrontend {
+    /// Name of the frontend, as given to `--frontend`.
+    pub name: String,
+    /// Full path to the executable.
+    pub path: PathBuf,
+    /// Whether an executable of the same name appears earlier in
+    /// `PATH`, in which case this one is never spawned.
+    pub shadowed: bool,
+}
+
+/// Finds all `rtic-scope-frontend-*` executables in `PATH`, in `PATH`
+/// order.
+pub fn find_in_path() -> Vec<DiscoveredFrontend> {
+    let mut found: Vec<DiscoveredFrontend> = vec![];
+    let paths = env::var_os("PATH").unwrap_or_default();
+    for dir in env::split_paths(&paths) {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // NOTE(continue) PATH may contain directories that do not exist
+            Err(_) => continue,
+        };
+        let mut frontends: Vec<DiscoveredFrontend> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix(FRONTEND_PREFIX)?
+                    .to_string();
+                let meta = fs::metadata(entry.path()).ok()?;
+                if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+                    return None;
+                }
+
+                Some(DiscoveredFrontend {
+                    shadowed: found.iter().any(|f| f.name == name),
+                    name,
+                    path: entry.path(),
+                })
+            })
+            .collect();
+        frontends.sort_by(|a, b| a.name.cmp(&b.name));
+        found.append(&mut frontends);
+    }
+
+    found
+}
+
+/// Spawns the frontend with `--describe` and reads back its
+/// description. The frontend is killed if it does not describe itself
+/// in time, which is the case for frontends that predate `--describe`.
+pub fn describe(frontend: &DiscoveredFrontend) -> Result<api::FrontendDescription, DescribeError> {
+    let mut child = Command::new(&frontend.path)
+        .arg("--describe")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(DescribeError::SpawnFailed)?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .expect("Pipe to frontend process failed");
+    let (tx, rx) = channel::bounded(1);
+    std::thread::spawn(move || {
+        let line = BufReader::new(stdout).lines().next();
+        let _ = tx.send(line);
+    });
+    let line = rx.recv_timeout(DESCRIBE_TIMEOUT);
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    match line {
+        Ok(Some(Ok(line))) => {
+            serde_json::from_str(&line).map_err(|_| DescribeError::Unsupported(line))
+        }
+        Ok(_) => Err(DescribeError::Unsupported("nothing".to_string())),
+        Err(_) => Err(DescribeError::Timeout),
+    }
+}
+
+/// Prints a table of all frontends found in `PATH`, their descriptions
+/// and whether they are compatible with this backend.
+pub fn list() {
+    let frontends = find_in_path();
+    if frontends.is_empty() {
+        crate::log::warn(format!("no {}* executables found in PATH", FRONTEND_PREFIX));
+        return;
+    }
+
+    println!("name\tversion\tapi\tcompatible\tpath");
+    for frontend in frontends.iter() {
+        let path = format!(
+            "{}{}",
+            frontend.path.display(),
+            if frontend.shadowed { " (shadowed)" } else { "" }
+        );
+        match describe(frontend) {
+            Ok(desc) => println!(
+                "{}\t{}\t{}\t{}\t{}",
+                frontend.name,
+                desc.version,
+                desc.api_version,
+                if api::is_compatible(&desc.api_version, api::VERSION) {
+                    "yes"
+                } else {
+                    "no"
+                },
+                path
+            ),
+            Err(e) => println!("{}\t?\t?\t{}\t{}", frontend.name, e, path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure handshakes are told apart from banners, and that invalid
+    /// JSON handshakes are reported.
+    #[test]
+    fn handshake() {
+        let socket = |line: &str| parse_handshake("dummy", line).map(|h| h.unwrap().socket);
+        assert_eq!(
+            socket("SOCKET /tmp/a.socket"),
+            Some("/tmp/a.socket".to_string())
+        );
+        assert_eq!(
+            socket("SOCKET @rtic-scope"),
+            Some("@rtic-scope".to_string())
+        );
+        assert_eq!(socket("/tmp/a.socket\n"), Some("/tmp/a.socket".to_string()));
+        assert_eq!(
+            socket(r#"{"socket":"@rtic-scope","shm":true}"#),
+            Some("@rtic-scope".to_string())
+        );
+        assert_eq!(socket("Frontend v1.0 starting..."), None);
+        assert_eq!(socket("/ is the root"), None);
+        assert!(matches!(
+            parse_handshake("dummy", "{\"sock\""),
+            Some(Err(HandshakeError::Invalid(_, _, _)))
+        ));
+    }
+}
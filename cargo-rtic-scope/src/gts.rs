@@ -0,0 +1,193 @@
+//! Re-anchoring of chunk timestamps on the global timestamps (GTS)
+//! emitted by targets configured with e.g.
+//! `GlobalTimestampOptions::Every8192Cycles`. Chunk timestamps are
+//! accumulated from local timestamps, and diverge from the clock of the
+//! target for the rest of the trace when local timestamps are lost,
+//! e.g. to an overflow. Global timestamps are absolute, and correct the
+//! divergence.
+//!
+//! A global timestamp is split over two packets: GTS1 carries its low
+//! 26 bits, and GTS2 its high bits. GTS2 is only emitted when the high
+//! bits change, which the preceding GTS1 signals. The global timestamp
+//! clock is assumed to be the timestamp clock (see `tpiu_freq`), but
+//! not to be zero at target reset: the global timestamp of the first
+//! chunk it is emitted in is anchored to the local timestamp of the
+//! chunk.
+use crate::timestamps::{cycles, from_cycles, shift_chunk};
+use crate::TraceData;
+
+use std::time::Duration;
+
+use itm::TracePacket;
+use rtic_scope_api as api;
+
+/// Number of bits of the global timestamp carried by GTS1.
+const LOW_BITS: u32 = 26;
+
+/// Deviation from the current correction, in cycles of the timestamp
+/// clock, below which a global timestamp is not re-anchored on. Global
+/// timestamps are emitted some cycles after they are sampled.
+const TOLERANCE: u64 = 64;
+
+#[derive(Default)]
+pub struct GlobalTimestamps {
+    /// The high bits of the global timestamp, if known.
+    high: Option<u64>,
+    /// The low bits of the last global timestamp, if any.
+    low: Option<u64>,
+    /// The first global timestamp and the offset from target reset of
+    /// the chunk it was emitted in.
+    anchor: Option<(u64, Duration)>,
+    /// Correction of the timestamps, in nanoseconds.
+    correction: i128,
+    /// Number of re-anchors.
+    pub reanchors: usize,
+}
+
+impl GlobalTimestamps {
+    /// Accounts the global timestamps in `data`, re-anchoring `chunk`
+    /// on them if its timestamp has diverged, which adds an
+    /// [`api::EventType::Reanchored`] event. The timestamps of all
+    /// chunks are corrected by the last re-anchor.
+    pub fn update(&mut self, data: &TraceData, chunk: &mut api::EventChunk, freq: u32) {
+        let mut global = None;
+        for packet in data.packets.iter() {
+            match packet {
+                TracePacket::GlobalTimestamp1 { ts, wrap, clkch } => {
+                    if *clkch {
+                        // NOTE the timestamp clock changed: the global
+                        // timestamp no longer relates to the anchor.
+                        self.high = None;
+                        self.anchor = None;
+                    }
+                    self.low = Some(*ts);
+                    // NOTE if wrapped, the high bits follow in a GTS2
+                    if !wrap {
+                        global = self.high.map(|high| (high << LOW_BITS) | ts);
+                    }
+                }
+                TracePacket::GlobalTimestamp2 { ts } => {
+                    self.high = Some(*ts);
+                    global = self.low.map(|low| (ts << LOW_BITS) | low);
+                }
+                _ => (),
+            }
+        }
+
+        // Only anchor on timestamps of known quality.
+        if let (Some(global), api::Timestamp::Sync(local)) = (global, &chunk.timestamp) {
+            let (anchor, anchor_offset) = *self.anchor.get_or_insert((global, *local));
+            let elapsed = from_cycles(global.saturating_sub(anchor), freq).as_nanos();
+            let correction =
+                (anchor_offset.as_nanos() + elapsed) as i128 - local.as_nanos() as i128;
+            let deviation = (correction - self.correction).unsigned_abs();
+            if cycles(Duration::from_nanos(deviation as u64), freq) > TOLERANCE {
+                crate::log::debug!(
+                    "re-anchored chunk at {:?} on global timestamp {}: corrected by {} ns",
+                    local,
+                    global,
+                    correction
+                );
+                chunk
+                    .events
+                    .push(api::EventType::Reanchored { local: *local });
+                self.correction = correction;
+                self.reanchors += 1;
+            }
+        }
+
+        if self.correction != 0 {
+            let (correction, later) = (
+                Duration::from_nanos(self.correction.unsigned_abs() as u64),
+                self.correction > 0,
+            );
+            shift_chunk(chunk, (correction, later));
+            // NOTE shift the cycles as recovered from the local
+            // timestamps instead of converting the shifted offset back.
+            let correction = cycles(correction, freq);
+            chunk.cycles = chunk.cycles.map(|c| {
+                if later {
+                    c + correction
+                } else {
+                    c.saturating_sub(correction)
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::chunk_at;
+    use crate::timestamps::offset;
+    use itm::Timestamp;
+
+    const FREQ: u32 = 1_000_000; // 1 cycle per µs
+
+    fn chunk(us: u64, packets: Vec<TracePacket>) -> (TraceData, api::EventChunk) {
+        let timestamp = Timestamp::Sync(Duration::from_micros(us));
+        (
+            TraceData {
+                timestamp: timestamp.clone(),
+                consumed_packets: packets.len(),
+                packets,
+                malformed_packets: vec![],
+            },
+            api::EventChunk {
+                cycles: Some(us),
+                ..chunk_at(timestamp, vec![])
+            },
+        )
+    }
+
+    fn gts1(ts: u64, wrap: bool) -> TracePacket {
+        TracePacket::GlobalTimestamp1 {
+            ts,
+            wrap,
+            clkch: false,
+        }
+    }
+
+    /// Ensure the timestamps of chunks are corrected once they diverge
+    /// from the global timestamps, including across a wrap of the low
+    /// bits, and that small deviations are ignored.
+    #[test]
+    fn reanchor() {
+        let mut gts = GlobalTimestamps::default();
+        let mut update = |us, packets| {
+            let (data, mut chunk) = chunk(us, packets);
+            gts.update(&data, &mut chunk, FREQ);
+            (
+                offset(&chunk.timestamp).as_micros(),
+                chunk.cycles,
+                chunk.events.len(),
+            )
+        };
+
+        const WRAP: u64 = 1 << LOW_BITS;
+        let gts2 = |ts| TracePacket::GlobalTimestamp2 { ts };
+
+        // The high bits are unknown until the first GTS2.
+        assert_eq!(
+            update(100, vec![gts1(WRAP - 600, false)]),
+            (100, Some(100), 0)
+        );
+        assert_eq!(
+            update(200, vec![gts1(WRAP - 500, true), gts2(0)]),
+            (200, Some(200), 0)
+        );
+        // Jitter within the tolerance.
+        assert_eq!(
+            update(300, vec![gts1(WRAP - 368, false)]),
+            (300, Some(300), 0)
+        );
+        // Local timestamps lost: 1000 µs are missing.
+        assert_eq!(
+            update(400, vec![gts1(700, true), gts2(1)]),
+            (1400, Some(1400), 1)
+        );
+        assert_eq!(update(500, vec![]), (1500, Some(1500), 0));
+        assert_eq!(update(600, vec![gts1(900, false)]), (1600, Some(1600), 0));
+    }
+}
@@ -0,0 +1,230 @@
+//! Bootstrapping of the `[package.metadata.rtic-scope]` table of a
+//! crate, as done by `cargo rtic-scope init`.
+use crate::diag;
+use crate::{manifest, InitOptions, RTICScopeError};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crossterm::tty::IsTty;
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum InitError {
+    #[error("Failed to read the metadata of the crate: {0}")]
+    CargoMetadata(#[from] cargo_metadata::Error),
+    #[error("No package found in the current directory")]
+    NoPackage,
+    #[error("{} already contains [package.metadata.rtic-scope]", .0.display())]
+    AlreadyInitialized(PathBuf),
+}
+
+impl diag::DiagnosableError for InitError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::NoPackage => vec!["Run `cargo rtic-scope init` from the directory of the crate of the RTIC application.".to_string()],
+            Self::AlreadyInitialized(_) => vec!["Edit the existing table, or remove it and run `cargo rtic-scope init` again.".to_string()],
+            _ => vec![],
+        }
+    }
+}
+
+/// The PAC of an RTIC application as guessed from its source and the
+/// dependencies of its crate.
+#[derive(Default)]
+struct PacGuess {
+    name: Option<String>,
+    version: Option<String>,
+    features: Option<Vec<String>>,
+    interrupt_path: Option<String>,
+}
+
+/// Extracts the `device` path from `#[rtic::app(device = ...)]`.
+fn find_device(src: &str) -> Option<String> {
+    let args = &src[src.find("app(")? + 4..];
+    let device = &args[args.find("device")? + "device".len()..];
+    let device = device.trim_start().strip_prefix('=')?.trim_start();
+    let end = device.find(|c: char| c == ',' || c == ')' || c.is_whitespace())?;
+    Some(device[..end].to_string())
+}
+
+/// Guesses the PAC from the `device` argument of the RTIC application
+/// in a binary target of `package` and the matching dependency.
+fn guess_pac(package: &cargo_metadata::Package) -> PacGuess {
+    let device = package
+        .targets
+        .iter()
+        .filter(|t| t.kind.iter().any(|k| k == "bin"))
+        .filter_map(|t| fs::read_to_string(&t.src_path).ok())
+        .find_map(|src| find_device(&src));
+    let device = match device {
+        Some(device) => device,
+        None => return PacGuess::default(),
+    };
+
+    let krate = device.split("::").next().unwrap_or_default().to_string();
+    let dependency = package
+        .dependencies
+        .iter()
+        .find(|dep| dep.rename.as_ref().unwrap_or(&dep.name).replace('-', "_") == krate);
+
+    PacGuess {
+        interrupt_path: Some(format!("{}::Interrupt", device)),
+        name: dependency.map(|dep| dep.name.clone()),
+        version: dependency.map(|dep| {
+            dep.req
+                .to_string()
+                .trim_start_matches(|c| c == '^' || c == '=')
+                .to_string()
+        }),
+        features: dependency.map(|dep| dep.features.clone()),
+    }
+}
+
+/// Asks for a value on stdin if it is a terminal.
+pub fn prompt<T: FromStr>(question: &str) -> Option<T> {
+    if !io::stdin().is_tty() {
+        return None;
+    }
+
+    loop {
+        eprint!("{:>12} {}: ", "", question);
+        let _ = io::stderr().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        match answer.trim() {
+            "" => return None,
+            answer => match answer.parse() {
+                Ok(value) => return Some(value),
+                Err(_) => continue,
+            },
+        }
+    }
+}
+
+/// Renders the table as TOML. Only the value types used by the
+/// manifest are supported.
+fn render(table: &Map<String, Value>) -> String {
+    fn value(v: &Value) -> String {
+        match v {
+            Value::String(s) => format!("{:?}", s),
+            Value::Array(a) => format!(
+                "[{}]",
+                a.iter().map(value).collect::<Vec<String>>().join(", ")
+            ),
+            v => v.to_string(),
+        }
+    }
+
+    let mut toml = "\n[package.metadata.rtic-scope]\n".to_string();
+    for (key, v) in table.iter().filter(|(_, v)| !v.is_null()) {
+        toml.push_str(&format!("{} = {}\n", key, value(v)));
+    }
+
+    toml
+}
+
+/// A starter snippet that configures the target for tracing with the
+/// given properties.
+fn snippet(manip: &manifest::ManifestProperties) -> String {
+    format!(
+        r#"use cortex_m_rtic_trace::{{
+    self, GlobalTimestampOptions, LocalTimestampOptions, TimestampClkSrc, TraceConfiguration,
+    TraceProtocol,
+}};
+
+// in #[init], with `mut ctx: init::Context`:
+cortex_m_rtic_trace::configure(
+    &mut ctx.core.DCB,
+    &mut ctx.core.TPIU,
+    &mut ctx.core.DWT,
+    &mut ctx.core.ITM,
+    {enter}, // dwt_enter_id
+    {exit}, // dwt_exit_id
+    &TraceConfiguration {{
+        delta_timestamps: LocalTimestampOptions::{lts:?},
+        absolute_timestamps: GlobalTimestampOptions::Disabled,
+        timestamp_clk_src: TimestampClkSrc::AsyncTPIU,
+        tpiu_freq: {freq},
+        tpiu_baud: {baud},
+        protocol: TraceProtocol::AsyncSWONRZ,
+    }},
+)
+.unwrap();"#,
+        enter = manip.dwt_enter_id,
+        exit = manip.dwt_exit_id,
+        lts = manip.lts_prescaler,
+        freq = manip.tpiu_freq,
+        baud = manip.tpiu_baud,
+    )
+}
+
+pub fn run(opts: &InitOptions) -> Result<(), RTICScopeError> {
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .map_err(InitError::from)?;
+    let package = metadata.root_package().ok_or(InitError::NoPackage)?;
+    if package.metadata.get("rtic-scope").is_some() {
+        return Err(InitError::AlreadyInitialized(package.manifest_path.clone().into()).into());
+    }
+
+    let guess = guess_pac(package);
+    let pac = &opts.pac;
+    let table = json!({
+        "pac_name": pac.pac_name.clone().or(guess.name).or_else(|| prompt("PAC name")),
+        "pac_version": pac.pac_version.clone().or(guess.version).or_else(|| prompt("PAC version")),
+        "pac_features": pac.pac_features.clone().or(guess.features),
+        "interrupt_path": pac.interrupt_path.clone().or(guess.interrupt_path).or_else(|| prompt("Path to the PAC Interrupt enum")),
+        "tpiu_freq": pac.tpiu_freq.or_else(|| prompt::<u32>("TPIU clock frequency [Hz]")),
+        "tpiu_baud": pac.tpiu_baud.or_else(|| prompt::<u32>("TPIU baud rate")),
+        "lts_prescaler": opts.lts_prescaler,
+        "dwt_enter_id": opts.dwt_enter_id,
+        "dwt_exit_id": opts.dwt_exit_id,
+        "expect_malformed": opts.expect_malformed,
+    });
+
+    // Validate before touching the manifest.
+    let manip = manifest::validate(&table)?;
+
+    let manifest_path = package.manifest_path.as_std_path();
+    let mut manifest = fs::OpenOptions::new().append(true).open(manifest_path)?;
+    manifest.write_all(render(table.as_object().unwrap()).as_bytes())?;
+    crate::log::status(
+        "Initialized",
+        format!(
+            "[package.metadata.rtic-scope] in {}",
+            manifest_path.display()
+        ),
+    );
+    crate::log::hint(format!(
+        "configure the target for tracing in #[init], e.g.:\n{}",
+        snippet(&manip)
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure the device path is found in the RTIC app attribute.
+    #[test]
+    fn device() {
+        assert_eq!(
+            find_device("#[rtic::app(device = stm32f4::stm32f401, dispatchers = [EXTI0])]"),
+            Some("stm32f4::stm32f401".to_string())
+        );
+        assert_eq!(
+            find_device("#[app(peripherals = true, device=nrf52840_pac)]"),
+            Some("nrf52840_pac".to_string())
+        );
+        assert_eq!(find_device("#[rtic::app(dispatchers = [EXTI0])]"), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! Estimation of timestamps of unknown quality from the surrounding
+//! timestamps of known quality.
+use std::time::Duration;
+
+use rtic_scope_api as api;
+
+/// The maximum number of chunks buffered while waiting for a known
+/// timestamp.
+pub const MAX_PENDING: usize = 4096;
+
+/// Buffers [`api::EventChunk`]s with timestamps of unknown quality
+/// (e.g. after an overflow) until the next chunk with a known timestamp
+/// is received. The buffered chunks are then assigned an
+/// [`api::EventChunk::estimated_timestamp`] linearly interpolated
+/// between the surrounding known timestamps, clamped to the bounds of
+/// each chunk's own timestamp.
+///
+/// Each chunk is accompanied by an arbitrary payload `T` (e.g. the raw
+/// trace data the chunk was built from) so that both can be forwarded
+/// to sinks in order.
+///
+/// At most [`MAX_PENDING`] chunks are buffered. If no known timestamp
+/// is received before then, the buffered chunks are forwarded without
+/// an estimate.
+pub struct Interpolator<T> {
+    enabled: bool,
+    last_known: Option<Duration>,
+    pending: Vec<(T, api::EventChunk)>,
+}
+
+/// The bounds of an unknown timestamp; `None` for known timestamps.
+fn bounds(timestamp: &api::Timestamp) -> Option<(Duration, Duration)> {
+    match timestamp {
+        api::Timestamp::Sync(_) | api::Timestamp::AssocEventDelay(_) => None,
+        api::Timestamp::UnknownDelay { prev, curr }
+        | api::Timestamp::UnknownAssocEventDelay { prev, curr } => Some((*prev, *curr)),
+    }
+}
+
+impl<T> Interpolator<T> {
+    /// Creates a new interpolator. If not `enabled`, chunks are passed
+    /// through as-is.
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            last_known: None,
+            pending: vec![],
+        }
+    }
+
+    /// Feeds a chunk to the interpolator. Returns the chunks that are
+    /// ready to be forwarded, in order.
+    pub fn push(&mut self, payload: T, chunk: api::EventChunk) -> Vec<(T, api::EventChunk)> {
+        if !self.enabled {
+            return vec![(payload, chunk)];
+        }
+
+        match bounds(&chunk.timestamp) {
+            Some(_) if self.pending.len() + 1 >= MAX_PENDING => {
+                crate::log::warn(format!(
+                    "no known timestamp within {} chunks: forwarding them without estimated timestamps",
+                    MAX_PENDING
+                ));
+                let mut ready: Vec<_> = self.pending.drain(..).collect();
+                ready.push((payload, chunk));
+                ready
+            }
+            Some(_) => {
+                self.pending.push((payload, chunk));
+                vec![]
+            }
+            None => {
+                let known = crate::timestamps::offset(&chunk.timestamp);
+                let mut ready = self.estimate(Some(known));
+                self.last_known = Some(known);
+                ready.push((payload, chunk));
+                ready
+            }
+        }
+    }
+
+    /// Returns all buffered chunks. Without a subsequent known
+    /// timestamp to interpolate towards, the midpoint of the bounds of
+    /// each chunk is used as estimate. Call at the end of the stream.
+    pub fn flush(&mut self) -> Vec<(T, api::EventChunk)> {
+        self.estimate(None)
+    }
+
+    fn estimate(&mut self, next_known: Option<Duration>) -> Vec<(T, api::EventChunk)> {
+        let n = self.pending.len() as u32;
+        let mut floor = self.last_known.unwrap_or_default();
+        let mut ready: Vec<(T, api::EventChunk)> = self.pending.drain(..).collect();
+        for (i, (_, chunk)) in ready.iter_mut().enumerate() {
+            let (prev, curr) = bounds(&chunk.timestamp).unwrap();
+            let estimate = match (self.last_known, next_known) {
+                (Some(start), Some(end)) if end > start => {
+                    start + (end - start) * (i as u32 + 1) / (n + 1)
+                }
+                _ => prev + curr.saturating_sub(prev) / 2,
+            }
+            .clamp(prev, curr.max(prev))
+            .max(floor);
+
+            floor = estimate;
+            chunk.estimated_timestamp = Some(estimate);
+        }
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, chunk_at};
+
+    /// Ensure unknown timestamps are interpolated between the
+    /// surrounding known timestamps, within their own bounds.
+    #[test]
+    fn interpolate() {
+        let ms = Duration::from_millis;
+        let mut interp = Interpolator::new(true);
+
+        assert_eq!(interp.push(0, chunk(ms(10), vec![])).len(), 1);
+        for i in 1..=3 {
+            let unknown = api::Timestamp::UnknownDelay {
+                prev: ms(10),
+                curr: ms(50),
+            };
+            assert!(interp.push(i, chunk_at(unknown, vec![])).is_empty());
+        }
+        let ready = interp.push(4, chunk(ms(50), vec![]));
+
+        let estimates: Vec<Option<Duration>> = ready
+            .iter()
+            .map(|(_, chunk)| chunk.estimated_timestamp)
+            .collect();
+        assert_eq!(estimates, [Some(ms(20)), Some(ms(30)), Some(ms(40)), None]);
+        assert_eq!(
+            ready.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+    }
+
+    /// Ensure buffered chunks are forwarded without an estimate once
+    /// the buffer is full.
+    #[test]
+    fn bounded() {
+        let ms = Duration::from_millis;
+        let mut interp = Interpolator::new(true);
+        let unknown = || api::Timestamp::UnknownDelay {
+            prev: ms(10),
+            curr: ms(50),
+        };
+
+        for i in 1..MAX_PENDING {
+            assert!(interp.push(i, chunk_at(unknown(), vec![])).is_empty());
+        }
+        let ready = interp.push(MAX_PENDING, chunk_at(unknown(), vec![]));
+        assert_eq!(ready.len(), MAX_PENDING);
+        assert!(ready
+            .iter()
+            .all(|(_, chunk)| chunk.estimated_timestamp.is_none()));
+        assert!(interp.flush().is_empty());
+    }
+}
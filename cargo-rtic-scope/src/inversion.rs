@@ -0,0 +1,257 @@
+//! Detection of tasks blocked by lower-priority tasks holding a shared
+//! resource, from the reconstructed preemption stack and resource lock
+//! events (see `cortex_m_rtic_trace::trace_lock`).
+//!
+//! The trace does not tell when a task was released, only when it was
+//! entered. Under the stack resource policy of RTIC, a task released
+//! while a lower-priority task holds a resource with a ceiling at least
+//! its priority is entered as soon as the resource is unlocked. A task
+//! of such a priority entered at the unlock of a resource held by
+//! another task was thus blocked for at most as long as the resource
+//! was held. Tasks of a priority above the ceiling preempt the holder
+//! regardless, and are not reported.
+use crate::timestamps::{cycles, offset};
+
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use rtic_scope_api as api;
+
+/// A task entered at most this many local timestamp ticks after a
+/// resource was unlocked is considered to have been blocked by it.
+/// Covers the exception entry latency.
+const UNLOCK_SLACK_TICKS: u64 = 64;
+
+/// A task that was blocked by another task holding a resource.
+#[derive(Debug, PartialEq)]
+pub struct Inversion {
+    /// The blocked task.
+    pub blocked: String,
+    /// The task that held the resource.
+    pub holder: String,
+    pub resource: String,
+    /// Offset from target reset when the resource was locked.
+    pub locked: Duration,
+    /// Offset from target reset when the resource was unlocked, and
+    /// the blocked task was entered.
+    pub unlocked: Duration,
+}
+
+/// A resource held by a task.
+struct Lock {
+    holder: String,
+    resource: String,
+    locked: Duration,
+}
+
+#[derive(Default)]
+pub struct InversionDetector {
+    /// Priorities of the tasks, by full task name.
+    priorities: IndexMap<String, u8>,
+    /// Priority ceilings of the resources, by name.
+    ceilings: IndexMap<String, u8>,
+    /// Cycles of the timestamp clock per local timestamp tick.
+    cycles_per_tick: u64,
+    /// Executing tasks, in the order they were entered.
+    stack: Vec<String>,
+    /// Resources held by executing tasks, in the order they were
+    /// locked.
+    locks: Vec<Lock>,
+    /// The resource unlocked last, if no task has been entered, exited
+    /// or returned to since.
+    unlocked: Option<(Lock, Duration)>,
+    pub inversions: Vec<Inversion>,
+}
+
+impl InversionDetector {
+    /// Creates a detector for an application with the given task
+    /// `priorities` and resource `ceilings`, traced with local
+    /// timestamps of `cycles_per_tick` cycles of the timestamp clock.
+    /// Tasks of unknown priority are never reported as blocked.
+    pub fn new(
+        priorities: IndexMap<String, u8>,
+        ceilings: IndexMap<String, u8>,
+        cycles_per_tick: u64,
+    ) -> Self {
+        Self {
+            priorities,
+            ceilings,
+            cycles_per_tick,
+            ..Self::default()
+        }
+    }
+
+    /// Whether `task` can be blocked by `lock` under the stack resource
+    /// policy: it has a priority above that of the holder, and at most
+    /// the ceiling of the resource. Resources of unknown ceiling are
+    /// not bounded by it.
+    fn blockable(&self, task: &str, lock: &Lock) -> bool {
+        let (priority, holder) =
+            match (self.priorities.get(task), self.priorities.get(&lock.holder)) {
+                (Some(priority), Some(holder)) => (priority, holder),
+                _ => return false,
+            };
+        priority > holder
+            && self
+                .ceilings
+                .get(&lock.resource)
+                .map_or(true, |ceiling| priority <= ceiling)
+    }
+
+    /// Accounts the task and resource events of `chunk`. `freq` is the
+    /// frequency of the timestamp clock.
+    pub fn update(&mut self, chunk: &api::EventChunk, freq: u32) {
+        let now = offset(&chunk.timestamp);
+        for event in chunk.events.iter() {
+            match event {
+                api::EventType::Task { name, action, .. } => {
+                    let unlocked = self.unlocked.take();
+                    match action {
+                        api::TaskAction::Entered => {
+                            if let Some((lock, at)) = unlocked {
+                                let delay = now.saturating_sub(at);
+                                if self.blockable(name, &lock)
+                                    && cycles(delay, freq)
+                                        <= UNLOCK_SLACK_TICKS * self.cycles_per_tick
+                                {
+                                    self.inversions.push(Inversion {
+                                        blocked: name.to_owned(),
+                                        holder: lock.holder,
+                                        resource: lock.resource,
+                                        locked: lock.locked,
+                                        unlocked: at,
+                                    });
+                                }
+                            }
+                            self.stack.push(name.to_owned());
+                        }
+                        api::TaskAction::Exited => {
+                            if let Some(i) = self.stack.iter().rposition(|t| t == name) {
+                                self.stack.remove(i);
+                            }
+                            self.locks.retain(|lock| lock.holder != *name);
+                        }
+                        api::TaskAction::Returned => (),
+                    }
+                }
+                api::EventType::Resource { name, action } => match action {
+                    api::ResourceAction::Locked => {
+                        if let Some(holder) = self.stack.last() {
+                            self.locks.push(Lock {
+                                holder: holder.to_owned(),
+                                resource: name.to_owned(),
+                                locked: now,
+                            });
+                        }
+                    }
+                    api::ResourceAction::Unlocked => {
+                        if let Some(i) = self.locks.iter().rposition(|l| l.resource == *name) {
+                            self.unlocked = Some((self.locks.remove(i), now));
+                        }
+                    }
+                },
+                // NOTE the state of the target is unknown afterwards.
+                api::EventType::Overflow => {
+                    self.stack.clear();
+                    self.locks.clear();
+                    self.unlocked = None;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, task};
+
+    fn resource(name: &str, action: api::ResourceAction) -> api::EventType {
+        api::EventType::Resource {
+            name: name.to_string(),
+            action,
+        }
+    }
+
+    fn detector(cycles_per_tick: u64) -> InversionDetector {
+        InversionDetector::new(
+            IndexMap::from([
+                ("low".to_string(), 1),
+                ("high".to_string(), 2),
+                ("highest".to_string(), 3),
+            ]),
+            IndexMap::from([("shared".to_string(), 2)]),
+            cycles_per_tick,
+        )
+    }
+
+    /// Ensure a task entered at the unlock of a resource held by another
+    /// task is reported as blocked, but not one entered later.
+    #[test]
+    fn detect() {
+        use api::ResourceAction::*;
+        use api::TaskAction::*;
+
+        let ns = Duration::from_nanos;
+        // 1 MHz: a cycle per µs.
+        let mut detector = detector(1);
+        for chunk in [
+            chunk(ns(0), vec![task("low", Entered)]),
+            chunk(ns(1_000), vec![resource("shared", Locked)]),
+            chunk(
+                ns(5_000),
+                vec![resource("shared", Unlocked), task("high", Entered)],
+            ),
+            chunk(ns(6_000), vec![task("high", Exited), task("low", Returned)]),
+            chunk(ns(7_000), vec![resource("shared", Locked)]),
+            chunk(ns(8_000), vec![resource("shared", Unlocked)]),
+            chunk(ns(100_000), vec![task("high", Entered)]),
+        ] {
+            detector.update(&chunk, 1_000_000);
+        }
+
+        assert_eq!(
+            detector.inversions,
+            vec![Inversion {
+                blocked: "high".to_string(),
+                holder: "low".to_string(),
+                resource: "shared".to_string(),
+                locked: Duration::from_micros(1),
+                unlocked: Duration::from_micros(5),
+            }]
+        );
+    }
+
+    /// Ensure only tasks of a priority above the holder's and at most
+    /// the ceiling of the resource are reported, and that the slack
+    /// scales with the local timestamp prescaler.
+    #[test]
+    fn priorities() {
+        use api::ResourceAction::*;
+        use api::TaskAction::*;
+
+        let ns = Duration::from_nanos;
+        // 1 MHz: a cycle per µs.
+        let inversions = |entered: &str, delay: u64, cycles_per_tick: u64| {
+            let mut detector = detector(cycles_per_tick);
+            for chunk in [
+                chunk(ns(0), vec![task("low", Entered)]),
+                chunk(ns(1_000), vec![resource("shared", Locked)]),
+                chunk(ns(5_000), vec![resource("shared", Unlocked)]),
+                chunk(ns(5_000 + delay * 1_000), vec![task(entered, Entered)]),
+            ] {
+                detector.update(&chunk, 1_000_000);
+            }
+            detector.inversions.len()
+        };
+
+        assert_eq!(inversions("high", 0, 1), 1);
+        assert_eq!(inversions("low", 0, 1), 0);
+        assert_eq!(inversions("highest", 0, 1), 0);
+        assert_eq!(inversions("unknown", 0, 1), 0);
+
+        assert_eq!(inversions("high", 100, 1), 0);
+        assert_eq!(inversions("high", 100, 16), 1);
+    }
+}
@@ -1,13 +1,222 @@
-//! Auxilliary functions for logging information to `stdout`.
+//! Auxilliary functions for logging information to `stderr`.
+//!
+//! All logged lines (except the live status line) are also recorded
+//! in the session log, if one is started; see [`start_session_log`].
+//!
+//! Internal details are logged via [`debug!`] and [`trace!`], which are
+//! only printed if enabled for the logging module via `-v`/`-vv` or
+//! [`FILTER_ENV`]; see [`set_filter`].
 use colored::Colorize;
 use crossterm::{
     cursor,
     terminal::{Clear, ClearType},
     ExecutableCommand,
 };
-use std::io::stderr;
+use std::fs;
+use std::io::{self, stderr, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// Environment variable with module-specific log levels, e.g.
+/// `recovery=debug,sources=trace`. A level without a module applies to
+/// all modules.
+pub const FILTER_ENV: &str = "RTIC_SCOPE_LOG";
+
+/// Verbosity of logged internal details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Only status lines, warnings, errors, and hints.
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => Err(format!(
+                "unknown log level {:?}; expected info, debug, or trace",
+                s
+            )),
+        }
+    }
+}
+
+/// Log levels of modules, by module path relative to the crate root
+/// (e.g. `sources::probe`), and of all other modules.
+struct Filter {
+    modules: Vec<(String, Level)>,
+    default: Level,
+}
+
+impl Filter {
+    /// Parses a filter of the form of [`FILTER_ENV`].
+    fn parse(s: &str, default: Level) -> Result<Self, String> {
+        let mut filter = Self {
+            modules: vec![],
+            default,
+        };
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => filter
+                    .modules
+                    .push((module.trim().to_string(), level.trim().parse()?)),
+                None => filter.default = filter.default.max(directive.parse()?),
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// The level of `module`, as given by [`module_path!`]: that of the
+    /// longest matching module of the filter, if any.
+    fn level(&self, module: &str) -> Level {
+        // NOTE the first segment is the name of the crate
+        let module = module.split_once("::").map_or("", |(_, path)| path);
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| {
+                module == prefix
+                    || module
+                        .strip_prefix(prefix.as_str())
+                        .map_or(false, |rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level of any module.
+    fn max_level(&self) -> Level {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Level::max)
+    }
+}
+
+static FILTER: Mutex<Filter> = Mutex::new(Filter {
+    modules: Vec::new(),
+    default: Level::Info,
+});
+
+/// [`Filter::max_level`] of [`FILTER`], such that disabled details are
+/// skipped without taking the lock.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Enables the logging of internal details: of all modules at
+/// `verbosity` (0 for [`Level::Info`], 1 for [`Level::Debug`], 2 or
+/// more for [`Level::Trace`]), and of the modules given in `filter`,
+/// which is of the form of [`FILTER_ENV`].
+pub fn set_filter(verbosity: u8, filter: Option<&str>) -> Result<(), String> {
+    let default = match verbosity {
+        0 => Level::Info,
+        1 => Level::Debug,
+        _ => Level::Trace,
+    };
+    let filter = Filter::parse(filter.unwrap_or_default(), default)?;
+    MAX_LEVEL.store(filter.max_level() as u8, Ordering::Relaxed);
+    *FILTER.lock().unwrap() = filter;
+
+    Ok(())
+}
+
+/// Whether messages of `level` are logged for `module`.
+pub fn enabled(module: &str, level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+        && FILTER.lock().unwrap().level(module) >= level
+}
+
+/// Logs an internal detail of `module`. Use [`debug!`] or [`trace!`]
+/// instead, which skip formatting the message unless it is logged.
+pub fn detail(module: &str, level: Level, msg: String) {
+    // NOTE the first segment is the name of the crate
+    let module = module.split_once("::").map_or("main", |(_, path)| path);
+    let msg = format!("[{}] {}", module, msg);
+    match level {
+        Level::Info => status("Info", msg),
+        Level::Debug => indent_with("Debug".dimmed().bold(), msg),
+        Level::Trace => indent_with("Trace".dimmed(), msg),
+    }
+}
+
+/// Logs an internal detail of the calling module if enabled; see
+/// [`set_filter`].
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled(module_path!(), $crate::log::Level::Debug) {
+            $crate::log::detail(module_path!(), $crate::log::Level::Debug, format!($($arg)*));
+        }
+    };
+}
+pub(crate) use debug;
+
+/// Like [`debug!`], for even finer details.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled(module_path!(), $crate::log::Level::Trace) {
+            $crate::log::detail(module_path!(), $crate::log::Level::Trace, format!($($arg)*));
+        }
+    };
+}
+pub(crate) use trace;
+
+/// Lines recorded before the session log is started are kept up to
+/// this number, and written once it is.
+const PENDING_LINES: usize = 1024;
+
+struct SessionLog {
+    file: Option<fs::File>,
+    pending: Vec<String>,
+}
+
+static SESSION_LOG: Mutex<SessionLog> = Mutex::new(SessionLog {
+    file: None,
+    pending: Vec::new(),
+});
+
+/// Records all subsequently logged lines, and those logged so far, to
+/// `path` with a wall-clock timestamp. Lines are written unbuffered so
+/// that the log survives a crash of the session.
+pub fn start_session_log(path: &Path) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    let mut log = SESSION_LOG.lock().unwrap();
+    for line in log.pending.drain(..) {
+        file.write_all(line.as_bytes())?;
+    }
+    log.file = Some(file);
+
+    Ok(())
+}
+
+fn record(header: &str, msg: &str) {
+    let now = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+    let mut lines = String::new();
+    for line in msg.lines() {
+        lines.push_str(&format!("{} {:>8} {}\n", now, header, line));
+    }
+
+    let mut log = SESSION_LOG.lock().unwrap();
+    match log.file.as_mut() {
+        Some(file) => {
+            // NOTE a failing session log must not fail the session.
+            let _ = file.write_all(lines.as_bytes());
+        }
+        None if log.pending.len() < PENDING_LINES => log.pending.push(lines),
+        None => (),
+    }
+}
 
 fn indent_with(header: colored::ColoredString, msg: String) {
+    // NOTE derefs to the uncolored header
+    record(&header, &msg);
+
     // clear current line
     let _ = stderr().execute(Clear(ClearType::CurrentLine));
 
@@ -47,3 +256,31 @@ pub fn frontend(msg: String) {
 pub fn hint(msg: String) {
     indent_with("Hint".blue().bold(), msg);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure the longest matching module of a filter applies, and the
+    /// default level otherwise.
+    #[test]
+    fn filter() {
+        let filter = Filter::parse(
+            "recovery=debug, sources=trace,sources::tty=info",
+            Level::Info,
+        )
+        .unwrap();
+        let level = |module: &str| filter.level(&format!("cargo_rtic_scope::{}", module));
+        assert_eq!(level("recovery"), Level::Debug);
+        assert_eq!(level("sources::probe"), Level::Trace);
+        assert_eq!(level("sources::tty"), Level::Info);
+        assert_eq!(level("sourcesx"), Level::Info);
+        assert_eq!(level("stats"), Level::Info);
+        assert_eq!(filter.max_level(), Level::Trace);
+
+        let filter = Filter::parse("debug", Level::Info).unwrap();
+        assert_eq!(filter.level("cargo_rtic_scope::stats"), Level::Debug);
+        assert_eq!(filter.max_level(), Level::Debug);
+        assert!(Filter::parse("recovery=loud", Level::Info).is_err());
+    }
+}
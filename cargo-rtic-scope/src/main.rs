@@ -3,29 +3,64 @@
 
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::{bail, Context};
+use anyhow::Context;
 use async_std::{prelude::*, process};
 use cargo_metadata::Artifact;
 use chrono::Local;
 use crossbeam_channel as channel;
 use futures::executor::block_on;
-use probe_rs_cli_util::{
-    common_options::{CargoOptions, FlashOptions},
-    flash,
-};
+use probe_rs_cli_util::common_options::{CargoOptions, FlashOptions, ProbeOptions};
 use rtic_scope_api as api;
 use structopt::StructOpt;
 use thiserror::Error;
 
+mod attach;
+mod auxiliary;
+mod baseline;
+mod bench;
 mod build;
+mod config;
+mod cores;
+mod corpus;
+mod cpu_load;
+mod crash;
+mod decoding;
 mod diag;
+mod drift;
+mod dump_regs;
+mod export;
+#[cfg(test)]
+mod fixtures;
+mod flashing;
+mod frontends;
+mod gts;
+mod init;
+mod interpolation;
+mod inversion;
 mod log;
 mod manifest;
+mod merge;
+mod normalize;
+mod obfuscate;
+mod phase;
+mod plan;
+mod probe_select;
+mod progress;
+mod query;
+mod queues;
 mod recovery;
+mod reset;
+mod schedulability;
 mod sinks;
 mod sources;
+mod stats;
+mod step;
+mod stimulus;
+mod timestamps;
+mod warnings;
+mod watchdog;
 
 use build::{CargoError, CargoWrapper};
 use recovery::TraceMetadata;
@@ -35,10 +70,131 @@ pub type TraceData = itm::TimestampedTracePackets;
 #[derive(Debug, StructOpt)]
 struct Opts {
     /// PATH, relative, or absolute path to the frontend(s) to forward
-    /// recorded/replayed trace to. Tested in that order.
-    #[structopt(long = "frontend", short = "-F", default_value = "dummy")]
+    /// recorded/replayed trace to. Tested in that order. Extends the
+    /// `frontends` set in `[package.metadata.rtic-scope]`. The dummy
+    /// frontend is used if no frontends are given.
+    #[structopt(long = "frontend", short = "-F")]
     frontends: Vec<String>,
 
+    /// Ignore the `frontends` set in `[package.metadata.rtic-scope]`.
+    #[structopt(long = "no-default-frontends")]
+    no_default_frontends: bool,
+
+    /// Do not estimate timestamps of unknown quality (e.g. after an
+    /// overflow) from the surrounding timestamps of known quality.
+    #[structopt(long = "no-interpolation")]
+    no_interpolation: bool,
+
+    /// Forward an execution interval-oriented stream of task actions to
+    /// frontends: each task execution is described by a single enter
+    /// and a single exit. Returns to preempted tasks are dropped, and
+    /// tasks still executing at an overflow are exited.
+    #[structopt(long = "normalize-actions")]
+    normalize_actions: bool,
+
+    /// Exit tasks that have not exited this many seconds after they
+    /// were entered (e.g. 0.1), as when their exit was lost to an
+    /// overflow. The exit is marked as estimated, and preceded by a
+    /// MissingExit anomaly. The time a task is preempted counts towards
+    /// the timeout.
+    #[structopt(long = "watchdog", parse(try_from_str = parse_seconds))]
+    watchdog: Option<std::time::Duration>,
+
+    /// The watchdog timeout of a single task in seconds, as
+    /// <task>=<seconds> (e.g. app::uart=0.5). Overrides --watchdog for
+    /// the task. May be given several times.
+    #[structopt(long = "watchdog-task", parse(try_from_str = parse_task_timeout))]
+    watchdog_tasks: Vec<(String, std::time::Duration)>,
+
+    /// Do not build the application, but use a pre-built artifact: a
+    /// file with the output of `cargo build --message-format=json`, of
+    /// which the single executable artifact is used, or an ELF file.
+    /// The metadata is still recovered from the sources, and the target
+    /// flashed when tracing.
+    #[structopt(long = "artifact", parse(from_os_str))]
+    artifact: Option<PathBuf>,
+
+    /// Root source file (e.g. src/main.rs) of the target the ELF file
+    /// given to --artifact was built from. By default, the bin or
+    /// example target named after the ELF file is used.
+    #[structopt(long = "artifact-src", parse(from_os_str), requires("artifact"))]
+    artifact_src: Option<PathBuf>,
+
+    /// Exit with a non-zero status if any warnings were emitted during
+    /// the trace/replay session (overflows, malformed or non-mappable
+    /// packets).
+    #[structopt(long = "strict")]
+    strict: bool,
+
+    /// Trace firmware built from other sources than the metadata is
+    /// recovered from, as told by the build ID embedded via
+    /// `#[cortex_m_rtic_trace::build_id]`. Trace data may then be
+    /// attributed to the wrong tasks.
+    #[structopt(long = "allow-stale-maps")]
+    allow_stale_maps: bool,
+
+    /// Do not print the live status line during the trace/replay
+    /// session. Warnings and the final summary are still printed.
+    #[structopt(long = "quiet")]
+    quiet: bool,
+
+    /// Only warn once about each kind of packet that cannot be mapped
+    /// or decoded, for each reason. Repeats are counted, and summarized
+    /// every minute and at the end of the session.
+    #[structopt(long = "quiet-warnings")]
+    quiet_warnings: bool,
+
+    /// Log internal details to stderr: `-v` for debug and `-vv` for
+    /// trace details. Details can also be enabled per module via the
+    /// `RTIC_SCOPE_LOG` environment variable, e.g.
+    /// `RTIC_SCOPE_LOG=recovery=debug,sources=trace`.
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: u8,
+
+    /// Apply the options of this profile of the user configuration
+    /// (e.g. `[profile.rack]`) where they are not given on the command
+    /// line. The TPIU frequency and baud rate of the profile only apply
+    /// where the manifest does not set them either.
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+
+    /// Evaluate an expression over the event stream, e.g.
+    /// 'count(task == "app::uart", action == Entered) per 1s', printing
+    /// its value per window of trace time, or at the end if no window
+    /// is given. Aggregates are count and rate; fields are task,
+    /// resource, action and event. May be given several times.
+    #[structopt(long = "eval")]
+    eval: Vec<query::Query>,
+
+    /// Forward events to the frontends that support it through a
+    /// shared-memory ring buffer of the given size in bytes, in which
+    /// each chunk is serialized once, instead of over a socket per
+    /// frontend. Frontends that fall behind miss the overwritten
+    /// chunks.
+    #[structopt(long = "shm-ring")]
+    shm_ring: Option<u64>,
+
+    /// Watch this directory for frontends to attach during the session,
+    /// created if missing. A frontend attaches by listening on a socket
+    /// it creates in the directory, after which it is sent the metadata
+    /// greeting and the events from then on. It detaches by removing
+    /// the socket.
+    #[structopt(long = "attach-dir", parse(from_os_str))]
+    attach_dir: Option<PathBuf>,
+
+    /// Publish the metadata and events of the session to an MQTT
+    /// broker, given as mqtt://host[:port][/prefix], under the topics
+    /// <prefix>/metadata, <prefix>/events and <prefix>/trailer. The
+    /// broker is reconnected to if the connection is lost; events are
+    /// dropped meanwhile.
+    #[structopt(long = "mqtt")]
+    mqtt: Option<sinks::MqttUrl>,
+
+    /// Publish the number of events of each kind to <prefix>/stats once
+    /// per this many seconds, instead of each event chunk.
+    #[structopt(long = "mqtt-interval", parse(try_from_str = parse_seconds), requires("mqtt"))]
+    mqtt_interval: Option<std::time::Duration>,
+
     #[structopt(subcommand)]
     cmd: Command,
 }
@@ -52,6 +208,20 @@ struct TraceOptions {
     #[structopt(name = "serial", long = "serial")]
     serial: Option<String>,
 
+    /// Read the raw SWO stream from the SWO port (e.g. localhost:2332)
+    /// of a running SEGGER J-Link GDB Server, instead of via probe-rs.
+    /// The SWO capture is configured and the target reset via monitor
+    /// commands over the GDB port of the server (see --jlink-gdb). As
+    /// the server holds the probe, the target is not flashed: flash it
+    /// via the J-Link tools beforehand.
+    #[structopt(long = "jlink-swo", conflicts_with_all(&["serial", "swo-silence"]))]
+    jlink_swo: Option<String>,
+
+    /// The GDB port of the J-Link GDB Server. Defaults to port 2331 of
+    /// the host of --jlink-swo.
+    #[structopt(long = "jlink-gdb", requires("jlink-swo"))]
+    jlink_gdb: Option<String>,
+
     /// Output directory for recorded trace streams. By default, the
     /// build chache of <bin> is used (usually ./target/).
     #[structopt(long = "trace-dir", parse(from_os_str))]
@@ -65,23 +235,258 @@ struct TraceOptions {
     #[structopt(long = "clear-traces")]
     remove_prev_traces: bool,
 
-    /// Only resolve the translation maps; do not program or trace the target.
+    /// Only record the trace data of the chunks with selected events in
+    /// the trace file, e.g. 'events: task | overflow'; frontends still
+    /// receive all events. Alternatives are separated by |, each the
+    /// kind of an event or filters as of --eval, separated by commas.
+    /// Events derived from several chunks (e.g. lines of stimulus
+    /// ports) may be incomplete when replayed.
+    #[structopt(long = "file-filter")]
+    file_filter: Option<query::EventFilter>,
+
+    /// Also upload the trace file during the session, in gzip-compressed
+    /// segments PUT under this URL (e.g. of an S3-compatible store).
+    /// Segments are retried on failure; a manifest listing them is
+    /// uploaded at the end of the session. Requires curl in PATH. As
+    /// the name of each object is appended to the URL, presigned URLs
+    /// are not supported.
+    #[structopt(
+        long = "upload",
+        conflicts_with("snapshot"),
+        parse(try_from_str = parse_upload_url)
+    )]
+    upload: Option<String>,
+
+    /// Sign uploads with AWS Signature Version 4, as curl --aws-sigv4
+    /// (e.g. "aws:amz:eu-north-1:s3"), with the credentials of
+    /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+    #[structopt(long = "upload-sigv4", requires("upload"))]
+    upload_sigv4: Option<String>,
+
+    /// Size of the uploaded segments, in bytes, before compression.
+    /// Segments are also uploaded once a minute.
+    #[structopt(long = "upload-segment", default_value = "8388608", requires("upload"))]
+    upload_segment: usize,
+
+    /// Flash and trace this ELF file, built outside of cargo (e.g. by
+    /// CMake linking a Rust staticlib), instead of building the
+    /// application. The manifest properties are read from the package
+    /// of the working directory (or --manifest-path); the application
+    /// is recovered from --app-src, which is required.
+    #[structopt(long = "elf", parse(from_os_str), requires("app-src"))]
+    elf: Option<PathBuf>,
+
+    /// Only resolve the translation maps and the DWT comparators they
+    /// rely on; do not program or trace the target. What is programmed
+    /// into the comparators is read back if a probe is attached.
     #[structopt(long = "resolve-only")]
     resolve_only: bool,
 
+    /// Print the planned session, from the resolved manifest properties
+    /// to the frontends that would be spawned, and exit without
+    /// touching the target.
+    #[structopt(long = "plan")]
+    plan: bool,
+
     /// Do not attempt to flash, configure and/or reset the target:
     /// start tracing immediately.
     #[structopt(long = "dont-touch-target", requires("serial"))]
     dont_touch_target: bool,
 
+    /// Only keep the last <snapshot> seconds of trace in memory, and
+    /// only write them to the trace file when the target hard faults,
+    /// on Ctrl-\, or on a command to --snapshot-socket.
+    #[structopt(long = "snapshot", parse(try_from_str = parse_seconds))]
+    snapshot: Option<std::time::Duration>,
+
+    /// Bind a Unix socket at this path on which each "snapshot" line
+    /// written triggers a snapshot (e.g. `echo snapshot | socat -
+    /// UNIX-CONNECT:<path>`).
+    #[structopt(long = "snapshot-socket", parse(from_os_str), requires("snapshot"))]
+    snapshot_socket: Option<PathBuf>,
+
+    /// With several probes connected, select the probe whose target
+    /// chip matches this glob pattern (e.g. "stm32f4*"). Each probe is
+    /// briefly attached to in order to identify its target.
+    #[structopt(long = "chip-filter")]
+    chip_filter: Option<String>,
+
+    /// Index of the core to trace on a multi-core target. Each core is
+    /// traced in a session of its own; the index is recorded in the
+    /// trace such that the traces can be analyzed together via
+    /// `--merge` of stats and export.
+    #[structopt(long = "core")]
+    core: Option<u8>,
+
+    /// How to reset the target before tracing: sysreset (SYSRESETREQ),
+    /// vectreset (VECTRESET, core only; ARMv7-M only), hardware (nRESET
+    /// asserted by the probe, if it can), or none. --reset-halt halts
+    /// the target at its reset vector with any method but none.
+    #[structopt(long = "reset-method", default_value = "sysreset")]
+    reset_method: reset::ResetMethod,
+
+    /// Re-apply the SWO configuration if no trace data is received for
+    /// this many seconds after some has been, as when the firmware
+    /// reconfigures the SWO pin. The outage is reported as a TraceGap
+    /// event, also on replay. Only use with firmware that is never idle
+    /// for as long, or idleness is mistaken for an outage.
+    #[structopt(long = "swo-silence", parse(try_from_str = parse_seconds), conflicts_with("serial"))]
+    swo_silence: Option<std::time::Duration>,
+
+    /// After flashing, read back the firmware from the target and
+    /// compare it with the ELF file.
+    #[structopt(long = "verify")]
+    verify: bool,
+
+    /// Replace task and resource names in the trace file with stable
+    /// pseudonyms. The mapping is stored in (and extended from) the
+    /// given file, which should not be shared along with the trace.
+    #[structopt(long = "obfuscate-names", parse(from_os_str))]
+    obfuscate_names: Option<PathBuf>,
+
+    /// Log the raw bytes read from the probe or serial device, before
+    /// decoding, as a hexdump with host arrival timestamps to the given
+    /// file. Useful when debugging transport issues (e.g. garbled SWO).
+    #[structopt(long = "debug-raw-log", parse(from_os_str))]
+    debug_raw_log: Option<PathBuf>,
+
+    /// Record everything printed during the session (status lines,
+    /// warnings, errors, hints, and frontend stderr) with timestamps to
+    /// the given file, instead of to <trace file>.log.
+    #[structopt(long = "log-file", parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    /// Do not record a session log next to the trace file.
+    #[structopt(long = "no-log-file", conflicts_with("log-file"))]
+    no_log_file: bool,
+
+    /// Compare the execution times of each task against those of a
+    /// baseline at the end of the session, and fail if any regressed.
+    /// The baseline is a trace file, or the report of `cargo rtic-scope
+    /// stats --aggregate --format json --units ns`.
+    #[structopt(long = "baseline", parse(from_os_str))]
+    baseline: Option<PathBuf>,
+
+    /// How much longer, in percent, the longest and mean execution
+    /// time of a task may be than in the baseline.
+    #[structopt(long = "baseline-tolerance", default_value = "10")]
+    baseline_tolerance: f64,
+
+    /// Read the samples of an external instrument (e.g. the current
+    /// drawn by the target, as measured by a power analyzer) during the
+    /// session from this CSV file, FIFO, or serial device, and merge
+    /// them into the event stream as AuxSample events. The header names
+    /// the columns: time, in seconds, followed by a column per channel.
+    /// The clock of the instrument is aligned on the sync pulse of the
+    /// target (see `cortex_m_rtic_trace::sync_pulse`): at the first
+    /// non-zero value of a sync column, or at time zero without one.
+    /// The samples are not recorded in the trace file.
+    #[structopt(long = "aux-csv", parse(from_os_str))]
+    aux_csv: Option<PathBuf>,
+
     #[structopt(flatten)]
     pac: ManifestOptions,
 
+    #[structopt(flatten)]
+    app: AppOptions,
+
     #[structopt(flatten)]
     flash_options: FlashOptions,
 }
 
+fn parse_seconds(s: &str) -> Result<std::time::Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0.0 => std::time::Duration::try_from_secs_f64(secs)
+            .map_err(|e| format!("{} is not a valid number of seconds: {}", s, e)),
+        _ => Err(format!("{} is not a positive number of seconds", s)),
+    }
+}
+
+fn parse_upload_url(s: &str) -> Result<String, String> {
+    if s.contains('?') {
+        return Err(format!(
+            "{} has a query, but the name of each object is appended to the path: presigned URLs are not supported; sign uploads via --upload-sigv4 instead",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_task_timeout(s: &str) -> Result<(String, std::time::Duration), String> {
+    let (task, secs) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{} is not of the form <task>=<seconds>", s))?;
+    Ok((task.to_string(), parse_seconds(secs)?))
+}
+
+/// Where to find the RTIC application to recover metadata from.
 #[derive(StructOpt, Debug)]
+struct AppOptions {
+    /// Source file containing the #[rtic::app] declaration. By
+    /// default, the module tree of the target is searched, starting
+    /// from its root source file.
+    #[structopt(long = "app-src", parse(from_os_str))]
+    app_src: Option<PathBuf>,
+
+    /// Module path of the RTIC application to trace (e.g. "app" or
+    /// "tests::app"), if several are declared. By default, the
+    /// application enabled by the built features, or else the one
+    /// named after the target, is selected.
+    #[structopt(long = "app")]
+    app_path: Option<String>,
+
+    /// Trace an application not written with RTIC. No metadata is
+    /// recovered: exception traces are reported under the names of
+    /// the exceptions (read from the PAC, if configured), and DWT data
+    /// traces are left unmapped.
+    #[structopt(long = "no-rtic", conflicts_with_all(&["app-src", "app-path"]))]
+    no_rtic: bool,
+}
+
+impl AppOptions {
+    /// Reads the manifest properties and builds the lookup maps of the
+    /// traced application, reporting the progress to `progress`.
+    fn recover(
+        &self,
+        cargo: &CargoWrapper,
+        artifact: &Artifact,
+        opts: Option<&ManifestOptions>,
+        progress: &progress::Observer,
+    ) -> Result<(manifest::ManifestProperties, recovery::TraceLookupMaps), RTICScopeError> {
+        use progress::Stage;
+        use recovery::TraceLookupMaps;
+
+        progress
+            .borrow_mut()
+            .started(Stage::Recovery, Some(1 + TraceLookupMaps::RECOVERY_STEPS));
+        let recovered = if self.no_rtic {
+            let manip = manifest::ManifestProperties::bare(cargo, opts)?;
+            progress.borrow_mut().advanced(Stage::Recovery, 1);
+            let maps = TraceLookupMaps::bare(cargo, &manip)?;
+            progress
+                .borrow_mut()
+                .advanced(Stage::Recovery, TraceLookupMaps::RECOVERY_STEPS);
+            (manip, maps)
+        } else {
+            let manip = manifest::ManifestProperties::new(cargo, opts)?;
+            progress.borrow_mut().advanced(Stage::Recovery, 1);
+            let maps = TraceLookupMaps::from(
+                cargo,
+                artifact,
+                &manip,
+                self.app_src.as_deref(),
+                self.app_path.as_deref(),
+                progress,
+            )?;
+            (manip, maps)
+        };
+        progress.borrow_mut().finished(Stage::Recovery);
+
+        Ok(recovered)
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
 pub struct ManifestOptions {
     /// Name of the PAC used in traced application.
     #[structopt(long = "pac-name", name = "pac-name")]
@@ -100,13 +505,45 @@ pub struct ManifestOptions {
     interrupt_path: Option<String>,
 
     /// Speed in Hz of the TPIU trace clock. Used to calculate
-    /// timestamps of received timestamps.
+    /// timestamps of received timestamps. When replaying a raw trace
+    /// file, defaults to the frequency reported by the target in the
+    /// file, if any.
     #[structopt(long = "tpiu-freq")]
     tpiu_freq: Option<u32>,
 
     /// Baud rate of the communication from the target TPIU.
     #[structopt(long = "tpiu-baud")]
     tpiu_baud: Option<u32>,
+
+    /// Exceptions or interrupts to ignore (e.g. "SysTick"): their
+    /// trace packets are dropped before they are mapped to RTIC tasks.
+    #[structopt(long = "ignore-exceptions")]
+    ignore_exceptions: Option<Vec<String>>,
+
+    /// Decoder of the trace stream: itm, or vendor to drop the
+    /// vendor-specific hardware source packets some silicon emits
+    /// instead of reporting them as malformed.
+    #[structopt(long = "decoder")]
+    decoder: Option<sources::DecoderKind>,
+
+    /// How traced functions nested in software tasks are named after
+    /// their paths: full (e.g. app::foo::bar), leaf (bar), or
+    /// app-relative (foo::bar). Software tasks keep their full path.
+    #[structopt(long = "name-policy")]
+    name_policy: Option<recovery::NamePolicy>,
+
+    /// The TPIU options of the profile selected via `--profile`, which
+    /// apply where neither the command line nor the manifest sets them.
+    #[structopt(skip)]
+    profile: ProfileManifestOptions,
+}
+
+/// The options of a [`config::Profile`] that layer below
+/// `[{package,workspace}.metadata.rtic-scope]`.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileManifestOptions {
+    tpiu_freq: Option<u32>,
+    tpiu_baud: Option<u32>,
 }
 
 /// Replay a previously recorded trace stream for post-mortem analysis.
@@ -115,13 +552,29 @@ struct ReplayOptions {
     #[structopt(name = "list", long = "list", short = "l")]
     list: bool,
 
+    /// With --list, also print how each trace was recorded: the version
+    /// of cargo-rtic-scope, the command line, the effective options and
+    /// manifest properties, and the DWT comparators the trace relies
+    /// on.
+    #[structopt(long = "verbose", requires("list"))]
+    verbose: bool,
+
     /// Relative path to trace file to replay.
     #[structopt(name = "trace-file", long = "trace-file")]
     trace_file: Option<PathBuf>,
 
-    #[structopt(required_unless_one(&["list", "raw-file", "trace-file"]))]
+    #[structopt(required_unless_one(&["list", "raw-file", "trace-file", "stdin"]))]
     index: Option<usize>,
 
+    /// Read the trace from stdin instead of from a file, e.g. `ssh rig
+    /// cat trace | cargo rtic-scope replay --stdin`.
+    #[structopt(
+        name = "stdin",
+        long = "stdin",
+        conflicts_with_all(&["trace-file", "raw-file", "list"])
+    )]
+    stdin: bool,
+
     #[structopt(flatten)]
     raw_options: RawFileOptions,
 
@@ -130,6 +583,56 @@ struct ReplayOptions {
     #[structopt(name = "trace-dir", long = "trace-dir", parse(from_os_str))]
     trace_dir: Option<PathBuf>,
 
+    /// Correct timestamps for the drift of the target timestamp clock
+    /// relative to the host clock, as estimated during tracing.
+    #[structopt(long = "drift-correct")]
+    drift_correct: bool,
+
+    /// Restore the task and resource names of a trace recorded with
+    /// `--obfuscate-names` from the given mapping file.
+    #[structopt(long = "deobfuscate-names", parse(from_os_str))]
+    deobfuscate_names: Option<PathBuf>,
+
+    /// Rename the tasks and resources of the trace to their names in
+    /// the current sources of the application, which is built to
+    /// recover them. Hardware tasks are matched by the interrupt they
+    /// are bound to, and software tasks and resources by their ID. The
+    /// renames are applied once confirmed.
+    #[structopt(long = "remap", conflicts_with("raw-file"))]
+    remap: bool,
+
+    /// Apply the renames of --remap without asking for confirmation.
+    #[structopt(long = "yes", short = "y", requires("remap"))]
+    yes: bool,
+
+    /// Only replay the trace data recorded this many seconds after
+    /// target reset or later. The trace data before is skipped via the
+    /// index of the trace file, if it has one.
+    #[structopt(long = "from", parse(try_from_str = parse_seconds), conflicts_with("raw-file"))]
+    from: Option<std::time::Duration>,
+
+    /// Only replay the trace data recorded up to this many seconds after
+    /// target reset.
+    #[structopt(long = "to", parse(try_from_str = parse_seconds), conflicts_with("raw-file"))]
+    to: Option<std::time::Duration>,
+
+    /// Pause after each chunk, print it, and wait for a command on
+    /// stdin: Enter or `n` steps to the next chunk, `c` continues to the
+    /// end, `c <n>` continues for n chunks, and `u <task>` continues
+    /// until the task enters.
+    #[structopt(long = "interactive", conflicts_with("stdin"))]
+    interactive: bool,
+
+    /// Only pause at chunks with task events.
+    #[structopt(long = "step-tasks", requires("interactive"))]
+    step_tasks: bool,
+
+    /// Record everything printed during the session (status lines,
+    /// warnings, errors, hints, and frontend stderr) with timestamps to
+    /// the given file.
+    #[structopt(long = "log-file", parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
     #[structopt(flatten)]
     cargo_options: CargoOptions,
 }
@@ -138,19 +641,325 @@ struct ReplayOptions {
 struct RawFileOptions {
     /// Path to the file containing raw trace data that should be
     /// replayed.
-    #[structopt(name = "raw-file", long = "raw-file", requires("virtual-freq"))]
+    #[structopt(name = "raw-file", long = "raw-file")]
     file: Option<PathBuf>,
 
+    /// Number of leading bytes of the raw file to ignore.
+    #[structopt(long = "skip-bytes", default_value = "0", requires("raw-file"))]
+    skip_bytes: u64,
+
+    /// Treat the raw file as TPIU-formatted and decode the trace
+    /// source with the given ID. By default, TPIU framing is
+    /// auto-detected and the ITM is expected on trace source ID 1.
+    #[structopt(long = "tpiu-stream", requires("raw-file"))]
+    tpiu_stream: Option<u8>,
+
+    /// Map the raw file via the metadata of the given trace file,
+    /// recorded from the same build of the application, and decode it
+    /// as that trace was decoded (e.g. under the same TPIU frequency).
+    /// The application is then not built, and no PAC or TPIU options
+    /// need to be given.
+    #[structopt(
+        long = "metadata-from",
+        parse(from_os_str),
+        requires("raw-file"),
+        conflicts_with_all(&["pac-name", "pac-version", "pac-features", "interrupt-path", "tpiu-freq", "app-src", "app-path", "no-rtic"])
+    )]
+    metadata_from: Option<PathBuf>,
+
     #[structopt(long = "comment", short = "c", hidden = true)]
     comment: Option<String>,
     #[structopt(flatten)]
     pac: ManifestOptions,
+    #[structopt(flatten)]
+    app: AppOptions,
+}
+
+/// Print per-task execution statistics and the CPU load of a previously
+/// recorded trace.
+#[derive(StructOpt, Debug)]
+pub struct StatsOptions {
+    /// Relative path to trace file to analyze.
+    #[structopt(name = "trace-file", long = "trace-file")]
+    trace_file: Option<PathBuf>,
+
+    /// Index of the trace to analyze, as listed by `replay --list`.
+    #[structopt(required_unless_one(&["trace-file", "aggregate"]))]
+    index: Option<usize>,
+
+    /// Directory of previously recorded trace streams. By default,
+    /// the build cache of <bin> is used (usually ./target/).
+    #[structopt(name = "trace-dir", long = "trace-dir", parse(from_os_str))]
+    trace_dir: Option<PathBuf>,
+
+    /// Correct timestamps for the drift of the target timestamp clock
+    /// relative to the host clock, as estimated during tracing.
+    #[structopt(long = "drift-correct")]
+    drift_correct: bool,
+
+    /// Units to report execution times in: ns, or cycles of the
+    /// timestamp clock.
+    #[structopt(long = "units", default_value = "ns")]
+    units: stats::Units,
+
+    /// Evaluate an expression over the events of the trace; see `--eval`
+    /// of trace and replay.
+    #[structopt(long = "eval")]
+    eval: Vec<query::Query>,
+
+    /// Traces of the other cores of a multi-core target (see `trace
+    /// --core`) to analyze along with the given trace, as one dataset.
+    #[structopt(long = "merge", parse(from_os_str))]
+    merge: Vec<PathBuf>,
+
+    /// Analyze all traces in the trace directory instead of a single
+    /// one, and print a report of the statistics of each task across
+    /// all traces, along with those of each trace.
+    #[structopt(
+        name = "aggregate",
+        long = "aggregate",
+        conflicts_with_all(&["trace-file", "index", "merge"])
+    )]
+    aggregate: bool,
+
+    /// Only aggregate the traces recorded on or after the given date,
+    /// e.g. 2021-11-01.
+    #[structopt(long = "since", requires("aggregate"))]
+    since: Option<chrono::NaiveDate>,
+
+    /// Only aggregate the traces of the given program. Defaults to the
+    /// program of the most recently recorded trace.
+    #[structopt(long = "program", requires("aggregate"))]
+    program: Option<String>,
+
+    /// Format of the report of --aggregate: json, or csv. Defaults to
+    /// json.
+    #[structopt(long = "format", requires("aggregate"))]
+    format: Option<stats::ReportFormat>,
+
+    /// Only analyze the trace data recorded this many seconds after
+    /// target reset or later. The trace data before is skipped via the
+    /// index of the trace file, if it has one.
+    #[structopt(long = "from", parse(try_from_str = parse_seconds), conflicts_with("aggregate"))]
+    from: Option<std::time::Duration>,
+
+    /// Only analyze the trace data recorded up to this many seconds
+    /// after target reset.
+    #[structopt(long = "to", parse(try_from_str = parse_seconds), conflicts_with("aggregate"))]
+    to: Option<std::time::Duration>,
+
+    /// Merge the samples of an external instrument (e.g. a power
+    /// analyzer) from this CSV file into the trace, and report the
+    /// integral of each channel over the execution of each task (e.g.
+    /// energy from samples of power); see `trace --aux-csv`.
+    #[structopt(long = "aux-csv", parse(from_os_str), conflicts_with_all(&["aggregate", "merge"]))]
+    aux_csv: Option<PathBuf>,
+}
+
+/// Export the task execution intervals of a previously recorded trace
+/// for analysis in external tools.
+#[derive(StructOpt, Debug)]
+pub struct ExportOptions {
+    /// Relative path to trace file to export.
+    #[structopt(name = "trace-file", long = "trace-file")]
+    trace_file: Option<PathBuf>,
+
+    /// Index of the trace to export, as listed by `replay --list`.
+    #[structopt(required_unless("trace-file"))]
+    index: Option<usize>,
+
+    /// Directory of previously recorded trace streams. By default,
+    /// the build cache of <bin> is used (usually ./target/).
+    #[structopt(name = "trace-dir", long = "trace-dir", parse(from_os_str))]
+    trace_dir: Option<PathBuf>,
+
+    /// Write the executions of each task to a file of its own, named
+    /// after the task. Otherwise, all executions are written to
+    /// executions.csv.
+    #[structopt(long = "per-task")]
+    per_task: bool,
+
+    /// Format of the exported files: csv, or chrome for a trace.json
+    /// in the Chrome trace event format, which Perfetto also reads. A
+    /// Chrome trace groups tasks by priority level and shows
+    /// preemptions; its times are always in microseconds.
+    #[structopt(long = "format", default_value = "csv")]
+    format: export::ExportFormat,
+
+    /// Units to export times in: ns, or cycles of the timestamp clock.
+    #[structopt(long = "units", default_value = "ns")]
+    units: stats::Units,
+
+    /// Only export executions whose timestamps are of at least this
+    /// quality: 4 if synchronous to their events, 3 if delayed relative
+    /// to an associated event, 2 if delayed by an unknown amount, 1 if
+    /// both, and 0 if diverged after an overflow.
+    #[structopt(long = "min-quality", default_value = "0", possible_values = &["0", "1", "2", "3", "4"])]
+    min_quality: u8,
+
+    /// Shift exported times such that the sync pulse of the trace (see
+    /// `cortex_m_rtic_trace::sync_pulse`) is at this time, in seconds.
+    /// Aligns the exported timeline with that of external measurement
+    /// equipment triggered on the pulse. Times before the pulse are
+    /// then negative.
+    #[structopt(long = "align-to", allow_hyphen_values = true)]
+    align_to: Option<f64>,
+
+    /// Traces of the other cores of a multi-core target (see `trace
+    /// --core`) to export along with the given trace, as one dataset.
+    #[structopt(long = "merge", parse(from_os_str))]
+    merge: Vec<PathBuf>,
+
+    /// Directory to write the exported files to. Created if it does
+    /// not exist.
+    #[structopt(long = "out-dir", parse(from_os_str))]
+    out_dir: PathBuf,
+}
+
+/// Write a [package.metadata.rtic-scope] table to the manifest of the
+/// crate in the current directory. The PAC is guessed from the device
+/// of the RTIC application; missing values are asked for.
+#[derive(StructOpt, Debug)]
+pub struct InitOptions {
+    #[structopt(flatten)]
+    pac: ManifestOptions,
+
+    /// Prescaler of the local timestamps (1, 4, 16 or 64).
+    #[structopt(long = "lts-prescaler", default_value = "1")]
+    lts_prescaler: u8,
+
+    /// DWT unit ID used to trace the entering of software tasks.
+    #[structopt(long = "dwt-enter-id", default_value = "1")]
+    dwt_enter_id: usize,
+
+    /// DWT unit ID used to trace the exiting of software tasks.
+    #[structopt(long = "dwt-exit-id", default_value = "2")]
+    dwt_exit_id: usize,
+
+    /// Whether malformed packets are expected from the target.
+    #[structopt(long = "expect-malformed")]
+    expect_malformed: bool,
+}
+
+/// Attach to the target and print the registers that configure
+/// tracing (DEMCR, ITM, DWT and TPIU), decoded. If run in the crate of
+/// the traced application, mismatches with its manifest are warned
+/// about.
+#[derive(StructOpt, Debug)]
+pub struct DumpRegsOptions {
+    #[structopt(flatten)]
+    probe_options: ProbeOptions,
+
+    #[structopt(flatten)]
+    pac: ManifestOptions,
+}
+
+/// Measure the throughput of the trace pipeline (decoding, mapping,
+/// and serialization for sinks) on this host against a synthetic trace
+/// stream, e.g. to find the highest sustainable packet rate before a
+/// lab session.
+#[derive(StructOpt, Debug)]
+pub struct BenchOptions {
+    /// For how many seconds to run the benchmark.
+    #[structopt(long = "duration", default_value = "10", parse(try_from_str = parse_seconds))]
+    duration: std::time::Duration,
+
+    /// Generate this many packets per second instead of as many as can
+    /// be decoded. Per-stage latencies then include waiting for data.
+    #[structopt(long = "rate")]
+    rate: Option<u64>,
+}
+
+/// Replay each raw trace file of a regression corpus as `replay
+/// --raw-file` would, against the lookup maps of a synthetic
+/// application, and check that decoding and mapping it neither panics
+/// nor changes its events.
+#[derive(StructOpt, Debug)]
+pub struct CorpusOptions {
+    /// Directory of the corpus: raw trace files with the raw extension,
+    /// each with its expected events in a file of the same name with
+    /// the events extension.
+    #[structopt(default_value = "corpus", parse(from_os_str))]
+    dir: PathBuf,
+
+    /// Record the events of each file as expected instead of comparing
+    /// them.
+    #[structopt(long = "bless")]
+    bless: bool,
+
+    /// Afterwards, mutate the corpus files this many times and add the
+    /// mutations that make the pipeline panic to the corpus. Requires
+    /// the fuzz feature.
+    #[structopt(long = "fuzz")]
+    fuzz: Option<usize>,
+}
+
+/// Merge trace files, e.g. the segments of a capture, into a single
+/// trace file. The traces must be of the same build of an application,
+/// traced from the same core. Timestamps are re-based onto the earliest
+/// target reset of the traces, and the trace data is interleaved in
+/// time order.
+#[derive(StructOpt, Debug)]
+pub struct MergeOptions {
+    /// The trace files to merge.
+    #[structopt(parse(from_os_str), required = true, min_values = 2)]
+    files: Vec<PathBuf>,
+
+    /// The merged trace file to write, which must not exist.
+    #[structopt(long = "output", short = "o", parse(from_os_str))]
+    output: PathBuf,
+}
+
+/// Print the JSON Schema of a type of the API between the backend and
+/// frontends, e.g. to generate typed bindings for a frontend written in
+/// another language than Rust.
+#[derive(StructOpt, Debug)]
+pub struct ApiSchemaOptions {
+    /// The API type: EventChunk, Handshake, Greeting, ControlMessage,
+    /// or FrontendDescription.
+    #[structopt(default_value = "EventChunk")]
+    api_type: String,
+}
+
+/// Show the effective host-side defaults, or get or set them in the
+/// user configuration file (~/.config/rtic-scope/config.toml). Keys are
+/// frontends (a comma-separated list), trace_dir, probe_speed (in kHz)
+/// and color. Command-line options and the manifest take precedence
+/// over the user configuration.
+#[derive(StructOpt, Debug)]
+pub struct ConfigOptions {
+    #[structopt(subcommand)]
+    action: Option<ConfigAction>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ConfigAction {
+    /// Print the value of a key in the user configuration, if set.
+    Get { key: String },
+    /// Set a key in the user configuration.
+    Set { key: String, value: String },
+    /// Remove a key from the user configuration.
+    Unset { key: String },
 }
 
 #[derive(StructOpt, Debug)]
 enum Command {
     Trace(TraceOptions),
     Replay(ReplayOptions),
+    Stats(StatsOptions),
+    Export(ExportOptions),
+    Init(InitOptions),
+    #[structopt(name = "dump-regs")]
+    DumpRegs(DumpRegsOptions),
+    Bench(BenchOptions),
+    Corpus(CorpusOptions),
+    Merge(MergeOptions),
+    Config(ConfigOptions),
+    /// List all frontends found in PATH and whether they are compatible
+    /// with this backend.
+    Frontends,
+    #[structopt(name = "api-schema")]
+    ApiSchema(ApiSchemaOptions),
 }
 
 #[derive(Debug, Error)]
@@ -172,6 +981,36 @@ pub enum RTICScopeError {
     SourceError(#[from] sources::SourceError),
     #[error(transparent)]
     SinkError(#[from] sinks::SinkError),
+    #[error(transparent)]
+    ProbeSelectError(#[from] probe_select::ProbeSelectError),
+    #[error(transparent)]
+    InitError(#[from] init::InitError),
+    #[error(transparent)]
+    ConfigError(#[from] config::ConfigError),
+    #[error(transparent)]
+    CorpusError(#[from] corpus::CorpusError),
+    #[error(transparent)]
+    FlashingError(#[from] flashing::FlashingError),
+    #[error(transparent)]
+    MergeError(#[from] merge::MergeError),
+    #[error(transparent)]
+    HandshakeError(#[from] frontends::HandshakeError),
+    #[error(transparent)]
+    BaselineError(#[from] baseline::BaselineError),
+    #[error(transparent)]
+    AuxError(#[from] auxiliary::AuxError),
+
+    // session errors
+    #[error("All sinks are broken. Cannot continue.")]
+    AllSinksBroken,
+    #[error("Warnings were emitted in --strict mode: {overflows} overflow(s), {malformed} malformed and {nonmappable} non-mappable packet(s)")]
+    StrictWarnings {
+        overflows: usize,
+        malformed: usize,
+        nonmappable: usize,
+    },
+    #[error("{0} execution time(s) regressed against the baseline")]
+    Regressions(usize),
 
     // everything else
     #[error(transparent)]
@@ -189,7 +1028,73 @@ impl diag::DiagnosableError for RTICScopeError {
     }
 }
 
+/// Exit statuses of `cargo rtic-scope`, documented in `--help`.
+mod exit_status {
+    pub const ERROR: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const CONFIGURATION: i32 = 3;
+    pub const TARGET: i32 = 4;
+    pub const TRACE: i32 = 5;
+    pub const SINK: i32 = 6;
+    pub const STRICT: i32 = 7;
+    pub const REGRESSION: i32 = 8;
+
+    pub const HELP: &str = "EXIT STATUS:
+    0    Success
+    1    Unclassified error
+    2    Invalid command line arguments
+    3    Invalid configuration, or failure to build or recover metadata from the application
+    4    Failure to attach to, configure or read from the probe, target or serial device
+    5    Malformed or unreadable trace data
+    6    All sinks (trace file, frontends) broke
+    7    Warnings were emitted in --strict mode
+    8    Execution times regressed against the --baseline";
+}
+
 impl RTICScopeError {
+    /// The exit status of the process when failing with this error.
+    /// See [`exit_status::HELP`].
+    pub fn exit_status(&self) -> i32 {
+        fn source_status(e: &sources::SourceError) -> i32 {
+            use sources::SourceError::*;
+            match e {
+                IterDeserError(_) | IterIOError(_) | DecodeError(_) => exit_status::TRACE,
+                _ => exit_status::TARGET,
+            }
+        }
+
+        match self {
+            Self::CommonProbeOperationError(_)
+            | Self::ProbeSelectError(_)
+            | Self::FlashingError(_) => exit_status::TARGET,
+            Self::IOError(_) | Self::CorpusError(_) | Self::HandshakeError(_) => exit_status::ERROR,
+            Self::ManifestError(_)
+            | Self::MetadataError(_)
+            | Self::CargoError(_)
+            | Self::InitError(_)
+            | Self::ConfigError(_)
+            | Self::BaselineError(_)
+            | Self::AuxError(_) => exit_status::CONFIGURATION,
+            Self::MergeError(_) => exit_status::TRACE,
+            Self::SourceError(e) => source_status(e),
+            Self::SinkError(sinks::SinkError::SourceError(e)) => source_status(e),
+            Self::SinkError(_) | Self::AllSinksBroken => exit_status::SINK,
+            Self::StrictWarnings { .. } => exit_status::STRICT,
+            Self::Regressions(_) => exit_status::REGRESSION,
+            // Classify by the first known error in the chain, if any.
+            Self::Other(e) => e
+                .chain()
+                .find_map(|e| {
+                    if let Some(e) = e.downcast_ref::<RTICScopeError>() {
+                        Some(e.exit_status())
+                    } else {
+                        e.downcast_ref::<sources::SourceError>().map(source_status)
+                    }
+                })
+                .unwrap_or(exit_status::ERROR),
+        }
+    }
+
     pub fn render(&self) {
         log::err(format!("{:#?}", self)); // TODO iterator over errors instead
 
@@ -204,6 +1109,15 @@ impl RTICScopeError {
                 Self::CargoError(e) => Some(e as &DE),
                 Self::SourceError(e) => Some(e as &DE),
                 Self::SinkError(e) => Some(e as &DE),
+                Self::ProbeSelectError(e) => Some(e as &DE),
+                Self::InitError(e) => Some(e as &DE),
+                Self::ConfigError(e) => Some(e as &DE),
+                Self::CorpusError(e) => Some(e as &DE),
+                Self::FlashingError(e) => Some(e as &DE),
+                Self::MergeError(e) => Some(e as &DE),
+                Self::HandshakeError(e) => Some(e as &DE),
+                Self::BaselineError(e) => Some(e as &DE),
+                Self::AuxError(e) => Some(e as &DE),
                 _ => None,
             }
             .map(|e| e.diagnose())
@@ -216,9 +1130,12 @@ impl RTICScopeError {
 }
 
 fn main() {
-    if let Err(e) = block_on(main_try()) {
+    // Report the progress of setting up the session on the status line.
+    let progress: progress::Observer =
+        std::rc::Rc::new(std::cell::RefCell::new(progress::StatusLine::default()));
+    if let Err(e) = block_on(main_try(progress)) {
         e.render();
-        std::process::exit(1); // TODO make retval depend on error type?
+        std::process::exit(e.exit_status());
     }
 }
 
@@ -229,7 +1146,9 @@ fn main() {
 // TODO remove this global
 static mut SESSION: Option<probe_rs::Session> = None;
 
-async fn main_try() -> Result<(), RTICScopeError> {
+/// Runs the command given on the command line, reporting the progress
+/// of setting up the session to `progress`.
+async fn main_try(progress: progress::Observer) -> Result<(), RTICScopeError> {
     // Handle CLI options
     let mut args: Vec<_> = std::env::args().collect();
     // When called by cargo, first argument will be "rtic-scope".
@@ -237,9 +1156,70 @@ async fn main_try() -> Result<(), RTICScopeError> {
         args.remove(1);
     }
     let matches = Opts::clap()
-        .after_help(CargoOptions::help_message("cargo rtic-scope trace").as_str())
-        .get_matches_from(&args);
-    let opts = Opts::from_clap(&matches);
+        .after_help(
+            format!(
+                "{}\n{}",
+                CargoOptions::help_message("cargo rtic-scope trace"),
+                exit_status::HELP
+            )
+            .as_str(),
+        )
+        .get_matches_from_safe(&args)
+        .unwrap_or_else(|e| {
+            if !e.use_stderr() {
+                e.exit(); // --help or --version
+            }
+            eprintln!("{}", e.message);
+            std::process::exit(exit_status::USAGE);
+        });
+    let mut opts = Opts::from_clap(&matches);
+    log::set_filter(opts.verbose, std::env::var(log::FILTER_ENV).ok().as_deref())
+        .map_err(|e| anyhow::anyhow!("Invalid {}: {}", log::FILTER_ENV, e))?;
+
+    // The user configuration is edited as-is, and provides defaults to
+    // all other subcommands.
+    if let Command::Config(opts) = &opts.cmd {
+        return config::run(opts);
+    }
+    let config = config::UserConfig::load()?;
+    let profile = config.profile(opts.profile.as_deref())?;
+    if let (Some(name), Some(_)) = (&opts.profile, profile) {
+        log::status("Applying", format!("profile {}", name));
+    }
+    apply_user_config(&mut opts, &config, profile)?;
+
+    // Handle subcommands that neither trace nor replay.
+    match &opts.cmd {
+        Command::Frontends => {
+            frontends::list();
+            return Ok(());
+        }
+        Command::ApiSchema(opts) => {
+            let schema = api::schema::of(&opts.api_type).with_context(|| {
+                format!(
+                    "No API type {:?}; expected one of {}",
+                    opts.api_type,
+                    api::schema::TYPES.join(", ")
+                )
+            })?;
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+            return Ok(());
+        }
+        Command::Stats(opts) => return stats::run(opts),
+        Command::Export(opts) => return export::run(opts),
+        Command::Init(opts) => return init::run(opts),
+        Command::DumpRegs(opts) => return dump_regs::run(opts),
+        Command::Bench(opts) => return bench::run(opts),
+        Command::Corpus(opts) => return corpus::run(opts),
+        Command::Merge(opts) => return merge::run(opts),
+        _ => (),
+    }
+
+    if let (Command::Trace(TraceOptions { elf: Some(_), .. }), Some(_)) =
+        (&opts.cmd, &opts.artifact)
+    {
+        return Err(anyhow::anyhow!("--elf and --artifact cannot be used together").into());
+    }
 
     // Should we quit early?
     if let Command::Trace(opts) = &opts.cmd {
@@ -250,33 +1230,145 @@ async fn main_try() -> Result<(), RTICScopeError> {
         }
     }
 
+    // Read the baseline before tracing, such that an unusable one is
+    // not reported after the session.
+    let baseline = match &opts.cmd {
+        Command::Trace(TraceOptions {
+            baseline: Some(path),
+            baseline_tolerance,
+            ..
+        }) => Some(baseline::Baseline::load(path, *baseline_tolerance)?),
+        _ => None,
+    };
+    let aux = match &opts.cmd {
+        Command::Trace(TraceOptions {
+            aux_csv: Some(path),
+            ..
+        }) => Some(auxiliary::AuxInput::open(path)?),
+        _ => None,
+    };
+
+    // Select the probe by the chip of its target, if requested.
+    if let Command::Trace(TraceOptions {
+        chip_filter: Some(filter),
+        flash_options,
+        plan,
+        ..
+    }) = &mut opts.cmd
+    {
+        let po = &mut flash_options.probe_options;
+        if po.probe_selector.is_some() {
+            log::warn("--probe given; ignoring --chip-filter".to_string());
+        } else if !*plan {
+            // NOTE probes are not attached to when only planning
+            let (selector, chip) = probe_select::select_by_chip(filter)?;
+            po.probe_selector = Some(selector);
+            po.chip.get_or_insert(chip);
+        }
+    }
+
+    // Resolve which frontends to spawn, and where each is configured.
+    let mut frontend_origins = vec![];
+    if !opts.no_default_frontends {
+        // NOTE: a trace file may be replayed outside of a crate, in
+        // which case there are no defaults to read from the manifest.
+        let (defaults, provenance) = match cargo_metadata::MetadataCommand::new().no_deps().exec() {
+            Ok(cargo_metadata) => manifest::default_frontends(&cargo_metadata)?,
+            Err(_) => (vec![], manifest::Provenance::Default),
+        };
+        if defaults.is_empty() {
+            frontend_origins.extend(
+                config
+                    .frontends
+                    .iter()
+                    .flatten()
+                    .map(|frontend| (frontend.to_owned(), manifest::Provenance::UserConfig)),
+            );
+        } else {
+            frontend_origins.extend(defaults.into_iter().map(|frontend| (frontend, provenance)));
+        }
+    }
+    let given = opts
+        .frontends
+        .iter()
+        .map(|frontend| (frontend, manifest::Provenance::CommandLine));
+    let profiled = profile
+        .iter()
+        .flat_map(|profile| profile.frontends.iter().flatten())
+        .map(|frontend| (frontend, manifest::Provenance::Profile));
+    for (frontend, provenance) in given.chain(profiled) {
+        if !frontend_origins.iter().any(|(f, _)| f == frontend) {
+            frontend_origins.push((frontend.to_owned(), provenance));
+        }
+    }
+    if frontend_origins.is_empty() {
+        frontend_origins.push(("dummy".to_string(), manifest::Provenance::Default));
+    }
+    opts.frontends = frontend_origins.iter().map(|(f, _)| f.clone()).collect();
+
+    // Record how the trace is recorded, now that the options are final.
+    let invocation = recovery::Invocation::new(effective_options(&opts));
+
     // Build the RTIC application to be traced in the future (not
     // necessary for some commands), and create a wrapper around cargo,
     // reusing the target directory of the application.
-    #[allow(clippy::needless_question_mark)]
+    let cargo_options = match &opts.cmd {
+        Command::Trace(opts) => &opts.flash_options.cargo_options,
+        Command::Replay(opts) => &opts.cargo_options,
+        _ => return Err(anyhow::anyhow!("The subcommand neither traces nor replays").into()),
+    }
+    .to_cargo_options();
     let cart = async {
+        if let Command::Trace(TraceOptions {
+            elf: Some(elf),
+            app,
+            ..
+        }) = &opts.cmd
+        {
+            log::status("Using", format!("externally built {}", elf.display()));
+            // NOTE(unwrap) --elf requires --app-src
+            return CargoWrapper::external(elf, app.app_src.as_deref().unwrap(), cargo_options);
+        }
+        if let Some(artifact) = &opts.artifact {
+            log::status("Using", format!("pre-built {}", artifact.display()));
+            return CargoWrapper::prebuilt(artifact, opts.artifact_src.as_deref(), cargo_options);
+        }
+
         log::status("Building", "RTIC target application...".to_string());
-        Ok(CargoWrapper::new(
+        progress.borrow_mut().started(progress::Stage::Build, None);
+        let built = CargoWrapper::new(
             &env::current_dir().map_err(CargoError::CurrentDirError)?,
-            {
-                match &opts.cmd {
-                    Command::Trace(opts) => &opts.flash_options.cargo_options,
-                    Command::Replay(opts) => &opts.cargo_options,
-                }
-            }
-            .to_cargo_options(),
-        )?)
+            cargo_options,
+        )?;
+        progress.borrow_mut().finished(progress::Stage::Build);
+        Ok(built)
     };
 
     // Configure source and sinks. Recover the information we need to
     // map ITM packets to RTIC tasks.
-    let (source, mut sinks, metadata) = match opts.cmd {
-        Command::Trace(ref opts) => match trace(opts, cart).await? {
-            Some(tup) => tup,
-            None => return Ok(()), // NOTE --resolve-only was passed
-        },
+    let (source, mut sinks, metadata, crash_capture) = match opts.cmd {
+        Command::Trace(ref trace_opts) => {
+            match trace(
+                trace_opts,
+                cart,
+                opts.allow_stale_maps,
+                &frontend_origins,
+                invocation,
+                &progress,
+            )
+            .await?
+            {
+                Some(tup) => tup,
+                None => return Ok(()), // NOTE --resolve-only or --plan was passed
+            }
+        }
         Command::Replay(ref opts) => {
-            match replay(opts, cart).await.with_context(|| {
+            if let Some(log_file) = &opts.log_file {
+                log::start_session_log(log_file).with_context(|| {
+                    format!("Failed to create session log {}", log_file.display())
+                })?;
+            }
+            match replay(opts, cart, &progress).await.with_context(|| {
                 format!("Failed to {}", {
                     if opts.list {
                         "index traces"
@@ -289,49 +1381,84 @@ async fn main_try() -> Result<(), RTICScopeError> {
                 None => return Ok(()), // NOTE --list was passed
             }
         }
+        _ => return Err(anyhow::anyhow!("The subcommand neither traces nor replays").into()),
     };
+    if let Some(baseline) = &baseline {
+        baseline.check_program(&metadata.program_name)?;
+    }
 
-    // Spawn frontend children and get path to sockets. Create and push sinks.
+    // Spawn frontend children and get path to sockets. Create and push
+    // sinks. The shared-memory ring buffer, if any, is inherited by
+    // the frontends.
+    let shm_ring = opts
+        .shm_ring
+        .map(|size| sinks::ShmRing::new(size, opts.frontends.len()))
+        .transpose()
+        .context("Failed to create shared memory ring buffer")?;
+    let mut shm_consumers = vec![];
     let mut children = vec![];
-    for frontend in &opts.frontends {
+    for (i, frontend) in opts.frontends.iter().enumerate() {
         // Try to spawn the frontend from PATH. If that fails, try a relative path instead.
         let executables = [
-            format!("rtic-scope-frontend-{}", frontend), // PATH
-            format!("./{}", frontend),                   // relative
-            format!("/{}", frontend),                    // absolute
+            format!("{}{}", frontends::FRONTEND_PREFIX, frontend), // PATH
+            format!("./{}", frontend),                             // relative
+            format!("/{}", frontend),                              // absolute
         ];
         let mut child = executables
             .iter()
             .find_map(|e| {
-                process::Command::new(e)
+                let mut command = process::Command::new(e);
+                // NOTE stdin may carry the trace; see replay --stdin
+                command
+                    .stdin(process::Stdio::null())
                     .stdout(process::Stdio::piped())
-                    .stderr(process::Stdio::piped())
-                    .spawn()
-                    .ok()
+                    .stderr(process::Stdio::piped());
+                if let Some(ring) = &shm_ring {
+                    command
+                        .env(api::shm::FD_ENV, ring.fd().to_string())
+                        .env(api::shm::CONSUMER_ENV, i.to_string());
+                }
+                command.spawn().ok()
             })
             .with_context(|| {
                 format!(
-                    "Failed to spawn a frontend child process from tested paths (PATH, relative, absolute): {:#?}",
+                    "Failed to spawn a frontend child process from tested paths (PATH, relative, absolute): {:#?}. See `cargo rtic-scope frontends` for the frontends found in PATH.",
                     executables
                 )
             })?;
         {
-            let socket_path = {
-                async_std::io::BufReader::new(
-                    child
-                        .stdout
-                        .take()
-                        .context("Failed to pipe frontend stdout")?,
-                )
-                .lines()
-                .next()
-                .await
-                .context("next() failed")?
+            let handshake = frontends::read_handshake(
+                frontend,
+                child
+                    .stdout
+                    .take()
+                    .context("Failed to pipe frontend stdout")?,
+            )
+            .await?;
+            let socket = frontends::connect(frontend, &handshake.socket)?;
+            let mut sink =
+                sinks::FrontendSink::new(frontend.to_owned(), socket, handshake.subscription)
+                    .context("Failed to read from frontend socket")?
+                    .socket_path(&handshake.socket);
+            if handshake.ping {
+                sink = sink
+                    .pinged()
+                    .context("Failed to configure frontend socket")?;
             }
-            .context("Failed to read socket path from frontend child process")?;
-            let socket = std::os::unix::net::UnixStream::connect(&socket_path)
-                .context("Failed to connect to frontend socket")?;
-            sinks.push(Box::new(sinks::FrontendSink::new(socket)));
+            if handshake.greeting {
+                sink.greet(&metadata.greeting())
+                    .context("Failed to greet frontend")?;
+            }
+            if handshake.shm && shm_ring.is_some() {
+                sink = sink.via_shm();
+                shm_consumers.push(sinks::ShmConsumer {
+                    cursor: i,
+                    name: frontend.to_owned(),
+                    subscription: handshake.subscription,
+                    paused: sink.paused(),
+                });
+            }
+            sinks.push(Box::new(sink));
         }
 
         let stderr = child
@@ -340,6 +1467,18 @@ async fn main_try() -> Result<(), RTICScopeError> {
             .context("Failed to take frontend stderr")?;
         children.push((child, stderr));
     }
+    if let Some(ring) = shm_ring {
+        if !shm_consumers.is_empty() {
+            sinks.push(Box::new(sinks::ShmSink::new(ring, shm_consumers)));
+        }
+    }
+    if let Some(url) = &opts.mqtt {
+        sinks.push(Box::new(sinks::MqttSink::new(
+            url.clone(),
+            &metadata.greeting(),
+            opts.mqtt_interval,
+        )?));
+    }
 
     if let sources::BufferStatus::Unknown = source.avail_buffer() {
         log::warn(format!(
@@ -363,7 +1502,17 @@ async fn main_try() -> Result<(), RTICScopeError> {
 
     // All preparatory I/O and information recovery done. Forward all
     // trace packets to all sinks.
-    let stats = run_loop(source, sinks, metadata.clone(), &opts, stderrs).await;
+    let stats = run_loop(
+        source,
+        sinks,
+        metadata.clone(),
+        crash_capture,
+        aux,
+        &opts,
+        stderrs,
+        &progress,
+    )
+    .await;
 
     // Wait for frontends to proccess all packets and flush any
     // remaining stderr lines.
@@ -391,12 +1540,62 @@ async fn main_try() -> Result<(), RTICScopeError> {
     let stats = stats?;
     let duration = instant.elapsed();
     log::status(
-        match opts.cmd {
-            Command::Trace(_) => "Traced",
-            Command::Replay(_) => "Replayed",
+        if let Command::Trace(_) = opts.cmd {
+            "Traced"
+        } else {
+            "Replayed"
         },
         format!("{}.", format_status_message(&metadata, &stats, &duration)),
     );
+    for (sink, metrics) in stats.sink_metrics.iter() {
+        log::status("Drained", format!("{}: {}", sink, metrics.summary()));
+    }
+    if stats.gts.reanchors > 0 {
+        log::warn(format!(
+            "timestamps diverged from the global timestamps of the target and were re-anchored {} time(s)",
+            stats.gts.reanchors
+        ));
+    }
+    if let Some(details) = &stats.crash {
+        log::warn(format!(
+            "the trace ended with a crash of the target: {}",
+            details
+        ));
+    }
+
+    if let (Some(baseline), Some(tasks)) = (baseline, &stats.tasks) {
+        let regressions = baseline.compare(&tasks.tasks);
+        log::status(
+            "Compared",
+            format!(
+                "{} task(s) against {}: {} regression(s)",
+                tasks.tasks.len(),
+                baseline.path.display(),
+                regressions.len()
+            ),
+        );
+        for regression in regressions.iter() {
+            log::err(format!(
+                "{}: {} execution time {} ns exceeds the baseline {} ns by {:.1}%",
+                regression.task,
+                regression.statistic,
+                regression.measured,
+                regression.baseline,
+                regression.excess()
+            ));
+        }
+        if !regressions.is_empty() {
+            return Err(RTICScopeError::Regressions(regressions.len()));
+        }
+    }
+
+    if opts.strict && stats.overflows + stats.malformed + stats.nonmappable > 0 {
+        return Err(RTICScopeError::StrictWarnings {
+            overflows: stats.overflows,
+            malformed: stats.malformed,
+            nonmappable: stats.nonmappable,
+        });
+    }
 
     Ok(())
 }
@@ -430,14 +1629,36 @@ fn format_status_message(
     }
 
     format!(
-        "{}: {} packets processed in {time} (~{packets_per_sec:.1} packets/s; {} malformed, {} non-mappable); {sinks}",
+        "{}: {} packets processed into {} events in {time} (~{packets_per_sec:.1} packets/s; {} malformed{errors}, {} non-mappable{ignored}); {decoder}; {sinks}{init}{cpu_load}{drift}",
         metadata.program_name,
         stats.packets,
+        stats.events,
         stats.malformed,
         stats.nonmappable,
+        errors = match stats.decoder.errors() {
+            Some(errors) => format!(" ({})", errors),
+            None => "".to_string(),
+        },
+        decoder = stats.decoder.summary(),
+        ignored = match stats.ignored {
+            0 => "".to_string(),
+            n => format!(", {} ignored", n),
+        },
         time = format_duration(duration),
         packets_per_sec = stats.packets as f32 / duration.as_secs() as f32,
         sinks = format!("{}/{} sinks operational", stats.sinks.0, stats.sinks.1),
+        init = match stats.init.duration() {
+            Some(duration) => format!("; init ran for {:.3} ms", duration.as_secs_f64() * 1e3),
+            None => "".to_string(),
+        },
+        cpu_load = match stats.cpu_load.summary() {
+            Some((avg, max)) => format!("; CPU load {:.1}% average, {:.1}% maximum", avg, max),
+            None => "".to_string(),
+        },
+        drift = match stats.drift.as_ref().and_then(|drift| drift.model()) {
+            Some(model) => format!("; clock drift {:+.1} ppm", model.ppm),
+            None => "".to_string(),
+        },
     )
 }
 
@@ -478,103 +1699,613 @@ where
 struct Stats {
     /// How many ITM packets we have received from the source.
     pub packets: usize,
+    /// Whether the start of the trace has been reported.
+    pub started: bool,
     /// How many malformed ITM packets we have received from the source.
     pub malformed: usize,
+    /// How many events were produced from the received ITM packets.
+    pub events: usize,
     /// How many unmappable ITM packets we have received from the
     /// source.
     pub nonmappable: usize,
+    /// How many overflow packets we have received from the source.
+    pub overflows: usize,
+    /// How many exception trace packets were dropped because the
+    /// exception is ignored.
+    pub ignored: usize,
     /// How many sinks we started with, and how many that remained
     /// functional until the end.
     pub sinks: (usize, usize),
+    /// CPU load of the target, if it emits sleep counter overflow
+    /// events.
+    pub cpu_load: cpu_load::CpuLoad,
+    /// Drift of the trace clock relative to the host clock, if tracing
+    /// live.
+    pub drift: Option<drift::DriftEstimator>,
+    /// Description, drain latency and errors of each sink, in the order
+    /// the sinks were added. Each sink is drained along with its index.
+    pub sink_metrics: Vec<(String, sinks::SinkMetrics)>,
+    /// Why the target crashed, if it did.
+    pub crash: Option<String>,
+    /// Bytes read, synchronization and decode errors of the source.
+    pub decoder: decoding::DecoderStats,
+    /// Offset from target reset of the first sync marker, if any.
+    pub sync_anchor: Option<std::time::Duration>,
+    /// Outages of the trace stream; see [`recovery::TraceTrailer::gaps`].
+    pub gaps: Vec<(std::time::Duration, std::time::Duration)>,
+    /// How long `#[init]` ran, if the trace started within it.
+    pub init: phase::InitPhase,
+    /// Offset from target reset of the last chunk drained.
+    pub last_offset: std::time::Duration,
+    /// Correction of timestamps by the global timestamps of the target,
+    /// if it emits them.
+    pub gts: gts::GlobalTimestamps,
+    /// Execution times of each task in nanoseconds, if compared against
+    /// a baseline.
+    pub tasks: Option<stats::TaskStatistics>,
+    /// Depth of the queue of each dispatcher, by the traced spawns.
+    pub queues: queues::QueueTracker,
+    /// Fault records reported by the fault handlers of the target.
+    pub faults: crash::FaultRecords,
+    /// Samples of an external instrument, if read.
+    pub aux: Option<auxiliary::AuxInput>,
+    /// Warnings about packets that cannot be mapped or decoded.
+    pub warnings: warnings::Warnings,
 }
 
-async fn run_loop<R>(
-    mut source: Box<dyn sources::Source>,
-    mut sinks: Vec<Box<dyn sinks::Sink>>,
-    metadata: recovery::TraceMetadata,
-    opts: &Opts,
-    mut stderrs: StderrLines<R>,
-) -> Result<Stats, RTICScopeError>
-where
-    R: async_std::io::BufRead + std::marker::Unpin,
-{
-    // Setup SIGINT handler.
-    let (tx, halt) = channel::bounded(0);
-    ctrlc::set_handler(move || tx.send(()).expect("Could not signal SIGINT on channel"))
-        .context("Failed to install SIGINT handler")?;
-
-    // Keep tabs on which sinks have broken during drain, if any.
-    let mut sinks: Vec<(Box<dyn sinks::Sink>, bool)> =
-        sinks.drain(..).map(|s| (s, false)).collect();
+/// Accounts `chunk` in the `--eval` expressions, printing the values of
+/// the windows it ends.
+fn evaluate(evaluators: &mut [query::Evaluator], chunk: &api::EventChunk) {
+    for eval in evaluators.iter_mut() {
+        if let Some(sample) = eval.update(chunk) {
+            log::status("Evaluated", eval.format(&sample));
+        }
+    }
+}
 
-    let mut stats = Stats {
-        sinks: (sinks.len(), sinks.len()),
-        ..Stats::default()
+/// Drains the auxiliary samples read since the last drain, if any and
+/// if they can be aligned, to all sinks, up to the offset the trace has
+/// reached; or all of them, if `flush`.
+fn drain_aux(
+    metadata: &recovery::TraceMetadata,
+    stats: &mut Stats,
+    sinks: &mut Vec<(Box<dyn sinks::Sink>, bool, usize)>,
+    flush: bool,
+) -> Result<(), anyhow::Error> {
+    let (anchor, until) = (stats.sync_anchor, stats.last_offset);
+    let chunks = match stats.aux.as_mut() {
+        Some(aux) => {
+            let mut chunks = aux.poll(anchor, until, metadata.core());
+            if flush {
+                chunks.extend(aux.flush());
+            }
+            chunks
+        }
+        None => return Ok(()),
     };
+    for chunk in chunks.iter() {
+        drain_sinks(None, chunk, stats, sinks)?;
+    }
 
-    let handle_packet = |data: TraceData,
-                         stats: &mut Stats,
-                         sinks: &mut Vec<(Box<dyn sinks::Sink>, bool)>|
-     -> Result<(), anyhow::Error> {
-        // Try to recover RTIC information for the packets.
-        let chunk = metadata.build_event_chunk(data.clone());
+    Ok(())
+}
 
-        // Report any unmappable/unknown events that occured, and record stats
-        stats.packets += data.consumed_packets;
-        for event in chunk.events.iter() {
-            match event {
-                api::EventType::Unmappable(ref packet, ref reason) => {
-                    stats.nonmappable += 1;
-                    log::warn(format!(
-                        "cannot map {:?} packet: {}",
-                        packet, reason
-                    ));
-                }
-                api::EventType::Unknown(ref packet) => {
-                    stats.nonmappable += 1;
-                    log::warn(format!(
-                        "cannot map {:?} packet",
-                        packet
-                    ));
-                }
-                api::EventType::Invalid(ref malformed) => {
-                    stats.malformed += 1;
-                    log::warn(format!("malformed packet: {}: {:?}", malformed, malformed));
-                },
-                api::EventType::Overflow => log::warn("Overflow detected! Packets may have been dropped and/or timestamps will potentially be diverged until the next global timestamp.".to_string()),
-                _ => (),
-            }
-        }
+/// A chunk of session lifecycle events (e.g.
+/// [`api::EventType::SessionStart`]), which are not traced, at `at`
+/// from target reset.
+fn lifecycle_chunk(
+    metadata: &recovery::TraceMetadata,
+    at: std::time::Duration,
+    events: Vec<api::EventType>,
+) -> api::EventChunk {
+    api::EventChunk {
+        timestamp: api::Timestamp::Sync(at),
+        events,
+        estimated_timestamp: None,
+        cycles: None,
+        core: metadata.core(),
+        packets: None,
+    }
+}
 
-        for (sink, is_broken) in sinks.iter_mut() {
-            if let Err(e) = sink.drain(data.clone(), chunk.clone()) {
-                log::err(format!(
-                    "failed to drain trace packets to {}: {:?}",
-                    sink.describe(),
-                    e
-                ));
-                *is_broken = true;
-            }
+/// Drains the trace data, if any, and its associated event chunk to all
+/// sinks. Sinks that fail to drain are removed.
+fn drain_sinks(
+    data: Option<&TraceData>,
+    chunk: &api::EventChunk,
+    stats: &mut Stats,
+    sinks: &mut Vec<(Box<dyn sinks::Sink>, bool, usize)>,
+) -> Result<(), anyhow::Error> {
+    stats.last_offset = stats.last_offset.max(timestamps::offset(&chunk.timestamp));
+    for (sink, is_broken, i) in sinks.iter_mut() {
+        let start = std::time::Instant::now();
+        let drained = sink.drain(data, chunk);
+        let metrics = &mut stats.sink_metrics[*i].1;
+        if metrics.record(start.elapsed()) {
+            log::warn(format!(
+                "{} drained in {} ms: it may slow down the other sinks",
+                sink.describe(),
+                start.elapsed().as_millis()
+            ));
+        }
+        if let Err(e) = drained {
+            log::err(format!(
+                "failed to drain trace packets to {}: {:?}",
+                sink.describe(),
+                e
+            ));
+            metrics.errors += 1;
+            *is_broken = true;
         }
+    }
+
+    remove_broken_sinks(stats, sinks)
+}
 
-        // remove broken sinks, if any
-        //
-        // TODO replace weth Vec::drain_filter when stable.
-        sinks.retain(|(_, is_broken)| !is_broken);
-        stats.sinks.0 = sinks.len();
-        if sinks.is_empty() {
-            bail!("All sinks are broken. Cannot continue.");
+/// Drops the sinks whose other end has died (e.g. a crashed frontend);
+/// see [`sinks::Sink::check_alive`].
+fn check_sinks(
+    stats: &mut Stats,
+    sinks: &mut Vec<(Box<dyn sinks::Sink>, bool, usize)>,
+) -> Result<(), anyhow::Error> {
+    for (sink, is_broken, i) in sinks.iter_mut() {
+        if let Err(e) = sink.check_alive() {
+            log::err(format!("dropping {}: {}", sink.describe(), e));
+            stats.sink_metrics[*i].1.errors += 1;
+            *is_broken = true;
         }
+    }
 
-        Ok(())
-    };
+    remove_broken_sinks(stats, sinks)
+}
 
-    let (tx, packet) = channel::unbounded();
-    let packet_poller = std::thread::spawn(move || {
-        let mut buffer_warning = false;
+fn remove_broken_sinks(
+    stats: &mut Stats,
+    sinks: &mut Vec<(Box<dyn sinks::Sink>, bool, usize)>,
+) -> Result<(), anyhow::Error> {
+    // TODO replace weth Vec::drain_filter when stable.
+    sinks.retain(|(_, is_broken, _)| !is_broken);
+    stats.sinks.0 = sinks.len();
+    if sinks.is_empty() {
+        return Err(RTICScopeError::AllSinksBroken.into());
+    }
 
-        while let Some(data) = source.next() {
-            if !buffer_warning {
+    Ok(())
+}
+
+/// How often the status line is rendered during a trace/replay session.
+const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// How often the directory given to `--attach-dir` is polled for
+/// frontends that attach or detach.
+const ATTACH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// How long the source is waited on to stop reading and release the
+/// probe or device once the session has ended.
+const RELEASE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// The thread on which the source is iterated. The source is shut down
+/// when the poller is dropped, such that the thread ends and releases
+/// the probe or device even if the session ends on an error.
+struct Poller {
+    thread: Option<std::thread::JoinHandle<()>>,
+    shutdown: Option<sources::Shutdown>,
+    source: String,
+}
+
+impl Poller {
+    /// Shuts the source down and waits until the thread has ended,
+    /// discarding the trace data still sent over `packets`. The thread
+    /// is left to the OS if the source does not stop within
+    /// [`RELEASE_TIMEOUT`]. Sources that cannot be shut down (e.g.
+    /// files) hold nothing to release: their thread ends once `packets`
+    /// is dropped. Returns whether the probe or device is released,
+    /// i.e. whether the thread no longer holds it.
+    fn stop<T>(mut self, packets: &channel::Receiver<T>) -> bool {
+        match &self.shutdown {
+            Some(shutdown) => shutdown.signal(),
+            None => return true,
+        }
+
+        // NOTE the thread drops the source before its sender
+        let deadline = std::time::Instant::now() + RELEASE_TIMEOUT;
+        loop {
+            match packets.recv_deadline(deadline) {
+                Ok(_) => continue,
+                Err(channel::RecvTimeoutError::Disconnected) => break,
+                Err(channel::RecvTimeoutError::Timeout) => {
+                    log::warn(format!(
+                        "{} did not stop reading within {} s; not waiting for it",
+                        self.source,
+                        RELEASE_TIMEOUT.as_secs()
+                    ));
+                    return false;
+                }
+            }
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warn(format!("{} panicked while reading", self.source));
+            }
+        }
+
+        true
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        if let Some(shutdown) = &self.shutdown {
+            shutdown.signal();
+        }
+    }
+}
+
+/// Connects to the frontends that attached via `attach` since the last
+/// poll, or failed to connect before, and drops those that detached.
+fn attach_frontends(
+    attach: &mut attach::AttachDir,
+    metadata: &recovery::TraceMetadata,
+    stats: &mut Stats,
+    sinks: &mut Vec<(Box<dyn sinks::Sink>, bool, usize)>,
+) {
+    let (appeared, disappeared) = match attach.poll() {
+        Ok(changes) => changes,
+        Err(e) => {
+            log::warn(format!(
+                "failed to poll {} for frontends: {}",
+                attach.dir().display(),
+                e
+            ));
+            return;
+        }
+    };
+
+    for socket in disappeared {
+        if let Some(i) = sinks
+            .iter()
+            .position(|(sink, _, _)| sink.socket() == Some(socket.as_path()))
+        {
+            let (sink, _, _) = sinks.remove(i);
+            stats.sinks.1 -= 1;
+            log::status("Detached", sink.describe());
+        }
+    }
+    for socket in appeared {
+        let name = attach::frontend_name(&socket);
+        let sink = frontends::connect(&name, &socket.to_string_lossy())
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| {
+                Ok(
+                    sinks::FrontendSink::new(name.clone(), stream, api::Subscription::default())?
+                        .socket_path(&socket.to_string_lossy()),
+                )
+            })
+            .and_then(|mut sink| {
+                sink.greet(&metadata.greeting())?;
+                Ok(sink)
+            });
+        match sink {
+            Ok(sink) => {
+                log::status("Attached", sink.describe());
+                attach.attached(socket);
+                stats
+                    .sink_metrics
+                    .push((sink.describe(), sinks::SinkMetrics::default()));
+                sinks.push((Box::new(sink), false, stats.sink_metrics.len() - 1));
+                stats.sinks.1 += 1;
+            }
+            Err(e) if attach.failed(socket.clone()) => log::warn(format!(
+                "failed to attach frontend {}, retrying: {:#}",
+                name, e
+            )),
+            Err(e) => crate::log::debug!("failed to attach frontend {}: {:#}", name, e),
+        }
+    }
+    stats.sinks.0 = sinks.len();
+}
+
+async fn run_loop<R>(
+    mut source: Box<dyn sources::Source>,
+    mut sinks: Vec<Box<dyn sinks::Sink>>,
+    metadata: recovery::TraceMetadata,
+    crash_capture: Option<crash::CrashCapture>,
+    aux: Option<auxiliary::AuxInput>,
+    opts: &Opts,
+    mut stderrs: StderrLines<R>,
+    progress: &progress::Observer,
+) -> Result<Stats, RTICScopeError>
+where
+    R: async_std::io::BufRead + std::marker::Unpin,
+{
+    // Setup SIGINT handler.
+    let (tx, halt) = channel::bounded(0);
+    ctrlc::set_handler(move || tx.send(()).expect("Could not signal SIGINT on channel"))
+        .context("Failed to install SIGINT handler")?;
+
+    // Keep tabs on which sinks have broken during drain, if any, and
+    // on the metrics of each.
+    let sink_metrics = sinks
+        .iter()
+        .map(|sink| (sink.describe(), sinks::SinkMetrics::default()))
+        .collect();
+    let mut sinks: Vec<(Box<dyn sinks::Sink>, bool, usize)> = sinks
+        .drain(..)
+        .enumerate()
+        .map(|(i, sink)| (sink, false, i))
+        .collect();
+
+    let mut stats = Stats {
+        sinks: (sinks.len(), sinks.len()),
+        sink_metrics,
+        decoder: decoding::DecoderStats::new(source.byte_counter()),
+        // The host clock is only comparable to the trace clock when
+        // tracing live.
+        drift: match opts.cmd {
+            Command::Trace(_) => Some(drift::DriftEstimator::default()),
+            _ => None,
+        },
+        tasks: match opts.cmd {
+            Command::Trace(TraceOptions {
+                baseline: Some(_), ..
+            }) => Some(stats::TaskStatistics::new(
+                stats::Units::Nanoseconds,
+                metadata.tpiu_freq(),
+            )),
+            _ => None,
+        },
+        queues: queues::QueueTracker::new(metadata.dispatchers()),
+        aux,
+        warnings: warnings::Warnings::new(opts.quiet_warnings),
+        ..Stats::default()
+    };
+
+    // Correct timestamps for drift, if requested.
+    let drift_correction = match &opts.cmd {
+        Command::Replay(ReplayOptions {
+            drift_correct: true,
+            ..
+        }) => {
+            if metadata.drift().is_none() {
+                log::warn(
+                    "trace contains no drift estimate; timestamps will not be corrected"
+                        .to_string(),
+                );
+            }
+            metadata.drift()
+        }
+        _ => None,
+    };
+
+    let instant = std::time::Instant::now();
+
+    // Estimate timestamps of unknown quality, unless disabled.
+    let mut interpolator = interpolation::Interpolator::new(!opts.no_interpolation);
+
+    // Normalize task actions, if requested.
+    let mut normalizer = opts
+        .normalize_actions
+        .then(normalize::ActionNormalizer::default);
+
+    // Exit tasks that do not exit in time, if requested.
+    let mut watchdog = (opts.watchdog.is_some() || !opts.watchdog_tasks.is_empty()).then(|| {
+        watchdog::Watchdog::new(opts.watchdog, opts.watchdog_tasks.iter().cloned().collect())
+    });
+
+    // Reassemble the writes to the configured stimulus ports.
+    let mut reassembler = stimulus::Reassembler::new(metadata.stimulus_ports());
+
+    // Why the target crashed is captured by the packet poller, if
+    // attached to the target.
+    let capturing = crash_capture.is_some();
+    let (crash_tx, crashed) = channel::bounded(1);
+
+    // Pause the replay after the chunks drained, if requested.
+    let mut stepper = match opts.cmd {
+        Command::Replay(ReplayOptions {
+            interactive: true,
+            step_tasks,
+            ..
+        }) => Some(step::Stepper::new(step_tasks)),
+        _ => None,
+    };
+
+    // Evaluate expressions given via --eval.
+    let mut evaluators: Vec<query::Evaluator> = opts
+        .eval
+        .iter()
+        .cloned()
+        .map(query::Evaluator::new)
+        .collect();
+
+    let handle_packet = |data: TraceData,
+                         gap: Option<std::time::Duration>,
+                         stats: &mut Stats,
+                         sinks: &mut Vec<(Box<dyn sinks::Sink>, bool, usize)>,
+                         interpolator: &mut interpolation::Interpolator<TraceData>,
+                         watchdog: &mut Option<watchdog::Watchdog>,
+                         normalizer: &mut Option<normalize::ActionNormalizer>,
+                         reassembler: &mut stimulus::Reassembler,
+                         evaluators: &mut Vec<query::Evaluator>,
+                         stepper: &mut Option<step::Stepper>|
+     -> Result<(), anyhow::Error> {
+        log::trace!(
+            "{} packets ({} malformed) at {:?}",
+            data.packets.len(),
+            data.malformed_packets.len(),
+            data.timestamp
+        );
+
+        // Try to recover RTIC information for the packets, unless the
+        // firmware disagrees on what the software tasks are, or was
+        // built from other sources.
+        metadata.check_task_ids(&data)?;
+        if let Err(e) = metadata.check_build_id(&data) {
+            if !opts.allow_stale_maps {
+                return Err(e.into());
+            }
+            log::warn(format!("{}; trace data may be misattributed", e));
+        }
+        let mut chunk = metadata.build_event_chunk(&data);
+        stats.gts.update(&data, &mut chunk, metadata.tpiu_freq());
+        if let Some(duration) = gap {
+            chunk.events.push(api::EventType::TraceGap { duration });
+            if stats.gaps.len() < recovery::MAX_GAPS {
+                stats
+                    .gaps
+                    .push((timestamps::offset(&data.timestamp), duration));
+            }
+        }
+        // The trace only starts within #[init] if the target was reset.
+        if metadata.target_reset() {
+            stats.init.update(&data, &mut chunk);
+        }
+        reassembler.update(&data, &mut chunk);
+        if metadata.fault_trace() {
+            stats.faults.update(&data, &mut chunk);
+        }
+        if let Some(percent) = stats.cpu_load.update(&data, metadata.tpiu_freq()) {
+            chunk.events.push(api::EventType::CpuLoad { percent });
+        }
+        if let Some(drift) = stats.drift.as_mut() {
+            drift.sample(instant.elapsed(), &chunk);
+        }
+        if let Some(model) = drift_correction {
+            model.correct_chunk(&mut chunk);
+        }
+        if let (None, Some(anchor)) = (stats.sync_anchor, metadata.sync_marker(&data)) {
+            log::status(
+                "Anchored",
+                format!("sync pulse at {:.9} s", anchor.as_secs_f64()),
+            );
+            stats.sync_anchor = Some(anchor);
+        }
+        if crash::hard_fault_entered(&data) {
+            let details = if capturing {
+                crashed.recv().ok()
+            } else {
+                metadata.crash().map(String::from)
+            }
+            .unwrap_or_else(|| "HardFault".to_string());
+            chunk.events.push(api::EventType::Crash {
+                details: details.clone(),
+            });
+            stats.crash = Some(details);
+        }
+        if let Some(watchdog) = watchdog {
+            watchdog.update(&mut chunk);
+        }
+        stats.queues.update(&mut chunk);
+        if let Some(tasks) = stats.tasks.as_mut() {
+            tasks.update(&chunk);
+        }
+
+        if !stats.started {
+            stats.started = true;
+            chunk.events.insert(0, api::EventType::TraceStart);
+        }
+
+        // Report any unmappable/unknown events that occured, and record stats
+        if let Some(accounting) = chunk.packets {
+            stats.packets += accounting.consumed;
+            stats.malformed += accounting.malformed;
+            stats.ignored += accounting.ignored;
+            stats.events += accounting.events;
+        }
+        stats.decoder.update(&data);
+        let at = timestamps::offset(&chunk.timestamp);
+        for event in chunk.events.iter() {
+            match event {
+                api::EventType::Unmappable(ref packet, ref reason) => {
+                    stats.nonmappable += 1;
+                    stats
+                        .warnings
+                        .warn("cannot map", &format!("{:?}", packet), Some(reason), at);
+                }
+                api::EventType::Unknown(ref packet) => {
+                    stats.nonmappable += 1;
+                    stats
+                        .warnings
+                        .warn("cannot map", &format!("{:?}", packet), None, at);
+                }
+                api::EventType::Invalid(ref malformed) => {
+                    stats.warnings.warn(
+                        "malformed",
+                        &format!("{:?}", malformed),
+                        Some(&malformed.to_string()),
+                        at,
+                    );
+                }
+                api::EventType::Anomaly {
+                    kind: api::AnomalyKind::MissingExit,
+                    ref name,
+                } => {
+                    log::warn(format!(
+                        "{} did not exit within its watchdog timeout; exit estimated",
+                        name
+                    ));
+                }
+                api::EventType::Fault {
+                    kind,
+                    cfsr,
+                    address,
+                } => {
+                    log::warn(format!(
+                        "{:?} entered: {}",
+                        kind,
+                        crash::describe_fault(*cfsr, *address)
+                    ));
+                }
+                api::EventType::Overflow => {
+                    stats.overflows += 1;
+                    log::warn("Overflow detected! Packets may have been dropped and/or timestamps will potentially be diverged until the next global timestamp.".to_string());
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(normalizer) = normalizer {
+            normalizer.normalize(&mut chunk);
+        }
+
+        for (data, chunk) in interpolator.push(data, chunk) {
+            evaluate(evaluators, &chunk);
+            drain_sinks(Some(&data), &chunk, stats, sinks)?;
+            if let Some(stepper) = stepper {
+                stepper.step(&chunk, &halt);
+            }
+        }
+        drain_aux(&metadata, stats, sinks, false)?;
+
+        Ok(())
+    };
+
+    // Mark the start of the session, and the reset that the timestamps
+    // are offsets from, as recorded for a replayed trace.
+    let flashed = matches!(&opts.cmd, Command::Trace(opts) if !opts.dont_touch_target && opts.jlink_swo.is_none());
+    let events = metadata.session_start(source.describe(), flashed);
+    drain_sinks(
+        None,
+        &lifecycle_chunk(&metadata, std::time::Duration::ZERO, events),
+        &mut stats,
+        &mut sinks,
+    )?;
+
+    // A live target is waited on until its trace stream is synchronized.
+    let mut syncing = matches!(opts.cmd, Command::Trace(_));
+    if syncing {
+        progress.borrow_mut().started(progress::Stage::Sync, None);
+    }
+
+    let (tx, packet) = channel::unbounded();
+    let shutdown = source.shutdown();
+    let stopping = shutdown.clone();
+    let source_name = source.describe();
+    let thread = std::thread::spawn(move || {
+        let (mut buffer_warning, mut hard_faulted) = (false, false);
+
+        while let Some(data) = source.next() {
+            if !buffer_warning {
                 if let sources::BufferStatus::AvailWarn(avail, buf_sz) = source.avail_buffer() {
                     eprintln!(
                         "Source {} buffer is almost full ({}/{} bytes free) and it not read quickly enough",
@@ -585,31 +2316,140 @@ where
             }
 
             match data {
-                packet @ Ok(_) => tx.send(Some(packet)).unwrap(),
-                err @ Err(_) => {
-                    tx.send(Some(err)).unwrap();
+                Ok(data) => {
+                    let hard_fault = crash::hard_fault_entered(&data);
+                    let gap = source.take_gap();
+                    if tx.send(Some(Ok((data, gap)))).is_err() {
+                        break;
+                    }
+
+                    // The target does not recover from a HardFault:
+                    // stop reading and capture why it crashed.
+                    if hard_fault && crash_capture.is_some() {
+                        hard_faulted = true;
+                        break;
+                    }
+                }
+                // A source that has been shut down ends on an error
+                // of its reader: not an error of the session.
+                Err(_) if stopping.as_ref().map_or(false, |s| s.is_signalled()) => break,
+                Err(e) => {
+                    let _ = tx.send(Some(Err(e)));
                     break;
                 }
             }
         }
 
-        tx.send(None).unwrap(); // EOF
+        // Release the probe or device before signalling EOF.
+        drop(source);
+
+        // The source no longer borrows the session: the crash can be
+        // captured through it.
+        if let (true, Some(capture)) = (hard_faulted, &crash_capture) {
+            crash_tx
+                .send(capture.capture(unsafe { SESSION.as_mut().unwrap() }))
+                .unwrap();
+        }
+        let _ = tx.send(None); // EOF
     });
+    let packet_poller = Poller {
+        thread: Some(thread),
+        shutdown,
+        source: source_name,
+    };
 
-    let instant = std::time::Instant::now();
     use std::time::Duration;
 
+    // Render the status line at a fixed rate instead of after every
+    // packet, which would flood slow terminals at high packet rates.
+    let ticker = if opts.quiet {
+        channel::never()
+    } else {
+        channel::tick(STATUS_INTERVAL)
+    };
+
+    // Attach frontends started during the session, if requested.
+    let mut attach = opts
+        .attach_dir
+        .as_deref()
+        .map(attach::AttachDir::new)
+        .transpose()
+        .context("Failed to watch directory for frontends to attach")?;
+    let attach_ticker = if attach.is_some() {
+        channel::tick(ATTACH_INTERVAL)
+    } else {
+        channel::never()
+    };
+
+    // Summarize repeated warnings periodically, if suppressed.
+    let warnings_ticker = if opts.quiet_warnings {
+        channel::tick(warnings::SUMMARY_INTERVAL)
+    } else {
+        channel::never()
+    };
+
+    // Drop frontends that have died during the session.
+    let liveness_ticker = channel::tick(sinks::PING_INTERVAL);
+
+    let mut end = api::SessionEndReason::Exhausted;
     loop {
         channel::select! {
             recv(packet) -> packet => match packet.unwrap() {
                 Some(packet) => {
-                    handle_packet(packet.context("Failed to read trace data from source")?, &mut stats, &mut sinks)?;
+                    if packet.is_err() {
+                        // NOTE the source error is reported instead
+                        let _ = drain_sinks(
+                            None,
+                            &lifecycle_chunk(&metadata, stats.last_offset, vec![api::EventType::SessionEnd {
+                                reason: api::SessionEndReason::Failed,
+                            }]),
+                            &mut stats,
+                            &mut sinks,
+                        );
+                    }
+                    let (data, gap) = packet.context("Failed to read trace data from source")?;
+                    if syncing {
+                        syncing = false;
+                        progress.borrow_mut().finished(progress::Stage::Sync);
+                    }
+                    handle_packet(data, gap, &mut stats, &mut sinks, &mut interpolator, &mut watchdog, &mut normalizer, &mut reassembler, &mut evaluators, &mut stepper)?;
+                    if stats.crash.is_some() {
+                        end = api::SessionEndReason::Crashed;
+                        break;
+                    }
+                    if stepper.as_ref().map_or(false, |s| s.interrupted()) {
+                        end = api::SessionEndReason::Interrupted;
+                        break;
+                    }
                 },
                 None => break,
             },
             recv(halt) -> _ => {
+                end = api::SessionEndReason::Interrupted;
                 break;
             },
+            recv(ticker) -> _ => if !syncing {
+                let duration = instant.elapsed();
+                log::cont_status(
+                    if let Command::Trace(_) = opts.cmd {
+                        "Tracing"
+                    } else {
+                        "Replaying"
+                    },
+                    format!("{}...", format_status_message(&metadata, &stats, &duration)),
+                );
+            },
+            recv(attach_ticker) -> _ => {
+                if let Some(attach) = attach.as_mut() {
+                    attach_frontends(attach, &metadata, &mut stats, &mut sinks);
+                }
+            },
+            recv(warnings_ticker) -> _ => {
+                for summary in stats.warnings.summarize(false) {
+                    log::warn(summary);
+                }
+            },
+            recv(liveness_ticker) -> _ => check_sinks(&mut stats, &mut sinks)?,
             default(Duration::from_millis(100)) => (),
         }
 
@@ -617,23 +2457,122 @@ where
             log::frontend(error.context("Failed to read frontend stderr")?);
         }
 
-        let duration = instant.elapsed();
-        log::cont_status(
-            match opts.cmd {
-                Command::Trace(_) => "Tracing",
-                Command::Replay(_) => "Replaying",
-            },
-            format!("{}...", format_status_message(&metadata, &stats, &duration)),
-        );
+        // Act on the control messages sent by frontends.
+        let mut stop = false;
+        for (sink, _, _) in sinks.iter_mut() {
+            while let Some(msg) = sink.control() {
+                match msg {
+                    api::ControlMessage::Stop => {
+                        log::status("Stopping", format!("on request of {}", sink.describe()));
+                        stop = true;
+                    }
+                    api::ControlMessage::Pause => log::status("Paused", sink.describe()),
+                    api::ControlMessage::Resume => log::status("Resumed", sink.describe()),
+                    api::ControlMessage::Annotate { text } => log::status(
+                        "Annotated",
+                        format!(
+                            "{:.3} s into the session by {}: {}",
+                            instant.elapsed().as_secs_f64(),
+                            sink.describe(),
+                            text
+                        ),
+                    ),
+                    // NOTE answered pings are consumed by the sink
+                    api::ControlMessage::Pong { .. } => (),
+                }
+            }
+        }
+        if stop {
+            end = api::SessionEndReason::Stopped;
+            break;
+        }
+    }
+    if syncing {
+        progress.borrow_mut().finished(progress::Stage::Sync);
     }
 
-    // The thread can simply be joined in all cases except when a halt
-    // is signalled during which the thread is likely to wait for the
-    // next packet from source. All sinks and sources will be dropped at
-    // the end of this function, so we can safely drop the thread too,
-    // as we have no need for it. The program exits soon after, so we
-    // can let the OS reap the thread.
-    drop(packet_poller);
+    // Release the probe or device as soon as the session has ended.
+    let released = packet_poller.stop(&packet);
+
+    // Forward the chunks still awaiting a timestamp estimate.
+    for (data, chunk) in interpolator.flush() {
+        evaluate(&mut evaluators, &chunk);
+        drain_sinks(Some(&data), &chunk, &mut stats, &mut sinks)?;
+        if let Some(stepper) = stepper.as_mut() {
+            stepper.step(&chunk, &halt);
+        }
+    }
+    for eval in evaluators.iter_mut() {
+        if let Some(sample) = eval.finish() {
+            log::status("Evaluated", eval.format(&sample));
+        }
+    }
+    drain_aux(&metadata, &mut stats, &mut sinks, true)?;
+    if let Some(aux) = &stats.aux {
+        aux.finish();
+    }
+    for summary in stats.warnings.summarize(true) {
+        log::warn(summary);
+    }
+    drain_sinks(
+        None,
+        &lifecycle_chunk(
+            &metadata,
+            stats.last_offset,
+            vec![api::EventType::SessionEnd { reason: end }],
+        ),
+        &mut stats,
+        &mut sinks,
+    )?;
+
+    // Record what is only known after tracing has ended. The firmware
+    // programs the DWT comparators during init, so what they are
+    // programmed with is read back last: only once the source has
+    // released the session, which it otherwise still reads from.
+    let comparators = if metadata.comparators().is_empty() {
+        vec![]
+    } else if !released {
+        log::warn(
+            "cannot read back the DWT comparators: the source has not released the probe"
+                .to_string(),
+        );
+        vec![]
+    } else {
+        match unsafe { SESSION.as_mut() } {
+            Some(session) => {
+                match dump_regs::comparators(session, metadata.core().unwrap_or(0).into()) {
+                    Ok(comparators) => comparators,
+                    Err(e) => {
+                        log::warn(format!("cannot read back the DWT comparators: {}", e));
+                        vec![]
+                    }
+                }
+            }
+            None => vec![],
+        }
+    };
+    let mut programmed = metadata.comparators().to_vec();
+    recovery::DwtComparator::read_back(&mut programmed, &comparators);
+    for comparator in programmed.iter() {
+        crate::log::debug!("{}", comparator);
+    }
+    let trailer = recovery::TraceTrailer {
+        drift: stats.drift.as_ref().and_then(|drift| drift.model()),
+        crash: stats.crash.clone(),
+        sync_anchor: stats.sync_anchor,
+        comparators,
+        gaps: stats.gaps.clone(),
+    };
+    for (sink, _, i) in sinks.iter_mut() {
+        if let Err(e) = sink.drain_trailer(&trailer) {
+            log::warn(format!(
+                "failed to drain trailer to {}: {}",
+                sink.describe(),
+                e
+            ));
+            stats.sink_metrics[*i].1.errors += 1;
+        }
+    }
 
     Ok(stats)
 }
@@ -642,18 +2581,23 @@ type TraceTuple = (
     Box<dyn sources::Source>,
     Vec<Box<dyn sinks::Sink>>,
     recovery::TraceMetadata,
+    Option<crash::CrashCapture>,
 );
 
 async fn trace(
     opts: &TraceOptions,
     cart: impl futures::Future<Output = Result<(CargoWrapper, Artifact), CargoError>>,
+    allow_stale_maps: bool,
+    frontends: &[(String, manifest::Provenance)],
+    mut invocation: recovery::Invocation,
+    progress: &progress::Observer,
 ) -> Result<Option<TraceTuple>, RTICScopeError> {
     let (cargo, artifact) = cart.await?;
     let prog = format!("{} ({})", artifact.target.name, artifact.target.src_path,);
     log::status(
         "Recovering",
         format!("metadata for {}{}", prog, {
-            if opts.resolve_only || opts.dont_touch_target {
+            if opts.resolve_only || opts.dont_touch_target || opts.plan {
                 "..."
             } else {
                 " and preparing target..."
@@ -661,14 +2605,63 @@ async fn trace(
         }),
     );
 
-    // Read the RTIC Scope manifest metadata block
-    let manip = manifest::ManifestProperties::new(&cargo, Some(&opts.pac))?;
-
-    // Build the translation maps
-    let maps = recovery::TraceLookupMaps::from(&cargo, &artifact, &manip)?;
+    // Read the RTIC Scope manifest metadata block and build the
+    // translation maps
+    let (manip, maps) = opts
+        .app
+        .recover(&cargo, &artifact, Some(&opts.pac), progress)?;
 
+    // NOTE the DWT comparators are unused by applications not written
+    // with RTIC.
+    let mut comparators = if opts.app.no_rtic {
+        vec![]
+    } else {
+        recovery::DwtComparator::configured(&manip)
+    };
     if opts.resolve_only {
         println!("{:#?}", maps);
+        if !opts.dont_touch_target && opts.jlink_swo.is_none() {
+            match opts.flash_options.probe_options.simple_attach() {
+                Ok(mut session) => {
+                    match dump_regs::comparators(&mut session, opts.core.unwrap_or(0) as usize) {
+                        Ok(programmed) => {
+                            recovery::DwtComparator::read_back(&mut comparators, &programmed)
+                        }
+                        Err(e) => log::warn(format!("cannot read back the DWT comparators: {}", e)),
+                    }
+                }
+                Err(e) => crate::log::debug!("not reading back the DWT comparators: {:?}", e),
+            }
+        }
+        for comparator in comparators.iter() {
+            println!("{}", comparator);
+        }
+        return Ok(None);
+    }
+
+    // Refuse to trace firmware built from other sources than the
+    // metadata is recovered from (e.g. via --app-src).
+    let firmware_id = artifact
+        .executable
+        .as_ref()
+        .and_then(|elf| recovery::elf_build_id(elf.as_std_path()));
+    if let Some(Err(e)) = firmware_id.map(|id| maps.check_build_id(id)) {
+        if !allow_stale_maps {
+            return Err(e.into());
+        }
+        log::warn(format!("{}; trace data may be misattributed", e));
+    }
+
+    if opts.plan {
+        let metadata = TraceMetadata::from(
+            artifact.target.name.clone(),
+            maps,
+            Local::now(),
+            manip.tpiu_freq,
+            manip.irq_latency,
+            opts.comment.clone(),
+        );
+        plan::print(opts, &cargo, &artifact, &manip, &metadata, frontends)?;
         return Ok(None);
     }
 
@@ -680,9 +2673,19 @@ async fn trace(
             .unwrap_or(&cargo.target_dir().join("rtic-traces")),
         opts.remove_prev_traces,
     )
-    .context("Failed to generate trace sink file")?;
+    .context("Failed to generate trace sink file")?
+    .filter(opts.file_filter.clone());
+
+    if !opts.no_log_file {
+        let log_file = opts
+            .log_file
+            .clone()
+            .unwrap_or_else(|| trace_sink.path().with_extension("log"));
+        log::start_session_log(&log_file)
+            .with_context(|| format!("Failed to create session log {}", log_file.display()))?;
+    }
 
-    if !opts.dont_touch_target {
+    if !opts.dont_touch_target && opts.jlink_swo.is_none() {
         let session = unsafe {
             SESSION = Some(
                 opts.flash_options
@@ -700,60 +2703,128 @@ async fn trace(
             .flash_options
             .probe_options
             .build_flashloader(session, &elf.clone().into_std_path_buf())?;
-        flash::run_flash_download(
+        flashing::flash(
             session,
-            &elf.clone().into_std_path_buf(),
+            elf.as_std_path(),
             &opts.flash_options,
             flashloader,
-            true, // do_chip_erase
+            opts.verify,
+            opts.core.unwrap_or(0) as usize,
+            progress,
         )?;
     }
 
-    let trace_source: Box<dyn sources::Source> = if let Some(dev) = &opts.serial {
+    // Why the target crashed can only be read while attached to it.
+    let crash_capture = if opts.dont_touch_target || opts.jlink_swo.is_some() {
+        None
+    } else {
+        artifact
+            .executable
+            .as_ref()
+            .map(|elf| crash::CrashCapture::new(elf.as_std_path(), opts.core.unwrap_or(0)))
+    };
+
+    progress
+        .borrow_mut()
+        .started(progress::Stage::SourceSetup, None);
+    let raw_log = match &opts.debug_raw_log {
+        Some(path) => Some(sources::RawLog::create(path)?),
+        None => None,
+    };
+    let decoder: Box<dyn sources::TraceDecoder> = Box::new(manip.decoder);
+    let mut trace_source: Box<dyn sources::Source> = if let Some(dev) = &opts.serial {
         Box::new(sources::TTYSource::new(
             sources::tty::configure(dev, manip.tpiu_baud)
                 .with_context(|| format!("Failed to configure {}", dev))?,
             &manip,
+            decoder,
+            raw_log,
         ))
+    } else if let Some(swo) = &opts.jlink_swo {
+        Box::new(sources::JLinkSource::new(
+            swo,
+            opts.jlink_gdb.as_deref(),
+            &manip,
+            decoder,
+            raw_log,
+        )?)
     } else {
         Box::new(sources::ProbeSource::new(
             unsafe { SESSION.as_mut().unwrap() },
+            opts.core.unwrap_or(0) as usize,
             &manip,
+            decoder,
+            raw_log,
+            opts.swo_silence,
         )?)
     };
+    progress.borrow_mut().finished(progress::Stage::SourceSetup);
 
     // Sample the timestamp of target and flush metadata to file.
-    let metadata = TraceMetadata::from(
+    let mut metadata = TraceMetadata::from(
         artifact.target.name,
         maps,
         Local::now(), // XXX this is the approximate reset timestamp
         manip.tpiu_freq,
+        manip.irq_latency,
         opts.comment.clone(),
     );
-    trace_sink.drain_metadata(&metadata)?;
+    metadata.set_core(opts.core);
+    metadata
+        .set_target_reset(!opts.dont_touch_target && opts.reset_method != reset::ResetMethod::None);
+    metadata.set_decoding(&manip);
+    metadata.set_comparators(comparators);
+    invocation.manifest = Some(manip.clone());
+    metadata.set_invocation(Some(invocation));
+    let recorded = match &opts.obfuscate_names {
+        Some(path) => {
+            let mut pseudonyms = obfuscate::Pseudonyms::load(path)?;
+            let mut obfuscated = metadata.clone();
+            pseudonyms.obfuscate(&mut obfuscated);
+            pseudonyms.store(path)?;
+            obfuscated
+        }
+        None => metadata.clone(),
+    };
+    trace_sink.drain_metadata(&recorded)?;
+    let upload_sink = match &opts.upload {
+        Some(url) => Some(sinks::UploadSink::new(
+            sinks::UploadTarget {
+                url: url.clone(),
+                sigv4: opts.upload_sigv4.clone(),
+            },
+            &recorded,
+            opts.upload_segment,
+        )?),
+        None => None,
+    };
 
-    if !opts.dont_touch_target {
+    if opts.jlink_swo.is_some() {
+        progress.borrow_mut().started(progress::Stage::Reset, None);
+        trace_source.reset_target(opts.reset_method, opts.flash_options.reset_halt)?;
+        progress.borrow_mut().finished(progress::Stage::Reset);
+    } else if !opts.dont_touch_target {
         // Reset the target device
-        unsafe { SESSION.as_mut().unwrap() }
-            .core(0)
-            .and_then(|mut c| match opts.flash_options.reset_halt {
-                true => {
-                    let _ = c.reset_and_halt(std::time::Duration::from_millis(250))?;
-                    Ok(())
-                }
-                false => c.reset(),
-            })
-            .map_err(sources::SourceError::ResetError)?;
+        progress.borrow_mut().started(progress::Stage::Reset, None);
+        reset::reset(
+            unsafe { SESSION.as_mut().unwrap() },
+            opts.core.unwrap_or(0) as usize,
+            opts.reset_method,
+            opts.flash_options.reset_halt,
+        )?;
+        progress.borrow_mut().finished(progress::Stage::Reset);
     }
 
     log::status(
         "Recovered",
         format!(
             "{ntotal} task(s) from {prog}: {nhard} hard, {nsoft} soft.{}",
-            if !opts.dont_touch_target {
-                "Target reset and flashed."
-            } else {
-                ""
+            match (opts.dont_touch_target, opts.reset_method) {
+                (true, _) => "",
+                (false, reset::ResetMethod::None) if opts.jlink_swo.is_some() => "",
+                (false, _) if opts.jlink_swo.is_some() => "Target reset.",
+                (false, reset::ResetMethod::None) => "Target flashed.",
+                (false, _) => "Target reset and flashed.",
             },
             ntotal = metadata.hardware_tasks_len() + metadata.software_tasks_len(),
             prog = metadata.program_name,
@@ -762,100 +2833,531 @@ async fn trace(
         ),
     );
 
-    Ok(Some((trace_source, vec![Box::new(trace_sink)], metadata)))
+    let mut trace_sinks: Vec<Box<dyn sinks::Sink>> = vec![match opts.snapshot {
+        Some(window) => Box::new(sinks::SnapshotSink::new(
+            trace_sink,
+            window,
+            opts.snapshot_socket.clone(),
+        )?),
+        None => Box::new(trace_sink),
+    }];
+    if let Some(upload_sink) = upload_sink {
+        trace_sinks.push(Box::new(upload_sink));
+    }
+
+    Ok(Some((trace_source, trace_sinks, metadata, crash_capture)))
+}
+
+/// The properties to decode a raw trace file with, and the metadata to
+/// map it with, as recorded in `trace`; see `replay --metadata-from`.
+fn raw_metadata_from(
+    trace: &Path,
+    comment: &Option<String>,
+) -> Result<(manifest::ManifestProperties, recovery::TraceMetadata), RTICScopeError> {
+    let mut metadata =
+        sources::FileSource::new(fs::OpenOptions::new().read(true).open(trace)?)?.metadata();
+    let mut manip = manifest::ManifestProperties::synthetic();
+    manip.tpiu_freq = metadata.tpiu_freq();
+    match metadata.decoding() {
+        Some(decoding) => {
+            manip.lts_prescaler = decoding.lts_prescaler.clone();
+            manip.expect_malformed = decoding.expect_malformed;
+            manip.decoder = decoding.decoder;
+        }
+        None => {
+            manip.expect_malformed = false;
+            log::warn(format!(
+                "{} does not record how it was decoded; assuming an undivided local timestamp clock",
+                trace.display()
+            ));
+        }
+    }
+    log::status(
+        "Recovered",
+        format!(
+            "metadata of {} from {} ({} Hz TPIU frequency)",
+            metadata.program_name,
+            trace.display(),
+            manip.tpiu_freq
+        ),
+    );
+
+    // NOTE what is only known after tracing does not apply to the raw
+    // capture.
+    metadata.set_reset_timestamp(chrono::Local::now());
+    metadata.comment = comment.clone();
+    metadata.set_drift(None);
+    metadata.set_crash(None);
+    metadata.set_sync_anchor(None);
+
+    Ok((manip, metadata))
 }
 
 async fn replay(
     opts: &ReplayOptions,
     cart: impl futures::Future<Output = Result<(CargoWrapper, Artifact), CargoError>>,
+    progress: &progress::Observer,
 ) -> Result<Option<TraceTuple>, RTICScopeError> {
     match opts {
         ReplayOptions {
             raw_options:
                 RawFileOptions {
                     file: Some(file),
+                    skip_bytes,
+                    tpiu_stream,
+                    metadata_from,
                     comment,
                     pac,
+                    app,
                 },
             ..
         } => {
-            let (cargo, artifact) = cart.await?;
-            let manip = manifest::ManifestProperties::new(&cargo, None)?;
-            let src =
-                sources::RawFileSource::new(fs::OpenOptions::new().read(true).open(file)?, &manip);
-            let maps = recovery::TraceLookupMaps::from(&cargo, &artifact, &manip)?;
-            let metadata = recovery::TraceMetadata::from(
-                artifact.target.name,
-                maps,
-                chrono::Local::now(),
-                pac.tpiu_freq.unwrap_or(manip.tpiu_freq),
-                comment.clone(),
+            let (manip, metadata) = match metadata_from {
+                Some(trace) => raw_metadata_from(trace, comment)?,
+                None => {
+                    // Unless given, use the frequency of the timestamp clock
+                    // reported by the target, if any.
+                    let mut pac = pac.clone();
+                    if pac.tpiu_freq.is_none() {
+                        pac.tpiu_freq = sources::RawFileSource::reported_freq(
+                            fs::OpenOptions::new().read(true).open(file)?,
+                            *skip_bytes,
+                            *tpiu_stream,
+                        )?;
+                        if let Some(freq) = pac.tpiu_freq {
+                            log::status(
+                                "Recovered",
+                                format!("TPIU frequency of {} Hz from the trace", freq),
+                            );
+                        }
+                    }
+
+                    let (cargo, artifact) = cart.await?;
+                    let (manip, maps) = app.recover(&cargo, &artifact, Some(&pac), progress)?;
+                    let metadata = recovery::TraceMetadata::from(
+                        artifact.target.name,
+                        maps,
+                        chrono::Local::now(),
+                        manip.tpiu_freq,
+                        manip.irq_latency,
+                        comment.clone(),
+                    );
+                    (manip, metadata)
+                }
+            };
+            let src = sources::RawFileSource::new(
+                fs::OpenOptions::new().read(true).open(file)?,
+                &manip,
+                Box::new(manip.decoder),
+                *skip_bytes,
+                *tpiu_stream,
+            )?;
+            log::status(
+                "Replaying",
+                match src.framing() {
+                    sources::Framing::Itm => {
+                        format!("raw ITM stream from byte {}", skip_bytes)
+                    }
+                    sources::Framing::Tpiu { offset } => format!(
+                        "TPIU-formatted stream from byte {}",
+                        skip_bytes + offset as u64
+                    ),
+                },
             );
 
-            Ok(Some((Box::new(src), vec![], metadata)))
+            Ok(Some((Box::new(src), vec![], metadata, None)))
         }
         ReplayOptions {
             list: true,
             trace_dir,
             ..
         } => {
-            let traces = sinks::file::find_trace_files(
-                trace_dir.clone().unwrap_or(
-                    cargo_metadata::MetadataCommand::new()
-                        .exec()
-                        .context("cargo metadata command failed")?
-                        .target_directory
-                        .join("rtic-traces")
-                        .into(),
-                ),
-            )?;
+            let traces = sinks::file::find_trace_files(match trace_dir {
+                Some(dir) => dir.clone(),
+                None => default_trace_dir()?,
+            })?;
             println!("index\ttrace file");
             for (i, trace) in traces.enumerate() {
-                let metadata =
-                    sources::FileSource::new(fs::OpenOptions::new().read(true).open(&trace)?)?
-                        .metadata();
+                let header =
+                    sources::FileSource::header(fs::OpenOptions::new().read(true).open(&trace)?)?;
                 println!(
                     "{}\t{}\t{}",
                     i,
                     trace.display(),
-                    metadata.comment.unwrap_or_else(|| "".to_string())
+                    header.comment.unwrap_or_else(|| "".to_string())
                 );
+                if opts.verbose {
+                    print_invocation(header.invocation.as_ref());
+                    let mut comparators = header.comparators;
+                    if let Some(trailer) = sources::FileSource::trailer(
+                        fs::OpenOptions::new().read(true).open(&trace)?,
+                    )? {
+                        recovery::DwtComparator::read_back(&mut comparators, &trailer.comparators);
+                    }
+                    for comparator in comparators.iter() {
+                        println!("\t{}", comparator);
+                    }
+                }
             }
 
             Ok(None)
         }
+        ReplayOptions {
+            stdin: true,
+            deobfuscate_names,
+            ..
+        } => {
+            let src = sources::FileSource::stdin()?.range(opts.from, opts.to);
+            let metadata = deobfuscate(src.metadata(), deobfuscate_names)?;
+            let metadata = remap(metadata, opts, cart, progress).await?;
+            Ok(Some((Box::new(src), vec![], metadata, None)))
+        }
         ReplayOptions {
             trace_file: Some(file),
+            deobfuscate_names,
             ..
         } => {
-            let src = sources::FileSource::new(fs::OpenOptions::new().read(true).open(&file)?)?;
-            let metadata = src.metadata();
-            Ok(Some((Box::new(src), vec![], metadata)))
+            let src = sources::FileSource::open(file, opts.from, opts.to)?;
+            let metadata = deobfuscate(src.metadata(), deobfuscate_names)?;
+            let metadata = remap(metadata, opts, cart, progress).await?;
+            Ok(Some((Box::new(src), vec![], metadata, None)))
         }
         ReplayOptions {
             index: Some(idx),
             trace_dir,
+            deobfuscate_names,
             ..
         } => {
-            let mut traces = sinks::file::find_trace_files(
-                trace_dir.clone().unwrap_or(
-                    cargo_metadata::MetadataCommand::new()
-                        .exec()
-                        .context("cargo metadata command failed")?
-                        .target_directory
-                        .join("rtic-traces")
-                        .into(),
-                ),
-            )?;
-            let trace = traces
-                .nth(*idx)
-                .with_context(|| format!("No trace with index {}", *idx))?;
-
-            let src = sources::FileSource::new(fs::OpenOptions::new().read(true).open(&trace)?)?;
-            let metadata = src.metadata();
+            let trace = resolve_trace_file(&None, Some(*idx), trace_dir)?;
+            let src = sources::FileSource::open(&trace, opts.from, opts.to)?;
+            let metadata = deobfuscate(src.metadata(), deobfuscate_names)?;
+            let metadata = remap(metadata, opts, cart, progress).await?;
 
-            Ok(Some((Box::new(src), vec![], metadata)))
+            Ok(Some((Box::new(src), vec![], metadata, None)))
         }
         _ => unreachable!(),
     }
 }
+
+/// Prints how a trace was recorded; see `replay --list --verbose`.
+fn print_invocation(invocation: Option<&recovery::Invocation>) {
+    let invocation = match invocation {
+        Some(invocation) => invocation,
+        None => {
+            println!("\trecorded by a version that does not record its invocation");
+            return;
+        }
+    };
+
+    println!("\trecorded by cargo-rtic-scope {}", invocation.version);
+    if invocation.args.is_empty() {
+        println!("\tcommand line redacted by --obfuscate-names");
+    } else {
+        println!("\t$ {}", invocation.command_line());
+    }
+    if let Some(manifest) = &invocation.manifest {
+        println!(
+            "\tTPIU at {} Hz, {} baud; {:?} decoder, LTS prescaler {:?}{}",
+            manifest.tpiu_freq,
+            manifest.tpiu_baud,
+            manifest.decoder,
+            manifest.lts_prescaler,
+            if manifest.expect_malformed {
+                ", malformed packets expected"
+            } else {
+                ""
+            }
+        );
+    }
+    // NOTE(unwrap) the options are a struct of plain values
+    let options = serde_json::to_value(&invocation.options).unwrap();
+    for (option, value) in options.as_object().unwrap() {
+        match value {
+            serde_json::Value::Null => (),
+            serde_json::Value::String(value) => println!("\t{} = {}", option, value),
+            value => println!("\t{} = {}", option, value),
+        }
+    }
+}
+
+/// The options of a trace session merged from the command line, the
+/// user configuration, and the manifest, as recorded in its invocation.
+fn effective_options(opts: &Opts) -> recovery::EffectiveOptions {
+    let mut options = recovery::EffectiveOptions {
+        profile: opts.profile.clone(),
+        frontends: opts.frontends.clone(),
+        ..Default::default()
+    };
+    if let Command::Trace(opts) = &opts.cmd {
+        let po = &opts.flash_options.probe_options;
+        options.trace_dir = opts.trace_dir.clone();
+        options.probe = po.probe_selector.as_ref().map(|probe| {
+            format!(
+                "{:04x}:{:04x}{}",
+                probe.vendor_id,
+                probe.product_id,
+                probe
+                    .serial_number
+                    .as_ref()
+                    .map(|sn| format!(":{}", sn))
+                    .unwrap_or_default()
+            )
+        });
+        options.chip = po.chip.clone();
+        options.probe_speed = po.speed;
+        options.serial = opts.serial.clone();
+        options.jlink_swo = opts.jlink_swo.clone();
+        options.jlink_gdb = opts.jlink_gdb.clone();
+    }
+
+    options
+}
+
+/// Restores the names of the given metadata from the mapping file, if
+/// any. See `--deobfuscate-names`.
+fn deobfuscate(
+    mut metadata: TraceMetadata,
+    mapping: &Option<PathBuf>,
+) -> Result<TraceMetadata, RTICScopeError> {
+    if let Some(path) = mapping {
+        obfuscate::Pseudonyms::load(path)?.deobfuscate(&mut metadata);
+    }
+
+    Ok(metadata)
+}
+
+/// Renames the tasks and resources of the given metadata to their
+/// names in the current sources of the application, if requested and
+/// confirmed. See `replay --remap`.
+async fn remap(
+    mut metadata: TraceMetadata,
+    opts: &ReplayOptions,
+    cart: impl futures::Future<Output = Result<(CargoWrapper, Artifact), CargoError>>,
+    progress: &progress::Observer,
+) -> Result<TraceMetadata, RTICScopeError> {
+    if !opts.remap {
+        return Ok(metadata);
+    }
+
+    let (cargo, artifact) = cart.await?;
+    // Recover the names under the policy the trace was recorded with,
+    // or every nested task would appear renamed.
+    let mut pac = opts.raw_options.pac.clone();
+    if pac
+        .name_policy
+        .map_or(false, |p| p != metadata.name_policy())
+    {
+        log::warn(format!(
+            "--name-policy is ignored; the trace was recorded under the {:?} name policy",
+            metadata.name_policy()
+        ));
+    }
+    pac.name_policy = Some(metadata.name_policy());
+    let (_, current) = opts
+        .raw_options
+        .app
+        .recover(&cargo, &artifact, Some(&pac), progress)?;
+    let renames = metadata.renames(&current);
+    if renames.is_empty() {
+        log::status(
+            "Remapped",
+            "nothing: no tasks or resources were renamed".to_string(),
+        );
+        return Ok(metadata);
+    }
+    for (_, old, new) in renames.iter() {
+        log::status("Renaming", format!("{} to {}", old, new));
+    }
+
+    // NOTE stdin may carry the trace; see --stdin
+    let confirmed = opts.yes
+        || (!opts.stdin
+            && matches!(
+                init::prompt::<String>("apply these renames? [y/N]").as_deref(),
+                Some("y" | "Y" | "yes")
+            ));
+    if !confirmed {
+        log::warn(
+            "renames not confirmed; replaying under the recorded names (see --yes)".to_string(),
+        );
+        return Ok(metadata);
+    }
+
+    metadata.rename(|kind, name| {
+        renames
+            .iter()
+            .find(|(k, old, _)| *k == kind && old == name)
+            .map_or_else(|| name.to_owned(), |(_, _, new)| new.to_owned())
+    });
+    log::status("Remapped", format!("{} names", renames.len()));
+
+    Ok(metadata)
+}
+
+/// Fills in the options not given on the command line from the user
+/// configuration.
+fn apply_user_config(
+    opts: &mut Opts,
+    config: &config::UserConfig,
+    profile: Option<&config::Profile>,
+) -> Result<(), RTICScopeError> {
+    if let Some(color) = config.color {
+        colored::control::set_override(color);
+    }
+    if let Some(profile) = profile {
+        apply_profile(opts, profile)?;
+    }
+
+    let (trace_dir, probe_options) = match &mut opts.cmd {
+        Command::Trace(opts) => (
+            Some(&mut opts.trace_dir),
+            Some(&mut opts.flash_options.probe_options),
+        ),
+        Command::Replay(opts) => (Some(&mut opts.trace_dir), None),
+        Command::Stats(opts) => (Some(&mut opts.trace_dir), None),
+        Command::Export(opts) => (Some(&mut opts.trace_dir), None),
+        Command::DumpRegs(opts) => (None, Some(&mut opts.probe_options)),
+        _ => (None, None),
+    };
+    if let Some(trace_dir) = trace_dir {
+        if trace_dir.is_none() {
+            *trace_dir = config.trace_dir.clone();
+        }
+    }
+    if let Some(probe_options) = probe_options {
+        if probe_options.speed.is_none() {
+            probe_options.speed = config.probe_speed;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the options of `profile` that are not given on the command
+/// line; see [`config::Profile`]. The TPIU options of the profile are
+/// layered below the manifest, and its frontends are added once the
+/// frontends to spawn are resolved.
+fn apply_profile(opts: &mut Opts, profile: &config::Profile) -> Result<(), RTICScopeError> {
+    macro_rules! default {
+        ($opt:expr, $value:expr) => {
+            if $opt.is_none() {
+                $opt = $value.clone();
+            }
+        };
+    }
+
+    let probe_selector = profile
+        .probe
+        .as_ref()
+        .map(|probe| {
+            probe.parse::<probe_rs::DebugProbeSelector>().map_err(|e| {
+                config::ConfigError::InvalidValue {
+                    key: "probe".to_string(),
+                    value: probe.to_owned(),
+                    reason: e.to_string(),
+                }
+            })
+        })
+        .transpose()?;
+    let (trace_dir, probe_options, pac) = match &mut opts.cmd {
+        Command::Trace(opts) => {
+            if opts.serial.is_none() && opts.jlink_swo.is_none() {
+                opts.serial = profile.serial.clone();
+                opts.jlink_swo = profile.jlink_swo.clone();
+                opts.jlink_gdb = profile.jlink_gdb.clone();
+            }
+            default!(opts.chip_filter, profile.chip_filter);
+            (
+                Some(&mut opts.trace_dir),
+                Some(&mut opts.flash_options.probe_options),
+                Some(&mut opts.pac),
+            )
+        }
+        Command::Replay(opts) => (Some(&mut opts.trace_dir), None, None),
+        Command::Stats(opts) => (Some(&mut opts.trace_dir), None, None),
+        Command::Export(opts) => (Some(&mut opts.trace_dir), None, None),
+        Command::DumpRegs(opts) => (None, Some(&mut opts.probe_options), Some(&mut opts.pac)),
+        _ => (None, None, None),
+    };
+    if let Some(trace_dir) = trace_dir {
+        default!(*trace_dir, profile.trace_dir);
+    }
+    if let Some(po) = probe_options {
+        default!(po.probe_selector, probe_selector);
+        default!(po.chip, profile.chip);
+        default!(po.speed, profile.probe_speed);
+    }
+    if let Some(pac) = pac {
+        pac.profile = ProfileManifestOptions {
+            tpiu_freq: profile.tpiu_freq,
+            tpiu_baud: profile.tpiu_baud,
+        };
+    }
+
+    Ok(())
+}
+
+/// The default directory of recorded traces: `rtic-traces/` in the
+/// target directory of the current crate.
+fn default_trace_dir() -> Result<PathBuf, RTICScopeError> {
+    Ok(cargo_metadata::MetadataCommand::new()
+        .exec()
+        .context("cargo metadata command failed")?
+        .target_directory
+        .join("rtic-traces")
+        .into())
+}
+
+/// Resolves a recorded trace file from either an explicit path, or an
+/// index into the traces of `trace_dir` (or [`default_trace_dir`]).
+pub fn resolve_trace_file(
+    trace_file: &Option<PathBuf>,
+    index: Option<usize>,
+    trace_dir: &Option<PathBuf>,
+) -> Result<PathBuf, RTICScopeError> {
+    if let Some(file) = trace_file {
+        return Ok(file.to_owned());
+    }
+
+    let idx = index.context("Neither a trace file nor a trace index was given")?;
+    let mut traces = sinks::file::find_trace_files(match trace_dir {
+        Some(dir) => dir.clone(),
+        None => default_trace_dir()?,
+    })?;
+    Ok(traces
+        .nth(idx)
+        .with_context(|| format!("No trace with index {}", idx))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure the options of a profile are set where they are not given
+    /// on the command line, except for the TPIU options, which are
+    /// layered below the manifest instead.
+    #[test]
+    fn apply_profile() {
+        let mut opts = Opts::from_iter(["rtic-scope", "trace", "--chip", "stm32f401re"]);
+        let profile = config::Profile {
+            chip: Some("nrf52840".to_string()),
+            probe_speed: Some(4000),
+            tpiu_freq: Some(16_000_000),
+            tpiu_baud: Some(2_000_000),
+            ..Default::default()
+        };
+        super::apply_profile(&mut opts, &profile).unwrap();
+
+        let opts = match opts.cmd {
+            Command::Trace(opts) => opts,
+            _ => unreachable!(),
+        };
+        let probe_options = &opts.flash_options.probe_options;
+        assert_eq!(probe_options.chip.as_deref(), Some("stm32f401re"));
+        assert_eq!(probe_options.speed, Some(4000));
+        assert_eq!(opts.pac.tpiu_freq, None);
+        assert_eq!(opts.pac.tpiu_baud, None);
+        assert_eq!(opts.pac.profile.tpiu_freq, Some(16_000_000));
+        assert_eq!(opts.pac.profile.tpiu_baud, Some(2_000_000));
+    }
+}
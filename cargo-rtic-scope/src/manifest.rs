@@ -4,15 +4,22 @@
 //! supplied/overridden via command-line options.
 use crate::build::CargoWrapper;
 use crate::diag;
+use crate::recovery::{NamePolicy, BUILD_ID_PORT, FAULT_PORT, FREQ_PORT, ID_CHECK_PORT, SYNC_PORT};
+use crate::sources::DecoderKind;
+use crate::stimulus::{PortKind, StimulusPort};
 use crate::ManifestOptions;
 
 use std::convert::TryInto;
+use std::fmt;
 
 use cortex_m::peripheral::itm::LocalTimestampOptions;
+use indexmap::IndexMap;
+use rtic_scope_api as api;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Deserialize, Debug, Default)]
+#[cfg_attr(test, derive(Serialize))]
 struct ManifestPropertiesIntermediate {
     pub pac_name: Option<String>,
     pub pac_features: Option<Vec<String>>,
@@ -23,7 +30,22 @@ struct ManifestPropertiesIntermediate {
     pub lts_prescaler: Option<u8>,
     pub dwt_enter_id: Option<usize>,
     pub dwt_exit_id: Option<usize>,
+    pub dwt_lock_id: Option<usize>,
+    pub dwt_spawn_id: Option<usize>,
+    pub dwt_args_id: Option<usize>,
+    pub ignore_exceptions: Option<Vec<String>>,
     pub expect_malformed: Option<bool>,
+    pub decoder: Option<DecoderKind>,
+    pub trace_idle: Option<bool>,
+    pub fault_trace: Option<bool>,
+    pub build_id: Option<bool>,
+    pub sync_pulse: Option<bool>,
+    pub freq_report: Option<bool>,
+    pub name_policy: Option<NamePolicy>,
+    pub irq_entry_latency: Option<u32>,
+    pub irq_exit_latency: Option<u32>,
+    pub ports: Option<IndexMap<u8, StimulusPort>>,
+    pub tasks: Option<IndexMap<String, api::TaskHints>>,
 }
 
 impl ManifestPropertiesIntermediate {
@@ -47,11 +69,35 @@ impl ManifestPropertiesIntermediate {
             lts_prescaler,
             dwt_enter_id,
             dwt_exit_id,
-            expect_malformed
+            dwt_lock_id,
+            dwt_spawn_id,
+            dwt_args_id,
+            ignore_exceptions,
+            expect_malformed,
+            decoder,
+            trace_idle,
+            fault_trace,
+            build_id,
+            sync_pulse,
+            freq_report,
+            name_policy,
+            irq_entry_latency,
+            irq_exit_latency,
+            ports,
+            tasks
         );
     }
 }
 
+/// Constant interrupt entry and exit latencies of a target, in
+/// timestamp clock cycles. Subtracted from the timestamps of hardware
+/// task actions to calibrate them.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct InterruptLatency {
+    pub entry: u32,
+    pub exit: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ManifestProperties {
     pub pac_name: String,
@@ -63,7 +109,58 @@ pub struct ManifestProperties {
     pub lts_prescaler: LocalTimestampOptions,
     pub dwt_enter_id: usize,
     pub dwt_exit_id: usize,
+    /// DWT unit ID for resource lock tracing, if enabled.
+    pub dwt_lock_id: Option<usize>,
+    /// DWT unit ID for spawn tracing, if enabled.
+    pub dwt_spawn_id: Option<usize>,
+    /// DWT unit ID for software task argument tracing, if enabled.
+    pub dwt_args_id: Option<usize>,
+    /// Exceptions and interrupts (e.g. `SysTick`) whose trace packets
+    /// are dropped before they are mapped to RTIC tasks.
+    pub ignore_exceptions: Vec<String>,
     pub expect_malformed: bool,
+    /// Decoder of the trace stream; see [`crate::sources::decoder`].
+    #[serde(default)]
+    pub decoder: DecoderKind,
+    /// Whether thread mode is reported as the `#[idle]` task of the
+    /// application, if it has one, instead of being dropped.
+    pub trace_idle: bool,
+    /// Whether the firmware writes fault records on
+    /// [`FAULT_PORT`] via `cortex_m_rtic_trace::trace_fault`. The port
+    /// is then reserved, and its writes are decoded into
+    /// `EventType::Fault` events.
+    #[serde(default)]
+    pub fault_trace: bool,
+    /// Whether the firmware reports its build ID on [`BUILD_ID_PORT`]
+    /// (the `build-id` feature of cortex-m-rtic-trace). The port is then
+    /// reserved, and the reported build ID verified.
+    #[serde(default)]
+    pub build_id: bool,
+    /// Whether the firmware writes sync markers on [`SYNC_PORT`] via
+    /// `cortex_m_rtic_trace::sync_pulse`. The port is then reserved, and
+    /// the first marker anchors the trace.
+    #[serde(default)]
+    pub sync_pulse: bool,
+    /// Whether the firmware reports the frequency of the timestamp
+    /// clock on [`FREQ_PORT`] (the `freq-report` feature of
+    /// cortex-m-rtic-trace). The port is then reserved.
+    #[serde(default)]
+    pub freq_report: bool,
+    /// How the names of traced functions nested in software tasks are
+    /// derived from their paths; see [`NamePolicy`].
+    #[serde(default)]
+    pub name_policy: NamePolicy,
+    /// Constant interrupt entry and exit latencies of the target, in
+    /// timestamp clock cycles. Zero if not calibrated.
+    pub irq_latency: InterruptLatency,
+    /// ITM stimulus ports whose writes are reassembled into log lines
+    /// or telemetry frames; see [`crate::stimulus`].
+    #[serde(default)]
+    pub ports: IndexMap<u8, StimulusPort>,
+    /// Display hints of tasks, by full task name (e.g. `app::foo`),
+    /// forwarded to frontends.
+    #[serde(default)]
+    pub tasks: IndexMap<String, api::TaskHints>,
 }
 
 #[derive(Error, Debug)]
@@ -86,6 +183,16 @@ pub enum ManifestMetadataError {
     MissingDWTUnit,
     #[error("Manifest metadata is missing conditional whether malformed packets are expected")]
     MissingExpectMalformed,
+    #[error("ITM stimulus port {0} cannot be configured")]
+    InvalidStimulusPort(u8),
+    #[error("Field {1} of ITM stimulus port {0} does not fit in a 32-bit write")]
+    InvalidPortField(u8, String),
+    #[error("Bitfield ITM stimulus port {0} has no fields")]
+    EmptyBitfieldPort(u8),
+    #[error("Binary ITM stimulus port {0} has frames of 0 bytes")]
+    EmptyFrameSize(u8),
+    #[error("Color {1:?} of task {0} is not of the form #rrggbb")]
+    InvalidTaskColor(String, String),
 }
 
 impl diag::DiagnosableError for ManifestMetadataError {
@@ -94,20 +201,94 @@ impl diag::DiagnosableError for ManifestMetadataError {
             Self::MissingName => vec!["Add `pac_name = \"<your PAC name>\"` to [package.metadata.rtic-scope] in Cargo.toml or specify --pac-name".into()],
             Self::MissingVersion => vec!["Add `pac_version = \"your PAC version\"` to [package.metadata.rtic-scope] in Cargo.toml or specify --pac-version".into()],
             Self::MissingInterruptPath => vec!["Add `interrupt_path = \"path to your PAC's Interrupt enum\"` to [package.metadata.rtic-scope] in Cargo.toml or specify --pac-interrupt-path".into()],
-            Self::MissingFreq => vec!["Add `tpiu_freq = \"your TPIU frequency\"` to [package.metadata.rtic-scope] in Cargo.toml or specify --tpiu-freq".into()],
+            Self::MissingFreq => vec![
+                "Add `tpiu_freq = \"your TPIU frequency\"` to [package.metadata.rtic-scope] in Cargo.toml or specify --tpiu-freq".into(),
+                "Raw trace files recorded from firmware configured by cortex_m_rtic_trace::configure with the `freq-report` feature carry the frequency.".into(),
+            ],
             Self::MissingBaud => vec!["Add `tpiu_baud = \"your TPIU baud rate\"` to [package.metadata.rtic-scope] in Cargo.toml or specify --tpiu-baud".into()],
             Self::MissingLTSPrescaler => vec!["Add `lts_prescaler = <your LTS prescaler value (accepted values: 1, 4, 16, 64)>` to [package.metadata.rtic-scope] in Cargo.toml".into()],
             Self::MissingDWTUnit => vec!["Add `dwt_enter_id = \"your enter DWT unit ID\"` and `dwt_exit_id = \"your exit DWT unit ID\"` to [package.metadata.rtic-scope] in Cargo.toml".into()],
             Self::MissingExpectMalformed => vec!["Add `expect_malformed = <whether malformed packets are expected>` to [package.metadata.rtic-scope] in Cargo.toml".into()],
+            Self::InvalidStimulusPort(_) => vec!["Configure ports 0 through 30 via `ports` in [package.metadata.rtic-scope]. Ports 27 (fault records, if `fault_trace` is set), 28 (build ID, if `build_id` is set), 29 (sync marker, if `sync_pulse` is set), 30 (timestamp clock frequency, if `freq_report` is set), and 31 (software task IDs) are used by cortex-m-rtic-trace.".into()],
+            Self::InvalidPortField(port, field) => vec![format!("Set `fields.{} = [<first bit>, <width in bits>]` of port {} such that the field ends at or before bit 32", field, port)],
+            Self::EmptyBitfieldPort(port) => vec![format!("Declare the fields of port {} via `fields.<name> = [<first bit>, <width in bits>]`, or change its type", port)],
+            Self::EmptyFrameSize(port) => vec![format!("Unset the `size` of port {} to reassemble frames as written by `cortex_m_rtic_trace::write_frame`", port)],
+            Self::InvalidTaskColor(task, _) => vec![format!("Set `color = \"#ff0000\"` or similar in [package.metadata.rtic-scope.tasks.\"{}\"]", task)],
             _ => vec![],
         }
     }
 }
 
+impl ManifestPropertiesIntermediate {
+    /// Completes the properties of an application not written with
+    /// RTIC: the PAC is then optional (and only used to name
+    /// interrupts), and the DWT units are unused.
+    fn into_bare(mut self) -> Result<ManifestProperties, ManifestMetadataError> {
+        if self.pac_name.is_none() || self.pac_version.is_none() || self.interrupt_path.is_none() {
+            self.pac_name = Some(String::new());
+            self.pac_version = Some(String::new());
+            self.interrupt_path = Some(String::new());
+        }
+        self.dwt_enter_id.get_or_insert(0);
+        self.dwt_exit_id.get_or_insert(0);
+        self.dwt_lock_id = None;
+        self.dwt_spawn_id = None;
+        self.dwt_args_id = None;
+        self.expect_malformed.get_or_insert(false);
+
+        self.try_into()
+    }
+}
+
 impl TryInto<ManifestProperties> for ManifestPropertiesIntermediate {
     type Error = ManifestMetadataError;
 
     fn try_into(self) -> Result<ManifestProperties, Self::Error> {
+        let ports = self.ports.unwrap_or_default();
+        let fault_trace = self.fault_trace.unwrap_or(false);
+        let build_id = self.build_id.unwrap_or(false);
+        let sync_pulse = self.sync_pulse.unwrap_or(false);
+        let freq_report = self.freq_report.unwrap_or(false);
+        // The ports used by cortex-m-rtic-trace, if the application uses
+        // the features that write to them.
+        if let Some(port) = ports.keys().find(|port| match **port {
+            FAULT_PORT => fault_trace,
+            BUILD_ID_PORT => build_id,
+            SYNC_PORT => sync_pulse,
+            FREQ_PORT => freq_report,
+            ID_CHECK_PORT => true,
+            port => port > 31,
+        }) {
+            return Err(Self::Error::InvalidStimulusPort(*port));
+        }
+        for (port, config) in ports.iter() {
+            if config.kind == PortKind::Bitfield && config.fields.is_empty() {
+                return Err(Self::Error::EmptyBitfieldPort(*port));
+            }
+            if config.kind == PortKind::Binary && config.size == Some(0) {
+                return Err(Self::Error::EmptyFrameSize(*port));
+            }
+            if let Some((field, _)) = config
+                .fields
+                .iter()
+                .find(|(_, (offset, width))| *width == 0 || *offset as u32 + *width as u32 > 32)
+            {
+                return Err(Self::Error::InvalidPortField(*port, field.to_owned()));
+            }
+        }
+        let tasks = self.tasks.unwrap_or_default();
+        for (task, hints) in tasks.iter() {
+            match &hints.color {
+                Some(color) if !is_color(color) => {
+                    return Err(Self::Error::InvalidTaskColor(
+                        task.to_owned(),
+                        color.to_owned(),
+                    ))
+                }
+                _ => (),
+            }
+        }
+
         Ok(ManifestProperties {
             pac_name: self.pac_name.ok_or(Self::Error::MissingName)?,
             pac_version: self.pac_version.ok_or(Self::Error::MissingVersion)?,
@@ -124,23 +305,395 @@ impl TryInto<ManifestProperties> for ManifestPropertiesIntermediate {
                 .map_err(|_| Self::Error::MissingLTSPrescaler)?,
             dwt_enter_id: self.dwt_enter_id.ok_or(Self::Error::MissingDWTUnit)?,
             dwt_exit_id: self.dwt_exit_id.ok_or(Self::Error::MissingDWTUnit)?,
+            dwt_lock_id: self.dwt_lock_id,
+            dwt_spawn_id: self.dwt_spawn_id,
+            dwt_args_id: self.dwt_args_id,
+            ignore_exceptions: self.ignore_exceptions.unwrap_or_default(),
             expect_malformed: self
                 .expect_malformed
                 .ok_or(Self::Error::MissingExpectMalformed)?,
+            decoder: self.decoder.unwrap_or_default(),
+            trace_idle: self.trace_idle.unwrap_or(false),
+            fault_trace,
+            build_id,
+            sync_pulse,
+            freq_report,
+            name_policy: self.name_policy.unwrap_or_default(),
+            irq_latency: InterruptLatency {
+                entry: self.irq_entry_latency.unwrap_or(0),
+                exit: self.irq_exit_latency.unwrap_or(0),
+            },
+            ports,
+            tasks,
         })
     }
 }
 
+/// The keys of `[package.metadata.rtic-scope]`.
+const KEYS: &[&str] = &[
+    "pac_name",
+    "pac_features",
+    "pac_version",
+    "interrupt_path",
+    "tpiu_freq",
+    "tpiu_baud",
+    "lts_prescaler",
+    "dwt_enter_id",
+    "dwt_exit_id",
+    "dwt_lock_id",
+    "dwt_spawn_id",
+    "dwt_args_id",
+    "ignore_exceptions",
+    "expect_malformed",
+    "decoder",
+    "trace_idle",
+    "fault_trace",
+    "build_id",
+    "sync_pulse",
+    "freq_report",
+    "name_policy",
+    "irq_entry_latency",
+    "irq_exit_latency",
+    "ports",
+    "tasks",
+    "frontends",
+];
+
+/// Where the value of a key of `[package.metadata.rtic-scope]` is read
+/// from, in order of precedence. The frontends configured by each
+/// layer are spawned, such that `frontends` is extended rather than
+/// overridden by the layers above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Provenance {
+    CommandLine,
+    Package,
+    Workspace,
+    /// The profile selected via `--profile`; see
+    /// [`Profile`](crate::config::Profile).
+    Profile,
+    /// The user configuration; see
+    /// [`UserConfig`](crate::config::UserConfig).
+    UserConfig,
+    /// The key is not set; its default is used, if any.
+    Default,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::CommandLine => "command line",
+            Self::Package => "[package.metadata.rtic-scope]",
+            Self::Workspace => "[workspace.metadata.rtic-scope]",
+            Self::Profile => "profile",
+            Self::UserConfig => "user configuration",
+            Self::Default => "default",
+        })
+    }
+}
+
+/// Where each of [`KEYS`] is read from, given the package and
+/// workspace tables, the keys overridden on the command line, the keys
+/// set by the profile selected via `--profile`, and where
+/// each frontend to spawn is configured. `frontends` is attributed to
+/// the highest layer that configures any.
+fn provenance_of(
+    package: Option<&serde_json::Value>,
+    workspace: Option<&serde_json::Value>,
+    overridden: &[&str],
+    profiled: &[&str],
+    frontends: &[(String, Provenance)],
+) -> Vec<(&'static str, Provenance)> {
+    let has = |table: Option<&serde_json::Value>, key: &str| {
+        table.and_then(|table| table.get(key)).is_some()
+    };
+
+    KEYS.iter()
+        .map(|key| {
+            let provenance = if *key == "frontends" {
+                frontends
+                    .iter()
+                    .map(|(_, provenance)| *provenance)
+                    .min()
+                    .unwrap_or(Provenance::Default)
+            } else if overridden.contains(key) {
+                Provenance::CommandLine
+            } else if has(package, key) {
+                Provenance::Package
+            } else if has(workspace, key) {
+                Provenance::Workspace
+            } else if profiled.contains(key) {
+                Provenance::Profile
+            } else {
+                Provenance::Default
+            };
+            (*key, provenance)
+        })
+        .collect()
+}
+
+/// The keys of `table` that are not [`KEYS`], each with the most
+/// similar key, if any is similar enough to be a likely misspelling.
+fn unknown_keys(table: &serde_json::Value) -> Vec<(String, Option<&'static str>)> {
+    table
+        .as_object()
+        .into_iter()
+        .flat_map(|table| table.keys())
+        .filter(|key| !KEYS.contains(&key.as_str()))
+        .map(|key| {
+            let nearest = KEYS
+                .iter()
+                .map(|known| (distance(key, known), *known))
+                .min()
+                .filter(|(distance, known)| distance * 5 <= 2 * key.len().max(known.len()))
+                .map(|(_, known)| known);
+            (key.to_owned(), nearest)
+        })
+        .collect()
+}
+
+/// Warns about the keys of the `[<section>.metadata.rtic-scope]`
+/// `table` that are not read, which are otherwise silently ignored.
+fn audit_keys(section: &str, table: &serde_json::Value) {
+    for (key, nearest) in unknown_keys(table) {
+        crate::log::warn(format!(
+            "unknown key `{}` in [{}.metadata.rtic-scope] is ignored{}",
+            key,
+            section,
+            nearest
+                .map(|known| format!("; did you mean `{}`?", known))
+                .unwrap_or_default()
+        ));
+    }
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Whether `color` is of the form `#rrggbb`.
+fn is_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Validates a `[package.metadata.rtic-scope]` table the same way it is
+/// read when tracing.
+pub fn validate(table: &serde_json::Value) -> Result<ManifestProperties, ManifestMetadataError> {
+    audit_keys("package", table);
+    serde_json::from_value::<ManifestPropertiesIntermediate>(table.to_owned())?.try_into()
+}
+
+/// The subset of `[{package,workspace}.metadata.rtic-scope]` that is
+/// read without building the RTIC application.
+#[derive(Deserialize, Debug, Default)]
+#[cfg_attr(test, derive(Serialize))]
+struct FrontendDefaults {
+    pub frontends: Option<Vec<String>>,
+}
+
+/// Frontends to forward trace data to by default, from `frontends` in
+/// `[package.metadata.rtic-scope]`, or else
+/// `[workspace.metadata.rtic-scope]`. Entries that are paths (i.e. that
+/// contain a `/`) are resolved against the workspace root.
+pub fn default_frontends(
+    metadata: &cargo_metadata::Metadata,
+) -> Result<(Vec<String>, Provenance), ManifestMetadataError> {
+    let package_meta = metadata
+        .root_package()
+        .and_then(|pkg| pkg.metadata.get("rtic-scope"));
+    let workspace_meta = metadata.workspace_metadata.get("rtic-scope");
+
+    let mut frontends = (None, Provenance::Default);
+    for (meta, provenance) in [
+        (package_meta, Provenance::Package),
+        (workspace_meta, Provenance::Workspace),
+    ] {
+        let defaults: FrontendDefaults = match meta {
+            Some(meta) => serde_json::from_value(meta.clone())?,
+            None => continue,
+        };
+        if defaults.frontends.is_some() {
+            frontends = (defaults.frontends, provenance);
+            break;
+        }
+    }
+
+    Ok((
+        frontends
+            .0
+            .unwrap_or_default()
+            .into_iter()
+            .map(|frontend| {
+                if frontend.contains('/') {
+                    metadata.workspace_root.join(frontend).to_string()
+                } else {
+                    frontend
+                }
+            })
+            .collect(),
+        frontends.1,
+    ))
+}
+
 impl ManifestProperties {
     pub fn new(
         cargo: &CargoWrapper,
         opts: Option<&ManifestOptions>,
     ) -> Result<Self, ManifestMetadataError> {
-        let package_meta = cargo.package().unwrap().metadata.get("rtic-scope");
-        let workspace_meta = cargo.metadata().workspace_metadata.get("rtic-scope");
+        Self::from_metadata(cargo.package().unwrap(), cargo.metadata(), opts)
+    }
+
+    /// Reads the properties of an application not written with RTIC.
+    /// Only the TPIU and timestamp properties are required.
+    pub fn bare(
+        cargo: &CargoWrapper,
+        opts: Option<&ManifestOptions>,
+    ) -> Result<Self, ManifestMetadataError> {
+        Self::intermediate(cargo.package().unwrap(), cargo.metadata(), opts)?.into_bare()
+    }
+
+    /// Properties of the application emulated by
+    /// [`SyntheticSource`](crate::sources::SyntheticSource), without a
+    /// PAC.
+    pub fn synthetic() -> Self {
+        use crate::sources::synthetic;
+
+        Self {
+            pac_name: String::new(),
+            pac_version: String::new(),
+            pac_features: vec![],
+            interrupt_path: String::new(),
+            tpiu_freq: synthetic::FREQ,
+            tpiu_baud: 0,
+            lts_prescaler: LocalTimestampOptions::Enabled,
+            dwt_enter_id: synthetic::ENTER_COMPARATOR.into(),
+            dwt_exit_id: synthetic::EXIT_COMPARATOR.into(),
+            dwt_lock_id: None,
+            dwt_spawn_id: None,
+            dwt_args_id: None,
+            ignore_exceptions: vec![],
+            expect_malformed: true,
+            decoder: DecoderKind::Itm,
+            trace_idle: false,
+            fault_trace: false,
+            build_id: false,
+            sync_pulse: false,
+            freq_report: false,
+            name_policy: NamePolicy::Full,
+            irq_latency: InterruptLatency::default(),
+            ports: IndexMap::new(),
+            tasks: IndexMap::new(),
+        }
+    }
+
+    /// Where each key read by [`ManifestProperties::new`] is read from,
+    /// with the overrides of `opts`, and where each of the `frontends`
+    /// to spawn is configured.
+    pub fn provenance(
+        cargo: &CargoWrapper,
+        opts: Option<&ManifestOptions>,
+        frontends: &[(String, Provenance)],
+    ) -> Vec<(&'static str, Provenance)> {
+        let (mut overridden, mut profiled) = (vec![], vec![]);
+        if let Some(opts) = opts {
+            macro_rules! overridden {
+                ($($f:ident),+) => {{
+                    $(
+                        if opts.$f.is_some() {
+                            overridden.push(stringify!($f));
+                        }
+                    )+
+                }}
+            }
+            overridden!(
+                pac_name,
+                pac_version,
+                pac_features,
+                interrupt_path,
+                tpiu_freq,
+                tpiu_baud,
+                ignore_exceptions,
+                decoder,
+                name_policy
+            );
+            if opts.profile.tpiu_freq.is_some() {
+                profiled.push("tpiu_freq");
+            }
+            if opts.profile.tpiu_baud.is_some() {
+                profiled.push("tpiu_baud");
+            }
+        }
+
+        provenance_of(
+            cargo
+                .package()
+                .ok()
+                .and_then(|pkg| pkg.metadata.get("rtic-scope")),
+            cargo.metadata().workspace_metadata.get("rtic-scope"),
+            &overridden,
+            &profiled,
+            frontends,
+        )
+    }
+
+    /// The resolved value of `key` of `[package.metadata.rtic-scope]`.
+    pub fn value(&self, key: &str) -> serde_json::Value {
+        let properties = serde_json::to_value(self).unwrap_or_default();
+        match key {
+            "irq_entry_latency" => properties["irq_latency"]["entry"].clone(),
+            "irq_exit_latency" => properties["irq_latency"]["exit"].clone(),
+            key => properties[key].clone(),
+        }
+    }
+
+    /// Whether a PAC is configured. Always the case for RTIC
+    /// applications; see [`ManifestProperties::bare`].
+    pub fn has_pac(&self) -> bool {
+        !self.pac_name.is_empty()
+    }
+
+    /// Reads the properties from the metadata of `package` and its
+    /// workspace, without building the package.
+    pub fn from_metadata(
+        package: &cargo_metadata::Package,
+        metadata: &cargo_metadata::Metadata,
+        opts: Option<&ManifestOptions>,
+    ) -> Result<Self, ManifestMetadataError> {
+        Self::intermediate(package, metadata, opts)?.try_into()
+    }
+
+    /// Reads the properties from the metadata of `package` and its
+    /// workspace, completed with the profile and with the overrides of
+    /// `opts` applied.
+    fn intermediate(
+        package: &cargo_metadata::Package,
+        metadata: &cargo_metadata::Metadata,
+        opts: Option<&ManifestOptions>,
+    ) -> Result<ManifestPropertiesIntermediate, ManifestMetadataError> {
+        let package_meta = package.metadata.get("rtic-scope");
+        let workspace_meta = metadata.workspace_metadata.get("rtic-scope");
 
         use serde_json::from_value;
 
+        if let Some(pkg) = package_meta {
+            audit_keys("package", pkg);
+        }
+        if let Some(wrk) = workspace_meta {
+            audit_keys("workspace", wrk);
+        }
+
         // Read from cargo manifest
         let mut int = match (package_meta, workspace_meta) {
             (Some(pkg), Some(wrk)) => {
@@ -156,6 +709,12 @@ impl ManifestProperties {
         };
 
         if let Some(opts) = opts {
+            int.complete_with(ManifestPropertiesIntermediate {
+                tpiu_freq: opts.profile.tpiu_freq,
+                tpiu_baud: opts.profile.tpiu_baud,
+                ..Default::default()
+            });
+
             macro_rules! maybe_override {
                 ($($f:ident),+) => {{
                     $(
@@ -171,10 +730,183 @@ impl ManifestProperties {
                 pac_features,
                 interrupt_path,
                 tpiu_freq,
-                tpiu_baud
+                tpiu_baud,
+                ignore_exceptions,
+                decoder,
+                name_policy
+            );
+        }
+
+        Ok(int)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure task colors other than `#rrggbb` are rejected.
+    #[test]
+    fn task_color() {
+        assert!(is_color("#ff00A0"));
+        for color in ["ff0000", "#ff000", "#ff00000", "#gg0000", "red"] {
+            assert!(!is_color(color));
+        }
+    }
+
+    /// Ensure the ports used by cortex-m-rtic-trace cannot be
+    /// configured, and that the ports of its optional features can be
+    /// unless the features are used.
+    #[test]
+    fn reserved_ports() {
+        let validate = |port: u8,
+                        features: bool|
+         -> Result<ManifestProperties, ManifestMetadataError> {
+            let ports = serde_json::json!({ port.to_string(): { "type": "u32", "name": "adc" } });
+            ManifestPropertiesIntermediate {
+                ports: Some(serde_json::from_value(ports).unwrap()),
+                fault_trace: Some(features),
+                build_id: Some(features),
+                sync_pulse: Some(features),
+                freq_report: Some(features),
+                ..Default::default()
+            }
+            .try_into()
+        };
+
+        for port in 27..=30 {
+            assert!(matches!(
+                validate(port, false),
+                Err(ManifestMetadataError::MissingName)
+            ));
+        }
+        for port in 27..=32 {
+            assert!(matches!(
+                validate(port, true),
+                Err(ManifestMetadataError::InvalidStimulusPort(p)) if p == port
+            ));
+        }
+        for port in 31..=32 {
+            assert!(matches!(
+                validate(port, false),
+                Err(ManifestMetadataError::InvalidStimulusPort(p)) if p == port
+            ));
+        }
+    }
+
+    /// Ensure bitfield ports without fields are rejected.
+    #[test]
+    fn empty_bitfield_port() {
+        let validate =
+            |fields: serde_json::Value| -> Result<ManifestProperties, ManifestMetadataError> {
+                let ports = serde_json::json!({ "3": { "type": "bitfield", "name": "flags", "fields": fields } });
+                ManifestPropertiesIntermediate {
+                    ports: Some(serde_json::from_value(ports).unwrap()),
+                    ..Default::default()
+                }
+                .try_into()
+            };
+
+        assert!(matches!(
+            validate(serde_json::json!({})),
+            Err(ManifestMetadataError::EmptyBitfieldPort(3))
+        ));
+        assert!(matches!(
+            validate(serde_json::json!({ "mode": [0, 4] })),
+            Err(ManifestMetadataError::MissingName)
+        ));
+    }
+
+    /// Ensure binary ports of empty frames are rejected.
+    #[test]
+    fn empty_frame_size() {
+        let validate = |size: usize| -> Result<ManifestProperties, ManifestMetadataError> {
+            let ports =
+                serde_json::json!({ "3": { "type": "binary", "name": "imu", "size": size } });
+            ManifestPropertiesIntermediate {
+                ports: Some(serde_json::from_value(ports).unwrap()),
+                ..Default::default()
+            }
+            .try_into()
+        };
+
+        assert!(matches!(
+            validate(0),
+            Err(ManifestMetadataError::EmptyFrameSize(3))
+        ));
+        assert!(matches!(
+            validate(12),
+            Err(ManifestMetadataError::MissingName)
+        ));
+    }
+
+    /// Ensure command-line overrides take precedence over the package
+    /// table, which takes precedence over the workspace table, which
+    /// takes precedence over the profile, and that
+    /// frontends are attributed to the highest layer configuring any.
+    #[test]
+    fn provenance() {
+        let package = serde_json::json!({ "tpiu_freq": 16000000, "pac_name": "stm32f4" });
+        let workspace = serde_json::json!({ "tpiu_freq": 8000000, "tpiu_baud": 2000000 });
+        let frontends = [
+            ("dummy".to_string(), Provenance::UserConfig),
+            ("probe".to_string(), Provenance::Profile),
+        ];
+        let provenance = provenance_of(
+            Some(&package),
+            Some(&workspace),
+            &["pac_name"],
+            &["tpiu_freq", "tpiu_baud", "dwt_enter_id"],
+            &frontends,
+        );
+        let of = |key| provenance.iter().find(|(k, _)| *k == key).unwrap().1;
+
+        assert_eq!(of("pac_name"), Provenance::CommandLine);
+        assert_eq!(of("tpiu_freq"), Provenance::Package);
+        assert_eq!(of("tpiu_baud"), Provenance::Workspace);
+        assert_eq!(of("dwt_enter_id"), Provenance::Profile);
+        assert_eq!(of("lts_prescaler"), Provenance::Default);
+        assert_eq!(of("frontends"), Provenance::Profile);
+        assert_eq!(provenance.len(), KEYS.len());
+    }
+
+    /// Ensure all known keys are read, that all keys read are known,
+    /// and that misspelled keys are matched with the key they are
+    /// likely meant to be.
+    #[test]
+    fn unknown_keys() {
+        for key in KEYS.iter().filter(|key| **key != "frontends") {
+            let table = serde_json::json!({ *key: { "invalid": true } });
+            assert!(
+                serde_json::from_value::<ManifestPropertiesIntermediate>(table).is_err(),
+                "{} is not read",
+                key
             );
         }
+        for table in [
+            serde_json::to_value(ManifestPropertiesIntermediate::default()).unwrap(),
+            serde_json::to_value(FrontendDefaults::default()).unwrap(),
+        ] {
+            for key in table.as_object().unwrap().keys() {
+                assert!(KEYS.contains(&key.as_str()), "{} is not known", key);
+            }
+        }
 
-        int.try_into()
+        let table = serde_json::json!({
+            "tpiu_freq": 16000000,
+            "tpiu_frequency": 16000000,
+            "dwt_enter": 1,
+            "colour": "red",
+        });
+        let mut unknown = super::unknown_keys(&table);
+        unknown.sort();
+        assert_eq!(
+            unknown,
+            [
+                ("colour".to_string(), None),
+                ("dwt_enter".to_string(), Some("dwt_enter_id")),
+                ("tpiu_frequency".to_string(), Some("tpiu_freq")),
+            ]
+        );
     }
 }
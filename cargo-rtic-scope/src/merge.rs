@@ -0,0 +1,208 @@
+//! Merging of trace files, e.g. the segments of a capture, into a
+//! single trace file for archiving, as done by `cargo rtic-scope
+//! merge`.
+//!
+//! The traces must be compatible (see
+//! [`TraceMetadata::incompatibility`]). The timestamps of each trace
+//! are offsets from the target reset it followed, and are re-based onto
+//! the earliest reset of the traces by the difference of the reset
+//! timestamps sampled on the host, which is approximate. The trace data
+//! of all traces is then interleaved in time order.
+use crate::recovery::{TraceMetadata, TraceProvenance, TraceTrailer};
+use crate::sinks::{FileSink, Sink};
+use crate::sources::FileSource;
+use crate::timestamps::{self, offset, shift_timestamp};
+use crate::{MergeOptions, RTICScopeError, TraceData};
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MergeError {
+    #[error("Cannot merge {} into {}: {2}", .1.display(), .0.display())]
+    Incompatible(PathBuf, PathBuf, String),
+}
+
+impl crate::diag::DiagnosableError for MergeError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::Incompatible(_, _, _) => vec![
+                "Only traces of the same build of an application, traced from the same core, can be merged.".to_string(),
+                "Traces of the cores of a multi-core target are analyzed together via `--merge` of stats and export instead.".to_string(),
+            ],
+        }
+    }
+}
+
+/// A trace being merged.
+struct Segment {
+    src: FileSource,
+    /// Offset of the reset of the trace from the earliest reset.
+    shift: Duration,
+    /// The next trace data of the trace, if read ahead.
+    next: Option<TraceData>,
+}
+
+impl Segment {
+    /// Reads the next trace data, unless already read ahead, and
+    /// returns its re-based offset from target reset.
+    fn peek(&mut self) -> Result<Option<Duration>, RTICScopeError> {
+        if self.next.is_none() {
+            if let Some(data) = self.src.next() {
+                let mut data = data?;
+                shift_timestamp(&mut data.timestamp, (self.shift, true));
+                self.next = Some(data);
+            }
+        }
+
+        Ok(self.next.as_ref().map(|data| offset(&data.timestamp)))
+    }
+}
+
+pub fn run(opts: &MergeOptions) -> Result<(), RTICScopeError> {
+    let mut metadata: Vec<TraceMetadata> = vec![];
+    let mut srcs = vec![];
+    for path in opts.files.iter() {
+        let src = FileSource::new(fs::OpenOptions::new().read(true).open(path)?)?;
+        let meta = src.metadata();
+        if let Some(reason) = metadata
+            .first()
+            .and_then(|first| first.incompatibility(&meta))
+        {
+            return Err(
+                MergeError::Incompatible(opts.files[0].clone(), path.clone(), reason).into(),
+            );
+        }
+        metadata.push(meta);
+        srcs.push(src);
+    }
+
+    // NOTE(unwrap) at least two traces are required
+    let anchor = metadata.iter().map(|m| m.reset_timestamp()).min().unwrap();
+    let shifts: Vec<Duration> = metadata
+        .iter()
+        .map(|m| timestamps::elapsed(anchor, m.reset_timestamp()))
+        .collect();
+    if metadata.iter().any(|m| m.drift().is_some()) {
+        crate::log::warn(
+            "drift estimates do not apply to the merged trace and are dropped".to_string(),
+        );
+    }
+
+    let mut merged = metadata[0].clone();
+    merged.set_reset_timestamp(anchor);
+    let mut comments: Vec<String> = vec![];
+    for comment in metadata.iter().filter_map(|m| m.comment.clone()) {
+        if !comments.contains(&comment) {
+            comments.push(comment);
+        }
+    }
+    merged.comment = (!comments.is_empty()).then(|| comments.join("; "));
+    merged.set_merged_from(
+        opts.files
+            .iter()
+            .zip(metadata.iter())
+            .map(|(path, m)| TraceProvenance {
+                file: path.display().to_string(),
+                reset_timestamp: m.reset_timestamp(),
+                comment: m.comment.clone(),
+            })
+            .collect(),
+    );
+
+    // Only the trace that was reset last can have ended with a crash,
+    // and the sync anchor of the trace that was reset first is kept.
+    let last = (0..metadata.len()).max_by_key(|i| shifts[*i]).unwrap();
+    let first = (0..metadata.len()).min_by_key(|i| shifts[*i]).unwrap();
+    let mut gaps: Vec<(Duration, Duration)> = srcs
+        .iter()
+        .zip(shifts.iter())
+        .flat_map(|(src, shift)| src.gaps().iter().map(move |(at, gap)| (*at + *shift, *gap)))
+        .collect();
+    gaps.sort_by_key(|(at, _)| *at);
+    gaps.truncate(crate::recovery::MAX_GAPS);
+    let trailer = TraceTrailer {
+        drift: None,
+        crash: metadata[last].crash().map(String::from),
+        sync_anchor: metadata[first].sync_anchor(),
+        comparators: vec![],
+        gaps,
+    };
+
+    let mut segments: Vec<Segment> = srcs
+        .into_iter()
+        .zip(shifts)
+        .map(|(src, shift)| Segment {
+            src,
+            shift,
+            next: None,
+        })
+        .collect();
+    let mut sink = FileSink::create(opts.output.clone())?;
+    sink.drain_metadata(&merged)?;
+    let mut chunks = 0;
+    loop {
+        let mut earliest: Option<(usize, Duration)> = None;
+        for (i, segment) in segments.iter_mut().enumerate() {
+            if let Some(at) = segment.peek()? {
+                if earliest.map_or(true, |(_, earliest)| at < earliest) {
+                    earliest = Some((i, at));
+                }
+            }
+        }
+        let (i, _) = match earliest {
+            Some(earliest) => earliest,
+            None => break,
+        };
+
+        // NOTE(unwrap) peeked above
+        sink.drain_raw(&segments[i].next.take().unwrap())?;
+        chunks += 1;
+    }
+    sink.drain_trailer(&trailer)?;
+
+    crate::log::status(
+        "Merged",
+        format!(
+            "{} traces ({} chunks) into {}",
+            opts.files.len(),
+            chunks,
+            opts.output.display()
+        ),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::manifest::InterruptLatency;
+    use crate::recovery::{TraceLookupMaps, TraceMetadata};
+
+    /// Ensure only traces of the same application, core, and timestamp
+    /// clock are compatible.
+    #[test]
+    fn compatibility() {
+        let metadata = |program: &str, freq| {
+            TraceMetadata::from(
+                program.to_string(),
+                TraceLookupMaps::synthetic(),
+                chrono::Local::now(),
+                freq,
+                InterruptLatency::default(),
+                None,
+            )
+        };
+
+        let a = metadata("app", 16_000_000);
+        assert!(a.incompatibility(&metadata("app", 16_000_000)).is_none());
+        assert!(a.incompatibility(&metadata("other", 16_000_000)).is_some());
+        assert!(a.incompatibility(&metadata("app", 8_000_000)).is_some());
+        let mut b = metadata("app", 16_000_000);
+        b.set_core(Some(1));
+        assert!(a.incompatibility(&b).is_some());
+    }
+}
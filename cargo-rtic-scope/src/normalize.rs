@@ -0,0 +1,167 @@
+//! Normalization of task actions into an execution interval-oriented
+//! event stream.
+use rtic_scope_api as api;
+
+/// Rewrites the task actions of [`api::EventChunk`]s so that each
+/// execution of a task is described by exactly one
+/// [`api::TaskAction::Entered`] followed by exactly one
+/// [`api::TaskAction::Exited`]:
+///
+/// - A [`api::TaskAction::Returned`] immediately followed by an
+///   [`api::TaskAction::Exited`] of the same task is merged into the
+///   latter.
+/// - A [`api::TaskAction::Returned`] to a task that is executing (i.e.
+///   that was preempted) is dropped: the interval of the task spans
+///   any preemptions.
+/// - A [`api::TaskAction::Returned`] to a task that is not known to be
+///   executing (e.g. because it was entered before the trace started or
+///   during an overflow) is mapped to an [`api::TaskAction::Entered`].
+/// - An [`api::TaskAction::Exited`] of a task that is not known to be
+///   executing is dropped.
+/// - On an [`api::EventType::Overflow`], an estimated
+///   [`api::TaskAction::Exited`] is synthesized for each executing task,
+///   as the state of the target is unknown afterwards.
+#[derive(Default)]
+pub struct ActionNormalizer {
+    /// Tasks that are executing, in the order they were entered.
+    executing: Vec<String>,
+}
+
+impl ActionNormalizer {
+    pub fn normalize(&mut self, chunk: &mut api::EventChunk) {
+        let mut events = Vec::with_capacity(chunk.events.len());
+        let mut iter = std::mem::take(&mut chunk.events).into_iter().peekable();
+        while let Some(event) = iter.next() {
+            match event {
+                api::EventType::Task {
+                    name,
+                    action,
+                    calibrated_offset,
+                    args,
+                    estimated,
+                } => match action {
+                    api::TaskAction::Entered => {
+                        self.executing.push(name.clone());
+                        events.push(api::EventType::Task {
+                            name,
+                            action,
+                            calibrated_offset,
+                            args,
+                            estimated,
+                        });
+                    }
+                    api::TaskAction::Exited => {
+                        if self.exit(&name) {
+                            events.push(api::EventType::Task {
+                                name,
+                                action,
+                                calibrated_offset,
+                                args,
+                                estimated,
+                            });
+                        }
+                    }
+                    api::TaskAction::Returned => {
+                        let exits_next = matches!(
+                            iter.peek(),
+                            Some(api::EventType::Task { name: next, action: api::TaskAction::Exited, .. })
+                                if *next == name
+                        );
+                        if exits_next || self.executing.contains(&name) {
+                            continue;
+                        }
+
+                        self.executing.push(name.clone());
+                        events.push(api::EventType::Task {
+                            name,
+                            action: api::TaskAction::Entered,
+                            calibrated_offset,
+                            args,
+                            estimated,
+                        });
+                    }
+                },
+                api::EventType::Overflow => {
+                    events.extend(self.executing.drain(..).rev().map(|name| {
+                        api::EventType::Task {
+                            name,
+                            action: api::TaskAction::Exited,
+                            calibrated_offset: None,
+                            args: None,
+                            estimated: true,
+                        }
+                    }));
+                    events.push(api::EventType::Overflow);
+                }
+                event => events.push(event),
+            }
+        }
+
+        chunk.events = events;
+    }
+
+    /// Marks the most recently entered execution of the task as exited.
+    /// Returns `false` if the task is not known to be executing.
+    fn exit(&mut self, name: &str) -> bool {
+        match self.executing.iter().rposition(|n| n == name) {
+            Some(pos) => {
+                self.executing.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, task};
+    use api::TaskAction::*;
+
+    fn actions(chunk: &api::EventChunk) -> Vec<String> {
+        chunk
+            .events
+            .iter()
+            .map(|event| match event {
+                api::EventType::Task { name, action, .. } => format!("{}:{:?}", name, action),
+                event => format!("{:?}", event),
+            })
+            .collect()
+    }
+
+    /// Ensure a preempted task is described by a single interval and
+    /// that executing tasks are exited on overflow.
+    #[test]
+    fn normalize() {
+        let mut chunk = chunk(
+            std::time::Duration::ZERO,
+            vec![
+                task("a", Entered),
+                task("b", Entered),
+                task("b", Exited),
+                task("a", Returned),
+                task("c", Returned),
+                task("c", Exited),
+                task("d", Exited),
+                task("a", Exited),
+                task("e", Returned),
+                api::EventType::Overflow,
+            ],
+        );
+        ActionNormalizer::default().normalize(&mut chunk);
+
+        assert_eq!(
+            actions(&chunk),
+            [
+                "a:Entered",
+                "b:Entered",
+                "b:Exited",
+                "a:Exited",
+                "e:Entered",
+                "e:Exited",
+                "Overflow",
+            ]
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Obfuscation of the task and resource names in trace files, for when
+//! traces are shared with parties that should not learn the structure
+//! of the application.
+//!
+//! Names are replaced with pseudonyms (e.g. `task-3`) before the trace
+//! metadata is written. The mapping from names to pseudonyms is stored
+//! in a separate file, which is reused and extended by subsequent
+//! traces so that pseudonyms are stable. The same file restores the
+//! names on replay.
+use crate::recovery::{NameKind, TraceMetadata};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Pseudonyms {
+    /// Pseudonym of each name, in order of assignment.
+    names: IndexMap<String, String>,
+}
+
+impl Pseudonyms {
+    /// Reads the mapping from `path`. A missing file yields an empty
+    /// mapping.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(json) => Ok(serde_json::from_slice(&json)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn store(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    /// The pseudonym of `name`, assigning a new one if necessary.
+    fn pseudonym(&mut self, kind: NameKind, name: &str) -> String {
+        if let Some(pseudonym) = self.names.get(name) {
+            return pseudonym.to_owned();
+        }
+
+        let prefix = match kind {
+            NameKind::Task => "task",
+            NameKind::Resource => "resource",
+        };
+        let n = self
+            .names
+            .values()
+            .filter(|p| p.starts_with(prefix))
+            .count();
+        let pseudonym = format!("{}-{}", prefix, n);
+        self.names.insert(name.to_owned(), pseudonym.clone());
+        pseudonym
+    }
+
+    /// Replaces all task and resource names of `metadata` with their
+    /// pseudonyms. The command line the trace was recorded by is
+    /// dropped.
+    pub fn obfuscate(&mut self, metadata: &mut TraceMetadata) {
+        metadata.rename(|kind, name| self.pseudonym(kind, name));
+        metadata.redact_invocation();
+    }
+
+    /// Restores the task and resource names of `metadata`. Unknown
+    /// pseudonyms are kept as-is.
+    pub fn deobfuscate(&self, metadata: &mut TraceMetadata) {
+        metadata.rename(|_, pseudonym| {
+            self.names
+                .iter()
+                .find(|(_, p)| *p == pseudonym)
+                .map_or_else(|| pseudonym.to_owned(), |(name, _)| name.to_owned())
+        });
+    }
+}
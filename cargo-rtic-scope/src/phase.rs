@@ -0,0 +1,133 @@
+//! Host-side recovery of the `#[init]` phase of an RTIC application
+//! from exception trace. `#[init]` runs from target reset with
+//! interrupts disabled, so it has returned when the first exception
+//! trace packet is emitted, be it the entry of a task or the return to
+//! thread mode (i.e. `#[idle]`).
+use crate::timestamps::offset;
+use crate::TraceData;
+
+use std::time::Duration;
+
+use itm::TracePacket;
+use rtic_scope_api as api;
+
+/// Name of the `#[init]` phase in [`api::EventType::Phase`].
+const INIT: &str = "init";
+
+#[derive(Default)]
+pub struct InitPhase {
+    /// Whether the first chunk of the trace has been seen.
+    started: bool,
+    /// Whether the trace started within `#[init]`.
+    within: bool,
+    /// Offset from target reset at which `#[init]` returned, if known.
+    duration: Option<Duration>,
+}
+
+impl InitPhase {
+    /// Accounts the exception trace in `data`, adding the
+    /// [`api::EventType::Phase`] events of `#[init]` to `chunk`: its
+    /// entry to the first chunk of the trace, and its exit to the chunk
+    /// of the first exception trace packet. Neither is added if the
+    /// trace starts after `#[init]` returned.
+    pub fn update(&mut self, data: &TraceData, chunk: &mut api::EventChunk) {
+        let first = !self.started;
+        self.started = true;
+        if !first && !self.within {
+            return;
+        }
+
+        let exited = data
+            .packets
+            .iter()
+            .any(|packet| matches!(packet, TracePacket::ExceptionTrace { .. }));
+        if first && exited {
+            // Whether #[init] ended here or before cannot be told.
+            return;
+        }
+        if first {
+            self.within = true;
+            chunk.events.insert(
+                0,
+                api::EventType::Phase {
+                    name: INIT.to_string(),
+                    action: api::TaskAction::Entered,
+                },
+            );
+        } else if exited {
+            self.within = false;
+            self.duration = Some(offset(&data.timestamp));
+            chunk.events.insert(
+                0,
+                api::EventType::Phase {
+                    name: INIT.to_string(),
+                    action: api::TaskAction::Exited,
+                },
+            );
+        }
+    }
+
+    /// How long `#[init]` ran after target reset, if known.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::chunk_at;
+    use itm::{ExceptionAction, Timestamp, VectActive};
+
+    fn data(ms: u64, packets: Vec<TracePacket>) -> TraceData {
+        TraceData {
+            timestamp: Timestamp::Sync(Duration::from_millis(ms)),
+            consumed_packets: packets.len(),
+            packets,
+            malformed_packets: vec![],
+        }
+    }
+
+    fn phases(init: &mut InitPhase, data: &TraceData) -> Vec<String> {
+        let mut chunk = chunk_at(data.timestamp.clone(), vec![]);
+        init.update(data, &mut chunk);
+        chunk
+            .events
+            .iter()
+            .map(|event| format!("{:?}", event))
+            .collect()
+    }
+
+    /// Ensure #[init] is bounded by the first chunk of the trace and
+    /// the first exception trace packet, and only if the trace starts
+    /// within it.
+    #[test]
+    fn init() {
+        let port = TracePacket::Instrumentation {
+            port: 30,
+            payload: vec![0; 4],
+        };
+        let exception = TracePacket::ExceptionTrace {
+            exception: VectActive::ThreadMode,
+            action: ExceptionAction::Returned,
+        };
+
+        let mut init = InitPhase::default();
+        assert_eq!(
+            phases(&mut init, &data(0, vec![port.clone()])),
+            vec![r#"Phase { name: "init", action: Entered }"#]
+        );
+        assert!(phases(&mut init, &data(1, vec![port])).is_empty());
+        assert_eq!(
+            phases(&mut init, &data(3, vec![exception.clone()])),
+            vec![r#"Phase { name: "init", action: Exited }"#]
+        );
+        assert!(phases(&mut init, &data(4, vec![exception.clone()])).is_empty());
+        assert_eq!(init.duration(), Some(Duration::from_millis(3)));
+
+        let mut init = InitPhase::default();
+        assert!(phases(&mut init, &data(5, vec![exception.clone()])).is_empty());
+        assert!(phases(&mut init, &data(6, vec![exception])).is_empty());
+        assert_eq!(init.duration(), None);
+    }
+}
@@ -0,0 +1,222 @@
+//! The plan of a trace session, as printed by `trace --plan` instead
+//! of running it: the resolved manifest properties and where each was
+//! read from, how the target is reached, flashed, and reset, where the
+//! trace is written to, which frontends are spawned, and what was
+//! recovered from the application. Nothing is done to the target.
+use crate::build::CargoWrapper;
+use crate::manifest::{ManifestProperties, Provenance};
+use crate::recovery::TraceMetadata;
+use crate::sinks::FileSink;
+use crate::{RTICScopeError, TraceOptions};
+
+use std::io::{self, Write};
+
+use cargo_metadata::Artifact;
+
+/// Prints the plan of the session that `opts` describe. `frontends`
+/// are the frontends that would be spawned, each with where it is
+/// configured.
+pub fn print(
+    opts: &TraceOptions,
+    cargo: &CargoWrapper,
+    artifact: &Artifact,
+    manip: &ManifestProperties,
+    metadata: &TraceMetadata,
+    frontends: &[(String, Provenance)],
+) -> Result<(), RTICScopeError> {
+    write_properties(
+        &mut io::stdout(),
+        manip,
+        &ManifestProperties::provenance(cargo, Some(&opts.pac), frontends),
+        frontends,
+    )?;
+
+    println!("Trace source:");
+    let po = &opts.flash_options.probe_options;
+    if let Some(dev) = &opts.serial {
+        println!("  serial device {} at {} baud", dev, manip.tpiu_baud);
+    } else if let Some(swo) = &opts.jlink_swo {
+        println!(
+            "  J-Link SWO at {} (GDB server at {})",
+            swo,
+            opts.jlink_gdb
+                .as_deref()
+                .unwrap_or("port 2331 of the same host")
+        );
+    } else {
+        match (&po.probe_selector, &opts.chip_filter) {
+            (Some(probe), _) => println!(
+                "  probe {:04x}:{:04x}{}",
+                probe.vendor_id,
+                probe.product_id,
+                probe
+                    .serial_number
+                    .as_ref()
+                    .map(|sn| format!(":{}", sn))
+                    .unwrap_or_default()
+            ),
+            // NOTE probes are not attached to when only planning
+            (None, Some(filter)) => println!("  probe whose target matches {:?}", filter),
+            (None, None) => println!("  the only connected probe"),
+        }
+        println!(
+            "  chip {}",
+            po.chip.as_deref().unwrap_or("detected by the probe")
+        );
+    }
+    if let Some(core) = opts.core {
+        println!("  core {}", core);
+    }
+    if let Some(aux) = &opts.aux_csv {
+        println!("  auxiliary samples from {}", aux.display());
+    }
+
+    println!("Target:");
+    let elf = artifact
+        .executable
+        .as_ref()
+        .map(|elf| elf.to_string())
+        .unwrap_or_else(|| "no executable".to_string());
+    if opts.dont_touch_target {
+        println!("  not flashed, configured, or reset (--dont-touch-target)");
+    } else if opts.jlink_swo.is_some() {
+        println!("  not flashed: flash {} via the J-Link tools", elf);
+    } else {
+        println!(
+            "  flashed with {}{}",
+            elf,
+            if opts.verify { ", then verified" } else { "" }
+        );
+    }
+    if !opts.dont_touch_target {
+        println!(
+            "  reset via {:?}{}",
+            opts.reset_method,
+            if opts.flash_options.reset_halt {
+                ", halted at the reset vector"
+            } else {
+                ""
+            }
+        );
+    }
+
+    println!("Trace file:");
+    let trace_dir = opts
+        .trace_dir
+        .clone()
+        .unwrap_or_else(|| cargo.target_dir().join("rtic-traces"));
+    let trace_file = FileSink::trace_file_path(artifact, &trace_dir)?;
+    println!("  {}", trace_file.display());
+    if let Some(filter) = &opts.file_filter {
+        println!("  only the trace data of chunks with {}", filter);
+    }
+    if let Some(url) = &opts.upload {
+        println!(
+            "  uploaded to {} in segments of {} bytes{}",
+            url,
+            opts.upload_segment,
+            match &opts.upload_sigv4 {
+                Some(sigv4) => format!(", signed with {}", sigv4),
+                None => "".to_string(),
+            }
+        );
+    }
+    if opts.remove_prev_traces {
+        println!("  previous traces in {} are removed", trace_dir.display());
+    }
+    if !opts.no_log_file {
+        println!(
+            "  session log {}",
+            opts.log_file
+                .clone()
+                .unwrap_or_else(|| trace_file.with_extension("log"))
+                .display()
+        );
+    }
+
+    println!("Frontends:");
+    for (frontend, provenance) in frontends {
+        println!("  {} ({})", frontend, provenance);
+    }
+
+    println!("Recovered:");
+    println!(
+        "  {} hardware task(s), {} software task(s) of {}",
+        metadata.hardware_tasks_len(),
+        metadata.software_tasks_len(),
+        metadata.program_name
+    );
+    for (task, priority) in metadata.priorities() {
+        println!("  {} (priority {})", task, priority);
+    }
+    for dispatcher in metadata.dispatchers() {
+        println!(
+            "  dispatcher {} (priority {}): {}",
+            dispatcher.interrupt,
+            dispatcher.priority,
+            dispatcher.tasks.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Writes the resolved manifest properties to `out`, each with where it
+/// is read from. The value of `frontends` is the `frontends` to spawn.
+fn write_properties(
+    out: &mut impl Write,
+    manip: &ManifestProperties,
+    provenance: &[(&str, Provenance)],
+    frontends: &[(String, Provenance)],
+) -> io::Result<()> {
+    writeln!(out, "Manifest properties:")?;
+    for (key, provenance) in provenance {
+        let value = match *key {
+            "frontends" => serde_json::json!(frontends
+                .iter()
+                .map(|(frontend, _)| frontend)
+                .collect::<Vec<_>>()),
+            key => manip.value(key),
+        };
+        writeln!(out, "  {} = {} ({})", key, value, provenance)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure each property is printed with its resolved value and
+    /// where it is read from, frontends included.
+    #[test]
+    fn properties() {
+        let manip = ManifestProperties::synthetic();
+        let provenance = [
+            ("tpiu_freq", Provenance::Package),
+            ("tpiu_baud", Provenance::CommandLine),
+            ("frontends", Provenance::UserConfig),
+            ("irq_entry_latency", Provenance::Default),
+        ];
+        let frontends = [
+            ("dummy".to_string(), Provenance::UserConfig),
+            ("probe".to_string(), Provenance::UserConfig),
+        ];
+        let mut out = vec![];
+        write_properties(&mut out, &manip, &provenance, &frontends).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "Manifest properties:
+  tpiu_freq = {} ([package.metadata.rtic-scope])
+  tpiu_baud = 0 (command line)
+  frontends = [\"dummy\",\"probe\"] (user configuration)
+  irq_entry_latency = 0 (default)
+",
+                crate::sources::synthetic::FREQ
+            )
+        );
+    }
+}
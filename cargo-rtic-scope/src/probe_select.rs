@@ -0,0 +1,138 @@
+//! Selection of a debug probe by the chip of its attached target, for
+//! when several probes are connected.
+use crate::diag;
+use crate::log;
+
+use probe_rs::{config::TargetSelector, DebugProbeInfo, DebugProbeSelector, Probe};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ProbeSelectError {
+    #[error("No debug probes found")]
+    NoProbes,
+    #[error("No attached target matches chip filter {0:?}")]
+    NoMatch(String),
+    #[error("Several attached targets match chip filter {0:?}: {}", .1.join(", "))]
+    Ambiguous(String, Vec<String>),
+}
+
+impl diag::DiagnosableError for ProbeSelectError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            Self::NoMatch(_) => vec![
+                "Chips are identified by their probe-rs target names (e.g. STM32F401RETx). Probes whose target could not be identified are skipped.".to_string(),
+            ],
+            Self::Ambiguous(_, _) => vec![
+                "Narrow down --chip-filter, or select a probe via --probe <VID:PID[:serial]>.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
+
+/// Whether `name` matches the case-insensitive glob `pattern`, where
+/// `*` matches any sequence of characters and `?` any single
+/// character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some('*'), _) => {
+                matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..]))
+            }
+            (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+    matches(&pattern, &name)
+}
+
+/// Briefly attaches to the target of the probe to identify its chip.
+fn identify(info: &DebugProbeInfo) -> Result<String, probe_rs::Error> {
+    let session = info.open()?.attach(TargetSelector::Auto)?;
+    Ok(session.target().name.clone())
+}
+
+/// Attaches to the target of each connected probe and selects the
+/// single probe whose target chip matches `filter`. Returns the
+/// selector of that probe along with the name of the chip.
+pub fn select_by_chip(filter: &str) -> Result<(DebugProbeSelector, String), ProbeSelectError> {
+    let probes = Probe::list_all();
+    if probes.is_empty() {
+        return Err(ProbeSelectError::NoProbes);
+    }
+
+    let mut candidates = vec![];
+    for info in probes.iter() {
+        let desc = format!(
+            "{} ({:04x}:{:04x}:{})",
+            info.identifier,
+            info.vendor_id,
+            info.product_id,
+            info.serial_number.as_deref().unwrap_or("?")
+        );
+        let chip = match identify(info) {
+            Ok(chip) => chip,
+            Err(e) => {
+                log::warn(format!(
+                    "skipping probe {}: failed to identify target: {}",
+                    desc, e
+                ));
+                continue;
+            }
+        };
+
+        let matched = glob_match(filter, &chip);
+        log::status(
+            "Probed",
+            format!(
+                "{}: target {}{}",
+                desc,
+                chip,
+                if matched { "" } else { " (filtered out)" }
+            ),
+        );
+        if matched {
+            candidates.push((info, desc, chip));
+        }
+    }
+
+    match candidates.len() {
+        0 => Err(ProbeSelectError::NoMatch(filter.to_string())),
+        1 => {
+            let (info, desc, chip) = candidates.pop().unwrap();
+            log::status("Selected", format!("probe {} attached to {}", desc, chip));
+            Ok((
+                DebugProbeSelector {
+                    vendor_id: info.vendor_id,
+                    product_id: info.product_id,
+                    serial_number: info.serial_number.clone(),
+                },
+                chip,
+            ))
+        }
+        _ => Err(ProbeSelectError::Ambiguous(
+            filter.to_string(),
+            candidates.into_iter().map(|(_, desc, _)| desc).collect(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure chip names are matched case-insensitively.
+    #[test]
+    fn glob() {
+        assert!(glob_match("stm32f4*", "STM32F401RETx"));
+        assert!(glob_match("STM32F401??Tx", "STM32F401RETx"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("stm32f4*", "nRF52840_xxAA"));
+        assert!(!glob_match("stm32f401", "STM32F401RETx"));
+    }
+}
@@ -0,0 +1,136 @@
+//! Progress of the stages that precede tracing (building the
+//! application, recovering its metadata, flashing the target, setting
+//! up the trace source, and waiting for the target to synchronize), as
+//! reported to a [`ProgressObserver`]. The observer is given to the
+//! session setup: the command line reports progress via [`StatusLine`];
+//! a program that drives a session instead reports it its own way, e.g.
+//! as a progress bar.
+use crate::log;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// A stage of setting up a trace session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Building the application.
+    Build,
+    /// Recovering the metadata of the application from its sources, in
+    /// steps.
+    Recovery,
+    /// Erasing the flash of the target, in bytes.
+    Erase,
+    /// Programming the flash of the target, in bytes.
+    Program,
+    /// Reading back the flashed firmware, in bytes. See `--verify`.
+    Verify,
+    /// Opening and configuring the trace source.
+    SourceSetup,
+    /// Resetting the target, after which trace data is expected.
+    Reset,
+    /// Waiting for the trace stream of the target to synchronize, until
+    /// the first trace data is decoded.
+    Sync,
+}
+
+/// Observes the progress of the stages of setting up a session. A
+/// stage that is started is always finished unless setup fails; stages
+/// may be skipped, e.g. when the target is not flashed. [`Stage::Sync`]
+/// is also finished if the session ends before any trace data is
+/// decoded.
+pub trait ProgressObserver {
+    /// `stage` started. `total` is the amount of work of the stage, if
+    /// known in advance.
+    fn started(&mut self, _stage: Stage, _total: Option<u64>) {}
+
+    /// `done` more work of `stage` is done.
+    fn advanced(&mut self, _stage: Stage, _done: u64) {}
+
+    fn finished(&mut self, _stage: Stage) {}
+}
+
+/// An observer shared between the stages, and the progress callbacks
+/// of the libraries they use.
+pub type Observer = Rc<RefCell<dyn ProgressObserver>>;
+
+/// Reports the progress of recovery, flashing, and waiting for the
+/// target to synchronize on the status line, and how long each phase of
+/// flashing took once it is done. Other stages report their progress on
+/// the status line themselves.
+#[derive(Default)]
+pub struct StatusLine {
+    /// Work done and in total of the current stage.
+    work: (u64, u64),
+    started: Option<Instant>,
+}
+
+impl StatusLine {
+    /// Headers of the status line while `stage` is in progress and once
+    /// it is done, if reported.
+    fn headers(stage: Stage) -> Option<(&'static str, Option<&'static str>)> {
+        match stage {
+            Stage::Recovery => Some(("Recovering", None)),
+            Stage::Erase => Some(("Erasing", Some("Erased"))),
+            Stage::Program => Some(("Programming", Some("Programmed"))),
+            Stage::Verify => Some(("Verifying", Some("Verified"))),
+            Stage::Sync => Some(("Waiting", None)),
+            _ => None,
+        }
+    }
+}
+
+impl ProgressObserver for StatusLine {
+    fn started(&mut self, stage: Stage, total: Option<u64>) {
+        log::debug!("{:?} started", stage);
+        if Self::headers(stage).is_some() {
+            self.work = (0, total.unwrap_or_default());
+            self.started = Some(Instant::now());
+            self.advanced(stage, 0);
+        }
+    }
+
+    fn advanced(&mut self, stage: Stage, done: u64) {
+        let (active, _) = match Self::headers(stage) {
+            Some(headers) => headers,
+            None => return,
+        };
+        self.work.0 += done;
+        let percent = 100.0 * self.work.0 as f64 / self.work.1.max(1) as f64;
+        log::cont_status(
+            active,
+            match stage {
+                Stage::Recovery => format!("{:.0}%", percent),
+                Stage::Sync => "for the trace stream to synchronize...".to_string(),
+                _ => format!(
+                    "{} / {} ({:.0}%)",
+                    format_bytes(self.work.0),
+                    format_bytes(self.work.1),
+                    percent
+                ),
+            },
+        );
+    }
+
+    fn finished(&mut self, stage: Stage) {
+        log::debug!("{:?} finished", stage);
+        if let (Some((_, Some(done))), Some(started)) = (Self::headers(stage), self.started.take())
+        {
+            log::status(
+                done,
+                format!(
+                    "{} in {:.2} s",
+                    format_bytes(self.work.0),
+                    started.elapsed().as_secs_f64()
+                ),
+            );
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
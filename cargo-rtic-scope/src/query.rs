@@ -0,0 +1,623 @@
+//! Small expressions evaluated over a stream of event chunks, as given
+//! via `--eval`. For example,
+//!
+//! ```text
+//! count(task == "app::uart", action == Entered) per 1s
+//! ```
+//!
+//! counts the entries of the `app::uart` task in windows of one second
+//! of trace time. An expression is an aggregate of the events matching
+//! all of its filters, optionally evaluated `per` window:
+//!
+//! - `count(...)`: the number of matching events.
+//! - `rate(...)`: the number of matching events per second.
+//!
+//! A filter compares a field of an event to a value, with `==` or
+//! `!=`. Events without the field never match. The fields are
+//!
+//! - `task`: the name of the task of a task event;
+//! - `resource`: the name of the resource of a resource event;
+//! - `action`: the action of a task, resource or queue event (e.g.
+//!   `Entered`, `Locked`, `Rejected`);
+//! - `event`: the kind of event (e.g. `Task`, `Overflow`).
+//!
+//! Values are quoted strings or bare words.
+//!
+//! The same filters select the events a sink records, as given via
+//! `--file-filter`. For example,
+//!
+//! ```text
+//! events: task == "app::uart", action == Entered | overflow
+//! ```
+//!
+//! selects the entries of the `app::uart` task and overflows. A
+//! selection is alternatives separated by `|`, each either filters
+//! separated by `,`, all of which must match, or the kind of an event
+//! (e.g. `task`, `overflow`), in any case.
+use crate::timestamps::offset;
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rtic_scope_api as api;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Task,
+    Resource,
+    Action,
+    Event,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+    field: Field,
+    /// Whether the field must equal the value, or differ from it.
+    equal: bool,
+    value: String,
+}
+
+impl Filter {
+    fn matches(&self, event: &api::EventType) -> bool {
+        let field = match (self.field, event) {
+            (Field::Task, api::EventType::Task { name, .. })
+            | (Field::Resource, api::EventType::Resource { name, .. }) => name.to_owned(),
+            (Field::Action, api::EventType::Task { action, .. }) => format!("{:?}", action),
+            (Field::Action, api::EventType::Resource { action, .. }) => format!("{:?}", action),
+            (Field::Action, api::EventType::Queue { action, .. }) => format!("{:?}", action),
+            (Field::Event, event) => kind(event).to_string(),
+            _ => return false,
+        };
+
+        (field == self.value) == self.equal
+    }
+}
+
+/// Declares [`KINDS`] and [`kind`] from the same list of variants of
+/// [`api::EventType`], such that the match keeps both exhaustive.
+macro_rules! kinds {
+    ($($kind:ident $({ $($named:tt)* })? $(($($unnamed:tt)*))?),* $(,)?) => {
+        /// The names of the kinds of events; see [`kind`].
+        const KINDS: &[&str] = &[$(stringify!($kind)),*];
+
+        /// The name of the kind of an event, e.g. "Task".
+        pub fn kind(event: &api::EventType) -> &'static str {
+            match event {
+                $(
+                    api::EventType::$kind $({ $($named)* })? $(($($unnamed)*))?
+                        => stringify!($kind)
+                ),*
+            }
+        }
+    };
+}
+
+kinds! {
+    Overflow,
+    Task { .. },
+    Resource { .. },
+    Spawn { .. },
+    Queue { .. },
+    CpuLoad { .. },
+    Phase { .. },
+    Log { .. },
+    Telemetry { .. },
+    Channel { .. },
+    AuxSample { .. },
+    Fault { .. },
+    Crash { .. },
+    TraceGap { .. },
+    SessionStart { .. },
+    TargetReset { .. },
+    TraceStart,
+    SessionEnd { .. },
+    Reanchored { .. },
+    Anomaly { .. },
+    Unknown(_),
+    Unmappable(..),
+    Invalid(_),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Aggregate {
+    Count,
+    Rate,
+}
+
+/// A parsed expression. See the module documentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    /// The expression as given.
+    source: String,
+    aggregate: Aggregate,
+    filters: Vec<Filter>,
+    /// Length of the windows the expression is evaluated over, if not
+    /// over the complete stream.
+    window: Option<Duration>,
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Query {
+    /// Whether `event` matches all filters of the expression.
+    pub fn matches(&self, event: &api::EventType) -> bool {
+        self.filters.iter().all(|filter| filter.matches(event))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Number(f64),
+    Eq,
+    Ne,
+    Open,
+    Close,
+    Comma,
+    Pipe,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | ',' | '|' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '|' => Token::Pipe,
+                    _ => Token::Comma,
+                });
+            }
+            '=' | '!' => {
+                chars.next();
+                if chars.next() != Some('=') {
+                    return Err(format!("expected {}=", c));
+                }
+                tokens.push(if c == '=' { Token::Eq } else { Token::Ne });
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            c if c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    number.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Number(
+                    number
+                        .parse()
+                        .map_err(|_| format!("invalid number {:?}", number))?,
+                ));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = String::new();
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|c| c.is_alphanumeric() || **c == '_' || **c == ':')
+                {
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            c => return Err(format!("unexpected {:?}", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+
+        let aggregate = match tokens_next_word(&mut tokens)?.as_str() {
+            "count" => Aggregate::Count,
+            "rate" => Aggregate::Rate,
+            other => {
+                return Err(format!(
+                    "unknown aggregate {:?}; expected count or rate",
+                    other
+                ))
+            }
+        };
+        match tokens.next() {
+            Some(Token::Open) => (),
+            token => return Err(format!("expected (, found {:?}", token)),
+        }
+
+        let mut filters = vec![];
+        if tokens.peek() == Some(&Token::Close) {
+            tokens.next();
+        } else {
+            loop {
+                let field = tokens_next_word(&mut tokens)?;
+                filters.push(parse_filter(&field, &mut tokens)?);
+
+                match tokens.next() {
+                    Some(Token::Comma) => continue,
+                    Some(Token::Close) => break,
+                    token => return Err(format!("expected , or ), found {:?}", token)),
+                }
+            }
+        }
+
+        let window = match tokens.next() {
+            None => None,
+            Some(Token::Word(per)) if per == "per" => {
+                let length = match tokens.next() {
+                    Some(Token::Number(length)) => length,
+                    token => return Err(format!("expected a window length, found {:?}", token)),
+                };
+                let unit = match tokens_next_word(&mut tokens)?.as_str() {
+                    "us" => 1e-6,
+                    "ms" => 1e-3,
+                    "s" => 1.0,
+                    other => return Err(format!("unknown unit {:?}; expected us, ms or s", other)),
+                };
+                let window = Duration::try_from_secs_f64(length * unit)
+                    .map_err(|e| format!("invalid window length {}: {}", length, e))?;
+                if window.is_zero() {
+                    return Err("the window must not be empty".to_string());
+                }
+                Some(window)
+            }
+            token => return Err(format!("expected per or the end, found {:?}", token)),
+        };
+        if let Some(token) = tokens.next() {
+            return Err(format!("unexpected {:?} after the window", token));
+        }
+
+        Ok(Self {
+            source: s.trim().to_string(),
+            aggregate,
+            filters,
+            window,
+        })
+    }
+}
+
+/// Parses the comparison of `field` that follows it.
+fn parse_filter(field: &str, tokens: &mut impl Iterator<Item = Token>) -> Result<Filter, String> {
+    let field = match field {
+        "task" => Field::Task,
+        "resource" => Field::Resource,
+        "action" => Field::Action,
+        "event" => Field::Event,
+        other => {
+            return Err(format!(
+                "unknown field {:?}; expected task, resource, action or event",
+                other
+            ))
+        }
+    };
+    let equal = match tokens.next() {
+        Some(Token::Eq) => true,
+        Some(Token::Ne) => false,
+        token => return Err(format!("expected == or !=, found {:?}", token)),
+    };
+    let value = match tokens.next() {
+        Some(Token::Word(value)) | Some(Token::Str(value)) => value,
+        token => return Err(format!("expected a value, found {:?}", token)),
+    };
+
+    Ok(Filter {
+        field,
+        equal,
+        value,
+    })
+}
+
+fn tokens_next_word(tokens: &mut impl Iterator<Item = Token>) -> Result<String, String> {
+    match tokens.next() {
+        Some(Token::Word(word)) => Ok(word),
+        token => Err(format!("expected a word, found {:?}", token)),
+    }
+}
+
+/// A selection of events. See the module documentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventFilter {
+    /// The selection as given.
+    source: String,
+    /// Alternatives, of which an event must match all filters of any.
+    alternatives: Vec<Vec<Filter>>,
+}
+
+impl fmt::Display for EventFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &api::EventType) -> bool {
+        self.alternatives
+            .iter()
+            .any(|filters| filters.iter().all(|filter| filter.matches(event)))
+    }
+
+    /// Whether any event of `chunk` is selected.
+    pub fn selects(&self, chunk: &api::EventChunk) -> bool {
+        chunk.events.iter().any(|event| self.matches(event))
+    }
+}
+
+impl FromStr for EventFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+        match tokens.next() {
+            Some(Token::Word(word)) if word == "events:" => (),
+            token => return Err(format!("expected events:, found {:?}", token)),
+        }
+
+        let mut alternatives = vec![];
+        loop {
+            let word = tokens_next_word(&mut tokens)?;
+            let mut filters = vec![];
+            if matches!(tokens.peek(), Some(Token::Eq) | Some(Token::Ne)) {
+                filters.push(parse_filter(&word, &mut tokens)?);
+                while tokens.peek() == Some(&Token::Comma) {
+                    tokens.next();
+                    let field = tokens_next_word(&mut tokens)?;
+                    filters.push(parse_filter(&field, &mut tokens)?);
+                }
+            } else {
+                let kind = KINDS
+                    .iter()
+                    .find(|kind| kind.eq_ignore_ascii_case(&word))
+                    .ok_or_else(|| format!("unknown kind of event {:?}", word))?;
+                filters.push(Filter {
+                    field: Field::Event,
+                    equal: true,
+                    value: kind.to_string(),
+                });
+            }
+            alternatives.push(filters);
+
+            match tokens.next() {
+                None => break,
+                Some(Token::Pipe) => continue,
+                token => return Err(format!("expected | or the end, found {:?}", token)),
+            }
+        }
+
+        Ok(Self {
+            source: s.trim().to_string(),
+            alternatives,
+        })
+    }
+}
+
+/// The value of an expression over an interval of trace time.
+#[derive(Debug, PartialEq)]
+pub struct Sample {
+    /// Offsets from target reset of the start and end of the interval.
+    pub start: Duration,
+    pub end: Duration,
+    pub value: f64,
+}
+
+/// Evaluates a [`Query`] over a stream of event chunks.
+pub struct Evaluator {
+    pub query: Query,
+    /// Matching events in the current window.
+    count: u64,
+    /// Offsets of the first and last chunk of the current window.
+    span: Option<(Duration, Duration)>,
+}
+
+impl Evaluator {
+    pub fn new(query: Query) -> Self {
+        Self {
+            query,
+            count: 0,
+            span: None,
+        }
+    }
+
+    fn sample(&mut self, start: Duration, end: Duration) -> Sample {
+        let count = std::mem::take(&mut self.count) as f64;
+        Sample {
+            start,
+            end,
+            value: match self.query.aggregate {
+                Aggregate::Count => count,
+                Aggregate::Rate => match (end - start).as_secs_f64() {
+                    secs if secs > 0.0 => count / secs,
+                    _ => 0.0,
+                },
+            },
+        }
+    }
+
+    /// Accounts the events of `chunk`. Returns the sample of the window
+    /// ended by it, if any. Windows without chunks are skipped.
+    pub fn update(&mut self, chunk: &api::EventChunk) -> Option<Sample> {
+        let now = offset(&chunk.timestamp);
+        let mut sample = None;
+        if let (Some(window), Some((first, _))) = (self.query.window, self.span) {
+            let start = self.window_start(first);
+            if now >= start + window {
+                sample = Some(self.sample(start, start + window));
+                self.span = None;
+            }
+        }
+
+        let (first, _) = self.span.unwrap_or((now, now));
+        self.span = Some((first, now.max(first)));
+        self.count += chunk
+            .events
+            .iter()
+            .filter(|event| self.query.matches(event))
+            .count() as u64;
+
+        sample
+    }
+
+    /// Start of the window `t` falls in.
+    fn window_start(&self, t: Duration) -> Duration {
+        match self.query.window {
+            Some(window) => {
+                Duration::from_nanos((t.as_nanos() / window.as_nanos() * window.as_nanos()) as u64)
+            }
+            None => t,
+        }
+    }
+
+    /// E.g. "count(event == Overflow) per 1s = 2 over [3.000 s, 4.000 s)".
+    pub fn format(&self, sample: &Sample) -> String {
+        format!(
+            "{} = {} over [{:.3} s, {:.3} s)",
+            self.query,
+            match self.query.aggregate {
+                Aggregate::Count => format!("{}", sample.value),
+                Aggregate::Rate => format!("{:.3}", sample.value),
+            },
+            sample.start.as_secs_f64(),
+            sample.end.as_secs_f64()
+        )
+    }
+
+    /// The sample of the remaining, possibly incomplete, window up to
+    /// the last chunk; or of the complete stream if the expression is
+    /// not windowed.
+    pub fn finish(&mut self) -> Option<Sample> {
+        let (first, last) = self.span.take()?;
+        let start = self.window_start(first);
+        Some(self.sample(start, last))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, entered, task};
+
+    /// Ensure expressions are parsed, and malformed ones rejected.
+    #[test]
+    fn parse() {
+        let query: Query = r#"count(task == "app::uart", action != Exited) per 500ms"#
+            .parse()
+            .unwrap();
+        assert_eq!(query.aggregate, Aggregate::Count);
+        assert_eq!(
+            query.filters,
+            vec![
+                Filter {
+                    field: Field::Task,
+                    equal: true,
+                    value: "app::uart".to_string(),
+                },
+                Filter {
+                    field: Field::Action,
+                    equal: false,
+                    value: "Exited".to_string(),
+                },
+            ]
+        );
+        assert_eq!(query.window, Some(Duration::from_millis(500)));
+        assert_eq!("rate()".parse::<Query>().unwrap().window, None);
+
+        for malformed in [
+            "count(",
+            "sum()",
+            "count(task = app::uart)",
+            "count(priority == 1)",
+            "count() per 1h",
+            "count() per 0s",
+        ] {
+            assert!(malformed.parse::<Query>().is_err(), "{}", malformed);
+        }
+    }
+
+    /// Ensure windows that do not fit a duration are rejected rather than
+    /// panicked on.
+    #[test]
+    fn window_overflow() {
+        let e = "count() per 99999999999999999999999 s"
+            .parse::<Query>()
+            .unwrap_err();
+        assert!(e.starts_with("invalid window length"), "{}", e);
+    }
+
+    /// Ensure matching events are counted per window of trace time.
+    #[test]
+    fn evaluate() {
+        let chunk = |millis, name| chunk(Duration::from_millis(millis), vec![entered(name)]);
+
+        let mut eval = Evaluator::new(r#"count(task == "a") per 1s"#.parse().unwrap());
+        assert_eq!(eval.update(&chunk(100, "a")), None);
+        assert_eq!(eval.update(&chunk(200, "b")), None);
+        assert_eq!(eval.update(&chunk(900, "a")), None);
+        assert_eq!(
+            eval.update(&chunk(2_500, "a")),
+            Some(Sample {
+                start: Duration::from_secs(0),
+                end: Duration::from_secs(1),
+                value: 2.0,
+            })
+        );
+        assert_eq!(
+            eval.finish(),
+            Some(Sample {
+                start: Duration::from_secs(2),
+                end: Duration::from_millis(2_500),
+                value: 1.0,
+            })
+        );
+    }
+
+    /// Ensure events are selected by their kind, in any case, or by
+    /// filters, and that malformed selections are rejected.
+    #[test]
+    fn select() {
+        let filter: EventFilter = r#"events: task == "app::uart", action == Entered | OVERFLOW"#
+            .parse()
+            .unwrap();
+        assert!(filter.matches(&task("app::uart", api::TaskAction::Entered)));
+        assert!(!filter.matches(&task("app::uart", api::TaskAction::Exited)));
+        assert!(!filter.matches(&task("app::spi", api::TaskAction::Entered)));
+        assert!(filter.matches(&api::EventType::Overflow));
+
+        let filter: EventFilter = "events: task".parse().unwrap();
+        assert!(filter.matches(&task("app::spi", api::TaskAction::Exited)));
+        assert!(!filter.matches(&api::EventType::TraceStart));
+
+        for malformed in [
+            "task",
+            "events:",
+            "events: tasks",
+            "events: task |",
+            "events: task overflow",
+        ] {
+            assert!(malformed.parse::<EventFilter>().is_err(), "{}", malformed);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Recovery of the depth of the queue of each dispatcher from the
+//! traced spawns of the software tasks dispatched from it (see
+//! `cortex_m_rtic_trace::trace_queued`). A spawned task is queued until
+//! it is entered; the tasks of a dispatcher share its queue.
+use std::collections::HashMap;
+
+use rtic_scope_api as api;
+
+#[derive(Default)]
+pub struct QueueTracker {
+    /// The dispatcher of each software task.
+    dispatcher: HashMap<String, String>,
+    /// Number of spawned tasks of each dispatcher that have not yet
+    /// been entered, by task.
+    pending: HashMap<String, HashMap<String, usize>>,
+}
+
+impl QueueTracker {
+    pub fn new(dispatchers: &[api::Dispatcher]) -> Self {
+        Self {
+            dispatcher: dispatchers
+                .iter()
+                .flat_map(|d| d.tasks.iter().map(|t| (t.clone(), d.interrupt.clone())))
+                .collect(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Follows each spawn and entry of a software task in `chunk` with
+    /// an [`api::EventType::Queue`] event. Entries of tasks without a
+    /// traced spawn are not dequeues.
+    pub fn update(&mut self, chunk: &mut api::EventChunk) {
+        if self.dispatcher.is_empty() {
+            return;
+        }
+
+        let mut events = Vec::with_capacity(chunk.events.len());
+        for event in std::mem::take(&mut chunk.events) {
+            let queued = match &event {
+                api::EventType::Spawn { to, rejected, .. } => {
+                    self.dispatcher.get(to).cloned().map(|name| {
+                        let action = if *rejected {
+                            api::QueueAction::Rejected
+                        } else {
+                            *self
+                                .pending
+                                .entry(name.clone())
+                                .or_default()
+                                .entry(to.clone())
+                                .or_default() += 1;
+                            api::QueueAction::Enqueued
+                        };
+                        (name, action)
+                    })
+                }
+                api::EventType::Task {
+                    name,
+                    action: api::TaskAction::Entered,
+                    ..
+                } => self.dispatcher.get(name).cloned().and_then(|dispatcher| {
+                    let pending = self.pending.get_mut(&dispatcher)?.get_mut(name)?;
+                    if *pending == 0 {
+                        return None;
+                    }
+                    *pending -= 1;
+                    Some((dispatcher, api::QueueAction::Dequeued))
+                }),
+                // NOTE spawns and entries may have been lost.
+                api::EventType::Overflow => {
+                    self.pending.clear();
+                    None
+                }
+                _ => None,
+            };
+            events.push(event);
+            if let Some((name, action)) = queued {
+                let depth = self.pending.get(&name).map_or(0, |p| p.values().sum());
+                events.push(api::EventType::Queue {
+                    name,
+                    depth,
+                    action,
+                });
+            }
+        }
+
+        chunk.events = events;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, entered};
+    use std::time::Duration;
+
+    fn spawn(to: &str, rejected: bool) -> api::EventType {
+        api::EventType::Spawn {
+            from: "init".to_string(),
+            to: to.to_string(),
+            rejected,
+        }
+    }
+
+    fn queues(chunk: &api::EventChunk) -> Vec<(String, usize, api::QueueAction)> {
+        chunk
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                api::EventType::Queue {
+                    name,
+                    depth,
+                    action,
+                } => Some((name.clone(), *depth, *action)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Ensure the tasks of a dispatcher share its queue, that rejected
+    /// spawns are not queued, and that entries without a traced spawn
+    /// are not dequeues.
+    #[test]
+    fn depth() {
+        use api::QueueAction::*;
+
+        let mut tracker = QueueTracker::new(&[api::Dispatcher {
+            interrupt: "EXTI0".to_string(),
+            priority: 1,
+            tasks: vec!["a".to_string(), "b".to_string()],
+        }]);
+        let q = |depth, action| ("EXTI0".to_string(), depth, action);
+
+        let mut spawns = chunk(
+            Duration::ZERO,
+            vec![
+                spawn("a", false),
+                spawn("b", false),
+                spawn("b", true),
+                spawn("other", false),
+            ],
+        );
+        tracker.update(&mut spawns);
+        assert_eq!(
+            queues(&spawns),
+            [q(1, Enqueued), q(2, Enqueued), q(2, Rejected)]
+        );
+        assert!(matches!(spawns.events[1], api::EventType::Queue { .. }));
+
+        let mut entries = chunk(
+            Duration::ZERO,
+            vec![entered("b"), entered("b"), entered("a")],
+        );
+        tracker.update(&mut entries);
+        assert_eq!(queues(&entries), [q(1, Dequeued), q(0, Dequeued)]);
+    }
+}
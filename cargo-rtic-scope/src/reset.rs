@@ -0,0 +1,117 @@
+//! Reset of the target before tracing. The default system reset
+//! (SYSRESETREQ) does not work on all boards: external watchdogs and
+//! supervisors may hold or re-trigger it. See `--reset-method`.
+use crate::sources::SourceError;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use probe_rs::{MemoryInterface, Session};
+
+/// Application Interrupt and Reset Control Register.
+const AIRCR: u32 = 0xe000_ed0c;
+/// Key that must be written to AIRCR along with a reset request.
+const AIRCR_VECTKEY: u32 = 0x05fa << 16;
+/// Requests a reset of the core only, not of the peripherals.
+/// Only implemented on ARMv7-M.
+const AIRCR_VECTRESET: u32 = 1 << 0;
+
+/// Debug Exception and Monitor Control Register.
+const DEMCR: u32 = 0xe000_edfc;
+/// Halts the core on reset, before the first instruction is executed.
+const DEMCR_VC_CORERESET: u32 = 1 << 0;
+
+/// The nRESET pin of the SWJ port, as driven by `swj_pins`.
+const SWJ_NRESET: u32 = 1 << 7;
+
+/// How long nRESET is asserted for, and how long the core is given to
+/// halt after a reset.
+const RESET_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// How the target is reset before tracing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMethod {
+    /// A system reset requested via SYSRESETREQ.
+    SysReset,
+    /// A reset of the core only, requested via VECTRESET.
+    VectReset,
+    /// A reset via the nRESET pin, asserted by the probe.
+    Hardware,
+    /// No reset; the target is expected to be reset by other means.
+    None,
+}
+
+impl FromStr for ResetMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sysreset" => Ok(Self::SysReset),
+            "vectreset" => Ok(Self::VectReset),
+            "hardware" => Ok(Self::Hardware),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "unknown reset method {:?}; expected sysreset, vectreset, hardware, or none",
+                s
+            )),
+        }
+    }
+}
+
+/// Resets core `core` of the target of `session` via `method`. If
+/// `halt`, the core is halted before it executes its first
+/// instruction via the reset vector catch.
+pub fn reset(
+    session: &mut Session,
+    core: usize,
+    method: ResetMethod,
+    halt: bool,
+) -> Result<(), SourceError> {
+    reset_via(session, core, method, halt).map_err(SourceError::ResetError)
+}
+
+fn reset_via(
+    session: &mut Session,
+    core: usize,
+    method: ResetMethod,
+    halt: bool,
+) -> Result<(), probe_rs::Error> {
+    match method {
+        ResetMethod::SysReset => {
+            let mut core = session.core(core)?;
+            if halt {
+                core.reset_and_halt(RESET_TIMEOUT)?;
+            } else {
+                core.reset()?;
+            }
+            return Ok(());
+        }
+        ResetMethod::None => return Ok(()),
+        ResetMethod::VectReset | ResetMethod::Hardware => (),
+    }
+
+    let mut c = session.core(core)?;
+    let demcr = c.read_word_32(DEMCR)?;
+    if halt {
+        c.write_word_32(DEMCR, demcr | DEMCR_VC_CORERESET)?;
+    }
+    if method == ResetMethod::VectReset {
+        // NOTE(ok) the core does not acknowledge the write before it
+        // is reset
+        let _ = c.write_word_32(AIRCR, AIRCR_VECTKEY | AIRCR_VECTRESET);
+    } else {
+        drop(c);
+        let interface = session.get_arm_interface()?;
+        interface.swj_pins(0, SWJ_NRESET, 0)?;
+        std::thread::sleep(RESET_TIMEOUT);
+        interface.swj_pins(SWJ_NRESET, SWJ_NRESET, 0)?;
+        c = session.core(core)?;
+    }
+
+    if halt {
+        c.wait_for_core_halted(RESET_TIMEOUT)?;
+        c.write_word_32(DEMCR, demcr)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,252 @@
+//! Schedulability metrics of a recorded trace in the terms of
+//! response-time analysis, such that measured values can be compared
+//! against those of a design-time analysis: the worst observed response
+//! time of each task, and the utilization and busy periods of each
+//! priority level, by the priorities recovered from the application.
+//!
+//! Releases are only traced for software tasks whose spawns are traced
+//! (see `cortex_m_rtic_trace::trace_spawn`). The response time of other
+//! tasks is measured from when they were entered, and is thus a lower
+//! bound. Likewise, the level-i busy period spans from the entry of a
+//! task of priority i or higher until no such task executes; the time
+//! such tasks were pending before is not known.
+use crate::timestamps::offset;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use rtic_scope_api as api;
+
+/// The worst observed response time of a task.
+#[derive(Debug, Default, PartialEq)]
+pub struct Response {
+    /// Number of complete responses.
+    pub count: usize,
+    pub worst: Duration,
+    /// Whether all responses are measured from the release of the
+    /// task, instead of from its entry.
+    pub from_release: bool,
+}
+
+/// The utilization and busy periods of a priority level.
+#[derive(Debug, Default, PartialEq)]
+pub struct Level {
+    /// Time tasks of the level executed, excluding the time they were
+    /// preempted.
+    pub executed: Duration,
+    /// Number of complete busy periods.
+    pub busy_periods: usize,
+    pub longest_busy_period: Duration,
+}
+
+#[derive(Default)]
+pub struct Schedulability {
+    priorities: IndexMap<String, u8>,
+    /// Executing tasks and their priorities, in the order they were
+    /// entered.
+    stack: Vec<(String, u8)>,
+    /// Release times of spawned tasks that have not yet been entered.
+    released: HashMap<String, VecDeque<Duration>>,
+    /// When each executing task was released (or else entered), and
+    /// whether it was released.
+    responding: HashMap<String, (Duration, bool)>,
+    /// Start of the busy period of each level, if in one.
+    busy_since: BTreeMap<u8, Duration>,
+    first: Option<Duration>,
+    last: Option<Duration>,
+    pub responses: IndexMap<String, Response>,
+    pub levels: BTreeMap<u8, Level>,
+}
+
+impl Schedulability {
+    pub fn new(priorities: IndexMap<String, u8>) -> Self {
+        let levels = priorities
+            .values()
+            .map(|priority| (*priority, Level::default()))
+            .collect();
+        Self {
+            priorities,
+            levels,
+            ..Self::default()
+        }
+    }
+
+    /// Whether any priorities are known, without which nothing is
+    /// measured.
+    pub fn is_known(&self) -> bool {
+        !self.priorities.is_empty()
+    }
+
+    /// The priority of `task`. Only tasks with a known priority have
+    /// [`Schedulability::responses`].
+    pub fn priority(&self, task: &str) -> u8 {
+        self.priorities.get(task).copied().unwrap_or_default()
+    }
+
+    /// The highest priority of the executing tasks, if any.
+    fn ceiling(&self) -> Option<u8> {
+        self.stack.iter().map(|(_, priority)| *priority).max()
+    }
+
+    /// Accounts the task and spawn events of `chunk`.
+    pub fn update(&mut self, chunk: &api::EventChunk) {
+        let now = offset(&chunk.timestamp);
+        self.first.get_or_insert(now);
+        if let (Some(last), Some((_, priority))) = (self.last, self.stack.last()) {
+            if let Some(level) = self.levels.get_mut(priority) {
+                level.executed += now.saturating_sub(last);
+            }
+        }
+        self.last = Some(self.last.map_or(now, |last| last.max(now)));
+
+        for event in chunk.events.iter() {
+            match event {
+                api::EventType::Spawn {
+                    to,
+                    rejected: false,
+                    ..
+                } => {
+                    self.released
+                        .entry(to.to_owned())
+                        .or_default()
+                        .push_back(now);
+                }
+                api::EventType::Task {
+                    name,
+                    action: api::TaskAction::Entered,
+                    ..
+                } => {
+                    let priority = match self.priorities.get(name) {
+                        Some(priority) => *priority,
+                        None => continue,
+                    };
+                    let release = self
+                        .released
+                        .get_mut(name)
+                        .and_then(|released| released.pop_front());
+                    self.responding
+                        .insert(name.to_owned(), (release.unwrap_or(now), release.is_some()));
+
+                    let ceiling = self.ceiling();
+                    for level in self.levels.keys().filter(|level| {
+                        **level <= priority && ceiling.map_or(true, |ceiling| ceiling < **level)
+                    }) {
+                        self.busy_since.insert(*level, now);
+                    }
+                    self.stack.push((name.to_owned(), priority));
+                }
+                api::EventType::Task {
+                    name,
+                    action: api::TaskAction::Exited,
+                    ..
+                } => {
+                    if let Some(i) = self.stack.iter().rposition(|(task, _)| task == name) {
+                        self.stack.remove(i);
+                    }
+                    if let Some((start, released)) = self.responding.remove(name) {
+                        let response = self.responses.entry(name.to_owned()).or_insert(Response {
+                            from_release: true,
+                            ..Response::default()
+                        });
+                        response.count += 1;
+                        response.worst = response.worst.max(now.saturating_sub(start));
+                        response.from_release &= released;
+                    }
+
+                    let ceiling = self.ceiling();
+                    let ended: Vec<u8> = self
+                        .busy_since
+                        .keys()
+                        .filter(|level| ceiling.map_or(true, |ceiling| ceiling < **level))
+                        .copied()
+                        .collect();
+                    for level in ended {
+                        let since = self.busy_since.remove(&level).unwrap();
+                        let level = self.levels.get_mut(&level).unwrap();
+                        level.busy_periods += 1;
+                        level.longest_busy_period =
+                            level.longest_busy_period.max(now.saturating_sub(since));
+                    }
+                }
+                // NOTE the state of the target is unknown afterwards.
+                api::EventType::Overflow => {
+                    self.stack.clear();
+                    self.released.clear();
+                    self.responding.clear();
+                    self.busy_since.clear();
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Utilization of `level` in percent: the fraction of the trace
+    /// during which tasks of the level executed.
+    pub fn utilization(&self, level: &Level) -> Option<f64> {
+        let span = self.last?.saturating_sub(self.first?);
+        if span.is_zero() {
+            return None;
+        }
+
+        Some(100.0 * level.executed.as_secs_f64() / span.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, task};
+
+    /// Ensure response times are measured from spawns when traced,
+    /// that preempted time is not accounted to the preempted level, and
+    /// that busy periods span preemptions by higher priorities.
+    #[test]
+    fn response_and_busy_periods() {
+        use api::TaskAction::*;
+
+        let ms = Duration::from_millis;
+        let mut sched = Schedulability::new(IndexMap::from_iter([
+            ("low".to_string(), 1),
+            ("high".to_string(), 2),
+        ]));
+        for chunk in [
+            chunk(ms(0), vec![]),
+            chunk(
+                ms(1),
+                vec![api::EventType::Spawn {
+                    from: "thread".to_string(),
+                    to: "low".to_string(),
+                    rejected: false,
+                }],
+            ),
+            chunk(ms(2), vec![task("low", Entered)]),
+            chunk(ms(3), vec![task("high", Entered)]),
+            chunk(ms(5), vec![task("high", Exited), task("low", Returned)]),
+            chunk(ms(6), vec![task("low", Exited)]),
+            chunk(ms(10), vec![]),
+        ] {
+            sched.update(&chunk);
+        }
+
+        assert_eq!(
+            sched.responses["low"],
+            Response {
+                count: 1,
+                worst: ms(5),
+                from_release: true,
+            }
+        );
+        assert!(!sched.responses["high"].from_release);
+        assert_eq!(
+            sched.levels[&1],
+            Level {
+                executed: ms(2),
+                busy_periods: 1,
+                longest_busy_period: ms(4),
+            }
+        );
+        assert_eq!(sched.levels[&2].executed, ms(2));
+        assert!((sched.utilization(&sched.levels[&1]).unwrap() - 20.0).abs() < 1e-9);
+    }
+}
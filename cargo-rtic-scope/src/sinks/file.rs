@@ -1,23 +1,121 @@
 //! A simple file sink which receives JSON-serialized [`TraceData`].
 //! Used for replay functionality.
-use crate::recovery::TraceMetadata;
+//!
+//! Trace files start with [`TRACE_MAGIC`], followed by frames of a
+//! little-endian `u32` length and the JSON-serialized payload of that
+//! length: first the [`TraceMetadata`], then each [`TraceData`]. The
+//! framing allows a reader to detect and skip a truncated trailing
+//! frame, e.g. after a crash. Files written before the framing was
+//! introduced are concatenated JSON values without a magic.
+//!
+//! A sparse [`TraceIndex`] of the trace data is written next to the
+//! trace file at the end of the session, with which readers seek past
+//! the trace data preceding a point in the trace.
+//!
+//! If the sink filters events (see `--file-filter`), only the trace data
+//! of the chunks with selected events is written, in full. State that
+//! spans trace data (e.g. lines of stimulus ports, or the spawns and
+//! locks of tasks) is lost with the unwritten data, so a replay may not
+//! recover the same events: the sink warns if any data is filtered.
+//! Trace data drained without its chunk, e.g. as flushed by a
+//! snapshot, is written unfiltered.
+use crate::query::EventFilter;
+use crate::recovery::{TraceMetadata, TraceTrailer};
 use crate::sinks::{Sink, SinkError};
 use crate::TraceData;
 use std::fs;
 
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use cargo_metadata::Artifact;
 use chrono::prelude::*;
 use git2::{DescribeFormatOptions, DescribeOptions, Repository};
 use rtic_scope_api as api;
+use serde::{Deserialize, Serialize};
 use serde_json;
 
 const TRACE_FILE_EXT: &str = ".trace";
 
+/// Leading bytes of a framed trace file.
+pub const TRACE_MAGIC: &[u8] = b"RTIC Scope framed trace v1\n";
+
+/// How often written frames are flushed to disk.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many bytes of trace data are written between the entries of the
+/// index of a trace file.
+const INDEX_INTERVAL: u64 = 1 << 20;
+
+/// A frame of the trace file: the little-endian length of `payload`,
+/// followed by it.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// The trailer as written at the end of the trace file.
+pub fn trailer_bytes(trailer: &TraceTrailer) -> Result<Vec<u8>, SinkError> {
+    // NOTE the trailer is not framed: it is found by seeking backwards
+    // from EOF to the last newline, and serialized JSON never contains
+    // newlines.
+    Ok(format!("\n{}\n", serde_json::to_string(trailer)?).into_bytes())
+}
+
+/// The path of the index of the trace file at `trace`.
+pub fn index_path(trace: &Path) -> PathBuf {
+    trace.with_extension("index")
+}
+
+/// A sparse index of the trace data frames of a trace file, by their
+/// offset from target reset.
+#[derive(Serialize, Deserialize, Default)]
+pub struct TraceIndex {
+    /// Entries in file order.
+    pub entries: Vec<IndexEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IndexEntry {
+    /// Number of trace data frames preceding the frame.
+    pub seq: usize,
+    /// Position of the frame in the trace file.
+    pub position: u64,
+    /// Offset from target reset of the trace data of the frame.
+    pub offset: Duration,
+}
+
+impl TraceIndex {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// The last entry at or before `offset`, from which all trace data
+    /// at or after `offset` is read.
+    pub fn seek(&self, offset: Duration) -> Option<&IndexEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.offset <= offset)
+    }
+}
+
 pub struct FileSink {
     file: fs::File,
+    path: PathBuf,
+    last_sync: Instant,
+    /// Number of bytes written.
+    position: u64,
+    /// Number of trace data frames written.
+    frames: usize,
+    index: TraceIndex,
+    /// The events whose trace data is written, if not all.
+    filter: Option<EventFilter>,
+    /// Number of trace data frames not written by the filter.
+    filtered: usize,
 }
 
 impl FileSink {
@@ -29,6 +127,8 @@ impl FileSink {
         if remove_prev_traces {
             if let Ok(traces) = find_trace_files(trace_dir.to_path_buf()) {
                 for trace in traces {
+                    // NOTE(ok) traces recorded before indexing have no index
+                    let _ = fs::remove_file(index_path(&trace));
                     fs::remove_file(trace).map_err(|e| {
                         SinkError::SetupIOError(
                             Some("Failed to remove previous trace file".to_string()),
@@ -39,6 +139,22 @@ impl FileSink {
             }
         }
 
+        let file = Self::trace_file_path(artifact, trace_dir)?;
+        fs::create_dir_all(trace_dir).map_err(|e| {
+            SinkError::SetupIOError(
+                Some(format!(
+                    "Failed to create output trace directory {}",
+                    trace_dir.display()
+                )),
+                e,
+            )
+        })?;
+        Self::create(file)
+    }
+
+    /// Path of the trace file [`FileSink::generate_trace_file`] would
+    /// create now, without creating it.
+    pub fn trace_file_path(artifact: &Artifact, trace_dir: &Path) -> Result<PathBuf, SinkError> {
         // generate a short descroption on the format
         // "blinky-gbaadf00-dirty-2021-06-16T17:13:16.trace"
         let repo = find_git_repo(artifact.target.src_path.clone().into())?;
@@ -50,54 +166,147 @@ impl FileSink {
                     .dirty_suffix("-dirty"),
             ))?;
         let date = Local::now().format("%Y-%m-%dT%H:%M:%S").to_string();
-        let file = trace_dir.join(format!(
+
+        Ok(trace_dir.join(format!(
             "{}-g{}-{}{}",
             artifact.target.name, git_shortdesc, date, TRACE_FILE_EXT,
-        ));
+        )))
+    }
 
-        fs::create_dir_all(trace_dir).map_err(|e| {
-            SinkError::SetupIOError(
-                Some(format!(
-                    "Failed to create output trace directory {}",
-                    trace_dir.display()
-                )),
-                e,
-            )
-        })?;
+    /// Creates a trace file at `path`, which must not exist.
+    pub fn create(path: PathBuf) -> Result<Self, SinkError> {
         let file = fs::OpenOptions::new()
             .write(true)
             .create_new(true)
-            .open(&file)
+            .open(&path)
             .map_err(|e| {
                 SinkError::SetupIOError(
                     Some(format!(
                         "Failed to create output trace file {}",
-                        file.display()
+                        path.display()
                     )),
                     e,
                 )
             })?;
 
-        Ok(Self { file })
+        let mut sink = Self {
+            file,
+            path,
+            last_sync: Instant::now(),
+            position: TRACE_MAGIC.len() as u64,
+            frames: 0,
+            index: TraceIndex::default(),
+            filter: None,
+            filtered: 0,
+        };
+        sink.file.write_all(TRACE_MAGIC).map_err(|e| {
+            SinkError::SetupIOError(Some("Failed to write trace file magic".to_string()), e)
+        })?;
+
+        Ok(sink)
+    }
+
+    /// Only writes the trace data of the chunks with events selected by
+    /// `filter`.
+    pub fn filter(mut self, filter: Option<EventFilter>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Path of the trace file.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     /// Serialize [TraceMetadata] to replay file.
     pub fn drain_metadata(&mut self, metadata: &TraceMetadata) -> Result<(), SinkError> {
-        {
-            let json = serde_json::to_string(&metadata)?;
-            self.file.write_all(json.as_bytes())
+        self.write_frame(&serde_json::to_vec(&metadata)?)?;
+        self.file.sync_data().map_err(SinkError::DrainIOError)
+    }
+
+    /// Writes a length-prefixed frame, and flushes all written frames
+    /// to disk if [`SYNC_INTERVAL`] has passed since the last flush.
+    fn write_frame(&mut self, payload: &[u8]) -> Result<(), SinkError> {
+        let frame = frame(payload);
+        self.file
+            .write_all(&frame)
+            .map_err(SinkError::DrainIOError)?;
+        self.position += frame.len() as u64;
+
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.file.sync_data().map_err(SinkError::DrainIOError)?;
+            self.last_sync = Instant::now();
         }
-        .map_err(SinkError::DrainIOError)?;
 
         Ok(())
     }
+
+    fn write_data(&mut self, data: &TraceData) -> Result<(), SinkError> {
+        let indexed = self.index.entries.last().map(|entry| entry.position);
+        if indexed.map_or(true, |indexed| self.position - indexed >= INDEX_INTERVAL) {
+            self.index.entries.push(IndexEntry {
+                seq: self.frames,
+                position: self.position,
+                offset: crate::timestamps::offset(&data.timestamp),
+            });
+        }
+        self.frames += 1;
+
+        self.write_frame(&serde_json::to_vec(data)?)
+    }
 }
 
 impl Sink for FileSink {
-    fn drain(&mut self, data: TraceData, _: api::EventChunk) -> Result<(), SinkError> {
-        let json = serde_json::to_string(&data)?;
+    fn drain_raw(&mut self, data: &TraceData) -> Result<(), SinkError> {
+        self.write_data(data)
+    }
+
+    fn drain(
+        &mut self,
+        data: Option<&TraceData>,
+        chunk: &api::EventChunk,
+    ) -> Result<(), SinkError> {
+        let data = match data {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        if let Some(filter) = &self.filter {
+            if !filter.selects(chunk) {
+                self.filtered += 1;
+                return Ok(());
+            }
+        }
+
+        self.write_data(data)
+    }
+
+    fn drain_trailer(&mut self, trailer: &TraceTrailer) -> Result<(), SinkError> {
+        if let Some(filter) = &self.filter {
+            crate::log::status(
+                "Filtered",
+                format!(
+                    "{} of {} trace data frame(s) written to {} ({})",
+                    self.frames,
+                    self.frames + self.filtered,
+                    self.path.display(),
+                    filter
+                ),
+            );
+            if self.filtered > 0 {
+                crate::log::warn(format!(
+                    "a replay of {} may not recover the selected events faithfully: state spanning trace data (e.g. stimulus lines, spawns, locks) of the {} unwritten frame(s) is lost",
+                    self.path.display(),
+                    self.filtered,
+                ));
+            }
+        }
+
         self.file
-            .write_all(json.as_bytes())
+            .write_all(&trailer_bytes(trailer)?)
+            .and_then(|_| self.file.sync_data())
+            .map_err(SinkError::DrainIOError)?;
+
+        fs::write(index_path(&self.path), serde_json::to_vec(&self.index)?)
             .map_err(SinkError::DrainIOError)
     }
 
@@ -1,33 +1,338 @@
 //! Sub-proccess sink which received JSON-serialized
-//! [`api::EventChunk`]s.
+//! [`api::EventChunk`]s, and which may send [`api::ControlMessage`]s
+//! back over the same socket.
+//!
+//! A frontend is dropped once it has closed its socket (e.g. because
+//! it crashed) or, if it has asked for [`api::Ping`]s, once the oldest
+//! ping it has not answered was sent [`api::PING_TIMEOUT_SECS`] ago.
+//! Pongs are received on a thread of their own, such that a run loop
+//! that does not check on the frontend for a while (e.g. while paused)
+//! does not drop it. Its socket is then removed, along with the
+//! temporary directory it was created in.
 use crate::sinks::{Sink, SinkError};
-use crate::TraceData;
 
 use rtic_scope_api as api;
-use std::io::Write;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel as channel;
+
+/// How often frontends are pinged; see [`Sink::check_alive`].
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The pings sent to a frontend, and the last answered.
+struct Pings {
+    /// Sequence number of the last ping sent.
+    sent: u64,
+    /// Sequence numbers of the pings not known to be answered, and when
+    /// each was sent, oldest first.
+    unanswered: VecDeque<(u64, Instant)>,
+}
+
+/// `chunk` without the events not subscribed to, or `None` if none
+/// remain.
+pub fn subscribed<'a>(
+    chunk: &'a api::EventChunk,
+    subscription: &api::Subscription,
+) -> Option<Cow<'a, api::EventChunk>> {
+    if chunk.events.iter().all(|e| subscription.wants(e)) {
+        return Some(Cow::Borrowed(chunk));
+    }
+
+    let events: Vec<api::EventType> = chunk
+        .events
+        .iter()
+        .filter(|e| subscription.wants(e))
+        .cloned()
+        .collect();
+    if events.is_empty() {
+        return None;
+    }
+
+    Some(Cow::Owned(api::EventChunk {
+        timestamp: chunk.timestamp.to_owned(),
+        events,
+        estimated_timestamp: chunk.estimated_timestamp,
+        cycles: chunk.cycles,
+        core: chunk.core,
+        packets: chunk.packets,
+    }))
+}
 
 pub struct FrontendSink {
-    socket: std::os::unix::net::UnixStream,
+    /// Name of the frontend, as given to `--frontend`.
+    name: String,
+    socket: UnixStream,
+    subscription: api::Subscription,
+    /// Control messages read from the socket.
+    control: channel::Receiver<api::ControlMessage>,
+    /// Whether the frontend has asked not to receive events. Shared
+    /// with the [`ShmSink`](crate::sinks::ShmSink), if any.
+    paused: Arc<AtomicBool>,
+    /// Whether the frontend receives events from the shared-memory
+    /// ring buffer instead; see [`ShmSink`](crate::sinks::ShmSink).
+    via_shm: bool,
+    /// Whether the frontend has closed its socket.
+    closed: Arc<AtomicBool>,
+    /// Pings sent to the frontend, if it has asked for them.
+    pings: Option<Pings>,
+    /// Sequence number of the last answered ping, as received.
+    answered: Arc<AtomicU64>,
+    /// Path of the socket, if not in the abstract namespace.
+    socket_path: Option<PathBuf>,
 }
 
 impl FrontendSink {
-    pub fn new(socket: std::os::unix::net::UnixStream) -> Self {
-        Self { socket }
+    pub fn new(
+        name: String,
+        socket: UnixStream,
+        subscription: api::Subscription,
+    ) -> io::Result<Self> {
+        // Read control messages on a thread of their own: the socket
+        // cannot be made non-blocking for reads only.
+        let (tx, control) = channel::unbounded();
+        let reader = io::BufReader::new(socket.try_clone()?);
+        let frontend = name.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        let closing = closed.clone();
+        let answered = Arc::new(AtomicU64::new(0));
+        let answering = answered.clone();
+        std::thread::spawn(move || {
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(line) if line.trim().is_empty() => continue,
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                match serde_json::from_str(&line) {
+                    Ok(api::ControlMessage::Pong { ping }) => {
+                        answering.fetch_max(ping, Ordering::Relaxed);
+                    }
+                    Ok(msg) => {
+                        if tx.send(msg).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => crate::log::warn(format!(
+                        "ignoring invalid control message {:?} from frontend {}: {}",
+                        line, frontend, e
+                    )),
+                }
+            }
+            closing.store(true, Ordering::Relaxed);
+        });
+
+        Ok(Self {
+            name,
+            socket,
+            subscription,
+            control,
+            paused: Arc::new(AtomicBool::new(false)),
+            via_shm: false,
+            closed,
+            pings: None,
+            answered,
+            socket_path: None,
+        })
+    }
+
+    /// Pings the frontend, which is dropped if it does not answer. A
+    /// frontend that does not read from its socket for as long is
+    /// considered hung.
+    pub fn pinged(mut self) -> io::Result<Self> {
+        self.socket
+            .set_write_timeout(Some(Duration::from_secs(api::PING_TIMEOUT_SECS)))?;
+        self.pings = Some(Pings {
+            sent: 0,
+            unanswered: VecDeque::new(),
+        });
+        Ok(self)
+    }
+
+    /// Removes the socket at `socket` (as given in the handshake) once
+    /// the frontend is dropped for being dead.
+    pub fn socket_path(mut self, socket: &str) -> Self {
+        if !socket.starts_with('@') {
+            self.socket_path = Some(PathBuf::from(socket));
+        }
+        self
+    }
+
+    /// Removes the socket of a dead frontend, and the temporary
+    /// directory it was created in, if now empty.
+    fn remove_socket(&self) {
+        let path = match &self.socket_path {
+            Some(path) => path,
+            None => return,
+        };
+        if fs::remove_file(path).is_ok() {
+            crate::log::debug!("removed stale socket {}", path.display());
+        }
+        if let Some(dir) = path.parent() {
+            // NOTE(ok) fails if the directory is not empty
+            if dir.starts_with(std::env::temp_dir()) && dir != std::env::temp_dir() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+    }
+
+    /// Why the frontend is considered dead, if it is: it has closed its
+    /// socket, or not answered a ping for too long.
+    fn dead(&mut self) -> Option<String> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Some("closed its socket".to_string());
+        }
+        let pings = self.pings.as_mut()?;
+        let answered = self.answered.load(Ordering::Relaxed);
+        while matches!(pings.unanswered.front(), Some((ping, _)) if *ping <= answered) {
+            pings.unanswered.pop_front();
+        }
+        match pings.unanswered.front() {
+            Some((_, sent)) if sent.elapsed() >= Duration::from_secs(api::PING_TIMEOUT_SECS) => {
+                Some(format!(
+                    "did not answer a ping for {} s",
+                    api::PING_TIMEOUT_SECS
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends the next ping, if the frontend has asked for them.
+    fn ping(&mut self) -> io::Result<()> {
+        let pings = match self.pings.as_mut() {
+            Some(pings) => pings,
+            None => return Ok(()),
+        };
+        pings.sent += 1;
+        let json = serde_json::to_string(&api::Ping { ping: pings.sent })? + "\n";
+        self.socket.write_all(json.as_bytes())?;
+        pings.unanswered.push_back((pings.sent, Instant::now()));
+
+        Ok(())
+    }
+
+    /// Only use the socket for control messages: events are sent over
+    /// the shared-memory ring buffer.
+    pub fn via_shm(mut self) -> Self {
+        self.via_shm = true;
+        self
+    }
+
+    /// Whether the frontend has asked not to receive events, as
+    /// updated by [`Sink::control`].
+    pub fn paused(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
+
+    /// Sends `greeting` to the frontend. Must precede any events.
+    pub fn greet(&mut self, greeting: &api::Greeting) -> Result<(), SinkError> {
+        let json = serde_json::to_string(greeting)? + "\n";
+        self.socket
+            .write_all(json.as_bytes())
+            .map_err(SinkError::DrainIOError)
     }
 }
 
 impl Sink for FrontendSink {
-    fn drain(&mut self, _: TraceData, chunk: api::EventChunk) -> Result<(), SinkError> {
-        let json = serde_json::to_string(&chunk)?
+    fn drain_chunk(&mut self, chunk: &api::EventChunk) -> Result<(), SinkError> {
+        if self.paused.load(Ordering::Relaxed) || self.via_shm {
+            return Ok(());
+        }
+
+        let chunk = match subscribed(chunk, &self.subscription) {
+            Some(chunk) => chunk,
+            // NOTE(return) nothing the frontend subscribed to
+            None => return Ok(()),
+        };
         // reportedly required for async frontends
-        + "\n";
+        let json = serde_json::to_string(&chunk)? + "\n";
 
         self.socket
             .write_all(json.as_bytes())
             .map_err(SinkError::DrainIOError)
     }
 
+    fn control(&mut self) -> Option<api::ControlMessage> {
+        loop {
+            let msg = self.control.try_recv().ok()?;
+            match msg {
+                api::ControlMessage::Pause => self.paused.store(true, Ordering::Relaxed),
+                api::ControlMessage::Resume => self.paused.store(false, Ordering::Relaxed),
+                _ => (),
+            }
+
+            return Some(msg);
+        }
+    }
+
+    fn check_alive(&mut self) -> Result<(), SinkError> {
+        let reason = match self.dead() {
+            Some(reason) => reason,
+            None => match self.ping() {
+                Ok(()) => return Ok(()),
+                Err(e) => format!("could not be pinged: {}", e),
+            },
+        };
+
+        self.remove_socket();
+        Err(SinkError::FrontendDead(self.name.clone(), reason))
+    }
+
+    fn socket(&self) -> Option<&Path> {
+        self.socket_path.as_deref()
+    }
+
     fn describe(&self) -> String {
-        format!("frontend using socket {:?}", self.socket)
+        format!("frontend {} using socket {:?}", self.name, self.socket)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure a frontend is only dropped once its oldest unanswered
+    /// ping is too old, even if it was answered late, e.g. while the
+    /// run loop did not check on it.
+    #[test]
+    fn pings() {
+        let (backend, mut frontend) = UnixStream::pair().unwrap();
+        let mut sink = FrontendSink::new("test".to_string(), backend, api::Subscription::default())
+            .unwrap()
+            .pinged()
+            .unwrap();
+        let timeout = Duration::from_secs(api::PING_TIMEOUT_SECS);
+        let long_ago = || Instant::now().checked_sub(timeout).unwrap();
+
+        sink.check_alive().unwrap();
+        let mut line = String::new();
+        io::BufReader::new(frontend.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<api::Ping>(&line).unwrap(),
+            api::Ping { ping: 1 }
+        );
+
+        sink.pings.as_mut().unwrap().unanswered[0].1 = long_ago();
+        let pong = serde_json::to_string(&api::ControlMessage::Pong { ping: 1 }).unwrap();
+        writeln!(frontend, "{}", pong).unwrap();
+        while sink.answered.load(Ordering::Relaxed) < 1 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(sink.dead().is_none());
+        assert!(sink.control().is_none());
+
+        sink.check_alive().unwrap();
+        sink.pings.as_mut().unwrap().unanswered[0].1 = long_ago();
+        assert!(sink.check_alive().is_err());
     }
 }
@@ -0,0 +1,75 @@
+//! Drain latency and error metrics of a sink, used to tell which of
+//! several sinks slows down the trace/replay pipeline.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Drains that take longer than this are warned about.
+pub const SLOW_DRAIN: Duration = Duration::from_millis(50);
+
+/// Number of most recent drains the latency percentile is calculated
+/// over.
+const WINDOW: usize = 4096;
+
+/// How often a slow sink is warned about at most.
+const WARN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct SinkMetrics {
+    latencies: VecDeque<Duration>,
+    max: Duration,
+    /// Number of failed drains.
+    pub errors: usize,
+    last_warning: Option<Instant>,
+}
+
+impl SinkMetrics {
+    /// Records the latency of a drain. Returns whether the sink should
+    /// be warned about for draining slowly.
+    pub fn record(&mut self, latency: Duration) -> bool {
+        if self.latencies.len() == WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+        self.max = self.max.max(latency);
+
+        if latency <= SLOW_DRAIN
+            || matches!(self.last_warning, Some(last) if last.elapsed() < WARN_INTERVAL)
+        {
+            return false;
+        }
+        self.last_warning = Some(Instant::now());
+        true
+    }
+
+    /// The nearest-rank 95th percentile of the most recent drain
+    /// latencies.
+    pub fn p95(&self) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<Duration> = self.latencies.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (0.95 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.saturating_sub(1)])
+    }
+
+    /// The longest recorded drain latency.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// E.g. "p95 drain 12 ms (max 40 ms), 3 errors".
+    pub fn summary(&self) -> String {
+        let latency = match self.p95() {
+            Some(p95) => format!(
+                "p95 drain {:.1} ms (max {:.1} ms)",
+                p95.as_secs_f64() * 1e3,
+                self.max.as_secs_f64() * 1e3
+            ),
+            None => "nothing drained".to_string(),
+        };
+
+        format!("{}, {} errors", latency, self.errors)
+    }
+}
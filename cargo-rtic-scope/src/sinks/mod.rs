@@ -1,6 +1,7 @@
 //! A sink to which [`TraceData`] and [`api::EventChunk`]s are for
 //! online and post-mortem analysis.
 use crate::diag;
+use crate::recovery::TraceTrailer;
 use crate::TraceData;
 
 use rtic_scope_api as api;
@@ -26,6 +27,8 @@ pub enum SinkError {
     ResetError(#[from] probe_rs::Error),
     #[error("Failed to setup sink because the source failed: {0}")]
     SourceError(#[from] crate::sources::SourceError),
+    #[error("Frontend {0} {1}")]
+    FrontendDead(String, String),
 }
 
 impl diag::DiagnosableError for SinkError {}
@@ -34,9 +37,75 @@ pub mod file;
 pub use file::FileSink;
 
 mod frontend;
-pub use frontend::FrontendSink;
+pub use frontend::{FrontendSink, PING_INTERVAL};
 
+mod metrics;
+pub use metrics::SinkMetrics;
+
+mod mqtt;
+pub use mqtt::{MqttSink, MqttUrl};
+
+mod snapshot;
+pub use snapshot::SnapshotSink;
+
+mod shm;
+pub use shm::{ShmConsumer, ShmRing, ShmSink};
+
+mod upload;
+pub use upload::{UploadSink, UploadTarget};
+
+/// A sink receives the raw [`TraceData`] read from a source and/or the
+/// [`api::EventChunk`] mapped from it. Both payloads are lent to the
+/// sink: a sink that needs only one of them implements only the
+/// corresponding drain method, and never pays for a copy of the other.
 pub trait Sink {
-    fn drain(&mut self, data: TraceData, chunk: api::EventChunk) -> Result<(), SinkError>;
+    /// Drain raw trace data, as read from the source.
+    fn drain_raw(&mut self, _data: &TraceData) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Drain the RTIC events mapped from the raw trace data.
+    fn drain_chunk(&mut self, _chunk: &api::EventChunk) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Drain the raw trace data, if any, and the RTIC events mapped
+    /// from it. Sinks that select trace data by its events override
+    /// this to see both at once.
+    fn drain(
+        &mut self,
+        data: Option<&TraceData>,
+        chunk: &api::EventChunk,
+    ) -> Result<(), SinkError> {
+        if let Some(data) = data {
+            self.drain_raw(data)?;
+        }
+        self.drain_chunk(chunk)
+    }
+
+    /// Drain information that is only known after tracing has ended.
+    /// Called once, after all trace data has been drained.
+    fn drain_trailer(&mut self, _trailer: &TraceTrailer) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// The next control message received from the other end of the
+    /// sink, if any. Never blocks.
+    fn control(&mut self) -> Option<api::ControlMessage> {
+        None
+    }
+
+    /// Checks whether the other end of the sink is still alive, e.g.
+    /// by pinging it. Called every [`PING_INTERVAL`]; the sink is
+    /// dropped on error.
+    fn check_alive(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// The socket the other end of the sink listens on, if any.
+    fn socket(&self) -> Option<&std::path::Path> {
+        None
+    }
+
     fn describe(&self) -> String;
 }
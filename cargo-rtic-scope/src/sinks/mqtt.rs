@@ -0,0 +1,476 @@
+//! Sink which publishes to an MQTT broker (see `--mqtt`), e.g. for the
+//! dashboards of a test farm. The metadata of the trace is published
+//! (retained) to `<prefix>/metadata` on each connect, and each
+//! [`api::EventChunk`] to `<prefix>/events` as JSON; or, if aggregated
+//! (see `--mqtt-interval`), the number of events of each kind to
+//! `<prefix>/stats` once per interval. The trailer is published to
+//! `<prefix>/trailer` at the end of the session.
+//!
+//! Messages are published with QoS 0 over MQTT 3.1.1, by a worker
+//! thread that is fed by a queue of at most [`QUEUE_LEN`] messages, such
+//! that the broker never stalls the session. The broker is reconnected
+//! to with an exponential backoff if the connection is lost; messages
+//! published while disconnected, or while the queue is full, are
+//! dropped, such that an outage of the broker never ends the session.
+use crate::log;
+use crate::recovery::TraceTrailer;
+use crate::sinks::{Sink, SinkError};
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel as channel;
+use indexmap::IndexMap;
+use rtic_scope_api as api;
+use serde_json::json;
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_PREFIX: &str = "rtic-scope";
+
+/// How long connecting, and each write, may take before the broker is
+/// considered unreachable.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Delay before the first reconnect attempt, doubled after each failed
+/// attempt up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The most messages queued for the worker thread.
+const QUEUE_LEN: usize = 1024;
+
+/// A broker and the prefix of the topics published to, given as
+/// `mqtt://host[:port][/prefix]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttUrl {
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+}
+
+impl FromStr for MqttUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("mqtt://")
+            .ok_or_else(|| format!("{} is not an mqtt:// URL", s))?;
+        let (authority, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("invalid port {:?} of {}", port, s))?,
+            ),
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(format!("{} has no host", s));
+        }
+        let prefix = prefix.trim_end_matches('/');
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            prefix: if prefix.is_empty() {
+                DEFAULT_PREFIX.to_string()
+            } else {
+                prefix.to_string()
+            },
+        })
+    }
+}
+
+/// Encodes an MQTT control packet of the given type and flags.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+
+    packet
+}
+
+/// Encodes a length-prefixed UTF-8 string.
+fn string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// A CONNECT packet of a clean session without keep-alive.
+fn connect(client_id: &str) -> Vec<u8> {
+    let mut body = vec![];
+    string(&mut body, "MQTT");
+    body.extend_from_slice(&[4, 0x02, 0, 0]);
+    string(&mut body, client_id);
+
+    packet(0x10, &body)
+}
+
+/// A PUBLISH packet of QoS 0.
+fn publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = vec![];
+    string(&mut body, topic);
+    body.extend_from_slice(payload);
+
+    packet(0x30 | retain as u8, &body)
+}
+
+/// The number of events of each kind over an interval.
+#[derive(Default)]
+struct Summary {
+    chunks: usize,
+    events: IndexMap<&'static str, usize>,
+    /// Offset from target reset of the last chunk.
+    offset: Duration,
+}
+
+/// The connection to the broker, owned by the worker thread.
+struct Connection {
+    url: MqttUrl,
+    stream: Option<TcpStream>,
+    /// The serialized metadata of the trace.
+    metadata: Vec<u8>,
+    /// When to next attempt to connect, and the backoff after.
+    retry: (Instant, Duration),
+    /// Whether the broker was ever reached.
+    reached: bool,
+    /// Number of messages dropped, shared with the sink.
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Connection {
+    fn new(url: MqttUrl, metadata: Vec<u8>, dropped: Arc<AtomicUsize>) -> Self {
+        Self {
+            url,
+            stream: None,
+            metadata,
+            retry: (Instant::now(), MIN_BACKOFF),
+            reached: false,
+            dropped,
+        }
+    }
+
+    fn try_connect(&self) -> io::Result<TcpStream> {
+        let addr = (self.url.host.as_str(), self.url.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        stream.write_all(&connect(&format!("rtic-scope-{}", std::process::id())))?;
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        if connack[0] != 0x20 || connack[3] != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("broker refused the connection (code {})", connack[3]),
+            ));
+        }
+        stream.write_all(&publish(
+            &format!("{}/metadata", self.url.prefix),
+            &self.metadata,
+            true,
+        ))?;
+
+        Ok(stream)
+    }
+
+    /// Whether connected to the broker, reconnecting if the backoff has
+    /// passed.
+    fn connected(&mut self) -> bool {
+        if self.stream.is_some() {
+            return true;
+        }
+        let (at, backoff) = self.retry;
+        if Instant::now() < at {
+            return false;
+        }
+
+        match self.try_connect() {
+            Ok(stream) => {
+                let dropped = self.dropped.load(Ordering::Relaxed);
+                if dropped > 0 {
+                    log::status(
+                        "Reconnected",
+                        format!(
+                            "to MQTT broker {}:{}; {} message(s) were dropped",
+                            self.url.host, self.url.port, dropped
+                        ),
+                    );
+                }
+                self.stream = Some(stream);
+                self.retry = (Instant::now(), MIN_BACKOFF);
+                self.reached = true;
+                true
+            }
+            Err(e) => {
+                if !self.reached && backoff == MIN_BACKOFF {
+                    log::warn(format!(
+                        "cannot reach MQTT broker {}:{}: {}; retrying during the session",
+                        self.url.host, self.url.port, e
+                    ));
+                }
+                crate::log::debug!("failed to connect to MQTT broker: {}", e);
+                self.retry = (Instant::now() + backoff, (backoff * 2).min(MAX_BACKOFF));
+                false
+            }
+        }
+    }
+
+    /// Publishes `payload` to `<prefix>/<topic>`, unless disconnected.
+    fn publish(&mut self, topic: &str, payload: &[u8]) {
+        if !self.connected() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let packet = publish(&format!("{}/{}", self.url.prefix, topic), payload, false);
+        if let Err(e) = self.stream.as_mut().unwrap().write_all(&packet) {
+            log::warn(format!(
+                "lost connection to MQTT broker {}:{}: {}; reconnecting",
+                self.url.host, self.url.port, e
+            ));
+            self.stream = None;
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            self.retry = (Instant::now() + MIN_BACKOFF, MIN_BACKOFF * 2);
+        }
+    }
+
+    /// Publishes the queued messages until the sink is dropped, then
+    /// disconnects.
+    fn run(mut self, queue: channel::Receiver<(&'static str, Vec<u8>)>) {
+        self.connected();
+        for (topic, payload) in queue {
+            self.publish(topic, &payload);
+        }
+        if let Some(mut stream) = self.stream.take() {
+            // NOTE(ok) the session ends regardless
+            let _ = stream.write_all(&packet(0xe0, &[]));
+        }
+    }
+}
+
+pub struct MqttSink {
+    url: MqttUrl,
+    /// Messages to publish, by topic.
+    queue: Option<channel::Sender<(&'static str, Vec<u8>)>>,
+    worker: Option<JoinHandle<()>>,
+    /// How often the summary is published, if aggregated.
+    interval: Option<Duration>,
+    summary: Summary,
+    /// When the summary was last published.
+    published: Instant,
+    /// Number of messages dropped while disconnected, or while the
+    /// queue is full.
+    dropped: Arc<AtomicUsize>,
+}
+
+impl MqttSink {
+    /// Publishes to the broker of `url`, to which a connection is
+    /// attempted immediately by the worker thread. Chunks are
+    /// aggregated per `interval`, if given.
+    pub fn new(
+        url: MqttUrl,
+        greeting: &api::Greeting,
+        interval: Option<Duration>,
+    ) -> Result<Self, SinkError> {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let connection =
+            Connection::new(url.clone(), serde_json::to_vec(greeting)?, dropped.clone());
+        let (tx, rx) = channel::bounded(QUEUE_LEN);
+        let worker = std::thread::spawn(move || connection.run(rx));
+
+        Ok(Self {
+            url,
+            queue: Some(tx),
+            worker: Some(worker),
+            interval,
+            summary: Summary::default(),
+            published: Instant::now(),
+            dropped,
+        })
+    }
+
+    /// Queues `payload` to be published to `<prefix>/<topic>`, unless
+    /// the queue is full.
+    fn publish(&mut self, topic: &'static str, payload: Vec<u8>) {
+        if let Some(queue) = &self.queue {
+            if queue.try_send((topic, payload)).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Publishes the summary of the interval, and starts the next.
+    fn publish_summary(&mut self) {
+        let summary = std::mem::take(&mut self.summary);
+        let payload = json!({
+            "chunks": summary.chunks,
+            "events": summary.events,
+            "offset": summary.offset.as_secs_f64(),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+        });
+        self.publish("stats", payload.to_string().into_bytes());
+        self.published = Instant::now();
+    }
+}
+
+impl Sink for MqttSink {
+    fn drain_chunk(&mut self, chunk: &api::EventChunk) -> Result<(), SinkError> {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => {
+                self.publish("events", serde_json::to_vec(chunk)?);
+                return Ok(());
+            }
+        };
+
+        self.summary.chunks += 1;
+        self.summary.offset = crate::timestamps::offset(&chunk.timestamp);
+        for event in chunk.events.iter() {
+            *self
+                .summary
+                .events
+                .entry(crate::query::kind(event))
+                .or_default() += 1;
+        }
+        if self.published.elapsed() >= interval {
+            self.publish_summary();
+        }
+
+        Ok(())
+    }
+
+    fn drain_trailer(&mut self, trailer: &TraceTrailer) -> Result<(), SinkError> {
+        if self.interval.is_some() {
+            self.publish_summary();
+        }
+        self.publish("trailer", serde_json::to_vec(trailer)?);
+
+        // Let the worker publish the queued messages and disconnect.
+        // While the broker is unreachable, the queue is dropped within
+        // a connect attempt.
+        self.queue = None;
+        if let Some(worker) = self.worker.take() {
+            // NOTE(ok) the session ends regardless
+            let _ = worker.join();
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "MQTT sink: mqtt://{}:{}/{}",
+            self.url.host, self.url.port, self.url.prefix
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure URLs are parsed with their defaults, and that control
+    /// packets encode their remaining length.
+    #[test]
+    fn encode() {
+        assert_eq!(
+            "mqtt://farm.local:1884/rig-3/".parse(),
+            Ok(MqttUrl {
+                host: "farm.local".to_string(),
+                port: 1884,
+                prefix: "rig-3".to_string(),
+            })
+        );
+        let url: MqttUrl = "mqtt://broker".parse().unwrap();
+        assert_eq!((url.port, url.prefix.as_str()), (1883, "rtic-scope"));
+        for malformed in ["http://broker", "mqtt://", "mqtt://broker:x/a"] {
+            assert!(malformed.parse::<MqttUrl>().is_err(), "{}", malformed);
+        }
+
+        assert_eq!(packet(0xe0, &[]), [0xe0, 0]);
+        assert_eq!(&packet(0x30, &[0; 321])[..3], [0x30, 0xc1, 0x02]);
+        assert_eq!(
+            publish("a/b", b"{}", true),
+            [0x31, 7, 0, 3, b'a', b'/', b'b', b'{', b'}']
+        );
+        assert_eq!(connect("id")[..2], [0x10, 14]);
+    }
+
+    /// Ensure the broker is reconnected to after the backoff, which is
+    /// doubled after each failed attempt and reset once connected, and
+    /// that a lost connection is noticed.
+    #[test]
+    fn reconnect() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = std::thread::spawn(move || {
+            // Refuse the first connection, and accept the second.
+            for code in [5, 0] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut connect = [0; 2];
+                stream.read_exact(&mut connect).unwrap();
+                stream
+                    .read_exact(&mut vec![0; connect[1] as usize])
+                    .unwrap();
+                stream.write_all(&[0x20, 2, 0, code]).unwrap();
+                if code == 0 {
+                    let mut metadata = [0; 1];
+                    stream.read_exact(&mut metadata).unwrap();
+                    return metadata[0];
+                }
+            }
+            unreachable!();
+        });
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let url = MqttUrl {
+            host: "127.0.0.1".to_string(),
+            port,
+            prefix: "rig".to_string(),
+        };
+        let mut connection = Connection::new(url, b"{}".to_vec(), dropped.clone());
+        assert!(!connection.connected());
+        assert_eq!(connection.retry.1, MIN_BACKOFF * 2);
+        // No attempt is made during the backoff.
+        assert!(!connection.connected());
+        assert_eq!(connection.retry.1, MIN_BACKOFF * 2);
+        connection.publish("events", b"{}");
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+        connection.retry.0 = Instant::now();
+        assert!(connection.connected());
+        assert_eq!(connection.retry.1, MIN_BACKOFF);
+        // The retained metadata is published on connect.
+        assert_eq!(broker.join().unwrap(), 0x31);
+
+        // The broker has closed the connection.
+        for _ in 0..100 {
+            connection.publish("events", b"{}");
+            if connection.stream.is_none() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(connection.stream.is_none());
+        assert_eq!(connection.retry.1, MIN_BACKOFF * 2);
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+}
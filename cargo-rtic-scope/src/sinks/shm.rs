@@ -0,0 +1,368 @@
+//! Sink which serializes each [`api::EventChunk`] once to a
+//! shared-memory ring buffer, from which any number of frontends read
+//! at their own pace. See [`api::shm`] for the layout of the buffer.
+use crate::recovery::TraceTrailer;
+use crate::sinks::frontend::subscribed;
+use crate::sinks::{Sink, SinkError};
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use rtic_scope_api::{self as api, shm};
+
+/// A shared-memory ring buffer backed by a memfd, which is inherited by
+/// the frontends spawned after it is created.
+pub struct ShmRing {
+    fd: RawFd,
+    ptr: *mut u8,
+    len: usize,
+    /// Size of the data area, in bytes.
+    capacity: u64,
+    /// Number of read cursors.
+    consumers: usize,
+}
+
+impl ShmRing {
+    /// Creates a ring buffer of `capacity` bytes with a read cursor for
+    /// each of `consumers` frontends.
+    pub fn new(capacity: u64, consumers: usize) -> io::Result<Self> {
+        if consumers > shm::MAX_CONSUMERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "at most {} frontends can read a ring buffer",
+                    shm::MAX_CONSUMERS
+                ),
+            ));
+        }
+
+        // NOTE(flags) no MFD_CLOEXEC: the descriptor must be inherited
+        // by the frontends.
+        let fd = memfd_create(
+            &CString::new("rtic-scope").unwrap(),
+            MemFdCreateFlag::empty(),
+        )
+        .map_err(io::Error::from)?;
+        let len = shm::data_offset(consumers) + capacity as usize;
+        nix::unistd::ftruncate(fd, len as i64).map_err(io::Error::from)?;
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                fd,
+                0,
+            )
+        }
+        .map_err(io::Error::from)? as *mut u8;
+
+        // NOTE the memfd is zero-initialized: head, tail, and all
+        // cursors start at 0.
+        let ring = Self {
+            fd,
+            ptr,
+            len,
+            capacity,
+            consumers,
+        };
+        unsafe {
+            (ring.ptr.add(shm::MAGIC_OFFSET) as *mut u32).write(shm::MAGIC);
+            (ring.ptr.add(shm::VERSION_OFFSET) as *mut u32).write(shm::VERSION);
+        }
+        ring.word(shm::CAPACITY_OFFSET)
+            .store(capacity, Ordering::Release);
+        ring.word(shm::CONSUMERS_OFFSET)
+            .store(consumers as u64, Ordering::Release);
+
+        Ok(ring)
+    }
+
+    /// The file descriptor of the buffer, to be handed to frontends via
+    /// [`api::shm::FD_ENV`].
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// The 64-bit header field at `offset`.
+    fn word(&self, offset: usize) -> &AtomicU64 {
+        debug_assert!(offset % 8 == 0 && offset + 8 <= shm::data_offset(self.consumers));
+        unsafe { &*(self.ptr.add(offset) as *const AtomicU64) }
+    }
+
+    /// The read position of `consumer`.
+    fn cursor(&self, consumer: usize) -> u64 {
+        self.word(shm::CURSORS_OFFSET + 8 * consumer)
+            .load(Ordering::Acquire)
+    }
+
+    /// Copies `bytes` to the data area from position `pos`, wrapping
+    /// around its end.
+    fn write(&self, pos: u64, bytes: &[u8]) {
+        let data = shm::data_offset(self.consumers);
+        let start = (pos % self.capacity) as usize;
+        let first = bytes.len().min(self.capacity as usize - start);
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.add(data + start), first);
+            std::ptr::copy_nonoverlapping(
+                bytes[first..].as_ptr(),
+                self.ptr.add(data),
+                bytes.len() - first,
+            );
+        }
+    }
+}
+
+impl Drop for ShmRing {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.ptr as *mut _, self.len);
+        }
+        let _ = nix::unistd::close(self.fd);
+    }
+}
+
+/// A frontend reading the ring buffer.
+pub struct ShmConsumer {
+    /// Index of the read cursor of the frontend.
+    pub cursor: usize,
+    /// Name of the frontend, as given to `--frontend`.
+    pub name: String,
+    pub subscription: api::Subscription,
+    /// Whether the frontend has asked not to receive events; see
+    /// [`FrontendSink::paused`](crate::sinks::FrontendSink::paused).
+    pub paused: Arc<AtomicBool>,
+}
+
+pub struct ShmSink {
+    ring: ShmRing,
+    /// Positions and masks of the records in the buffer, oldest first.
+    records: VecDeque<(u64, u64)>,
+    head: u64,
+    tail: u64,
+    /// The frontends reading the buffer, and how many records each has
+    /// missed.
+    consumers: Vec<(ShmConsumer, usize)>,
+}
+
+impl ShmSink {
+    /// Writes to `ring`, read by the given frontends.
+    pub fn new(ring: ShmRing, consumers: Vec<ShmConsumer>) -> Self {
+        Self {
+            ring,
+            records: VecDeque::new(),
+            head: 0,
+            tail: 0,
+            consumers: consumers
+                .into_iter()
+                .map(|consumer| (consumer, 0))
+                .collect(),
+        }
+    }
+
+    /// Writes `chunk` as a single record for the cursors of `mask`.
+    fn write_record(&mut self, chunk: &api::EventChunk, mask: u64) -> Result<(), SinkError> {
+        let json = serde_json::to_vec(chunk)?;
+        let len = (shm::RECORD_HEADER_SIZE + json.len()) as u64;
+        if len > self.ring.capacity {
+            return Err(SinkError::DrainIOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a chunk of {} bytes does not fit in the ring buffer of {} bytes",
+                    len, self.ring.capacity
+                ),
+            )));
+        }
+
+        // Make room by evicting the oldest records, and account for
+        // those a frontend had not read yet. The tail is published
+        // before the records are overwritten, so that readers detect
+        // that they have been lapped.
+        let mut evicted = false;
+        while self.head + len - self.tail > self.ring.capacity {
+            let (start, mask) = self.records.pop_front().unwrap();
+            for (consumer, missed) in self.consumers.iter_mut() {
+                if mask & 1 << consumer.cursor != 0 && self.ring.cursor(consumer.cursor) <= start {
+                    *missed += 1;
+                }
+            }
+            self.tail = self.records.front().map_or(self.head, |(start, _)| *start);
+            evicted = true;
+        }
+        if evicted {
+            self.ring
+                .word(shm::TAIL_OFFSET)
+                .store(self.tail, Ordering::Release);
+        }
+
+        self.ring
+            .write(self.head, &(json.len() as u32).to_le_bytes());
+        self.ring.write(self.head + 4, &mask.to_le_bytes());
+        self.ring
+            .write(self.head + shm::RECORD_HEADER_SIZE as u64, &json);
+        self.records.push_back((self.head, mask));
+        self.head += len;
+        self.ring
+            .word(shm::HEAD_OFFSET)
+            .store(self.head, Ordering::Release);
+
+        Ok(())
+    }
+}
+
+impl Sink for ShmSink {
+    fn drain_chunk(&mut self, chunk: &api::EventChunk) -> Result<(), SinkError> {
+        // Serialize the chunk once per distinct subscription of the
+        // frontends that are not paused.
+        let mut subscriptions: Vec<(api::Subscription, u64)> = vec![];
+        for (consumer, _) in self
+            .consumers
+            .iter()
+            .filter(|(consumer, _)| !consumer.paused.load(Ordering::Relaxed))
+        {
+            match subscriptions
+                .iter_mut()
+                .find(|(subscription, _)| *subscription == consumer.subscription)
+            {
+                Some((_, mask)) => *mask |= 1 << consumer.cursor,
+                None => subscriptions.push((consumer.subscription, 1 << consumer.cursor)),
+            }
+        }
+
+        for (subscription, mask) in subscriptions {
+            if let Some(chunk) = subscribed(chunk, &subscription) {
+                self.write_record(&chunk, mask)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn drain_trailer(&mut self, _trailer: &TraceTrailer) -> Result<(), SinkError> {
+        self.ring
+            .word(shm::CLOSED_OFFSET)
+            .store(1, Ordering::Release);
+        for (consumer, missed) in self.consumers.iter().filter(|(_, missed)| *missed > 0) {
+            crate::log::warn(format!(
+                "frontend {} fell behind the shared memory ring buffer and missed {} chunks; consider a larger --shm-ring",
+                consumer.name, missed
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "shared memory ring buffer ({} bytes, {} frontends)",
+            self.ring.capacity,
+            self.consumers.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::chunk;
+    use std::time::Duration;
+
+    fn consumer(cursor: usize, subscription: api::Subscription) -> ShmConsumer {
+        ShmConsumer {
+            cursor,
+            name: cursor.to_string(),
+            subscription,
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The offsets of the chunks `reader` has not read yet, in
+    /// microseconds.
+    fn read(reader: &mut shm::Reader) -> Vec<u64> {
+        std::iter::from_fn(|| reader.next())
+            .map(|json| {
+                let chunk: api::EventChunk = serde_json::from_slice(&json).unwrap();
+                crate::timestamps::offset(&chunk.timestamp).as_micros() as u64
+            })
+            .collect()
+    }
+
+    /// Ensure records a frontend has fallen behind on are evicted and
+    /// accounted as missed, and that records that wrap around the end
+    /// of the data area are read back whole.
+    #[test]
+    fn eviction() {
+        let us = Duration::from_micros;
+        let size =
+            serde_json::to_vec(&chunk(us(100), vec![])).unwrap().len() + shm::RECORD_HEADER_SIZE;
+        let ring = ShmRing::new(size as u64 * 5 / 2, 2).unwrap();
+        let ptr = ring.ptr;
+        let mut sink = ShmSink::new(
+            ring,
+            vec![
+                consumer(0, api::Subscription::default()),
+                consumer(1, api::Subscription::default()),
+            ],
+        );
+        let mut fast = unsafe { shm::Reader::new(ptr, 0) }.unwrap();
+        let mut slow = unsafe { shm::Reader::new(ptr, 1) }.unwrap();
+
+        // NOTE(wrap) the third and eighth records wrap around the end
+        for micros in 100..108 {
+            sink.drain_chunk(&chunk(us(micros), vec![])).unwrap();
+            assert_eq!(read(&mut fast), [micros]);
+        }
+        // only the last two records fit
+        assert_eq!(read(&mut slow), [106, 107]);
+        assert_eq!(sink.consumers[0].1, 0);
+        assert_eq!(sink.consumers[1].1, 6);
+
+        assert!(!fast.closed());
+        sink.drain_trailer(&TraceTrailer::default()).unwrap();
+        assert!(fast.closed());
+    }
+
+    /// Ensure frontends only read the events they subscribed to, and
+    /// nothing while paused.
+    #[test]
+    fn subscriptions() {
+        let us = Duration::from_micros;
+        let ring = ShmRing::new(4096, 3).unwrap();
+        let ptr = ring.ptr;
+        let overflows = api::Subscription {
+            overflow: true,
+            ..api::Subscription::default()
+        };
+        let no_overflows = api::Subscription {
+            overflow: false,
+            ..api::Subscription::default()
+        };
+        let consumers = vec![
+            consumer(0, overflows),
+            consumer(1, no_overflows),
+            consumer(2, overflows),
+        ];
+        let paused = consumers[2].paused.clone();
+        let mut sink = ShmSink::new(ring, consumers);
+        let mut readers: Vec<shm::Reader> = (0..3)
+            .map(|i| unsafe { shm::Reader::new(ptr, i) }.unwrap())
+            .collect();
+
+        paused.store(true, Ordering::Relaxed);
+        sink.drain_chunk(&chunk(us(100), vec![api::EventType::Overflow]))
+            .unwrap();
+        paused.store(false, Ordering::Relaxed);
+        sink.drain_chunk(&chunk(us(200), vec![api::EventType::Overflow]))
+            .unwrap();
+
+        let read: Vec<Vec<u64>> = readers.iter_mut().map(read).collect();
+        assert_eq!(read, [vec![100, 200], vec![], vec![200]]);
+        assert_eq!(sink.records.len(), 2);
+    }
+}
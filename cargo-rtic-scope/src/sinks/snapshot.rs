@@ -0,0 +1,140 @@
+//! A sink which keeps the most recent [`TraceData`] in memory and only
+//! forwards it to an inner sink when a trigger fires. Used to record
+//! only the trace leading up to a rare event instead of the complete
+//! session.
+use crate::crash;
+use crate::recovery::TraceTrailer;
+use crate::sinks::{Sink, SinkError};
+use crate::TraceData;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use nix::sys::signal::{self, SigHandler, Signal};
+
+/// Set by the SIGQUIT (Ctrl-\) handler and the control socket.
+static TRIGGERED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sigquit(_: nix::libc::c_int) {
+    TRIGGERED.store(true, Ordering::SeqCst);
+}
+
+pub struct SnapshotSink<S: Sink> {
+    inner: S,
+    window: Duration,
+    buffer: VecDeque<TraceData>,
+    snapshots: usize,
+    socket: Option<PathBuf>,
+}
+
+/// Binds the control socket at `path`, on which each `snapshot` line
+/// written (e.g. by `echo snapshot | socat - UNIX-CONNECT:<path>`)
+/// triggers a snapshot.
+fn listen(path: &Path) -> Result<(), SinkError> {
+    let listener = UnixListener::bind(path).map_err(|e| {
+        SinkError::SetupIOError(Some(format!("Failed to bind {}", path.display())), e)
+    })?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            for line in BufReader::new(stream).lines().map_while(Result::ok) {
+                match line.trim() {
+                    "snapshot" => TRIGGERED.store(true, Ordering::SeqCst),
+                    "" => (),
+                    command => crate::log::warn(format!(
+                        "unknown snapshot control command {:?}; expected snapshot",
+                        command
+                    )),
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+impl<S: Sink> SnapshotSink<S> {
+    /// Wraps `inner`, buffering the trace data of the last `window` of
+    /// trace time. The buffer is flushed to `inner` on SIGQUIT (Ctrl-\),
+    /// on a `snapshot` command written to the control `socket`, if any,
+    /// or when the target enters the HardFault handler (e.g. after a
+    /// failed assertion).
+    pub fn new(inner: S, window: Duration, socket: Option<PathBuf>) -> Result<Self, SinkError> {
+        // NOTE(unsafe) the handler only stores to an atomic
+        let handler = SigHandler::Handler(handle_sigquit);
+        unsafe { signal::signal(Signal::SIGQUIT, handler) }.map_err(|e| {
+            SinkError::SetupIOError(
+                Some("Failed to install SIGQUIT handler".to_string()),
+                e.into(),
+            )
+        })?;
+        if let Some(socket) = &socket {
+            listen(socket)?;
+        }
+
+        Ok(Self {
+            inner,
+            window,
+            buffer: VecDeque::new(),
+            snapshots: 0,
+            socket,
+        })
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        for data in self.buffer.drain(..) {
+            self.inner.drain_raw(&data)?;
+        }
+        self.snapshots += 1;
+
+        Ok(())
+    }
+}
+
+impl<S: Sink> Sink for SnapshotSink<S> {
+    fn drain_raw(&mut self, data: &TraceData) -> Result<(), SinkError> {
+        let now = crate::timestamps::offset(&data.timestamp);
+        while let Some(oldest) = self.buffer.front() {
+            if now.saturating_sub(crate::timestamps::offset(&oldest.timestamp)) <= self.window {
+                break;
+            }
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(data.clone());
+
+        if TRIGGERED.swap(false, Ordering::SeqCst) || crash::hard_fault_entered(data) {
+            crate::log::status(
+                "Snapshot",
+                format!("triggered; flushing {} buffered chunks", self.buffer.len()),
+            );
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn drain_trailer(&mut self, trailer: &TraceTrailer) -> Result<(), SinkError> {
+        if let Some(socket) = &self.socket {
+            let _ = fs::remove_file(socket);
+        }
+        if self.snapshots == 0 {
+            crate::log::warn(
+                "snapshot never triggered; trace file contains no trace data".to_string(),
+            );
+        }
+
+        self.inner.drain_trailer(trailer)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "snapshot ({}s) of {}",
+            self.window.as_secs_f64(),
+            self.inner.describe()
+        )
+    }
+}
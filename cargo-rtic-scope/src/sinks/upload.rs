@@ -0,0 +1,512 @@
+//! Sink which uploads the trace file as it is recorded (see `trace
+//! --upload`), e.g. for soak tests on remote rigs. The trace file is
+//! split into segments of about `--upload-segment` bytes, or of the
+//! trace data of [`SEGMENT_INTERVAL`], whichever is smaller. Each
+//! segment is compressed as a gzip member and PUT to
+//! `<name>/segment-<n>.trace.gz`; the segments decompressed in order
+//! (e.g. `cat segment-*.trace.gz | gunzip`) are the trace file. At the
+//! end of the session, a manifest listing the segments is PUT to
+//! `<name>/manifest.json`.
+//!
+//! Segments are compressed and uploaded via the `curl` executable on a
+//! thread of their own, such that the session is never blocked by the
+//! endpoint. At most [`MAX_HELD`] recorded segments are held in memory
+//! until uploaded; further segments are spilled to disk. Failed uploads
+//! are retried with an exponential backoff, and given up on after
+//! [`ATTEMPTS`] attempts: the trace file recorded locally still holds
+//! them. The end of the session waits at most [`GRACE`] for the pending
+//! uploads and the manifest.
+use crate::log;
+use crate::recovery::{TraceMetadata, TraceTrailer};
+use crate::sinks::file::{frame, trailer_bytes, TRACE_MAGIC};
+use crate::sinks::{Sink, SinkError};
+use crate::TraceData;
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel as channel;
+use serde::Serialize;
+
+/// The longest span of a segment, such that the trace is shipped
+/// steadily at low data rates.
+pub const SEGMENT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many times an upload is attempted.
+const ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of an upload, doubled after each.
+const BACKOFF: Duration = Duration::from_secs(2);
+
+/// The most recorded segments held in memory until uploaded.
+const MAX_HELD: usize = 2;
+
+/// How long the end of the session waits for the pending uploads.
+const GRACE: Duration = Duration::from_secs(10);
+
+/// Where segments are uploaded to.
+#[derive(Debug, Clone)]
+pub struct UploadTarget {
+    /// URL objects are PUT to, with the name of the object appended to
+    /// the path. Has no query: presigned URLs, which are signed per
+    /// object, are not supported.
+    pub url: String,
+    /// Sign requests with AWS Signature Version 4 for S3-compatible
+    /// stores, as `--aws-sigv4` of `curl`, e.g. `aws:amz:eu-north-1:s3`.
+    /// The credentials are read from `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`.
+    pub sigv4: Option<String>,
+}
+
+impl UploadTarget {
+    /// The URL of the object `name`.
+    fn url(&self, name: &str) -> String {
+        format!("{}/{}", self.url.trim_end_matches('/'), name)
+    }
+
+    /// PUTs `body` as the object `name`.
+    fn put(&self, name: &str, body: &[u8], content_type: &str) -> io::Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(body)?;
+
+        let mut curl = Command::new("curl");
+        curl.args(["--fail", "--silent", "--show-error", "--upload-file"])
+            .arg(file.path())
+            .arg("--header")
+            .arg(format!("Content-Type: {}", content_type));
+        let user = match &self.sigv4 {
+            Some(sigv4) => {
+                let var = |name: &str| {
+                    std::env::var(name).map_err(|_| {
+                        io::Error::new(io::ErrorKind::NotFound, format!("{} is not set", name))
+                    })
+                };
+                // NOTE the credentials are passed on stdin such that
+                // they are not in the arguments of the process.
+                curl.arg("--aws-sigv4").arg(sigv4).args(["--config", "-"]);
+                Some(format!(
+                    "user = \"{}:{}\"\n",
+                    var("AWS_ACCESS_KEY_ID")?,
+                    var("AWS_SECRET_ACCESS_KEY")?
+                ))
+            }
+            None => None,
+        };
+        let mut child = curl
+            .arg(self.url(name))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let (Some(user), Some(mut stdin)) = (user, child.stdin.take()) {
+            stdin.write_all(user.as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// PUTs `body` as the object `name`, retrying with backoff.
+    fn put_retrying(&self, name: &str, body: &[u8], content_type: &str) -> bool {
+        let mut backoff = BACKOFF;
+        for attempt in 1..=ATTEMPTS {
+            match self.put(name, body, content_type) {
+                Ok(()) => return true,
+                Err(e) if attempt < ATTEMPTS => {
+                    crate::log::debug!("failed to upload {}: {}; retrying", name, e);
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(e) => log::warn(format!(
+                    "failed to upload {} after {} attempts: {}",
+                    name, ATTEMPTS, e
+                )),
+            }
+        }
+
+        false
+    }
+}
+
+/// The CRC-32 (ISO-HDLC) of `data`, as in the gzip trailer.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// `data` compressed as a gzip member.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gz = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    gz.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+    gz.extend_from_slice(&crc32(data).to_le_bytes());
+    gz.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    gz
+}
+
+/// A segment listed in the manifest.
+#[derive(Serialize)]
+struct Segment {
+    name: String,
+    /// Size of the segment before and after compression.
+    bytes: usize,
+    compressed: usize,
+    /// Number of trace data frames of the segment.
+    frames: usize,
+    /// Offsets from target reset of the first and last trace data of
+    /// the segment, if any.
+    offsets: Option<(Duration, Duration)>,
+    uploaded: bool,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    program: String,
+    reset_timestamp: chrono::DateTime<chrono::Local>,
+    segments: Vec<Segment>,
+}
+
+/// The recorded bytes of a segment.
+enum Body {
+    Held(Vec<u8>),
+    /// Spilled to the file at the path.
+    Spilled(PathBuf),
+}
+
+/// Uploads the queued segments until the sender is dropped, and then
+/// the manifest listing them, and reports the uploads. `held` is the
+/// number of segments queued with a held body.
+fn upload(
+    target: UploadTarget,
+    name: String,
+    mut manifest: Manifest,
+    queue: channel::Receiver<(Segment, Body)>,
+    held: Arc<AtomicUsize>,
+) {
+    for (mut segment, body) in queue {
+        let body = match body {
+            Body::Held(body) => {
+                held.fetch_sub(1, Ordering::Relaxed);
+                body
+            }
+            Body::Spilled(path) => match fs::read(&path) {
+                Ok(body) => {
+                    // NOTE(ok) the spill directory is removed at the end
+                    let _ = fs::remove_file(&path);
+                    body
+                }
+                Err(e) => {
+                    log::warn(format!(
+                        "failed to read back {} from {}: {}",
+                        segment.name,
+                        path.display(),
+                        e
+                    ));
+                    manifest.segments.push(segment);
+                    continue;
+                }
+            },
+        };
+        let gz = gzip(&body);
+        segment.compressed = gz.len();
+        segment.uploaded = target.put_retrying(&segment.name, &gz, "application/gzip");
+        manifest.segments.push(segment);
+    }
+
+    let failed = manifest.segments.iter().filter(|s| !s.uploaded).count();
+    let (bytes, compressed) = manifest
+        .segments
+        .iter()
+        .fold((0, 0), |(b, c), s| (b + s.bytes, c + s.compressed));
+    let manifest_name = format!("{}/manifest.json", name);
+    let uploaded = match serde_json::to_vec(&manifest) {
+        Ok(json) => target.put_retrying(&manifest_name, &json, "application/json"),
+        Err(e) => {
+            log::warn(format!("failed to serialize {}: {}", manifest_name, e));
+            false
+        }
+    };
+
+    log::status(
+        "Uploaded",
+        format!(
+            "{} segment(s) of {} ({} B, {} B compressed) to {}{}",
+            manifest.segments.len() - failed,
+            name,
+            bytes,
+            compressed,
+            target.url(&manifest_name),
+            if failed > 0 || !uploaded {
+                format!(
+                    "; {} segment(s){} failed to upload",
+                    failed,
+                    if uploaded { "" } else { " and the manifest" }
+                )
+            } else {
+                "".to_string()
+            }
+        ),
+    );
+}
+
+pub struct UploadSink {
+    target: UploadTarget,
+    /// Name of the trace, under which its objects are uploaded.
+    name: String,
+    /// Size of a segment, in bytes, before compression.
+    segment_size: usize,
+    /// The segment being recorded, and when it was started.
+    buffer: Vec<u8>,
+    frames: usize,
+    offsets: Option<(Duration, Duration)>,
+    started: Instant,
+    /// Number of segments recorded.
+    segments: usize,
+    /// Recorded segments for the uploading thread, which uploads the
+    /// manifest once the sender is dropped.
+    uploads: Option<channel::Sender<(Segment, Body)>>,
+    /// Number of queued segments held in memory.
+    held: Arc<AtomicUsize>,
+    /// Where segments are spilled to, once [`MAX_HELD`] are held.
+    spill: PathBuf,
+    /// Disconnected once the uploading thread is done.
+    done: channel::Receiver<()>,
+}
+
+impl UploadSink {
+    /// Uploads the trace of `metadata` to `target` in segments of about
+    /// `segment_size` bytes.
+    pub fn new(
+        target: UploadTarget,
+        metadata: &TraceMetadata,
+        segment_size: usize,
+    ) -> Result<Self, SinkError> {
+        Command::new("curl")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .status()
+            .map_err(|e| {
+                SinkError::SetupIOError(Some("Failed to run curl to upload with".to_string()), e)
+            })?;
+
+        let reset_timestamp = metadata.reset_timestamp();
+        let name = format!(
+            "{}-{}",
+            metadata.program_name,
+            reset_timestamp.format("%Y-%m-%dT%H-%M-%S")
+        );
+        let spill = tempfile::tempdir().map_err(|e| {
+            SinkError::SetupIOError(
+                Some("Failed to create a directory to spill segments to".to_string()),
+                e,
+            )
+        })?;
+        let manifest = Manifest {
+            program: metadata.program_name.clone(),
+            reset_timestamp,
+            segments: vec![],
+        };
+
+        // NOTE the queue holds at most MAX_HELD segment bodies, the
+        // rest are paths of spilled segments.
+        let (tx, rx) = channel::unbounded();
+        let (done_tx, done) = channel::bounded::<()>(0);
+        let held = Arc::new(AtomicUsize::new(0));
+        let (uploader, n, h) = (target.clone(), name.clone(), held.clone());
+        let spill_path = spill.path().to_owned();
+        std::thread::spawn(move || {
+            upload(uploader, n, manifest, rx, h);
+            drop((spill, done_tx));
+        });
+
+        let mut buffer = TRACE_MAGIC.to_vec();
+        buffer.extend(frame(&serde_json::to_vec(metadata)?));
+
+        Ok(Self {
+            name,
+            target,
+            segment_size,
+            buffer,
+            frames: 0,
+            offsets: None,
+            started: Instant::now(),
+            segments: 0,
+            uploads: Some(tx),
+            held,
+            spill: spill_path,
+            done,
+        })
+    }
+
+    /// Hands the recorded segment to the uploading thread, and starts
+    /// the next.
+    fn flush(&mut self) {
+        let segment = Segment {
+            name: format!("{}/segment-{:05}.trace.gz", self.name, self.segments),
+            bytes: self.buffer.len(),
+            compressed: 0,
+            frames: std::mem::take(&mut self.frames),
+            offsets: self.offsets.take(),
+            uploaded: false,
+        };
+        let buffer = std::mem::take(&mut self.buffer);
+        let body = if self.held.load(Ordering::Relaxed) < MAX_HELD {
+            self.held.fetch_add(1, Ordering::Relaxed);
+            Body::Held(buffer)
+        } else {
+            let path = self.spill.join(format!("segment-{:05}", self.segments));
+            match fs::write(&path, &buffer) {
+                Ok(()) => Body::Spilled(path),
+                Err(e) => {
+                    log::warn(format!(
+                        "failed to spill {} to {}: {}; holding it in memory",
+                        segment.name,
+                        path.display(),
+                        e
+                    ));
+                    self.held.fetch_add(1, Ordering::Relaxed);
+                    Body::Held(buffer)
+                }
+            }
+        };
+        if let Some(tx) = &self.uploads {
+            // NOTE(ok) the thread only ends once the sender is dropped
+            let _ = tx.send((segment, body));
+        }
+        self.segments += 1;
+        self.started = Instant::now();
+    }
+}
+
+impl Sink for UploadSink {
+    fn drain_raw(&mut self, data: &TraceData) -> Result<(), SinkError> {
+        let offset = crate::timestamps::offset(&data.timestamp);
+        self.buffer.extend(frame(&serde_json::to_vec(data)?));
+        self.frames += 1;
+        self.offsets = Some(match self.offsets {
+            Some((first, _)) => (first, offset),
+            None => (offset, offset),
+        });
+
+        if self.buffer.len() >= self.segment_size || self.started.elapsed() >= SEGMENT_INTERVAL {
+            self.flush();
+        }
+
+        Ok(())
+    }
+
+    fn drain_trailer(&mut self, trailer: &TraceTrailer) -> Result<(), SinkError> {
+        self.buffer.extend(trailer_bytes(trailer)?);
+        self.flush();
+
+        // Let the uploading thread upload the manifest once the queued
+        // segments are, but do not hold the end of the session on the
+        // endpoint for long.
+        self.uploads = None;
+        if let Err(channel::RecvTimeoutError::Timeout) = self.done.recv_timeout(GRACE) {
+            log::warn(format!(
+                "gave up waiting for the uploads of {} after {}s; the trace file holds the segments not uploaded",
+                self.name,
+                GRACE.as_secs()
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("upload sink: {}", self.target.url(&self.name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure segments are valid gzip members of the segment, and that
+    /// object URLs are formed from the base URL.
+    #[test]
+    fn segments() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let data = b"RTIC Scope framed trace v1\n".repeat(100);
+        let gz = gzip(&data);
+        assert_eq!(gz[..3], [0x1f, 0x8b, 8]);
+        assert!(gz.len() < data.len());
+        let (body, trailer) = gz[10..].split_at(gz.len() - 18);
+        assert_eq!(miniz_oxide::inflate::decompress_to_vec(body).unwrap(), data);
+        assert_eq!(trailer[..4], crc32(&data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+
+        let target = |url: &str| UploadTarget {
+            url: url.to_string(),
+            sigv4: None,
+        };
+        assert_eq!(
+            target("https://bucket.s3.amazonaws.com/traces/").url("a/manifest.json"),
+            "https://bucket.s3.amazonaws.com/traces/a/manifest.json"
+        );
+        assert_eq!(
+            target("http://localhost:9000/traces").url("a/segment-00000.trace.gz"),
+            "http://localhost:9000/traces/a/segment-00000.trace.gz"
+        );
+    }
+
+    /// Ensure segments beyond those held in memory are spilled to disk.
+    #[test]
+    fn spilled() {
+        let spill = tempfile::tempdir().unwrap();
+        let (tx, rx) = channel::unbounded();
+        let mut sink = UploadSink {
+            target: UploadTarget {
+                url: "http://localhost".to_string(),
+                sigv4: None,
+            },
+            name: "app".to_string(),
+            segment_size: 1,
+            buffer: vec![],
+            frames: 0,
+            offsets: None,
+            started: Instant::now(),
+            segments: 0,
+            uploads: Some(tx),
+            held: Arc::new(AtomicUsize::new(0)),
+            spill: spill.path().to_owned(),
+            done: channel::never(),
+        };
+        for segment in 0..MAX_HELD + 1 {
+            sink.buffer = vec![segment as u8; 4];
+            sink.flush();
+        }
+
+        let bodies: Vec<Body> = rx.try_iter().map(|(_, body)| body).collect();
+        assert_eq!(bodies.len(), MAX_HELD + 1);
+        assert!(bodies[..MAX_HELD]
+            .iter()
+            .all(|body| matches!(body, Body::Held(_))));
+        match &bodies[MAX_HELD] {
+            Body::Spilled(path) => {
+                assert_eq!(fs::read(path).unwrap(), vec![MAX_HELD as u8; 4])
+            }
+            Body::Held(_) => panic!("segment {} was not spilled", MAX_HELD),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Decoding of the byte stream read by a source into [`TraceData`]. The
+//! decoder is given to the source when it is constructed: the command
+//! line selects one of [`DecoderKind`] via `decoder` in
+//! `[package.metadata.rtic-scope]` or `--decoder`; an alternative
+//! decoder implements [`TraceDecoder`].
+use crate::manifest::ManifestProperties;
+use crate::sources::SourceError;
+use crate::TraceData;
+
+use std::io::Read;
+use std::str::FromStr;
+
+use itm::{Decoder, DecoderOptions, MalformedPacket, TimestampsConfiguration};
+use serde::{Deserialize, Serialize};
+
+/// Trace data decoded from a byte stream.
+pub type Packets<'a> = Box<dyn Iterator<Item = Result<TraceData, SourceError>> + Send + 'a>;
+
+/// Turns the byte stream read by a source into timestamped trace
+/// packets.
+pub trait TraceDecoder {
+    /// Decodes `stream` as configured by `opts`. Decoding ends at the
+    /// end of the stream unless `ignore_eof`, in which case more bytes
+    /// are waited for.
+    fn decode<'a>(
+        &self,
+        stream: Box<dyn Read + Send + 'a>,
+        ignore_eof: bool,
+        opts: &ManifestProperties,
+    ) -> Packets<'a>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecoderKind {
+    /// The ITM decoder of the `itm` crate.
+    Itm,
+    /// The ITM decoder, but hardware source packets with a
+    /// discriminator the architecture does not define are dropped
+    /// instead of being malformed. Some silicon emits such
+    /// vendor-specific packets.
+    Vendor,
+}
+
+impl Default for DecoderKind {
+    fn default() -> Self {
+        Self::Itm
+    }
+}
+
+impl FromStr for DecoderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "itm" => Ok(Self::Itm),
+            "vendor" => Ok(Self::Vendor),
+            _ => Err(format!("unknown decoder {}; expected itm or vendor", s)),
+        }
+    }
+}
+
+impl TraceDecoder for DecoderKind {
+    fn decode<'a>(
+        &self,
+        stream: Box<dyn Read + Send + 'a>,
+        ignore_eof: bool,
+        opts: &ManifestProperties,
+    ) -> Packets<'a> {
+        let packets = Decoder::new(stream, DecoderOptions { ignore_eof })
+            .timestamps(TimestampsConfiguration {
+                clock_frequency: opts.tpiu_freq,
+                lts_prescaler: opts.lts_prescaler,
+                expect_malformed: opts.expect_malformed,
+            })
+            .map(|res| res.map_err(SourceError::DecodeError));
+
+        match self {
+            Self::Itm => Box::new(packets),
+            Self::Vendor => Box::new(packets.map(|res| res.map(drop_vendor_packets))),
+        }
+    }
+}
+
+/// Whether `disc_id` is a hardware source packet discriminator defined
+/// by the architecture: event counter wraps, exception trace, PC
+/// sampling, and data trace.
+fn is_architectural(disc_id: u8) -> bool {
+    matches!(disc_id, 0..=2 | 8..=23)
+}
+
+/// Drops the malformed packets of `data` that are vendor-specific
+/// hardware source packets.
+fn drop_vendor_packets(mut data: TraceData) -> TraceData {
+    data.malformed_packets.retain(|malformed| match malformed {
+        MalformedPacket::InvalidHardwarePacket { disc_id, .. } if !is_architectural(*disc_id) => {
+            crate::log::debug!("dropped vendor-specific packet: {:?}", malformed);
+            false
+        }
+        _ => true,
+    });
+
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use itm::Timestamp;
+    use std::time::Duration;
+
+    /// Ensure only hardware source packets with a discriminator the
+    /// architecture does not define are dropped.
+    #[test]
+    fn vendor_packets() {
+        let data = TraceData {
+            timestamp: Timestamp::Sync(Duration::ZERO),
+            packets: vec![],
+            malformed_packets: vec![
+                MalformedPacket::InvalidHardwarePacket {
+                    disc_id: 3,
+                    payload: vec![],
+                },
+                MalformedPacket::InvalidHardwarePacket {
+                    disc_id: 8,
+                    payload: vec![],
+                },
+                MalformedPacket::InvalidHeader(0x7f),
+            ],
+            consumed_packets: 3,
+        };
+
+        let data = drop_vendor_packets(data);
+        assert_eq!(data.malformed_packets.len(), 2);
+        assert!(!data.malformed_packets.iter().any(|malformed| matches!(
+            malformed,
+            MalformedPacket::InvalidHardwarePacket { disc_id: 3, .. }
+        )));
+        assert_eq!("vendor".parse(), Ok(DecoderKind::Vendor));
+        assert!("custom".parse::<DecoderKind>().is_err());
+    }
+
+    /// A decoder that reports the number of bytes in the stream as a
+    /// single chunk.
+    struct Counting;
+
+    impl TraceDecoder for Counting {
+        fn decode<'a>(
+            &self,
+            mut stream: Box<dyn Read + Send + 'a>,
+            _ignore_eof: bool,
+            _opts: &ManifestProperties,
+        ) -> Packets<'a> {
+            let mut bytes = vec![];
+            stream.read_to_end(&mut bytes).unwrap();
+            Box::new(std::iter::once(Ok(TraceData {
+                timestamp: Timestamp::Sync(Duration::ZERO),
+                packets: vec![],
+                malformed_packets: vec![],
+                consumed_packets: bytes.len(),
+            })))
+        }
+    }
+
+    /// Ensure a source decodes its stream with the decoder it is given.
+    #[test]
+    fn custom_decoder() {
+        let src = crate::sources::RawFileSource::from_reader(
+            std::io::Cursor::new(vec![1, 2, 3]),
+            "bytes".to_string(),
+            &ManifestProperties::synthetic(),
+            Box::new(Counting),
+            1,
+            None,
+        )
+        .unwrap();
+        let data: Vec<TraceData> = src.map(Result::unwrap).collect();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].consumed_packets, 2);
+    }
+}
@@ -1,49 +1,388 @@
 //! File source from which serialized [`TraceData`] is read for replay
-//! purposes.
-use crate::recovery::TraceMetadata;
+//! purposes. The trace is either read from a file, or streamed from
+//! stdin (e.g. `ssh rig cat trace | cargo rtic-scope replay --stdin`).
+use crate::recovery::{TraceMetadata, TraceMetadataHeader, TraceTrailer};
+use crate::sinks::file::{index_path, TraceIndex, TRACE_MAGIC};
 use crate::sources::{BufferStatus, Source, SourceError};
+use crate::timestamps::offset;
 use crate::TraceData;
 
 use std::fs;
-use std::io::BufReader;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
 
-/// Something data is deserialized from. Always a file.
+/// Maximum size of a serialized [`TraceTrailer`].
+const TRAILER_MAX_SIZE: u64 = 1 << 16;
+
+/// Something data is deserialized from. A file, or a stream of one.
 pub struct FileSource {
-    reader: BufReader<fs::File>,
+    reader: Lookahead<Box<dyn Read + Send>>,
     metadata: TraceMetadata,
+    /// Whether the file is framed. See [`crate::sinks::file`].
+    framed: bool,
+    /// What the file is read from.
+    description: String,
+    /// Whether the file is streamed, in which case the trailer is not
+    /// seeked for, but is expected at the end of the stream.
+    streamed: bool,
+    /// Offsets from target reset of the first and last trace data to
+    /// read, if bounded.
+    range: (Option<Duration>, Option<Duration>),
+    /// Outages of the trace stream, as recorded in the trailer; see
+    /// [`TraceTrailer::gaps`].
+    gaps: Vec<(Duration, Duration)>,
+    /// Index of the first gap not yet reported.
+    next_gap: usize,
+    /// Duration of the outage preceding the last trace data read.
+    gap: Option<Duration>,
+}
+
+/// Reads the [`TraceTrailer`] of a trace file, if any. Returns the
+/// length of the file preceding the trailer along with the trailer.
+fn read_trailer(fd: &mut fs::File) -> io::Result<(u64, Option<TraceTrailer>)> {
+    let len = fd.seek(SeekFrom::End(0))?;
+    let start = len.saturating_sub(TRAILER_MAX_SIZE);
+    fd.seek(SeekFrom::Start(start))?;
+    let mut tail = vec![];
+    fd.read_to_end(&mut tail)?;
+    fd.seek(SeekFrom::Start(0))?;
+
+    let trailer = tail.strip_suffix(b"\n").and_then(|tail| {
+        let pos = tail.iter().rposition(|b| *b == b'\n')?;
+        let trailer = serde_json::from_slice::<TraceTrailer>(&tail[pos + 1..]).ok()?;
+        Some((start + pos as u64, trailer))
+    });
+
+    Ok(match trailer {
+        Some((len, trailer)) => (len, Some(trailer)),
+        None => (len, None),
+    })
+}
+
+/// Consumes [`TRACE_MAGIC`] from the reader if the file starts with
+/// it. Otherwise, the reader is left untouched. Returns whether the
+/// file is framed.
+fn read_magic<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    let framed = reader.fill_buf()?.starts_with(TRACE_MAGIC);
+    if framed {
+        reader.consume(TRACE_MAGIC.len());
+    }
+
+    Ok(framed)
+}
+
+/// Reads the next frame. Returns `None` on EOF and on a truncated
+/// trailing frame, which is left behind if the writer crashed.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    if reader.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+
+    let mut payload = vec![];
+    let read = reader.read_exact(&mut len[1..]).and_then(|_| {
+        payload.resize(u32::from_le_bytes(len) as usize, 0);
+        reader.read_exact(&mut payload)
+    });
+    match read {
+        Ok(()) => Ok(Some(payload)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            crate::log::warn("skipping truncated trailing frame of trace file".to_string());
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the remaining stream is exactly a [`TraceTrailer`], as
+/// written after the last trace data. Reads ahead until the end of the
+/// trailer, or until it is clear that there is none.
+fn at_trailer<R: Read>(reader: &mut Lookahead<R>) -> io::Result<bool> {
+    if !reader.fill_buf()?.starts_with(b"\n") {
+        return Ok(false);
+    }
+
+    // The trailer is a single line, after which the stream ends: peek
+    // one byte past the line to tell.
+    let line_len = |tail: &[u8]| Some(tail.get(1..)?.iter().position(|b| *b == b'\n')? + 2);
+    let tail = reader.peek(TRAILER_MAX_SIZE as usize + 1, |tail| {
+        line_len(tail).map(|len| len + 1)
+    })?;
+    Ok(line_len(tail) == Some(tail.len()) && serde_json::from_slice::<TraceTrailer>(tail).is_ok())
+}
+
+/// A buffered reader that can peek arbitrarily far ahead, unlike
+/// [`BufReader`], which only buffers what a single read yields.
+struct Lookahead<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Lookahead<R> {
+    const CAPACITY: usize = 8 * 1024;
+
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Peeks at the next bytes, at most `limit` of them, without
+    /// consuming them. Stops early at EOF or once `len` of the bytes
+    /// peeked so far yields the number of bytes to peek.
+    fn peek(&mut self, limit: usize, len: impl Fn(&[u8]) -> Option<usize>) -> io::Result<&[u8]> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let mut want = len(&self.buf).unwrap_or(limit).min(limit);
+        while self.buf.len() < want {
+            let filled = self.buf.len();
+            self.buf.resize(filled + Self::CAPACITY, 0);
+            let read = self.inner.read(&mut self.buf[filled..]);
+            self.buf.truncate(filled + *read.as_ref().unwrap_or(&0));
+            if read? == 0 {
+                break;
+            }
+            want = len(&self.buf).unwrap_or(limit).min(limit);
+        }
+
+        Ok(&self.buf[..want.min(self.buf.len())])
+    }
+}
+
+impl<R: Read> Read for Lookahead<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(out)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for Lookahead<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            self.pos = 0;
+            self.buf.resize(Self::CAPACITY, 0);
+            let read = self.inner.read(&mut self.buf);
+            self.buf.truncate(*read.as_ref().unwrap_or(&0));
+            read?;
+        }
+
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
 }
 
 impl FileSource {
-    pub fn new(fd: fs::File) -> Result<Self, SourceError> {
-        let mut reader = BufReader::new(fd);
-        let metadata = {
-            let mut stream =
-                serde_json::Deserializer::from_reader(&mut reader).into_iter::<TraceMetadata>();
-            if let Some(Ok(metadata)) = stream.next() {
-                metadata
-            } else {
-                return Err(SourceError::SetupError(
-                    "Failed to deserialize metadata header".to_string(),
-                ));
+    pub fn new(mut fd: fs::File) -> Result<Self, SourceError> {
+        let (len, trailer) = read_trailer(&mut fd).map_err(SourceError::SetupIOError)?;
+        let description = format!("{:?}", fd);
+        Self::from_reader(Box::new(fd.take(len)), description, trailer, false)
+    }
+
+    /// Opens the trace file at `path`, of which only the trace data
+    /// between the offsets from target reset `from` and `to` are read.
+    /// The trace data before `from` is seeked past via the index of the
+    /// trace, if it has one, instead of being read.
+    pub fn open(
+        path: &Path,
+        from: Option<Duration>,
+        to: Option<Duration>,
+    ) -> Result<Self, SourceError> {
+        let open = || fs::File::open(path).map_err(SourceError::SetupIOError);
+        let mut src = Self::new(open()?)?.range(from, to);
+
+        let entry = match from {
+            Some(from) if src.framed => TraceIndex::load(&index_path(path))
+                .ok()
+                .and_then(|index| index.seek(from).copied()),
+            _ => None,
+        };
+        if let Some(entry) = entry {
+            let mut fd = open()?;
+            let (len, _) = read_trailer(&mut fd).map_err(SourceError::SetupIOError)?;
+            if entry.position < len {
+                fd.seek(SeekFrom::Start(entry.position))
+                    .map_err(SourceError::SetupIOError)?;
+                src.reader = Lookahead::new(Box::new(fd.take(len - entry.position)));
+                crate::log::debug!(
+                    "seeked past {} trace data frames of {} via its index",
+                    entry.seq,
+                    path.display()
+                );
             }
+        }
+
+        Ok(src)
+    }
+
+    /// Only reads the trace data between the offsets from target reset
+    /// `from` and `to`.
+    pub fn range(mut self, from: Option<Duration>, to: Option<Duration>) -> Self {
+        self.range = (from, to);
+        self
+    }
+
+    /// Streams the trace from stdin. The trailer of the trace, and thus
+    /// its drift estimate, is not available.
+    pub fn stdin() -> Result<Self, SourceError> {
+        Self::from_reader(Box::new(io::stdin()), "stdin".to_string(), None, true)
+    }
+
+    fn from_reader(
+        reader: Box<dyn Read + Send>,
+        description: String,
+        trailer: Option<TraceTrailer>,
+        streamed: bool,
+    ) -> Result<Self, SourceError> {
+        let mut reader = Lookahead::new(reader);
+        let framed = read_magic(&mut reader).map_err(SourceError::SetupIOError)?;
+        let mut metadata = if framed {
+            read_frame(&mut reader)
+                .map_err(SourceError::SetupIOError)?
+                .and_then(|frame| serde_json::from_slice::<TraceMetadata>(&frame).ok())
+        } else {
+            serde_json::Deserializer::from_reader(&mut reader)
+                .into_iter::<TraceMetadata>()
+                .next()
+                .and_then(Result::ok)
+        }
+        .ok_or_else(|| {
+            SourceError::SetupError("Failed to deserialize metadata header".to_string())
+        })?;
+        let mut gaps = vec![];
+        if let Some(trailer) = trailer {
+            gaps = trailer.gaps;
+            metadata.set_drift(trailer.drift);
+            metadata.set_crash(trailer.crash);
+            metadata.set_sync_anchor(trailer.sync_anchor);
+            metadata.set_programmed_comparators(&trailer.comparators);
+        }
+
+        Ok(Self {
+            reader,
+            metadata,
+            framed,
+            description,
+            streamed,
+            range: (None, None),
+            gaps,
+            next_gap: 0,
+            gap: None,
+        })
+    }
+
+    /// Deserializes only the descriptive header of the trace file.
+    /// Considerably faster than [`FileSource::new`] for traces with
+    /// large lookup maps, and never reads past the metadata.
+    pub fn header(fd: fs::File) -> Result<TraceMetadataHeader, SourceError> {
+        let mut reader = BufReader::new(fd);
+        let framed = read_magic(&mut reader).map_err(SourceError::SetupIOError)?;
+        let header = if framed {
+            let mut len = [0; 4];
+            reader
+                .read_exact(&mut len)
+                .map_err(SourceError::SetupIOError)?;
+            serde_json::Deserializer::from_reader(reader.take(u32::from_le_bytes(len).into()))
+                .into_iter::<TraceMetadataHeader>()
+                .next()
+        } else {
+            serde_json::Deserializer::from_reader(reader)
+                .into_iter::<TraceMetadataHeader>()
+                .next()
         };
 
-        Ok(Self { reader, metadata })
+        match header {
+            Some(Ok(header)) => Ok(header),
+            _ => Err(SourceError::SetupError(
+                "Failed to deserialize metadata header".to_string(),
+            )),
+        }
+    }
+
+    /// Reads only the trailer of the trace file, if it has one.
+    pub fn trailer(mut fd: fs::File) -> Result<Option<TraceTrailer>, SourceError> {
+        let (_, trailer) = read_trailer(&mut fd).map_err(SourceError::SetupIOError)?;
+
+        Ok(trailer)
     }
 
     pub fn metadata(&self) -> TraceMetadata {
         self.metadata.clone()
     }
+
+    /// The outages of the trace stream recorded in the trailer, if any.
+    pub fn gaps(&self) -> &[(Duration, Duration)] {
+        &self.gaps
+    }
 }
 
 impl Iterator for FileSource {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let data = match self.next_data()? {
+                Ok(data) => data,
+                Err(e) => return Some(Err(e)),
+            };
+            let at = offset(&data.timestamp);
+            if self.range.1.map_or(false, |to| at > to) {
+                return None;
+            }
+            if self.range.0.map_or(true, |from| at >= from) {
+                // NOTE outages before the first trace data read are not
+                // reported, as when seeked past.
+                let skipped = self.range.0.unwrap_or_default();
+                while let Some((gap_at, gap)) = self.gaps.get(self.next_gap) {
+                    if *gap_at > at {
+                        break;
+                    }
+                    if *gap_at >= skipped {
+                        self.gap = Some(self.gap.unwrap_or_default() + *gap);
+                    }
+                    self.next_gap += 1;
+                }
+                return Some(Ok(data));
+            }
+        }
+    }
+}
+
+impl FileSource {
+    fn next_data(&mut self) -> Option<Result<TraceData, SourceError>> {
+        if self.streamed {
+            match at_trailer(&mut self.reader) {
+                Ok(true) => return None,
+                Ok(false) => (),
+                Err(e) => return Some(Err(SourceError::IterIOError(e))),
+            }
+        }
+
+        if self.framed {
+            return match read_frame(&mut self.reader) {
+                Ok(Some(frame)) => {
+                    Some(serde_json::from_slice(&frame).map_err(SourceError::IterDeserError))
+                }
+                Ok(None) => None,
+                Err(e) => Some(Err(SourceError::IterIOError(e))),
+            };
+        }
+
         let mut stream =
             serde_json::Deserializer::from_reader(&mut self.reader).into_iter::<TraceData>();
         match stream.next() {
             Some(Ok(data)) => Some(Ok(data)),
+            Some(Err(e)) if e.is_eof() => {
+                crate::log::warn("skipping truncated trailing trace data".to_string());
+                None
+            }
             Some(Err(e)) => Some(Err(SourceError::IterDeserError(e))),
             None => None,
         }
@@ -56,6 +395,140 @@ impl Source for FileSource {
     }
 
     fn describe(&self) -> String {
-        format!("file ({:?})", self.reader.get_ref())
+        format!("file ({})", self.description)
+    }
+
+    fn take_gap(&mut self) -> Option<Duration> {
+        self.gap.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure a truncated trailing frame is skipped.
+    #[test]
+    fn truncated_frame() {
+        let mut file = vec![];
+        for payload in [&b"{}"[..], b"[1,2,3]"] {
+            file.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            file.extend_from_slice(payload);
+        }
+        file.truncate(file.len() - 1);
+
+        let mut reader = &file[..];
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"{}");
+        assert!(read_frame(&mut reader).unwrap().is_none());
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    /// A reader that yields a single byte per read, as a pipe may.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let n = out.len().min(self.0.len()).min(1);
+            out[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    /// Ensure the trailer of a streamed trace is detected even if it
+    /// arrives in pieces, and that trace data starting with a newline
+    /// is not mistaken for it.
+    #[test]
+    fn streamed_trailer() {
+        let trailer = crate::sinks::file::trailer_bytes(&TraceTrailer::default()).unwrap();
+        let mut reader = Lookahead::new(Trickle(&trailer));
+        assert!(at_trailer(&mut reader).unwrap());
+
+        let mut stream = trailer.clone();
+        stream.extend_from_slice(&[0x0a, 0x00, 0x00, 0x00]);
+        let mut reader = Lookahead::new(Trickle(&stream));
+        assert!(!at_trailer(&mut reader).unwrap());
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, stream);
+    }
+
+    /// Ensure the index seeks to the last entry at or before an offset.
+    #[test]
+    fn index_seek() {
+        use crate::sinks::file::IndexEntry;
+
+        let entry = |seq, secs| IndexEntry {
+            seq,
+            position: seq as u64 * 100,
+            offset: Duration::from_secs(secs),
+        };
+        let index = TraceIndex {
+            entries: vec![entry(0, 0), entry(10, 5), entry(20, 10)],
+        };
+        let seek = |secs| index.seek(Duration::from_secs(secs)).map(|entry| entry.seq);
+        assert_eq!(seek(4), Some(0));
+        assert_eq!(seek(5), Some(10));
+        assert_eq!(seek(60), Some(20));
+    }
+
+    /// Ensure recorded outages are reported along with the trace data
+    /// that followed them, unless it is skipped.
+    #[test]
+    fn gaps() {
+        use crate::manifest::InterruptLatency;
+        use crate::recovery::TraceLookupMaps;
+        use crate::sinks::file::frame;
+
+        let ms = Duration::from_millis;
+        let metadata = TraceMetadata::from(
+            "app".to_string(),
+            TraceLookupMaps::synthetic(),
+            chrono::Local::now(),
+            16_000_000,
+            InterruptLatency::default(),
+            None,
+        );
+        let mut file = TRACE_MAGIC.to_vec();
+        file.extend(frame(&serde_json::to_vec(&metadata).unwrap()));
+        for at in [1, 2, 3, 4] {
+            let data = TraceData {
+                timestamp: itm::Timestamp::Sync(ms(at)),
+                packets: vec![],
+                malformed_packets: vec![],
+                consumed_packets: 0,
+            };
+            file.extend(frame(&serde_json::to_vec(&data).unwrap()));
+        }
+        let source = |from| {
+            let trailer = TraceTrailer {
+                gaps: vec![(ms(2), ms(10)), (ms(3), ms(20)), (ms(3), ms(5))],
+                ..Default::default()
+            };
+            FileSource::from_reader(
+                Box::new(io::Cursor::new(file.clone())),
+                String::new(),
+                Some(trailer),
+                false,
+            )
+            .unwrap()
+            .range(from, None)
+        };
+
+        let mut src = source(None);
+        let gaps: Vec<Option<Duration>> = std::iter::from_fn(|| {
+            let data = src.next()?;
+            data.unwrap();
+            Some(src.take_gap())
+        })
+        .collect();
+        assert_eq!(gaps, [None, Some(ms(10)), Some(ms(25)), None]);
+
+        let mut src = source(Some(ms(3)));
+        src.next().unwrap().unwrap();
+        assert_eq!(src.take_gap(), Some(ms(25)));
+        let mut src = source(Some(ms(4)));
+        src.next().unwrap().unwrap();
+        assert_eq!(src.take_gap(), None);
     }
 }
@@ -0,0 +1,257 @@
+//! Detection and removal of the framing that may wrap an ITM byte
+//! stream in third-party captures (e.g. from logic analyzers).
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+/// TPIU full synchronization packet.
+const TPIU_SYNC: [u8; 4] = [0xff, 0xff, 0xff, 0x7f];
+/// Size of a TPIU frame.
+const TPIU_FRAME_SIZE: usize = 16;
+/// How many TPIU synchronization packets must be found at frame-aligned
+/// offsets for a stream to be considered TPIU-formatted. A single
+/// occurrence may just as well be ITM payload.
+const TPIU_SYNC_REPEATS: usize = 2;
+
+/// The framing of a raw trace byte stream.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Framing {
+    /// A plain ITM byte stream. The bytes before its first
+    /// synchronization packet, if any, are kept: the decoder reports
+    /// them as malformed.
+    Itm,
+    /// An ITM byte stream wrapped in TPIU frames, the first of which
+    /// starts at the given offset.
+    Tpiu { offset: usize },
+}
+
+impl Framing {
+    /// Detects the framing of a byte stream from its first bytes.
+    ///
+    /// A stream is considered TPIU-formatted if TPIU synchronization
+    /// packets recur at frame-aligned offsets, the first of which
+    /// starts the frames; leading garbage before it is skipped.
+    /// Otherwise, the stream is considered a plain ITM stream.
+    pub fn detect(prefix: &[u8]) -> Self {
+        (0..prefix.len())
+            .filter(|pos| prefix[*pos..].starts_with(&TPIU_SYNC))
+            .find_map(|pos| {
+                let (offset, syncs) = aligned_syncs(prefix, pos);
+                (syncs >= TPIU_SYNC_REPEATS).then(|| Self::Tpiu { offset })
+            })
+            .unwrap_or(Self::Itm)
+    }
+
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::Itm => 0,
+            Self::Tpiu { offset } => *offset,
+        }
+    }
+}
+
+/// Walks the frames of `stream` from the TPIU synchronization packet at
+/// `pos`. Returns the offset of the first frame, and the number of
+/// synchronization packets found between frames.
+fn aligned_syncs(stream: &[u8], pos: usize) -> (usize, usize) {
+    let mut syncs = 0;
+    let mut first_frame = None;
+    let mut after_sync = false;
+    let mut pos = pos;
+    while pos + TPIU_SYNC.len() <= stream.len() {
+        if stream[pos..].starts_with(&TPIU_SYNC) {
+            // consecutive synchronization packets count once
+            if !after_sync {
+                syncs += 1;
+            }
+            after_sync = true;
+            pos += TPIU_SYNC.len();
+        } else {
+            first_frame.get_or_insert(pos);
+            after_sync = false;
+            pos += TPIU_FRAME_SIZE;
+        }
+    }
+
+    (first_frame.unwrap_or(pos), syncs)
+}
+
+/// Extracts the bytes of a single trace source from a stream of TPIU
+/// frames. See the ARM CoreSight Architecture Specification, D4.2
+/// "Formatter frames".
+pub struct TpiuDeframer<R: Read> {
+    reader: R,
+    /// The trace source ID of which bytes are yielded.
+    stream_id: u8,
+    /// The trace source ID the current byte belongs to.
+    current_id: u8,
+    out: VecDeque<u8>,
+}
+
+impl<R: Read> TpiuDeframer<R> {
+    /// Creates a new deframer that yields the bytes of the trace source
+    /// `stream_id` from `reader`, which must be aligned to a frame.
+    pub fn new(reader: R, stream_id: u8) -> Self {
+        Self {
+            reader,
+            stream_id,
+            current_id: 0,
+            out: VecDeque::new(),
+        }
+    }
+
+    /// Reads the next frame, dropping any synchronization packets in
+    /// between frames. Returns `false` on EOF.
+    fn read_frame(&mut self, frame: &mut [u8; TPIU_FRAME_SIZE]) -> io::Result<bool> {
+        let mut filled = 0;
+        loop {
+            while filled < TPIU_FRAME_SIZE {
+                match self.reader.read(&mut frame[filled..])? {
+                    0 => return Ok(false),
+                    n => filled += n,
+                }
+            }
+
+            if frame.starts_with(&TPIU_SYNC) {
+                frame.copy_within(TPIU_SYNC.len().., 0);
+                filled -= TPIU_SYNC.len();
+                continue;
+            }
+
+            return Ok(true);
+        }
+    }
+
+    fn emit(&mut self, byte: u8) {
+        if self.current_id == self.stream_id {
+            self.out.push_back(byte);
+        }
+    }
+
+    fn deframe(&mut self, frame: &[u8; TPIU_FRAME_SIZE]) {
+        let aux = frame[TPIU_FRAME_SIZE - 1];
+        for i in 0..(TPIU_FRAME_SIZE / 2) {
+            let even = frame[2 * i];
+            let aux_bit = (aux >> i) & 1;
+            // The last pair lacks an odd byte: its place is taken by
+            // the auxiliary byte.
+            let odd = if i < TPIU_FRAME_SIZE / 2 - 1 {
+                Some(frame[2 * i + 1])
+            } else {
+                None
+            };
+
+            if even & 1 == 1 {
+                // ID change. If the auxiliary bit is set, the change
+                // takes effect after the following data byte.
+                let new_id = even >> 1;
+                match (aux_bit, odd) {
+                    (1, Some(odd)) => {
+                        self.emit(odd);
+                        self.current_id = new_id;
+                    }
+                    (_, odd) => {
+                        self.current_id = new_id;
+                        if let Some(odd) = odd {
+                            self.emit(odd);
+                        }
+                    }
+                }
+            } else {
+                // Data byte whose least significant bit is stored in
+                // the auxiliary byte.
+                self.emit(even | aux_bit);
+                if let Some(odd) = odd {
+                    self.emit(odd);
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for TpiuDeframer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut frame = [0; TPIU_FRAME_SIZE];
+        while self.out.is_empty() {
+            if !self.read_frame(&mut frame)? {
+                return Ok(0);
+            }
+            self.deframe(&frame);
+        }
+
+        let n = buf.len().min(self.out.len());
+        for (dst, src) in buf.iter_mut().zip(self.out.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FRAME: [u8; TPIU_FRAME_SIZE] = [
+        0x03, // ID change to 1, effective immediately (aux bit 0 is clear)
+        0xaa,
+        0x10,
+        0x11,
+        0x20,
+        0x21,
+        0x30,
+        0x31,
+        0x40,
+        0x41,
+        0x50,
+        0x51,
+        0x60,
+        0x61,
+        0x70,
+        0b0000_0010, // LSB of byte 2 is set
+    ];
+
+    /// Ensure the bytes of the selected trace source are extracted.
+    #[test]
+    fn deframe() {
+        let mut stream = vec![0x12];
+        for _ in 0..2 {
+            stream.extend_from_slice(&TPIU_SYNC);
+            stream.extend_from_slice(&FRAME);
+        }
+        let framing = Framing::detect(&stream);
+        assert_eq!(framing, Framing::Tpiu { offset: 5 });
+
+        let mut bytes = vec![];
+        TpiuDeframer::new(&stream[framing.offset()..], 1)
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert_eq!(
+            bytes,
+            [0xaa, 0x11, 0x11, 0x20, 0x21, 0x30, 0x31, 0x40, 0x41, 0x50, 0x51, 0x60, 0x61, 0x70]
+                .repeat(2)
+        );
+
+        let mut bytes = vec![];
+        TpiuDeframer::new(&FRAME[..], 2)
+            .read_to_end(&mut bytes)
+            .unwrap();
+        assert!(bytes.is_empty());
+    }
+
+    /// Ensure ITM streams are kept whole, also if their payload contains
+    /// a TPIU synchronization packet that does not recur at
+    /// frame-aligned offsets.
+    #[test]
+    fn detect_itm() {
+        let stream = [0x12, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x70];
+        assert_eq!(Framing::detect(&stream), Framing::Itm);
+
+        let mut stream = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x80];
+        // a 32-bit instrumentation packet of 0x7fffffff
+        stream.extend_from_slice(&[0x03, 0xff, 0xff, 0xff, 0x7f]);
+        for _ in 0..8 {
+            stream.extend_from_slice(&[0x01, 0x41]);
+        }
+        assert_eq!(Framing::detect(&stream), Framing::Itm);
+        assert_eq!(Framing::Itm.offset(), 0);
+    }
+}
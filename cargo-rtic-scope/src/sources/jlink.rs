@@ -0,0 +1,256 @@
+//! Source which reads raw ITM packets from the SWO port of a running
+//! SEGGER J-Link GDB Server, for labs that capture SWO via J-Link
+//! instead of via probe-rs. The GDB Server holds the probe, so the SWO
+//! configuration and the reset of the target are requested via monitor
+//! commands over its GDB port.
+use crate::manifest::ManifestProperties;
+use crate::reset::ResetMethod;
+use crate::sources::decoder::{Packets, TraceDecoder};
+use crate::sources::{
+    raw_log, Cancellable, Counted, RawLog, Shutdown, Source, SourceError, POLL_INTERVAL,
+};
+use crate::TraceData;
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+/// Default GDB port of the J-Link GDB Server.
+const GDB_PORT: u16 = 2331;
+
+/// A connection to the GDB port of the J-Link GDB Server, over which
+/// monitor commands are sent via the GDB remote serial protocol.
+struct Monitor {
+    stream: TcpStream,
+}
+
+impl Monitor {
+    fn connect(addr: &str) -> io::Result<Self> {
+        Ok(Self {
+            stream: TcpStream::connect(addr)?,
+        })
+    }
+
+    /// Sends `monitor <cmd>`, returning the console output of the
+    /// command.
+    fn command(&mut self, cmd: &str) -> io::Result<String> {
+        let payload = format!("qRcmd,{}", encode_hex(cmd.as_bytes()));
+        let checksum = payload.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", payload, checksum)?;
+
+        let mut output = vec![];
+        loop {
+            let reply = self.read_packet()?;
+            match reply.as_str() {
+                "OK" | "" => break,
+                r if r.starts_with('E') && r.len() == 3 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("monitor {} failed: {}", cmd, r),
+                    ))
+                }
+                // Console output, streamed until the command is done.
+                r if r.starts_with('O') => output.extend(decode_hex(&r[1..])?),
+                r => {
+                    output.extend(decode_hex(r)?);
+                    break;
+                }
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&output).trim().to_string())
+    }
+
+    /// Reads the next packet, skipping acknowledgements, and
+    /// acknowledges it.
+    fn read_packet(&mut self) -> io::Result<String> {
+        let mut byte = [0u8; 1];
+        loop {
+            self.stream.read_exact(&mut byte)?;
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut payload = vec![];
+        loop {
+            self.stream.read_exact(&mut byte)?;
+            if byte[0] == b'#' {
+                break;
+            }
+            payload.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        self.stream.read_exact(&mut checksum)?;
+        self.stream.write_all(b"+")?;
+
+        Ok(String::from_utf8_lossy(&payload).into_owned())
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> io::Result<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed reply from GDB server: {:?}", s),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// The SWO port of the J-Link GDB Server. As the decoder ignores EOF,
+/// such that reads that time out while the target is silent do not end
+/// the stream, the server closing the port is an error instead.
+struct SwoStream(TcpStream);
+
+impl Read for SwoStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf)? {
+            0 if !buf.is_empty() => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the J-Link GDB Server closed the SWO port",
+            )),
+            n => Ok(n),
+        }
+    }
+}
+
+pub struct JLinkSource {
+    addr: String,
+    monitor: Monitor,
+    bytes: Arc<AtomicU64>,
+    shutdown: Shutdown,
+    decoder: Packets<'static>,
+}
+
+impl JLinkSource {
+    /// Configures the SWO capture of the J-Link GDB Server whose GDB
+    /// port is at `gdb` (by default port 2331 of the host of `swo`) and
+    /// reads trace data from its SWO port at `swo` as decoded by
+    /// `decoder`, logging the raw bytes read to `raw_log`, if given.
+    pub fn new(
+        swo: &str,
+        gdb: Option<&str>,
+        opts: &ManifestProperties,
+        decoder: Box<dyn TraceDecoder>,
+        raw_log: Option<RawLog>,
+    ) -> Result<Self, SourceError> {
+        let gdb = match gdb {
+            Some(gdb) => gdb.to_string(),
+            None => {
+                let host = swo.rsplit_once(':').map_or(swo, |(host, _)| host);
+                format!("{}:{}", host, GDB_PORT)
+            }
+        };
+        let mut monitor = Monitor::connect(&gdb).map_err(SourceError::SetupIOError)?;
+
+        // Capture the SWO stream in UART mode at the configured baud
+        // rate, from all stimulus ports.
+        let cmd = format!(
+            "SWO EnableTarget {} {} 0xFFFFFFFF 0",
+            opts.tpiu_freq, opts.tpiu_baud
+        );
+        let output = monitor
+            .command(&cmd)
+            .map_err(|e| SourceError::SetupError(format!("{}: {}", gdb, e)))?;
+        crate::log::debug!("monitor {}: {}", cmd, output);
+
+        let stream = TcpStream::connect(swo).map_err(SourceError::SetupIOError)?;
+        stream
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(SourceError::SetupIOError)?;
+        let reader = Cancellable::new(SwoStream(stream));
+        let shutdown = reader.shutdown();
+        let reader = Counted::new(reader);
+        Ok(Self {
+            addr: swo.to_string(),
+            monitor,
+            bytes: reader.counter(),
+            shutdown,
+            decoder: decoder.decode(raw_log::tee(reader, raw_log), true, opts),
+        })
+    }
+}
+
+impl Iterator for JLinkSource {
+    type Item = Result<TraceData, SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decoder.next()
+    }
+}
+
+impl Source for JLinkSource {
+    /// Resets the target via the J-Link GDB Server, which resets it in
+    /// halted state, and resumes it unless `reset_halt`.
+    fn reset_target(&mut self, method: ResetMethod, reset_halt: bool) -> Result<(), SourceError> {
+        // See the reset types of the J-Link GDB Server.
+        let kind = match method {
+            ResetMethod::SysReset => 0,
+            ResetMethod::VectReset => 1,
+            ResetMethod::Hardware => 2,
+            ResetMethod::None => return Ok(()),
+        };
+        let mut cmds = vec![format!("reset {}", kind)];
+        if !reset_halt {
+            cmds.push("go".to_string());
+        }
+        for cmd in cmds {
+            self.monitor
+                .command(&cmd)
+                .map_err(|e| SourceError::SetupError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn byte_counter(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.bytes.clone())
+    }
+
+    fn shutdown(&self) -> Option<Shutdown> {
+        Some(self.shutdown.clone())
+    }
+
+    fn describe(&self) -> String {
+        format!("J-Link GDB Server (SWO port {})", self.addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Ensure the SWO port being closed ends the stream with an error,
+    /// while reads that time out do not.
+    #[test]
+    fn closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+        let mut reader = Cancellable::new(SwoStream(stream));
+        let (mut server, _) = listener.accept().unwrap();
+
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        server.write_all(&[1, 2]).unwrap();
+        drop(server);
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(
+            reader.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::ConnectionAborted
+        );
+    }
+}
@@ -4,6 +4,11 @@
 use crate::diag;
 use crate::TraceData;
 
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use thiserror::Error;
 
 #[derive(Debug)]
@@ -39,12 +44,32 @@ pub enum SourceError {
     ResetError(#[source] probe_rs::Error),
     #[error("Failed to decode ITM packets: {0}")]
     DecodeError(#[from] itm::DecoderError),
+    #[error("Target architecture {0} does not support ITM/DWT tracing")]
+    UnsupportedArchitecture(String),
 }
 
-impl diag::DiagnosableError for SourceError {}
+impl diag::DiagnosableError for SourceError {
+    fn diagnose(&self) -> Vec<String> {
+        match self {
+            SourceError::ResetError(_) => vec![
+                "Boards with an external watchdog or supervisor may not reset on a system reset request. Try another --reset-method (sysreset, vectreset, hardware, or none).".to_string(),
+                "A hardware reset requires a probe that can drive nRESET, and nRESET to be connected to it.".to_string(),
+            ],
+            SourceError::UnsupportedArchitecture(_) => vec![
+                "RTIC Scope requires an ARMv7-M (e.g. Cortex-M3/M4/M7) or ARMv8-M Mainline (e.g. Cortex-M33) target.".to_string(),
+                "ARMv6-M (Cortex-M0/M0+) and ARMv8-M Baseline (Cortex-M23) targets do not implement the ITM and DWT units.".to_string(),
+            ],
+            _ => vec![],
+        }
+    }
+}
 
 pub trait Source: Iterator<Item = Result<TraceData, SourceError>> + std::marker::Send {
-    fn reset_target(&mut self, _reset_halt: bool) -> Result<(), SourceError> {
+    fn reset_target(
+        &mut self,
+        _method: crate::reset::ResetMethod,
+        _reset_halt: bool,
+    ) -> Result<(), SourceError> {
         Ok(())
     }
 
@@ -53,9 +78,124 @@ pub trait Source: Iterator<Item = Result<TraceData, SourceError>> + std::marker:
         BufferStatus::Unknown
     }
 
+    /// Counter of the bytes read so far, if the source decodes a byte
+    /// stream. Shared such that it can be read while the source is
+    /// iterated on another thread.
+    fn byte_counter(&self) -> Option<Arc<AtomicU64>> {
+        None
+    }
+
+    /// Duration of the outage of the trace stream that preceded the
+    /// last trace data read, if any. See `--swo-silence`.
+    fn take_gap(&mut self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// Flag by which the source is told to stop reading, if it may
+    /// otherwise wait for trace data indefinitely. Shared such that it
+    /// can be signalled while the source is iterated on another thread.
+    fn shutdown(&self) -> Option<Shutdown> {
+        None
+    }
+
     fn describe(&self) -> String;
 }
 
+/// A reader that counts the bytes read from `inner`.
+pub struct Counted<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Counted<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            count: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.count.clone()
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+
+        Ok(n)
+    }
+}
+
+/// How long a read from a live source may block before it is checked
+/// whether the source has been shut down.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Flag by which a live source is told to stop reading; see
+/// [`Cancellable`].
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn signal(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_signalled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A reader of a live stream that fails once shut down, such that the
+/// decoder reading from it ends. Reads of `inner` must time out after
+/// about [`POLL_INTERVAL`]: timed out reads return no bytes, which the
+/// decoder retries.
+pub struct Cancellable<R> {
+    inner: R,
+    shutdown: Shutdown,
+}
+
+impl<R: Read> Cancellable<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+}
+
+impl<R: Read> Read for Cancellable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.shutdown.is_signalled() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "source has been shut down",
+            ));
+        }
+
+        match self.inner.read(buf) {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(0)
+            }
+            res => res,
+        }
+    }
+}
+
+pub mod decoder;
+pub use decoder::{DecoderKind, TraceDecoder};
+
 mod file;
 pub use file::FileSource;
 
@@ -65,5 +205,51 @@ pub use tty::TTYSource;
 mod probe;
 pub use probe::ProbeSource;
 
+mod jlink;
+pub use jlink::JLinkSource;
+
+mod framing;
+
+pub mod raw_log;
+pub use framing::Framing;
+pub use raw_log::RawLog;
+
 mod raw_file;
 pub use raw_file::RawFileSource;
+
+pub mod synthetic;
+pub use synthetic::SyntheticSource;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A stream that times out on every other read.
+    struct Stalling(bool);
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0 = !self.0;
+            if self.0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            buf[0] = 0x70;
+            Ok(1)
+        }
+    }
+
+    /// Ensure timed out reads return no bytes, and that reads fail once
+    /// the source is shut down.
+    #[test]
+    fn cancellable() {
+        let mut reader = Cancellable::new(Stalling(false));
+        let shutdown = reader.shutdown();
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+
+        shutdown.signal();
+        assert!(reader.read(&mut buf).is_err());
+        assert!(reader.read(&mut buf).is_err());
+    }
+}
@@ -1,37 +1,162 @@
 //! Source which reads [`TraceData`] from a [`Session`].
 use crate::manifest::ManifestProperties;
-use crate::sources::{Source, SourceError};
+use crate::sources::decoder::{Packets, TraceDecoder};
+use crate::sources::{raw_log, Cancellable, Counted, RawLog, Shutdown, Source, SourceError};
 use crate::TraceData;
 
-use itm::{Decoder, DecoderOptions, Timestamps, TimestampsConfiguration};
-use probe_rs::{
-    architecture::arm::{SwoConfig, SwoReader},
-    Session,
-};
+use std::io::{self, Read};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use probe_rs::{architecture::arm::SwoConfig, CoreType, Session};
 
 pub struct ProbeSource<'a> {
-    decoder: Timestamps<SwoReader<'a>>,
+    decoder: Packets<'a>,
     target_name: String,
+    bytes: Arc<AtomicU64>,
+    /// Duration of the outages of the SWO stream since the last trace
+    /// data was returned; see [`SwoReader`].
+    gap: Arc<Mutex<Option<Duration>>>,
+    shutdown: Shutdown,
+}
+
+/// The SWO operations of a [`Session`], such that [`SwoReader`] can be
+/// tested without a probe.
+trait Swo {
+    fn read_swo(&mut self) -> Result<Vec<u8>, probe_rs::Error>;
+    fn setup_swv(&mut self, core: usize, cfg: &SwoConfig) -> Result<(), probe_rs::Error>;
+}
+
+impl Swo for Session {
+    fn read_swo(&mut self) -> Result<Vec<u8>, probe_rs::Error> {
+        Session::read_swo(self)
+    }
+
+    fn setup_swv(&mut self, core: usize, cfg: &SwoConfig) -> Result<(), probe_rs::Error> {
+        Session::setup_swv(self, core, cfg)
+    }
+}
+
+/// Reads the SWO stream from the probe. If the stream falls silent for
+/// `silence` after data has been read, the SWO configuration of `core`
+/// is re-applied, as the firmware may have reconfigured the SWO pin.
+/// Retried every `silence` until data is read again.
+struct SwoReader<'a, S: Swo> {
+    session: &'a mut S,
+    core: usize,
+    cfg: SwoConfig,
+    silence: Option<Duration>,
+    /// Data read from the probe, but not yet from the reader.
+    buf: Vec<u8>,
+    /// When data was last read, or the configuration last re-applied.
+    last_data: Option<Instant>,
+    /// When the stream fell silent, if it has not recovered since.
+    lost_since: Option<Instant>,
+    gap: Arc<Mutex<Option<Duration>>>,
+}
+
+impl<'a, S: Swo> Read for SwoReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            let data = self
+                .session
+                .read_swo()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let now = Instant::now();
+            if !data.is_empty() {
+                crate::log::trace!("read {} bytes of SWO data", data.len());
+                if let Some(since) = self.lost_since.take() {
+                    let outage = now - since;
+                    crate::log::status(
+                        "Recovered",
+                        format!("SWO stream after {:.1} s", outage.as_secs_f64()),
+                    );
+                    let mut gap = self.gap.lock().unwrap();
+                    *gap = Some(gap.unwrap_or_default() + outage);
+                }
+                self.last_data = Some(now);
+            } else if let (Some(last), Some(silence)) = (self.last_data, self.silence) {
+                if now - last >= silence {
+                    if self.lost_since.is_none() {
+                        crate::log::warn(format!(
+                            "no trace data for {:.1} s; re-applying the SWO configuration",
+                            silence.as_secs_f64()
+                        ));
+                        self.lost_since = Some(last);
+                    }
+                    self.session
+                        .setup_swv(self.core, &self.cfg)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    self.last_data = Some(now);
+                }
+            }
+            self.buf.extend(data);
+        }
+
+        let len = buf.len().min(self.buf.len());
+        buf[..len].copy_from_slice(&self.buf[..len]);
+        self.buf.drain(..len);
+
+        Ok(len)
+    }
 }
 
 impl<'a> ProbeSource<'a> {
-    pub fn new(session: &'a mut Session, opts: &ManifestProperties) -> Result<Self, SourceError> {
+    /// Reads trace data over SWO from `core` of the target of
+    /// `session` as decoded by `decoder`, logging the raw bytes read to
+    /// `raw_log`, if given. If
+    /// `silence` is given, the SWO configuration is re-applied whenever
+    /// the stream falls silent for as long.
+    pub fn new(
+        session: &'a mut Session,
+        core: usize,
+        opts: &ManifestProperties,
+        decoder: Box<dyn TraceDecoder>,
+        raw_log: Option<RawLog>,
+        silence: Option<Duration>,
+    ) -> Result<Self, SourceError> {
+        // ITM and DWT are only implemented on ARMv7-M and ARMv8-M
+        // Mainline. ARMv8-M Baseline is not distinguished here, but is
+        // rejected by cortex-m-rtic-trace on the target.
+        match session.target().core_type {
+            CoreType::Armv7m | CoreType::Armv7em | CoreType::Armv8m => (),
+            core_type => {
+                return Err(SourceError::UnsupportedArchitecture(format!(
+                    "{:?}",
+                    core_type
+                )))
+            }
+        }
+
         // Configure probe and target for tracing
         let cfg = SwoConfig::new(opts.tpiu_freq)
             .set_baud(opts.tpiu_baud)
             .set_continuous_formatting(false);
         session
-            .setup_swv(0, &cfg)
+            .setup_swv(core, &cfg)
             .map_err(SourceError::ProbeError)?;
 
+        let target_name = session.target().name.clone();
+        let gap = Arc::new(Mutex::new(None));
+        let reader = Cancellable::new(SwoReader {
+            session,
+            core,
+            cfg,
+            silence,
+            buf: vec![],
+            last_data: None,
+            lost_since: None,
+            gap: gap.clone(),
+        });
+        let shutdown = reader.shutdown();
+        let reader = Counted::new(reader);
         Ok(Self {
-            target_name: session.target().name.clone(),
-            decoder: Decoder::new(session.swo_reader()?, DecoderOptions { ignore_eof: true })
-                .timestamps(TimestampsConfiguration {
-                    clock_frequency: opts.tpiu_freq,
-                    lts_prescaler: opts.lts_prescaler,
-                    expect_malformed: opts.expect_malformed,
-                }),
+            target_name,
+            bytes: reader.counter(),
+            gap,
+            shutdown,
+            decoder: decoder.decode(raw_log::tee(reader, raw_log), true, opts),
         })
     }
 }
@@ -40,14 +165,78 @@ impl<'a> Iterator for ProbeSource<'a> {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.decoder
-            .next()
-            .map(|res| res.map_err(SourceError::DecodeError))
+        self.decoder.next()
     }
 }
 
 impl<'a> Source for ProbeSource<'a> {
+    fn byte_counter(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.bytes.clone())
+    }
+
+    fn take_gap(&mut self) -> Option<Duration> {
+        self.gap.lock().unwrap().take()
+    }
+
+    fn shutdown(&self) -> Option<Shutdown> {
+        Some(self.shutdown.clone())
+    }
+
     fn describe(&self) -> String {
         format!("probe (attached to {})", self.target_name)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Yields the given reads, and records the cores configured.
+    struct Probe {
+        reads: VecDeque<Vec<u8>>,
+        setups: Vec<usize>,
+    }
+
+    impl Swo for Probe {
+        fn read_swo(&mut self) -> Result<Vec<u8>, probe_rs::Error> {
+            Ok(self.reads.pop_front().unwrap_or_default())
+        }
+
+        fn setup_swv(&mut self, core: usize, _cfg: &SwoConfig) -> Result<(), probe_rs::Error> {
+            self.setups.push(core);
+            Ok(())
+        }
+    }
+
+    /// Ensure the SWO configuration of the traced core is re-applied
+    /// once the stream falls silent, and that the outage is reported
+    /// once data is read again.
+    #[test]
+    fn silence() {
+        let mut probe = Probe {
+            reads: vec![vec![1, 2], vec![], vec![3]].into(),
+            setups: vec![],
+        };
+        let gap = Arc::new(Mutex::new(None));
+        let mut reader = SwoReader {
+            session: &mut probe,
+            core: 1,
+            cfg: SwoConfig::new(16_000_000),
+            silence: Some(Duration::ZERO),
+            buf: vec![],
+            last_data: None,
+            lost_since: None,
+            gap: gap.clone(),
+        };
+
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert!(gap.lock().unwrap().is_none());
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+        assert!(gap.lock().unwrap().is_some());
+        assert_eq!(probe.setups, [1]);
+    }
+}
@@ -1,30 +1,161 @@
 //! Source which reads raw ITM packets from a file.
 use crate::manifest::ManifestProperties;
-use crate::sources::{BufferStatus, Source, SourceError};
+use crate::recovery::FREQ_PORT;
+use crate::sources::decoder::{Packets, TraceDecoder};
+use crate::sources::framing::{Framing, TpiuDeframer};
+use crate::sources::{BufferStatus, Counted, Source, SourceError};
 use crate::TraceData;
 
 use std::fs;
+use std::io::{Cursor, Read};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
 
-use itm::{Decoder, DecoderOptions, Timestamps, TimestampsConfiguration};
+use cortex_m::peripheral::itm::LocalTimestampOptions;
+use itm::{Decoder, DecoderOptions, TimestampsConfiguration, TracePacket};
+
+/// How many bytes are inspected to detect the framing of the file.
+const DETECTION_WINDOW: u64 = 64 * 1024;
+
+/// Trace source ID of the ITM, as configured by `cortex-m-rtic-trace`.
+const DEFAULT_TPIU_STREAM: u8 = 1;
 
 /// Something data is deserialized from. Always a file.
 pub struct RawFileSource {
     file_name: String,
-    decoder: Timestamps<std::fs::File>,
+    framing: Framing,
+    bytes: Arc<AtomicU64>,
+    decoder: Packets<'static>,
+}
+
+/// Skips the first `skip_bytes` of `reader` and reads the bytes in
+/// which the framing is detected. The framing is TPIU if `tpiu_stream`
+/// is given.
+fn read_prefix<R: Read>(
+    reader: &mut R,
+    skip_bytes: u64,
+    tpiu_stream: Option<u8>,
+) -> Result<(Vec<u8>, Framing), SourceError> {
+    std::io::copy(&mut reader.take(skip_bytes), &mut std::io::sink())
+        .map_err(SourceError::SetupIOError)?;
+    let mut prefix = vec![];
+    reader
+        .take(DETECTION_WINDOW)
+        .read_to_end(&mut prefix)
+        .map_err(SourceError::SetupIOError)?;
+
+    let framing = match (Framing::detect(&prefix), tpiu_stream) {
+        (Framing::Tpiu { offset }, Some(_)) => Framing::Tpiu { offset },
+        (_, Some(_)) => Framing::Tpiu { offset: 0 },
+        (framing, None) => framing,
+    };
+
+    Ok((prefix, framing))
+}
+
+/// The ITM stream of `stream`, deframed if TPIU-formatted.
+fn itm_stream<R>(stream: R, framing: Framing, tpiu_stream: Option<u8>) -> Box<dyn Read + Send>
+where
+    R: Read + Send + 'static,
+{
+    match framing {
+        Framing::Itm => Box::new(stream),
+        Framing::Tpiu { .. } => Box::new(TpiuDeframer::new(
+            stream,
+            tpiu_stream.unwrap_or(DEFAULT_TPIU_STREAM),
+        )),
+    }
 }
 
 impl RawFileSource {
-    pub fn new(file: fs::File, opts: &ManifestProperties) -> Self {
-        Self {
-            file_name: format!("{:?}", file),
-            decoder: Decoder::new(file, DecoderOptions { ignore_eof: true }).timestamps(
-                TimestampsConfiguration {
-                    clock_frequency: opts.tpiu_freq,
-                    lts_prescaler: opts.lts_prescaler,
-                    expect_malformed: opts.expect_malformed,
-                },
-            ),
+    /// The frequency of the timestamp clock reported by the target at
+    /// the start of a raw trace file, if any. See
+    /// `cortex_m_rtic_trace::FREQ_PORT`. Arguments as for
+    /// [`RawFileSource::new`].
+    pub fn reported_freq(
+        mut file: fs::File,
+        skip_bytes: u64,
+        tpiu_stream: Option<u8>,
+    ) -> Result<Option<u32>, SourceError> {
+        let (mut prefix, framing) = read_prefix(&mut file, skip_bytes, tpiu_stream)?;
+        let stream = itm_stream(
+            Cursor::new(prefix.split_off(framing.offset())),
+            framing,
+            tpiu_stream,
+        );
+
+        // NOTE the clock frequency is irrelevant: only the packets are
+        // inspected.
+        let packets = Decoder::new(stream, DecoderOptions { ignore_eof: false }).timestamps(
+            TimestampsConfiguration {
+                clock_frequency: 1,
+                lts_prescaler: LocalTimestampOptions::Enabled,
+                expect_malformed: true,
+            },
+        );
+        for data in packets {
+            let data = match data {
+                Ok(data) => data,
+                Err(_) => break,
+            };
+            for packet in data.packets {
+                if let TracePacket::Instrumentation { port, payload } = packet {
+                    if port == FREQ_PORT && payload.len() == 4 {
+                        return Ok(Some(u32::from_le_bytes([
+                            payload[0], payload[1], payload[2], payload[3],
+                        ])));
+                    }
+                }
+            }
         }
+
+        Ok(None)
+    }
+
+    /// Opens a raw trace file, the first `skip_bytes` of which are
+    /// ignored, and decodes it with `decoder`. The framing of the
+    /// remaining bytes is auto-detected unless `tpiu_stream` is given,
+    /// in which case the file is TPIU-formatted and the ITM trace
+    /// source has the given ID.
+    pub fn new(
+        file: fs::File,
+        opts: &ManifestProperties,
+        decoder: Box<dyn TraceDecoder>,
+        skip_bytes: u64,
+        tpiu_stream: Option<u8>,
+    ) -> Result<Self, SourceError> {
+        let file_name = format!("{:?}", file);
+        Self::from_reader(file, file_name, opts, decoder, skip_bytes, tpiu_stream)
+    }
+
+    /// As [`RawFileSource::new`], but reads the raw trace from
+    /// `reader`, described by `file_name`.
+    pub fn from_reader<R>(
+        mut reader: R,
+        file_name: String,
+        opts: &ManifestProperties,
+        decoder: Box<dyn TraceDecoder>,
+        skip_bytes: u64,
+        tpiu_stream: Option<u8>,
+    ) -> Result<Self, SourceError>
+    where
+        R: Read + Send + 'static,
+    {
+        let (mut prefix, framing) = read_prefix(&mut reader, skip_bytes, tpiu_stream)?;
+        let stream = Counted::new(Cursor::new(prefix.split_off(framing.offset())).chain(reader));
+        let bytes = stream.counter();
+        let stream = itm_stream(stream, framing, tpiu_stream);
+
+        Ok(Self {
+            file_name,
+            framing,
+            bytes,
+            decoder: decoder.decode(stream, true, opts),
+        })
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
     }
 }
 
@@ -32,9 +163,7 @@ impl Iterator for RawFileSource {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.decoder
-            .next()
-            .map(|res| res.map_err(SourceError::DecodeError))
+        self.decoder.next()
     }
 }
 
@@ -43,7 +172,11 @@ impl Source for RawFileSource {
         BufferStatus::NotApplicable
     }
 
+    fn byte_counter(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.bytes.clone())
+    }
+
     fn describe(&self) -> String {
-        format!("raw file ({:?})", self.file_name)
+        format!("raw file ({:?}, {:?})", self.file_name, self.framing)
     }
 }
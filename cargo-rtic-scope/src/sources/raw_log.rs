@@ -0,0 +1,105 @@
+//! Logging of the raw bytes read by byte-stream sources, before they are
+//! decoded. Used to debug transport issues (e.g. garbled SWO).
+//!
+//! Each read is logged as a record header with the host arrival time
+//! (relative to when the log was created) and the stream offset,
+//! followed by a hexdump of the bytes read:
+//!
+//! ```text
+//! # +0.001234s offset 0x00000000 (20 bytes)
+//! 00000000  70 00 00 00 00 00 80 00  0b 01 02 03 04 05 06 07  |p...............|
+//! 00000010  08 09 0a 0b                                       |....|
+//! ```
+use crate::sources::SourceError;
+
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Instant;
+
+pub struct RawLog {
+    file: BufWriter<fs::File>,
+    start: Instant,
+    offset: u64,
+    failed: bool,
+}
+
+impl RawLog {
+    pub fn create(path: &Path) -> Result<Self, SourceError> {
+        Ok(Self {
+            file: BufWriter::new(fs::File::create(path).map_err(SourceError::SetupIOError)?),
+            start: Instant::now(),
+            offset: 0,
+            failed: false,
+        })
+    }
+
+    fn record(&mut self, bytes: &[u8]) -> io::Result<()> {
+        writeln!(
+            self.file,
+            "# +{:.6}s offset {:#010x} ({} bytes)",
+            self.start.elapsed().as_secs_f64(),
+            self.offset,
+            bytes.len()
+        )?;
+        for (i, line) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let (first, second) = hex.split_at(hex.len().min(8));
+            let ascii: String = line
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() || *b == b' ' {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(
+                self.file,
+                "{:08x}  {:<23}  {:<23}  |{}|",
+                self.offset + i as u64 * 16,
+                first.join(" "),
+                second.join(" "),
+                ascii
+            )?;
+        }
+        self.offset += bytes.len() as u64;
+
+        // The log is most useful right before a crash or hang: do not
+        // keep it buffered.
+        self.file.flush()
+    }
+}
+
+/// A reader that logs all bytes read from `inner` to a [`RawLog`].
+struct Tee<R> {
+    inner: R,
+    log: RawLog,
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 && !self.log.failed {
+            if let Err(e) = self.log.record(&buf[..n]) {
+                crate::log::warn(format!(
+                    "failed to write raw byte log; logging disabled: {}",
+                    e
+                ));
+                self.log.failed = true;
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+/// Wraps `reader` such that all bytes read from it are logged to `log`,
+/// if given.
+pub fn tee<'a, R: Read + Send + 'a>(reader: R, log: Option<RawLog>) -> Box<dyn Read + Send + 'a> {
+    match log {
+        Some(log) => Box::new(Tee { inner: reader, log }),
+        None => Box::new(reader),
+    }
+}
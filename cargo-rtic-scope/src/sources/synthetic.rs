@@ -0,0 +1,161 @@
+//! Source which decodes an endless, generated ITM stream. Used to
+//! benchmark the trace pipeline without a target; see
+//! `cargo rtic-scope bench`.
+//!
+//! The stream repeats the execution of a hardware task that runs a
+//! software task, as traced by `cortex-m-rtic-trace`, with a local
+//! timestamp after each packet. See
+//! [`TraceLookupMaps::synthetic`](crate::recovery::TraceLookupMaps::synthetic)
+//! for the matching lookup maps.
+use crate::sources::{BufferStatus, Counted, Source, SourceError};
+use crate::TraceData;
+
+use std::io::{self, Read};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cortex_m::peripheral::itm::LocalTimestampOptions;
+use itm::{Decoder, DecoderOptions, Timestamps, TimestampsConfiguration};
+
+/// Frequency of the emulated timestamp clock.
+pub const FREQ: u32 = 16_000_000;
+/// Exception number of the emulated hardware task.
+pub const HARDWARE_TASK: u16 = 32;
+/// DWT comparators the emulated software task is traced with.
+pub const ENTER_COMPARATOR: u8 = 1;
+pub const EXIT_COMPARATOR: u8 = 2;
+/// ID of the emulated software task.
+pub const SOFTWARE_TASK: u8 = 0;
+
+/// An exception trace packet. `function` is 1 for entered, 2 for
+/// exited, and 3 for returned.
+pub fn exception(number: u16, function: u8) -> Vec<u8> {
+    vec![0x0e, number as u8, function << 4 | (number >> 8) as u8]
+}
+
+/// A single-byte data trace data value packet of a write.
+fn data_value(comparator: u8, value: u8) -> Vec<u8> {
+    vec![0b1000_1101 | comparator << 4, value]
+}
+
+/// A synchronous local timestamp packet (format 1).
+pub fn local_timestamp(mut delta: u32) -> Vec<u8> {
+    let mut packet = vec![0xc0];
+    loop {
+        let byte = (delta & 0x7f) as u8;
+        delta >>= 7;
+        if delta == 0 {
+            packet.push(byte);
+            return packet;
+        }
+        packet.push(byte | 0x80);
+    }
+}
+
+/// The generated byte stream, optionally limited to a rate.
+struct Stream {
+    pattern: Vec<u8>,
+    pos: usize,
+    /// Bytes per second, if limited.
+    rate: Option<f64>,
+    start: Instant,
+    emitted: u64,
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = buf.len();
+        if let Some(rate) = self.rate {
+            loop {
+                let allowed = (self.start.elapsed().as_secs_f64() * rate) as u64;
+                if allowed > self.emitted {
+                    len = len.min((allowed - self.emitted) as usize);
+                    break;
+                }
+                std::thread::sleep(Duration::from_micros(100));
+            }
+        }
+
+        for byte in buf[..len].iter_mut() {
+            *byte = self.pattern[self.pos];
+            self.pos = (self.pos + 1) % self.pattern.len();
+        }
+        self.emitted += len as u64;
+
+        Ok(len)
+    }
+}
+
+pub struct SyntheticSource {
+    rate: Option<u64>,
+    bytes: Arc<AtomicU64>,
+    decoder: Timestamps<Box<dyn Read + Send>>,
+}
+
+impl SyntheticSource {
+    /// Generates about `rate` packets per second, or as many as can be
+    /// decoded if not given.
+    pub fn new(rate: Option<u64>) -> Self {
+        let packets = [
+            exception(HARDWARE_TASK, 1),
+            local_timestamp(100),
+            data_value(ENTER_COMPARATOR, SOFTWARE_TASK),
+            local_timestamp(50),
+            data_value(EXIT_COMPARATOR, SOFTWARE_TASK),
+            local_timestamp(200),
+            exception(HARDWARE_TASK, 2),
+            local_timestamp(20),
+        ];
+        let bytes_per_packet = packets.concat().len() as f64 / packets.len() as f64;
+
+        let stream = Counted::new(Stream {
+            pattern: packets.concat(),
+            pos: 0,
+            rate: rate.map(|rate| rate as f64 * bytes_per_packet),
+            start: Instant::now(),
+            emitted: 0,
+        });
+
+        Self {
+            rate,
+            bytes: stream.counter(),
+            decoder: Decoder::new(
+                Box::new(stream) as Box<dyn Read + Send>,
+                DecoderOptions { ignore_eof: false },
+            )
+            .timestamps(TimestampsConfiguration {
+                clock_frequency: FREQ,
+                lts_prescaler: LocalTimestampOptions::Enabled,
+                expect_malformed: false,
+            }),
+        }
+    }
+}
+
+impl Iterator for SyntheticSource {
+    type Item = Result<TraceData, SourceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decoder
+            .next()
+            .map(|res| res.map_err(SourceError::DecodeError))
+    }
+}
+
+impl Source for SyntheticSource {
+    fn avail_buffer(&self) -> BufferStatus {
+        BufferStatus::NotApplicable
+    }
+
+    fn byte_counter(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.bytes.clone())
+    }
+
+    fn describe(&self) -> String {
+        match self.rate {
+            Some(rate) => format!("synthetic source ({} packets/s)", rate),
+            None => "synthetic source (unlimited rate)".to_string(),
+        }
+    }
+}
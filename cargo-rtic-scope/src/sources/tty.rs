@@ -2,15 +2,23 @@
 //! properly configuring it. Commonly used if `probe-rs` cannot read the
 //! target device.
 use crate::manifest::ManifestProperties;
-use crate::sources::{BufferStatus, Source, SourceError};
+use crate::sources::decoder::{Packets, TraceDecoder};
+use crate::sources::{
+    raw_log, BufferStatus, Cancellable, Counted, RawLog, Shutdown, Source, SourceError,
+    POLL_INTERVAL,
+};
 use crate::TraceData;
 
 use std::fs;
+use std::io::{self, Read};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
 
-use itm::{Decoder, DecoderOptions, Timestamps, TimestampsConfiguration};
 use nix::{
+    errno::Errno,
     libc,
+    poll::{poll, PollFd, PollFlags},
     unistd::{sysconf, SysconfVar},
 };
 
@@ -41,22 +49,47 @@ pub fn configure(device: &str, baud_rate: u32) -> Result<fs::File, SourceError>
     Ok(file)
 }
 
+/// Reads from a device, but only once it is readable, such that reads
+/// time out after [`POLL_INTERVAL`] instead of blocking until the
+/// target emits trace data.
+struct Polled(fs::File);
+
+impl Read for Polled {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fds = [PollFd::new(self.0.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, POLL_INTERVAL.as_millis() as libc::c_int) {
+            Ok(0) | Err(Errno::EINTR) => Err(io::ErrorKind::TimedOut.into()),
+            Ok(_) => self.0.read(buf),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
 pub struct TTYSource {
     fd: RawFd,
-    decoder: Timestamps<fs::File>,
+    bytes: Arc<AtomicU64>,
+    shutdown: Shutdown,
+    decoder: Packets<'static>,
 }
 
 impl TTYSource {
-    pub fn new(device: fs::File, opts: &ManifestProperties) -> Self {
+    /// Reads trace data from `device` as decoded by `decoder`, logging
+    /// the raw bytes read to `raw_log`, if given.
+    pub fn new(
+        device: fs::File,
+        opts: &ManifestProperties,
+        decoder: Box<dyn TraceDecoder>,
+        raw_log: Option<RawLog>,
+    ) -> Self {
+        let fd = device.as_raw_fd();
+        let reader = Cancellable::new(Polled(device));
+        let shutdown = reader.shutdown();
+        let reader = Counted::new(reader);
         Self {
-            fd: device.as_raw_fd(),
-            decoder: Decoder::new(device, DecoderOptions { ignore_eof: true }).timestamps(
-                TimestampsConfiguration {
-                    clock_frequency: opts.tpiu_freq,
-                    lts_prescaler: opts.lts_prescaler,
-                    expect_malformed: opts.expect_malformed,
-                },
-            ),
+            fd,
+            bytes: reader.counter(),
+            shutdown,
+            decoder: decoder.decode(raw_log::tee(reader, raw_log), true, opts),
         }
     }
 }
@@ -65,9 +98,7 @@ impl Iterator for TTYSource {
     type Item = Result<TraceData, SourceError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.decoder
-            .next()
-            .map(|res| res.map_err(SourceError::DecodeError))
+        self.decoder.next()
     }
 }
 
@@ -92,6 +123,14 @@ impl Source for TTYSource {
         }
     }
 
+    fn byte_counter(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.bytes.clone())
+    }
+
+    fn shutdown(&self) -> Option<Shutdown> {
+        Some(self.shutdown.clone())
+    }
+
     fn describe(&self) -> String {
         format!("TTY (fd: {})", self.fd)
     }
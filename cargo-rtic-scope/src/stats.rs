@@ -0,0 +1,865 @@
+//! Per-task execution statistics of a recorded trace, as printed by
+//! `cargo rtic-scope stats`.
+use crate::auxiliary;
+use crate::cores::{CrossCoreSpawns, MergedTraces, Utilization};
+use crate::cpu_load::CpuLoad;
+use crate::inversion::InversionDetector;
+use crate::query::Evaluator;
+use crate::schedulability::Schedulability;
+use crate::sources::FileSource;
+use crate::timestamps::{
+    cycles_per_tick, interval_in, offset, offset_in, Divergence, TimestampQuality,
+};
+use crate::{RTICScopeError, StatsOptions};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use indexmap::IndexMap;
+use rtic_scope_api as api;
+use serde::{Deserialize, Serialize};
+
+/// The units execution times are reported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    Nanoseconds,
+    /// Cycles of the timestamp clock.
+    Cycles,
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ns" | "nanoseconds" => Ok(Self::Nanoseconds),
+            "cycles" => Ok(Self::Cycles),
+            _ => Err(format!("unknown units {:?}; expected ns or cycles", s)),
+        }
+    }
+}
+
+impl Units {
+    /// Suffix of values in these units, e.g. in column names.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Self::Nanoseconds => "ns",
+            Self::Cycles => "cycles",
+        }
+    }
+}
+
+/// Execution time statistics of a single task, in some [`Units`].
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskStats {
+    /// Number of complete executions.
+    pub count: usize,
+    /// Total execution time of all executions.
+    pub total: u128,
+    /// Shortest execution time.
+    pub min: Option<u128>,
+    /// Longest execution time.
+    pub max: u128,
+}
+
+impl TaskStats {
+    fn record(&mut self, duration: u128) {
+        self.count += 1;
+        self.total += duration;
+        self.min = Some(self.min.map_or(duration, |min| min.min(duration)));
+        self.max = self.max.max(duration);
+    }
+
+    pub fn mean(&self) -> u128 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        self.total / self.count as u128
+    }
+
+    /// Accounts the executions of `other`, e.g. of another trace.
+    fn combine(&mut self, other: &Self) {
+        self.count += other.count;
+        self.total += other.total;
+        self.min = match (self.min, other.min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// A complete execution of a task. An execution spans from a task
+/// being entered until it is exited, including any time the task was
+/// preempted.
+pub struct Execution {
+    pub task: String,
+    /// Offset from target reset when the task was entered.
+    pub start: Duration,
+    /// Offset from target reset when the task was exited.
+    pub end: Duration,
+    pub quality: TimestampQuality,
+}
+
+/// Pairs the enters and exits of tasks into [`Execution`]s.
+#[derive(Default)]
+pub struct Executions {
+    entered: HashMap<String, (Duration, TimestampQuality)>,
+    divergence: Divergence,
+}
+
+impl Executions {
+    /// Returns the executions completed in `chunk`.
+    pub fn update(&mut self, chunk: &api::EventChunk) -> Vec<Execution> {
+        let now = offset(&chunk.timestamp);
+        let quality = self.divergence.update(chunk);
+        let mut completed = vec![];
+        for event in chunk.events.iter() {
+            if let api::EventType::Task { name, action, .. } = event {
+                match action {
+                    api::TaskAction::Entered => {
+                        self.entered.insert(name.to_owned(), (now, quality));
+                    }
+                    api::TaskAction::Exited => {
+                        if let Some((start, start_quality)) = self.entered.remove(name) {
+                            completed.push(Execution {
+                                task: name.to_owned(),
+                                start,
+                                end: now.max(start),
+                                quality: start_quality.worst(quality),
+                            });
+                        }
+                    }
+                    api::TaskAction::Returned => (),
+                }
+            }
+        }
+
+        completed
+    }
+}
+
+/// A task that preempted another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preemption {
+    /// The task that was running.
+    pub preempted: String,
+    pub preempting: String,
+    /// Offset from target reset when the preempting task was entered.
+    pub at: Duration,
+}
+
+/// Reconstructs the preemption stack from the enters and exits of
+/// tasks into [`Preemption`]s.
+#[derive(Default)]
+pub struct Preemptions {
+    /// Entered tasks, the running task last.
+    stack: Vec<String>,
+}
+
+impl Preemptions {
+    /// Returns the preemptions that occurred in `chunk`.
+    pub fn update(&mut self, chunk: &api::EventChunk) -> Vec<Preemption> {
+        let now = offset(&chunk.timestamp);
+        let mut preemptions = vec![];
+        for event in chunk.events.iter() {
+            match event {
+                api::EventType::Task { name, action, .. } => match action {
+                    api::TaskAction::Entered => {
+                        if let Some(running) = self.stack.last() {
+                            preemptions.push(Preemption {
+                                preempted: running.to_owned(),
+                                preempting: name.to_owned(),
+                                at: now,
+                            });
+                        }
+                        self.stack.push(name.to_owned());
+                    }
+                    api::TaskAction::Exited => {
+                        if let Some(i) = self.stack.iter().rposition(|t| t == name) {
+                            self.stack.remove(i);
+                        }
+                    }
+                    api::TaskAction::Returned => (),
+                },
+                // NOTE enters and exits may have been lost.
+                api::EventType::Overflow => self.stack.clear(),
+                _ => (),
+            }
+        }
+
+        preemptions
+    }
+
+    /// The running task, if any.
+    pub fn running(&self) -> Option<&str> {
+        self.stack.last().map(String::as_str)
+    }
+}
+
+/// The integral over time of each auxiliary channel (see
+/// [`api::EventType::AuxSample`]) while each task ran, e.g. the energy
+/// each task drew, from samples of power. A sample holds until the next
+/// sample of its channel. Tasks are not accounted while preempted;
+/// while no task runs, [`Energy::NO_TASK`] is.
+#[derive(Default)]
+pub struct Energy {
+    preemptions: Preemptions,
+    /// The last value of each channel, and until when it is accounted.
+    last: HashMap<String, (f64, Duration)>,
+    /// The integral of each channel, in its units times seconds, by
+    /// task and channel.
+    pub integrals: IndexMap<String, IndexMap<String, f64>>,
+}
+
+impl Energy {
+    pub const NO_TASK: &'static str = "(no task)";
+
+    pub fn update(&mut self, chunk: &api::EventChunk) {
+        let now = offset(&chunk.timestamp);
+        let running = self.preemptions.running().unwrap_or(Self::NO_TASK);
+        for (channel, (value, since)) in self.last.iter_mut() {
+            if now > *since {
+                *self
+                    .integrals
+                    .entry(running.to_owned())
+                    .or_default()
+                    .entry(channel.to_owned())
+                    .or_default() += *value * (now - *since).as_secs_f64();
+                *since = now;
+            }
+        }
+
+        self.preemptions.update(chunk);
+        for event in chunk.events.iter() {
+            if let api::EventType::AuxSample { channel, value } = event {
+                let since = self
+                    .last
+                    .get(channel)
+                    .map_or(now, |(_, since)| now.max(*since));
+                self.last.insert(channel.to_owned(), (*value, since));
+            }
+        }
+    }
+}
+
+/// The statistics of a single core of the analyzed traces.
+struct CoreStatistics {
+    core: Option<u8>,
+    /// Frequency of the timestamp clock.
+    freq: u32,
+    tasks: TaskStatistics,
+    cpu_load: CpuLoad,
+    inversions: InversionDetector,
+    utilization: Utilization,
+    schedulability: Schedulability,
+}
+
+/// Execution time statistics of all tasks.
+pub struct TaskStatistics {
+    pub tasks: IndexMap<String, TaskStats>,
+    executions: Executions,
+    units: Units,
+    /// Frequency of the timestamp clock.
+    freq: u32,
+}
+
+impl TaskStatistics {
+    pub fn new(units: Units, freq: u32) -> Self {
+        Self {
+            tasks: IndexMap::new(),
+            executions: Executions::default(),
+            units,
+            freq,
+        }
+    }
+
+    pub fn update(&mut self, chunk: &api::EventChunk) {
+        for execution in self.executions.update(chunk) {
+            self.tasks
+                .entry(execution.task)
+                .or_default()
+                .record(interval_in(
+                    self.units,
+                    execution.start,
+                    execution.end,
+                    self.freq,
+                ));
+        }
+    }
+}
+
+/// The format of the report of `stats --aggregate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(format!(
+                "unknown report format {:?}; expected json or csv",
+                s
+            )),
+        }
+    }
+}
+
+/// A trace analyzed by `stats --aggregate`.
+#[derive(Serialize)]
+struct Run {
+    trace: PathBuf,
+    reset: chrono::DateTime<chrono::Local>,
+    /// The statistics of each task in this trace.
+    tasks: IndexMap<String, TaskStats>,
+}
+
+/// The report of `stats --aggregate`.
+#[derive(Serialize)]
+struct AggregateReport {
+    units: &'static str,
+    /// The program all runs are of.
+    program: String,
+    /// The statistics of each task across all runs.
+    tasks: IndexMap<String, TaskStats>,
+    runs: Vec<Run>,
+}
+
+impl AggregateReport {
+    fn new(units: Units, program: String, runs: Vec<Run>) -> Self {
+        let mut tasks: IndexMap<String, TaskStats> = IndexMap::new();
+        for run in runs.iter() {
+            for (name, task) in run.tasks.iter() {
+                tasks.entry(name.to_owned()).or_default().combine(task);
+            }
+        }
+
+        Self {
+            units: units.suffix(),
+            program,
+            tasks,
+            runs,
+        }
+    }
+
+    /// The report as CSV: a row of each task across all runs, with an
+    /// empty trace column, followed by a row of each task per run.
+    /// Fields are quoted as per RFC 4180 where needed.
+    fn csv(&self) -> String {
+        let mut csv = format!(
+            "task,trace,count,min_{unit},mean_{unit},max_{unit},total_{unit}\n",
+            unit = self.units
+        );
+        let runs = std::iter::once(("".to_string(), &self.tasks)).chain(
+            self.runs
+                .iter()
+                .map(|run| (run.trace.display().to_string(), &run.tasks)),
+        );
+        for (trace, tasks) in runs {
+            for (name, task) in tasks.iter() {
+                csv.push_str(&format!(
+                    "{},{},{},{},{},{},{}\n",
+                    csv_field(name),
+                    csv_field(&trace),
+                    task.count,
+                    task.min.unwrap_or_default(),
+                    task.mean(),
+                    task.max,
+                    task.total,
+                ));
+            }
+        }
+
+        csv
+    }
+}
+
+/// `field` as a CSV field: quoted, with quotes doubled, if it contains a
+/// separator, quote, or line break.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains(|c: char| matches!(c, ',' | '"' | '\n' | '\r')) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Analyzes every trace of a program in the trace directory recorded
+/// since `--since`, and prints a report of the execution statistics of
+/// each task across all of them, and per trace. The program is given by
+/// `--program`, or else is that of the most recently recorded trace, as
+/// the statistics of different programs are not comparable. See
+/// `--aggregate`.
+fn aggregate(opts: &StatsOptions) -> Result<(), RTICScopeError> {
+    let dir = match &opts.trace_dir {
+        Some(dir) => dir.clone(),
+        None => crate::default_trace_dir()?,
+    };
+    let mut traces: Vec<PathBuf> = crate::sinks::file::find_trace_files(dir.clone())?.collect();
+    traces.sort();
+
+    let mut headers = vec![];
+    for trace in traces {
+        let header = FileSource::header(fs::OpenOptions::new().read(true).open(&trace)?)?;
+        if let Some(since) = opts.since {
+            if header.reset_timestamp.naive_local().date() < since {
+                continue;
+            }
+        }
+        headers.push((trace, header));
+    }
+    let program = match &opts.program {
+        Some(program) => program.clone(),
+        None => match headers
+            .iter()
+            .max_by_key(|(_, header)| header.reset_timestamp)
+        {
+            Some((_, header)) => header.program_name.clone(),
+            None => {
+                return Err(anyhow::anyhow!("No traces to aggregate in {}", dir.display()).into())
+            }
+        },
+    };
+    let total = headers.len();
+    headers.retain(|(_, header)| header.program_name == program);
+    if headers.len() < total {
+        crate::log::status(
+            "Skipping",
+            format!(
+                "{} trace(s) of other programs than {}",
+                total - headers.len(),
+                program
+            ),
+        );
+    }
+
+    let mut runs = vec![];
+    for (trace, header) in headers {
+        crate::log::cont_status("Analyzing", format!("{}", trace.display()));
+        let mut src = MergedTraces::open(&[trace.clone()], opts.drift_correct)?;
+        let freq = src.metadata().next().unwrap().tpiu_freq();
+        let mut tasks = TaskStatistics::new(opts.units, freq);
+        for chunk in &mut src {
+            let (_, _, chunk) = chunk?;
+            tasks.update(&chunk);
+        }
+        runs.push(Run {
+            trace,
+            reset: header.reset_timestamp,
+            tasks: tasks.tasks,
+        });
+    }
+    if runs.is_empty() {
+        return Err(
+            anyhow::anyhow!("No traces of {} to aggregate in {}", program, dir.display()).into(),
+        );
+    }
+    crate::log::status(
+        "Aggregated",
+        format!("{} trace(s) of {}", runs.len(), program),
+    );
+
+    let report = AggregateReport::new(opts.units, program, runs);
+    match opts.format.unwrap_or(ReportFormat::Json) {
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        ReportFormat::Csv => print!("{}", report.csv()),
+    }
+
+    Ok(())
+}
+
+/// Reads the trace selected by `opts` and prints the execution
+/// statistics of each task, the CPU load of the target, the worst
+/// observed response time of each task and the utilization and busy
+/// periods of each priority level (see [`crate::schedulability`]), the
+/// tasks blocked by lower-priority tasks holding a resource, and the
+/// values of any `--eval` expressions. With `--merge`, the statistics are
+/// printed per core, along with the utilization of each core and the
+/// spawns of tasks across cores.
+pub fn run(opts: &StatsOptions) -> Result<(), RTICScopeError> {
+    if opts.aggregate {
+        return aggregate(opts);
+    }
+
+    let trace = crate::resolve_trace_file(&opts.trace_file, opts.index, &opts.trace_dir)?;
+    let mut paths = vec![trace];
+    paths.extend(opts.merge.iter().cloned());
+    let mut traces = MergedTraces::open_range(&paths, opts.drift_correct, opts.from, opts.to)?;
+    let merged = traces.is_merged();
+    for invocation in traces
+        .metadata()
+        .filter_map(|metadata| metadata.invocation())
+    {
+        crate::log::status(
+            "Recorded",
+            format!(
+                "by cargo-rtic-scope {}: {}",
+                invocation.version,
+                if invocation.args.is_empty() {
+                    "(command line redacted)".to_string()
+                } else {
+                    invocation.command_line()
+                }
+            ),
+        );
+    }
+
+    let mut cores: Vec<CoreStatistics> = traces
+        .metadata()
+        .map(|metadata| CoreStatistics {
+            core: metadata.core(),
+            freq: metadata.tpiu_freq(),
+            tasks: TaskStatistics::new(opts.units, metadata.tpiu_freq()),
+            cpu_load: CpuLoad::default(),
+            inversions: InversionDetector::new(
+                metadata.priorities().clone(),
+                metadata.ceilings(),
+                cycles_per_tick(
+                    metadata
+                        .decoding()
+                        .map_or(&itm::LocalTimestampOptions::Enabled, |d| &d.lts_prescaler),
+                ),
+            ),
+            utilization: Utilization::default(),
+            schedulability: Schedulability::new(metadata.priorities().clone()),
+        })
+        .collect();
+    let mut spawns = CrossCoreSpawns::default();
+    let mut evaluators: Vec<Evaluator> = opts.eval.iter().cloned().map(Evaluator::new).collect();
+    let mut samples = vec![];
+
+    // Merge the auxiliary samples, if any, into the trace.
+    let mut aux = match &opts.aux_csv {
+        Some(path) => {
+            let metadata = traces.metadata().next().unwrap();
+            auxiliary::load(path, metadata.sync_anchor(), metadata.core())?
+        }
+        None => vec![],
+    }
+    .into_iter()
+    .peekable();
+    let mut energy = Energy::default();
+    for chunk in &mut traces {
+        let (i, data, chunk) = chunk?;
+        while let Some(sample) =
+            aux.next_if(|sample| offset(&sample.timestamp) <= offset(&chunk.timestamp))
+        {
+            energy.update(&sample);
+        }
+        if opts.aux_csv.is_some() {
+            energy.update(&chunk);
+        }
+        let core = &mut cores[i];
+        core.cpu_load.update(&data, core.freq);
+        core.tasks.update(&chunk);
+        core.inversions.update(&chunk, core.freq);
+        core.utilization.update(&chunk);
+        core.schedulability.update(&chunk);
+        spawns.update(core.core.unwrap_or_default(), &chunk);
+        for eval in evaluators.iter_mut() {
+            if let Some(sample) = eval.update(&chunk) {
+                samples.push(eval.format(&sample));
+            }
+        }
+    }
+    for eval in evaluators.iter_mut() {
+        if let Some(sample) = eval.finish() {
+            samples.push(eval.format(&sample));
+        }
+    }
+
+    // Per-core columns and summaries are only printed when merging
+    // traces, in which case every trace has a core.
+    let label = |core: &CoreStatistics| match (merged, core.core) {
+        (true, Some(core)) => format!("core {}: ", core),
+        _ => "".to_string(),
+    };
+    println!(
+        "{}task\tcount\tmin [{unit}]\tmean [{unit}]\tmax [{unit}]\ttotal [{unit}]",
+        if merged { "core\t" } else { "" },
+        unit = opts.units.suffix()
+    );
+    for core in cores.iter() {
+        for (name, task) in core.tasks.tasks.iter() {
+            println!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}",
+                match (merged, core.core) {
+                    (true, Some(core)) => format!("{}\t", core),
+                    _ => "".to_string(),
+                },
+                name,
+                task.count,
+                task.min.unwrap_or_default(),
+                task.mean(),
+                task.max,
+                task.total,
+            );
+        }
+    }
+
+    for core in cores.iter() {
+        if let Some((avg, max)) = core.cpu_load.summary() {
+            println!(
+                "{}CPU load: {:.1}% average, {:.1}% maximum",
+                label(core),
+                avg,
+                max
+            );
+        }
+        if let (true, Some(percent)) = (merged, core.utilization.percent()) {
+            println!("{}utilization: {:.1}%", label(core), percent);
+        }
+    }
+
+    for core in cores.iter().filter(|core| core.schedulability.is_known()) {
+        let sched = &core.schedulability;
+        println!(
+            "{}worst observed response times; measured from entry unless the spawns of a task are traced:",
+            label(core)
+        );
+        println!(
+            "task\tpriority\tcount\tworst response [{unit}]\tmeasured from",
+            unit = opts.units.suffix()
+        );
+        for (name, response) in sched.responses.iter() {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                name,
+                sched.priority(name),
+                response.count,
+                offset_in(opts.units, response.worst, core.freq),
+                if response.from_release {
+                    "release"
+                } else {
+                    "entry"
+                },
+            );
+        }
+        println!("{}priority levels:", label(core));
+        println!(
+            "priority\tutilization\tbusy periods\tlongest busy period [{unit}]",
+            unit = opts.units.suffix()
+        );
+        for (priority, level) in sched.levels.iter() {
+            println!(
+                "{}\t{:.1}%\t{}\t{}",
+                priority,
+                sched.utilization(level).unwrap_or_default(),
+                level.busy_periods,
+                offset_in(opts.units, level.longest_busy_period, core.freq),
+            );
+        }
+    }
+
+    for core in cores
+        .iter()
+        .filter(|core| !core.inversions.inversions.is_empty())
+    {
+        println!(
+            "{}{} priority inversion(s); blocking times are upper bounds:",
+            label(core),
+            core.inversions.inversions.len()
+        );
+        println!(
+            "blocked\tholder\tresource\tunlocked [{unit}]\tblocking [{unit}]",
+            unit = opts.units.suffix()
+        );
+        for inversion in core.inversions.inversions.iter() {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                inversion.blocked,
+                inversion.holder,
+                inversion.resource,
+                offset_in(opts.units, inversion.unlocked, core.freq),
+                interval_in(opts.units, inversion.locked, inversion.unlocked, core.freq),
+            );
+        }
+    }
+
+    let spawns = spawns.finish();
+    if !spawns.is_empty() {
+        println!("{} cross-core spawn(s):", spawns.len());
+        println!("from core\tfrom\tto core\tto\tcount");
+        for (spawn, count) in spawns.iter() {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                spawn.from_core, spawn.from, spawn.to_core, spawn.to, count
+            );
+        }
+    }
+
+    if !energy.integrals.is_empty() {
+        println!("auxiliary channels integrated over the execution of each task, in their units times seconds:");
+        println!("task\tchannel\tintegral");
+        for (task, channels) in energy.integrals.iter() {
+            for (channel, integral) in channels.iter() {
+                println!("{}\t{}\t{}", task, channel, integral);
+            }
+        }
+    }
+
+    for sample in samples {
+        println!("{}", sample);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, chunk_at, task};
+    use crate::timestamps::DataRelation;
+
+    /// Ensure an execution takes the worse timestamp quality of its
+    /// start and end, and that timestamps diverge at an overflow.
+    #[test]
+    fn timestamp_quality() {
+        let ms = Duration::from_millis;
+        let mut executions = Executions::default();
+        executions.update(&chunk_at(
+            api::Timestamp::Sync(ms(1)),
+            vec![task("app::foo", api::TaskAction::Entered)],
+        ));
+        let completed = executions.update(&chunk_at(
+            api::Timestamp::UnknownDelay {
+                prev: ms(1),
+                curr: ms(2),
+            },
+            vec![task("app::foo", api::TaskAction::Exited)],
+        ));
+        assert_eq!(completed[0].quality.relation, DataRelation::UnknownDelay);
+        assert_eq!(completed[0].quality.score(), 2);
+
+        executions.update(&chunk_at(
+            api::Timestamp::Sync(ms(3)),
+            vec![
+                api::EventType::Overflow,
+                task("app::foo", api::TaskAction::Entered),
+            ],
+        ));
+        let completed = executions.update(&chunk_at(
+            api::Timestamp::Sync(ms(4)),
+            vec![task("app::foo", api::TaskAction::Exited)],
+        ));
+        assert_eq!(completed[0].quality.relation, DataRelation::Sync);
+        assert!(completed[0].quality.diverged);
+        assert_eq!(completed[0].quality.score(), 0);
+    }
+
+    /// Ensure a task entered while another runs preempts it, and that
+    /// the preemption stack is lost at an overflow.
+    #[test]
+    fn preemptions() {
+        use api::TaskAction::*;
+
+        let ms = Duration::from_millis;
+        let mut preemptions = Preemptions::default();
+        let mut update = |t, events| preemptions.update(&chunk(ms(t), events));
+
+        assert!(update(1, vec![task("low", Entered)]).is_empty());
+        assert_eq!(
+            update(2, vec![task("high", Entered)]),
+            [Preemption {
+                preempted: "low".to_string(),
+                preempting: "high".to_string(),
+                at: ms(2),
+            }]
+        );
+        assert!(update(
+            3,
+            vec![
+                task("high", Exited),
+                task("low", Exited),
+                task("other", Entered),
+            ]
+        )
+        .is_empty());
+        assert!(update(4, vec![api::EventType::Overflow, task("high", Entered)]).is_empty());
+    }
+
+    /// Ensure auxiliary samples are integrated over the time each task
+    /// ran, excluding the time it was preempted.
+    #[test]
+    fn energy() {
+        use api::TaskAction::*;
+
+        let ms = Duration::from_millis;
+        let power = |value| api::EventType::AuxSample {
+            channel: "power".to_string(),
+            value,
+        };
+        let mut energy = Energy::default();
+        for (t, events) in [
+            (0, vec![power(1.0)]),
+            (10, vec![task("low", Entered)]),
+            (20, vec![task("high", Entered)]),
+            (25, vec![power(3.0)]),
+            (30, vec![task("high", Exited)]),
+            (40, vec![task("low", Exited)]),
+            (50, vec![]),
+        ] {
+            energy.update(&chunk(ms(t), events));
+        }
+
+        let integral = |task: &str| energy.integrals[task]["power"];
+        assert!((integral(Energy::NO_TASK) - 0.04).abs() < 1e-9);
+        assert!((integral("low") - 0.04).abs() < 1e-9);
+        assert!((integral("high") - 0.02).abs() < 1e-9);
+    }
+
+    /// Ensure the statistics of each task are combined across runs,
+    /// and reported per run, with CSV fields quoted where needed.
+    #[test]
+    fn aggregate() {
+        let run = |trace: &str, tasks: Vec<(&str, u128)>| {
+            let mut stats: IndexMap<String, TaskStats> = IndexMap::new();
+            for (name, duration) in tasks {
+                stats.entry(name.to_string()).or_default().record(duration);
+            }
+            Run {
+                trace: PathBuf::from(trace),
+                reset: chrono::Local::now(),
+                tasks: stats,
+            }
+        };
+
+        let report = AggregateReport::new(
+            Units::Nanoseconds,
+            "app".to_string(),
+            vec![
+                run("a.trace", vec![("app::foo", 10), ("app::foo", 20)]),
+                run("b,\"1\".trace", vec![("app::bar", 5), ("app::foo", 60)]),
+            ],
+        );
+        assert_eq!(
+            report.tasks["app::foo"],
+            TaskStats {
+                count: 3,
+                total: 90,
+                min: Some(10),
+                max: 60,
+            }
+        );
+        assert_eq!(
+            report.csv(),
+            "task,trace,count,min_ns,mean_ns,max_ns,total_ns\n\
+             app::foo,,3,10,30,60,90\n\
+             app::bar,,1,5,5,5,5\n\
+             app::foo,a.trace,2,10,15,20,30\n\
+             app::bar,\"b,\"\"1\"\".trace\",1,5,5,5,5\n\
+             app::foo,\"b,\"\"1\"\".trace\",1,60,60,60,60\n"
+        );
+    }
+}
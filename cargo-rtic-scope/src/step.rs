@@ -0,0 +1,261 @@
+//! Stepping through a replay (see `replay --interactive`): the run loop
+//! pauses after a chunk has been drained to all sinks, the chunk is
+//! printed, and the replay continues once a command is read from stdin.
+//! While paused, the replay can still be interrupted.
+use crate::log;
+
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use crossbeam_channel as channel;
+use crossterm::tty::IsTty;
+use rtic_scope_api as api;
+
+/// Commands accepted when paused.
+const USAGE: &str = "<Enter> or n: next chunk; c: continue to the end; c <n>: continue for n chunks; u <task>: continue until <task> enters";
+
+#[derive(Debug, PartialEq)]
+enum StepCommand {
+    /// Pause again at the next chunk.
+    Next,
+    /// Pause again after this many chunks, or never.
+    Continue(Option<usize>),
+    /// Pause again once the task enters.
+    Until(String),
+}
+
+impl FromStr for StepCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words[..] {
+            [] | ["n"] => Ok(Self::Next),
+            ["c"] => Ok(Self::Continue(None)),
+            ["c", n] => match n.parse() {
+                Ok(n) if n > 0 => Ok(Self::Continue(Some(n))),
+                _ => Err(format!("{} is not a positive number of chunks", n)),
+            },
+            ["u", task] => Ok(Self::Until(task.to_string())),
+            _ => Err(format!("unknown command {:?}", s.trim())),
+        }
+    }
+}
+
+pub struct Stepper {
+    /// Only pause at chunks with task events.
+    tasks_only: bool,
+    /// Number of chunks to pass before pausing again.
+    skip: usize,
+    /// Task whose entry to pause at, if any.
+    until: Option<String>,
+    /// Whether the replay was continued to the end.
+    running: bool,
+    /// Whether the replay was interrupted while paused.
+    interrupted: bool,
+    /// Lines read from stdin. Disconnected once stdin is closed.
+    lines: channel::Receiver<String>,
+}
+
+impl Stepper {
+    /// Pauses after each chunk, or only after those with task events if
+    /// `tasks_only`. Never pauses if stdin is not a terminal.
+    pub fn new(tasks_only: bool) -> Self {
+        let running = !io::stdin().is_tty();
+        if running {
+            log::warn("stdin is not a terminal; not pausing the replay".to_string());
+        } else {
+            log::hint(format!("when paused: {}", USAGE));
+        }
+
+        Self {
+            tasks_only,
+            skip: 0,
+            until: None,
+            running,
+            interrupted: false,
+            lines: if running {
+                channel::never()
+            } else {
+                read_lines()
+            },
+        }
+    }
+
+    /// Pauses after `chunk`, if due, until a command is read from stdin
+    /// or `halt` is signalled. Once signalled, the replay is continued
+    /// to the end without pausing; see [`Stepper::interrupted`].
+    pub fn step(&mut self, chunk: &api::EventChunk, halt: &channel::Receiver<()>) {
+        if !self.pauses(chunk) {
+            return;
+        }
+
+        let mut msg = format!(
+            "chunk at {:.9} s",
+            crate::timestamps::offset(&chunk.timestamp).as_secs_f64()
+        );
+        for event in chunk.events.iter() {
+            msg.push_str(&format!("\n  {:?}", event));
+        }
+        log::status("Paused", msg);
+
+        match self.read_command(halt) {
+            Some(StepCommand::Next) => (),
+            Some(StepCommand::Continue(None)) => self.running = true,
+            Some(StepCommand::Continue(Some(n))) => self.skip = n - 1,
+            Some(StepCommand::Until(task)) => self.until = Some(task),
+            None => {
+                self.interrupted = true;
+                self.running = true;
+            }
+        }
+    }
+
+    /// Whether the replay was interrupted while paused.
+    pub fn interrupted(&self) -> bool {
+        self.interrupted
+    }
+
+    /// Whether to pause after `chunk`.
+    fn pauses(&mut self, chunk: &api::EventChunk) -> bool {
+        if self.running {
+            return false;
+        }
+        if self.tasks_only
+            && !chunk
+                .events
+                .iter()
+                .any(|event| matches!(event, api::EventType::Task { .. }))
+        {
+            return false;
+        }
+
+        if let Some(task) = &self.until {
+            let entered = chunk.events.iter().any(|event| {
+                matches!(event, api::EventType::Task {
+                    name,
+                    action: api::TaskAction::Entered,
+                    ..
+                } if name == task)
+            });
+            if entered {
+                self.until = None;
+            }
+            return entered;
+        }
+        if self.skip > 0 {
+            self.skip -= 1;
+            return false;
+        }
+
+        true
+    }
+
+    /// Reads commands from stdin until one is valid. The replay is
+    /// continued to the end if stdin is closed, and `None` is returned
+    /// if `halt` is signalled first.
+    fn read_command(&self, halt: &channel::Receiver<()>) -> Option<StepCommand> {
+        loop {
+            eprint!("{:>12} ", "(step)");
+            let _ = io::stderr().flush();
+            channel::select! {
+                recv(self.lines) -> line => match line {
+                    Ok(line) => match line.parse() {
+                        Ok(command) => return Some(command),
+                        Err(e) => log::warn(format!("{}; {}", e, USAGE)),
+                    },
+                    Err(_) => return Some(StepCommand::Continue(None)),
+                },
+                recv(halt) -> _ => return None,
+            }
+        }
+    }
+}
+
+/// Reads the lines of stdin on a thread of its own, such that the
+/// replay can be interrupted while waiting for a command.
+fn read_lines() -> channel::Receiver<String> {
+    let (tx, rx) = channel::unbounded();
+    std::thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) if tx.send(line).is_ok() => (),
+                Ok(_) => break,
+                Err(e) => {
+                    log::warn(format!(
+                        "failed to read from stdin: {}; continuing to the end",
+                        e
+                    ));
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, entered};
+    use std::time::Duration;
+
+    /// Ensure commands are parsed, and that the replay pauses where the
+    /// last command says.
+    #[test]
+    fn commands() {
+        assert_eq!("".parse(), Ok(StepCommand::Next));
+        assert_eq!("c 3\n".parse(), Ok(StepCommand::Continue(Some(3))));
+        assert_eq!("u foo".parse(), Ok(StepCommand::Until("foo".to_string())));
+        assert!("c 0".parse::<StepCommand>().is_err());
+        assert!("x".parse::<StepCommand>().is_err());
+
+        let mut stepper = Stepper {
+            tasks_only: true,
+            skip: 1,
+            until: None,
+            running: false,
+            interrupted: false,
+            lines: channel::never(),
+        };
+        assert!(!stepper.pauses(&chunk(Duration::ZERO, vec![api::EventType::Overflow])));
+        assert!(!stepper.pauses(&chunk(Duration::ZERO, vec![entered("foo")])));
+        assert!(stepper.pauses(&chunk(Duration::ZERO, vec![entered("foo")])));
+
+        stepper.until = Some("bar".to_string());
+        assert!(!stepper.pauses(&chunk(Duration::ZERO, vec![entered("foo")])));
+        assert!(stepper.pauses(&chunk(Duration::ZERO, vec![entered("bar")])));
+        assert!(stepper.pauses(&chunk(Duration::ZERO, vec![entered("foo")])));
+    }
+
+    /// Ensure a paused replay reads commands, continues to the end once
+    /// stdin is closed, and is interrupted by a halt signal.
+    #[test]
+    fn pause() {
+        let (lines_tx, lines) = channel::unbounded();
+        let (halt_tx, halt) = channel::unbounded();
+        let mut stepper = Stepper {
+            tasks_only: false,
+            skip: 0,
+            until: None,
+            running: false,
+            interrupted: false,
+            lines,
+        };
+
+        lines_tx.send("c 2".to_string()).unwrap();
+        stepper.step(&chunk(Duration::ZERO, vec![]), &halt);
+        assert_eq!(stepper.skip, 1);
+        assert!(!stepper.interrupted());
+
+        stepper.skip = 0;
+        halt_tx.send(()).unwrap();
+        stepper.step(&chunk(Duration::ZERO, vec![]), &halt);
+        assert!(stepper.interrupted() && stepper.running);
+
+        stepper.running = false;
+        drop(lines_tx);
+        stepper.step(&chunk(Duration::ZERO, vec![]), &channel::never());
+        assert!(stepper.running);
+    }
+}
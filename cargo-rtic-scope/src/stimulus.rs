@@ -0,0 +1,321 @@
+//! Reassembly of the writes to the ITM stimulus ports configured via
+//! `ports` in `[package.metadata.rtic-scope]` into log lines and
+//! binary telemetry frames. A single write carries at most four bytes,
+//! so messages of any length are written piecewise. Numeric ports
+//! instead carry a value per 4-byte write.
+use crate::TraceData;
+
+use indexmap::IndexMap;
+use itm::TracePacket;
+use rtic_scope_api as api;
+use serde::{Deserialize, Serialize};
+
+/// The longest line of a text port, in bytes. Longer lines are dropped,
+/// e.g. if the target never writes a newline.
+const MAX_LINE: usize = 1 << 12;
+
+/// What a configured stimulus port carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortKind {
+    /// Text, one message per line. Lines that are not valid UTF-8 are
+    /// reported as telemetry instead.
+    Text,
+    /// Binary frames.
+    Binary,
+    /// Little-endian unsigned 32-bit integers.
+    U32,
+    /// Little-endian 32-bit floats.
+    F32,
+    /// 32-bit words of unsigned bitfields; see [`StimulusPort::fields`].
+    Bitfield,
+}
+
+/// A stimulus port configured via `ports.<port> = { type = "binary",
+/// name = "imu" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StimulusPort {
+    #[serde(rename = "type")]
+    pub kind: PortKind,
+    /// Name the messages of the port are reported under.
+    pub name: String,
+    /// Size of each binary frame in bytes. If unset, each frame is
+    /// written as its length in a 16-bit write followed by its bytes;
+    /// see `cortex_m_rtic_trace::write_frame`.
+    #[serde(default)]
+    pub size: Option<usize>,
+    /// The fields of a bitfield port, by name, each as its first bit
+    /// and its width in bits, e.g. `fields.mode = [0, 4]`.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub fields: IndexMap<String, (u8, u8)>,
+}
+
+/// The state of a single port.
+struct Port {
+    config: StimulusPort,
+    buf: Vec<u8>,
+    /// Length of the frame being reassembled, if its length has been
+    /// written.
+    len: Option<usize>,
+}
+
+impl Port {
+    /// Accounts a write of `payload`, returning the completed messages.
+    /// Fails if the write cannot be part of a frame.
+    fn write(&mut self, payload: &[u8]) -> Result<Vec<api::EventType>, String> {
+        let name = &self.config.name;
+        let mut events = vec![];
+        match (self.config.kind, self.config.size) {
+            (PortKind::Text, _) => {
+                self.buf.extend_from_slice(payload);
+                while let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+                    let mut line: Vec<u8> = self.buf.drain(..=pos).collect();
+                    line.pop();
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                    events.push(match String::from_utf8(line) {
+                        Ok(message) => api::EventType::Log {
+                            name: name.to_owned(),
+                            message,
+                        },
+                        Err(e) => api::EventType::Telemetry {
+                            name: name.to_owned(),
+                            bytes: e.into_bytes(),
+                        },
+                    });
+                }
+                if self.buf.len() > MAX_LINE {
+                    self.buf.clear();
+                    return Err(format!(
+                        "line written to {} exceeds {} bytes; dropped",
+                        name, MAX_LINE
+                    ));
+                }
+            }
+            (PortKind::Binary, Some(size)) => {
+                self.buf.extend_from_slice(payload);
+                // NOTE(size > 0) empty frames are rejected by the manifest
+                while self.buf.len() >= size {
+                    events.push(api::EventType::Telemetry {
+                        name: name.to_owned(),
+                        bytes: self.buf.drain(..size).collect(),
+                    });
+                }
+            }
+            (kind @ (PortKind::U32 | PortKind::F32 | PortKind::Bitfield), _) => {
+                let word = u32::from_le_bytes(payload.try_into().map_err(|_| {
+                    format!(
+                        "{}-byte write to {}; expected a 4-byte value",
+                        payload.len(),
+                        name
+                    )
+                })?);
+                let channel = |name: String, value: f64| api::EventType::Channel { name, value };
+                match kind {
+                    PortKind::U32 => events.push(channel(name.to_owned(), word.into())),
+                    PortKind::F32 => {
+                        // NOTE not representable in JSON
+                        let value = f32::from_bits(word);
+                        if !value.is_finite() {
+                            return Err(format!("non-finite value {} written to {}", value, name));
+                        }
+                        events.push(channel(name.to_owned(), value.into()));
+                    }
+                    _ => {
+                        for (field, (offset, width)) in self.config.fields.iter() {
+                            let value = (u64::from(word) >> offset) & ((1u64 << width) - 1);
+                            events.push(channel(format!("{}.{}", name, field), value as f64));
+                        }
+                    }
+                }
+            }
+            (PortKind::Binary, None) => match (self.len, payload) {
+                (None, [a, b]) => self.len = Some(u16::from_le_bytes([*a, *b]).into()),
+                (None, _) => {
+                    return Err(format!(
+                        "{}-byte write to {} outside of a frame; expected a 2-byte frame length",
+                        payload.len(),
+                        name
+                    ))
+                }
+                (Some(_), _) => self.buf.extend_from_slice(payload),
+            },
+        }
+
+        // NOTE bytes written past the length of a frame in the same
+        // write are padding, such that frames may be written in words.
+        if let Some(len) = self.len.filter(|len| self.buf.len() >= *len) {
+            self.buf.truncate(len);
+            self.len = None;
+            events.push(api::EventType::Telemetry {
+                name: name.to_owned(),
+                bytes: self.buf.drain(..).collect(),
+            });
+        }
+
+        Ok(events)
+    }
+}
+
+/// Reassembles the writes to the configured stimulus ports.
+pub struct Reassembler {
+    ports: IndexMap<u8, Port>,
+}
+
+impl Reassembler {
+    pub fn new(ports: &IndexMap<u8, StimulusPort>) -> Self {
+        Self {
+            ports: ports
+                .iter()
+                .map(|(port, config)| {
+                    (
+                        *port,
+                        Port {
+                            config: config.clone(),
+                            buf: vec![],
+                            len: None,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Accounts the writes to the configured ports in `data`, adding
+    /// the completed log lines and telemetry frames to `chunk`. Writes
+    /// that cannot be reassembled are added as
+    /// [`api::EventType::Unmappable`]. As writes may have been lost,
+    /// partial messages are dropped at an overflow.
+    pub fn update(&mut self, data: &TraceData, chunk: &mut api::EventChunk) {
+        for packet in data.packets.iter() {
+            match packet {
+                TracePacket::Overflow => {
+                    for port in self.ports.values_mut() {
+                        port.buf.clear();
+                        port.len = None;
+                    }
+                }
+                TracePacket::Instrumentation { port, payload } => {
+                    if let Some(port) = self.ports.get_mut(port) {
+                        match port.write(payload) {
+                            Ok(events) => chunk.events.extend(events),
+                            Err(reason) => chunk
+                                .events
+                                .push(api::EventType::Unmappable(packet.clone(), reason)),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn port(kind: PortKind, size: Option<usize>) -> Port {
+        Port {
+            config: StimulusPort {
+                kind,
+                name: "port".to_string(),
+                size,
+                fields: IndexMap::new(),
+            },
+            buf: vec![],
+            len: None,
+        }
+    }
+
+    /// Ensure text is reported per line, lines that are not valid UTF-8
+    /// as telemetry, and that overlong lines are dropped.
+    #[test]
+    fn text() {
+        let mut text = port(PortKind::Text, None);
+        assert!(text.write(b"hel").unwrap().is_empty());
+        let events = text.write(b"lo\r\n\xff\n").unwrap();
+        assert!(matches!(
+            &events[0],
+            api::EventType::Log { message, .. } if message == "hello"
+        ));
+        assert!(matches!(
+            &events[1],
+            api::EventType::Telemetry { bytes, .. } if bytes == &[0xff]
+        ));
+
+        assert!(text.write(&[b'a'; MAX_LINE]).unwrap().is_empty());
+        assert!(text.write(b"a").is_err());
+        assert!(text.buf.is_empty());
+    }
+
+    /// Ensure binary frames are reassembled across writes, by size or
+    /// by their written length, and that padding is dropped.
+    #[test]
+    fn binary() {
+        let mut sized = port(PortKind::Binary, Some(3));
+        assert!(sized.write(&[1, 2]).unwrap().is_empty());
+        let events = sized.write(&[3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[1],
+            api::EventType::Telemetry { bytes, .. } if bytes == &[4, 5, 6]
+        ));
+
+        let mut framed = port(PortKind::Binary, None);
+        assert!(framed.write(&[1, 2, 3, 4]).is_err());
+        assert!(framed.write(&[5, 0]).unwrap().is_empty());
+        assert!(framed.write(&[1, 2, 3, 4]).unwrap().is_empty());
+        let events = framed.write(&[5, 0, 0, 0]).unwrap();
+        assert!(matches!(
+            &events[..],
+            [api::EventType::Telemetry { bytes, .. }] if bytes == &[1, 2, 3, 4, 5]
+        ));
+        assert!(matches!(
+            &framed.write(&[0, 0]).unwrap()[..],
+            [api::EventType::Telemetry { bytes, .. }] if bytes.is_empty()
+        ));
+    }
+
+    /// Ensure numeric ports yield a value per 4-byte write, and a value
+    /// per field of bitfield ports.
+    #[test]
+    fn numeric() {
+        let values = |port: &mut Port, payload: &[u8]| -> Vec<(String, f64)> {
+            port.write(payload)
+                .unwrap()
+                .into_iter()
+                .map(|event| match event {
+                    api::EventType::Channel { name, value } => (name, value),
+                    event => panic!("unexpected event {:?}", event),
+                })
+                .collect()
+        };
+
+        let mut int = port(PortKind::U32, None);
+        assert_eq!(
+            values(&mut int, &[1, 1, 0, 0]),
+            [("port".to_string(), 257.0)]
+        );
+        assert!(int.write(&[1, 1]).is_err());
+
+        let mut float = port(PortKind::F32, None);
+        assert_eq!(
+            values(&mut float, &1.5f32.to_le_bytes()),
+            [("port".to_string(), 1.5)]
+        );
+        assert!(float.write(&f32::NAN.to_le_bytes()).is_err());
+
+        let mut bits = port(PortKind::Bitfield, None);
+        bits.config.fields =
+            IndexMap::from_iter([("mode".to_string(), (0, 4)), ("level".to_string(), (4, 28))]);
+        assert_eq!(
+            values(&mut bits, &0xffff_fff3u32.to_le_bytes()),
+            [
+                ("port.mode".to_string(), 3.0),
+                ("port.level".to_string(), 0x0fff_ffff as f64)
+            ]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Timestamp arithmetic of the backend: offsets from target reset,
+//! their conversion to and from cycles of the timestamp clock, shifting
+//! and correcting the timestamps of chunks, wall-clock times, and the
+//! quality of timestamps, which diverge at an overflow. Used by
+//! recovery, analysis, and export alike.
+//!
+//! Offsets only depend on the frequency of the timestamp clock, never
+//! on the baud rate of the trace port. Cycles are converted in integer
+//! arithmetic, rounded to the nearest nanosecond or cycle, such that
+//! cycles converted to an offset and back are those cycles. Offsets
+//! are reported in the [`Units`] of analysis and export.
+use crate::stats::Units;
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use itm::LocalTimestampOptions;
+use rtic_scope_api as api;
+
+/// Offset of the given timestamp from target reset. For timestamps of
+/// unknown quality, the upper bound is used.
+pub fn offset(timestamp: &api::Timestamp) -> Duration {
+    match timestamp {
+        api::Timestamp::Sync(offset) | api::Timestamp::AssocEventDelay(offset) => *offset,
+        api::Timestamp::UnknownDelay { prev: _, curr }
+        | api::Timestamp::UnknownAssocEventDelay { prev: _, curr } => *curr,
+    }
+}
+
+/// The given offset in cycles of a timestamp clock of frequency
+/// `freq`, rounded to the nearest cycle.
+pub fn cycles(offset: Duration, freq: u32) -> u64 {
+    ((offset.as_nanos() * freq as u128 + 500_000_000) / 1_000_000_000) as u64
+}
+
+/// The number of cycles of the timestamp clock per local timestamp
+/// tick with the given `prescaler`.
+pub fn cycles_per_tick(prescaler: &LocalTimestampOptions) -> u64 {
+    match prescaler {
+        LocalTimestampOptions::Disabled | LocalTimestampOptions::Enabled => 1,
+        LocalTimestampOptions::EnabledDiv4 => 4,
+        LocalTimestampOptions::EnabledDiv16 => 16,
+        LocalTimestampOptions::EnabledDiv64 => 64,
+    }
+}
+
+/// The given offset in cycles of a timestamp clock of frequency `freq`
+/// as counted by local timestamps with the given `prescaler`: rounded
+/// to the nearest whole number of local timestamp ticks, each of which
+/// spans `prescaler` cycles.
+pub fn tick_cycles(offset: Duration, freq: u32, prescaler: &LocalTimestampOptions) -> u64 {
+    let cycles_per_tick = cycles_per_tick(prescaler);
+    let ticks = (offset.as_nanos() * freq as u128 + cycles_per_tick as u128 * 500_000_000)
+        / (cycles_per_tick as u128 * 1_000_000_000);
+    ticks as u64 * cycles_per_tick
+}
+
+/// The given offset from target reset in `units`, given the frequency
+/// of the timestamp clock.
+pub fn offset_in(units: Units, offset: Duration, freq: u32) -> u128 {
+    match units {
+        Units::Nanoseconds => offset.as_nanos(),
+        Units::Cycles => cycles(offset, freq) as u128,
+    }
+}
+
+/// The length of the interval from `start` to `end` in `units`. In
+/// cycles, both ends are rounded to the nearest cycle before the
+/// difference is taken, so that the length is exact.
+pub fn interval_in(units: Units, start: Duration, end: Duration, freq: u32) -> u128 {
+    offset_in(units, end, freq).saturating_sub(offset_in(units, start, freq))
+}
+
+/// The shift of times in `units` that moves `anchor` to `t0` seconds.
+/// See `--align-to`.
+pub fn alignment(anchor: Duration, t0: f64, units: Units, freq: u32) -> i128 {
+    let t0 = match units {
+        Units::Nanoseconds => t0 * 1e9,
+        Units::Cycles => t0 * freq as f64,
+    };
+
+    t0.round() as i128 - offset_in(units, anchor, freq) as i128
+}
+
+/// The offset of the given number of cycles of a timestamp clock of
+/// frequency `freq`, rounded to the nearest nanosecond. Saturates at
+/// the longest offset in nanoseconds.
+pub fn from_cycles(cycles: u64, freq: u32) -> Duration {
+    let freq = freq.max(1) as u128;
+    let nanos = (cycles as u128 * 1_000_000_000 + freq / 2) / freq;
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+/// Applies `f` to each offset of `timestamp`.
+pub fn map_timestamp(timestamp: &mut api::Timestamp, f: impl Fn(Duration) -> Duration) {
+    match timestamp {
+        api::Timestamp::Sync(t) | api::Timestamp::AssocEventDelay(t) => *t = f(*t),
+        api::Timestamp::UnknownDelay { prev, curr }
+        | api::Timestamp::UnknownAssocEventDelay { prev, curr } => {
+            *prev = f(*prev);
+            *curr = f(*curr);
+        }
+    }
+}
+
+/// Applies `f` to each offset of the timestamps of `chunk`.
+pub fn map_chunk(chunk: &mut api::EventChunk, f: impl Fn(Duration) -> Duration) {
+    map_timestamp(&mut chunk.timestamp, &f);
+    if let Some(estimate) = chunk.estimated_timestamp.as_mut() {
+        *estimate = f(*estimate);
+    }
+}
+
+/// Shifts `t` by `(offset, later)`. Offsets are never shifted to
+/// before target reset.
+fn shift(t: Duration, (offset, later): (Duration, bool)) -> Duration {
+    if later {
+        t + offset
+    } else {
+        t.saturating_sub(offset)
+    }
+}
+
+/// Shifts `timestamp` by `(offset, later)`.
+pub fn shift_timestamp(timestamp: &mut api::Timestamp, by: (Duration, bool)) {
+    map_timestamp(timestamp, |t| shift(t, by));
+}
+
+/// Shifts the timestamps of `chunk` by `(offset, later)`.
+pub fn shift_chunk(chunk: &mut api::EventChunk, by: (Duration, bool)) {
+    map_chunk(chunk, |t| shift(t, by));
+}
+
+/// Time elapsed from `earlier` until `later`, or zero if `later` is
+/// not later.
+pub fn elapsed(earlier: DateTime<Local>, later: DateTime<Local>) -> Duration {
+    (later - earlier).to_std().unwrap_or_default()
+}
+
+/// Time elapsed from the Unix epoch until `t`.
+pub fn unix_time(t: DateTime<Local>) -> Duration {
+    std::time::SystemTime::from(t)
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// How a timestamp relates to the events it is attached to, from best
+/// to worst; see [`api::Timestamp`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DataRelation {
+    Sync,
+    AssocEventDelay,
+    UnknownDelay,
+    UnknownAssocEventDelay,
+}
+
+impl DataRelation {
+    pub fn of(timestamp: &api::Timestamp) -> Self {
+        match timestamp {
+            api::Timestamp::Sync(_) => Self::Sync,
+            api::Timestamp::AssocEventDelay(_) => Self::AssocEventDelay,
+            api::Timestamp::UnknownDelay { .. } => Self::UnknownDelay,
+            api::Timestamp::UnknownAssocEventDelay { .. } => Self::UnknownAssocEventDelay,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sync => "sync",
+            Self::AssocEventDelay => "assoc-event-delay",
+            Self::UnknownDelay => "unknown-delay",
+            Self::UnknownAssocEventDelay => "unknown-assoc-event-delay",
+        }
+    }
+}
+
+/// Quality of a timestamp, or of the worse of several: its
+/// [`DataRelation`], and whether it has diverged. Timestamps diverge
+/// at an overflow, as local timestamps may have been dropped and global
+/// timestamps are not traced to recover from it; all later timestamps
+/// are thus offset by an unknown amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampQuality {
+    pub relation: DataRelation,
+    pub diverged: bool,
+}
+
+impl TimestampQuality {
+    /// The highest score: a synchronous timestamp that has not diverged.
+    pub const MAX_SCORE: u8 = 4;
+
+    /// Scores the quality from 0 (diverged) to [`Self::MAX_SCORE`],
+    /// decreasing with the [`DataRelation`] of the timestamps.
+    pub fn score(&self) -> u8 {
+        if self.diverged {
+            0
+        } else {
+            Self::MAX_SCORE - self.relation as u8
+        }
+    }
+
+    /// The worse of both qualities.
+    pub fn worst(self, other: Self) -> Self {
+        Self {
+            relation: self.relation.max(other.relation),
+            diverged: self.diverged || other.diverged,
+        }
+    }
+}
+
+/// Follows the chunks of a trace to tell whether their timestamps have
+/// diverged. Offsets are monotonic until they do.
+#[derive(Default)]
+pub struct Divergence {
+    /// Whether an overflow has occurred.
+    diverged: bool,
+}
+
+impl Divergence {
+    /// The quality of the timestamp of `chunk`.
+    pub fn update(&mut self, chunk: &api::EventChunk) -> TimestampQuality {
+        self.diverged |= chunk
+            .events
+            .iter()
+            .any(|event| matches!(event, api::EventType::Overflow));
+
+        TimestampQuality {
+            relation: DataRelation::of(&chunk.timestamp),
+            diverged: self.diverged,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::chunk;
+    use crate::manifest::ManifestProperties;
+    use crate::sources::synthetic::{exception, local_timestamp, HARDWARE_TASK};
+    use crate::sources::TraceDecoder;
+    use std::ops::Range;
+
+    /// A xorshift generator, such that properties are checked against
+    /// the same inputs on every run.
+    struct Inputs(u64);
+
+    impl Inputs {
+        fn next(&mut self, range: Range<u64>) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            range.start + self.0 % (range.end - range.start)
+        }
+    }
+
+    /// Decodes a stream of exception traces, each followed by a local
+    /// timestamp of the given delta, into the offsets of its chunks.
+    fn decode(deltas: &[u32], manip: &ManifestProperties) -> Vec<Duration> {
+        let stream: Vec<u8> = deltas
+            .iter()
+            .flat_map(|delta| [exception(HARDWARE_TASK, 1), local_timestamp(*delta)].concat())
+            .collect();
+
+        manip
+            .decoder
+            .decode(Box::new(std::io::Cursor::new(stream)), false, manip)
+            .map(|data| offset(&data.unwrap().timestamp))
+            .collect()
+    }
+
+    /// Ensure offsets converted to cycles and back are exact, and that
+    /// conversions are monotonic.
+    #[test]
+    fn cycles_round_trip() {
+        let mut inputs = Inputs(0x2545_f491_4f6c_dd1d);
+        for _ in 0..10_000 {
+            let freq = inputs.next(1..400_000_000) as u32;
+            let n = inputs.next(0..1 << 40);
+
+            assert_eq!(cycles(from_cycles(n, freq), freq), n, "{} Hz", freq);
+            assert!(from_cycles(n, freq) <= from_cycles(n + 1, freq));
+            let t = Duration::from_nanos(inputs.next(0..1 << 50));
+            assert!(cycles(t, freq) <= cycles(t + Duration::from_nanos(1), freq));
+        }
+    }
+
+    /// Ensure decoded offsets are the sum of the local timestamps in
+    /// cycles of the timestamp clock (of typical frequencies), and do
+    /// not depend on the baud rate.
+    #[test]
+    fn decoded_offsets() {
+        let mut inputs = Inputs(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..50 {
+            let deltas: Vec<u32> = (0..20).map(|_| inputs.next(1..1 << 20) as u32).collect();
+            let mut manip = ManifestProperties::synthetic();
+            manip.expect_malformed = false;
+            manip.tpiu_freq = inputs.next(1_000_000..16_000_001) as u32;
+            let offsets = decode(&deltas, &manip);
+
+            let mut sum = 0;
+            assert_eq!(offsets.len(), deltas.len());
+            for (offset, delta) in offsets.iter().zip(deltas.iter()) {
+                sum += *delta as u64;
+                assert_eq!(cycles(*offset, manip.tpiu_freq), sum);
+            }
+            assert!(offsets.windows(2).all(|w| w[0] <= w[1]));
+
+            manip.tpiu_baud = inputs.next(9_600..10_000_000) as u32;
+            assert_eq!(decode(&deltas, &manip), offsets);
+        }
+    }
+
+    /// Ensure decoded offsets in cycles account for the local timestamp
+    /// prescaler.
+    #[test]
+    fn prescaled_cycles() {
+        let mut inputs = Inputs(0x0123_4567_89ab_cdef);
+        let deltas: Vec<u32> = (0..20).map(|_| inputs.next(1..1 << 16) as u32).collect();
+        let mut manip = ManifestProperties::synthetic();
+        manip.expect_malformed = false;
+        manip.lts_prescaler = LocalTimestampOptions::EnabledDiv16;
+        let offsets = decode(&deltas, &manip);
+
+        let mut sum = 0;
+        for (offset, delta) in offsets.iter().zip(deltas.iter()) {
+            sum += *delta as u64;
+            assert_eq!(
+                tick_cycles(*offset, manip.tpiu_freq, &manip.lts_prescaler),
+                sum * 16
+            );
+        }
+    }
+
+    /// Ensure timestamps diverge at the first overflow, and stay
+    /// diverged.
+    #[test]
+    fn divergence() {
+        let mut inputs = Inputs(0xdead_beef_cafe_f00d);
+        let mut divergence = Divergence::default();
+        let mut overflowed = false;
+        for _ in 0..1_000 {
+            let overflow = inputs.next(0..100) == 0;
+            overflowed |= overflow;
+            let chunk = chunk(
+                Duration::ZERO,
+                if overflow {
+                    vec![api::EventType::Overflow]
+                } else {
+                    vec![]
+                },
+            );
+
+            let quality = divergence.update(&chunk);
+            assert_eq!(quality.diverged, overflowed);
+            assert_eq!(quality.score() == 0, overflowed);
+        }
+        assert!(overflowed);
+    }
+
+    /// Ensure shifted offsets are never before target reset.
+    #[test]
+    fn shifted() {
+        let ms = Duration::from_millis;
+        let mut timestamp = api::Timestamp::UnknownDelay {
+            prev: ms(1),
+            curr: ms(5),
+        };
+        shift_timestamp(&mut timestamp, (ms(2), false));
+        assert!(matches!(
+            timestamp,
+            api::Timestamp::UnknownDelay { prev, curr } if prev == ms(0) && curr == ms(3)
+        ));
+        shift_timestamp(&mut timestamp, (ms(2), true));
+        assert_eq!(offset(&timestamp), ms(5));
+    }
+
+    /// Ensure the sync anchor is moved to the given time.
+    #[test]
+    fn alignment() {
+        let anchor = Duration::from_micros(1500);
+        assert_eq!(
+            super::alignment(anchor, 0.0, Units::Nanoseconds, 16_000_000),
+            -1_500_000
+        );
+        assert_eq!(
+            super::alignment(anchor, 0.002, Units::Nanoseconds, 16_000_000),
+            500_000
+        );
+        assert_eq!(
+            super::alignment(anchor, 0.0, Units::Cycles, 16_000_000),
+            -24_000
+        );
+    }
+
+    /// Ensure cycles are rounded to the nearest nanosecond, and that
+    /// offsets past the longest in nanoseconds saturate.
+    #[test]
+    fn cycles() {
+        assert_eq!(from_cycles(3, 16_000_000), Duration::from_nanos(188));
+        assert_eq!(from_cycles(u64::MAX, 1), Duration::from_nanos(u64::MAX));
+    }
+}
@@ -0,0 +1,158 @@
+//! Aggregation of the warnings about packets that cannot be mapped or
+//! decoded, which some targets emit thousands of per minute. With
+//! `--quiet-warnings`, only the first occurrence of each warning is
+//! printed; repeats are counted, and summarized periodically and at the
+//! end of the session. Warnings are grouped by the kind of the packet
+//! and the reason.
+use std::time::Duration;
+
+use indexmap::IndexMap;
+
+/// How often repeated warnings are summarized during a session.
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Group {
+    /// Occurrences during the session.
+    count: usize,
+    /// Occurrences when last summarized.
+    summarized: usize,
+    /// Offset from target reset of the first occurrence.
+    first: Duration,
+}
+
+#[derive(Default)]
+pub struct Warnings {
+    /// Whether repeats are suppressed.
+    quiet: bool,
+    /// Occurrences of each warning, by its message without the packet
+    /// payload.
+    groups: IndexMap<String, Group>,
+}
+
+impl Warnings {
+    pub fn new(quiet: bool) -> Self {
+        Self {
+            quiet,
+            groups: IndexMap::new(),
+        }
+    }
+
+    /// Records a warning about `packet` (as formatted by `{:?}`) at
+    /// `at` from target reset, e.g. "cannot map" with an optional
+    /// `reason`. Returns the warning to print, unless suppressed.
+    fn record(
+        &mut self,
+        what: &str,
+        packet: &str,
+        reason: Option<&str>,
+        at: Duration,
+    ) -> Option<String> {
+        let suffix = reason.map(|r| format!(": {}", r)).unwrap_or_default();
+        if !self.quiet {
+            return Some(format!("{} {} packet{}", what, packet, suffix));
+        }
+
+        let key = format!("{} {}{}", what, packet_kind(packet), suffix);
+        let group = self.groups.entry(key).or_insert(Group {
+            count: 0,
+            summarized: 0,
+            first: at,
+        });
+        group.count += 1;
+        (group.count == 1).then(|| {
+            format!(
+                "{} {} packet{}; repeats are summarized",
+                what, packet, suffix
+            )
+        })
+    }
+
+    /// Prints the warning [`Warnings::record`] returns, if any.
+    pub fn warn(&mut self, what: &str, packet: &str, reason: Option<&str>, at: Duration) {
+        if let Some(warning) = self.record(what, packet, reason, at) {
+            crate::log::warn(warning);
+        }
+    }
+
+    /// Summarizes the warnings repeated since the last summary, or all
+    /// repeated warnings at the end of the session.
+    pub fn summarize(&mut self, end: bool) -> Vec<String> {
+        self.groups
+            .iter_mut()
+            .filter(|(_, group)| group.count > 1 && (end || group.count > group.summarized))
+            .map(|(key, group)| {
+                group.summarized = group.count;
+                format!(
+                    "{}: {} occurrences (first at +{:.1} s)",
+                    key,
+                    thousands(group.count),
+                    group.first.as_secs_f64()
+                )
+            })
+            .collect()
+    }
+}
+
+/// The kind of a packet formatted by `{:?}`, e.g. "DataTracePC" of
+/// "DataTracePC { comparator: 1, pc: 134218434 }".
+fn packet_kind(packet: &str) -> &str {
+    packet
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or(packet)
+}
+
+/// `n` with its digits grouped in thousands, e.g. "15 203".
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(' ');
+        }
+        grouped.push(c);
+    }
+
+    grouped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Ensure repeats are suppressed and summarized by the kind of the
+    /// packet and the reason.
+    #[test]
+    fn aggregate() {
+        let mut warnings = Warnings::new(true);
+        let pc = |pc| format!("DataTracePC {{ comparator: 1, pc: {} }}", pc);
+        let at = Duration::from_millis(1_200);
+
+        assert!(warnings
+            .record("cannot map", &pc(1), Some("no comparator"), at)
+            .is_some());
+        for i in 2..=15_203 {
+            let at = at + Duration::from_millis(i);
+            assert!(warnings
+                .record("cannot map", &pc(i), Some("no comparator"), at)
+                .is_none());
+        }
+        assert!(warnings.record("cannot map", "Sync", None, at).is_some());
+        assert_eq!(
+            warnings.summarize(false),
+            ["cannot map DataTracePC: no comparator: 15 203 occurrences (first at +1.2 s)"]
+        );
+        assert!(warnings.summarize(false).is_empty());
+        assert_eq!(warnings.summarize(true).len(), 1);
+
+        let mut warnings = Warnings::new(false);
+        assert_eq!(
+            warnings.record("cannot map", &pc(1), None, at),
+            Some(format!("cannot map {} packet", pc(1)))
+        );
+        assert!(warnings.record("cannot map", &pc(1), None, at).is_some());
+        assert!(warnings.summarize(true).is_empty());
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(1_000_000), "1 000 000");
+    }
+}
@@ -0,0 +1,183 @@
+//! Synthesis of the exits of tasks that have not exited within a
+//! timeout, as when their exit was lost to an overflow. Frontends would
+//! otherwise show such a task as executing forever. See `--watchdog`.
+use crate::timestamps::offset;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rtic_scope_api as api;
+
+/// Exits each task that has not exited within its timeout, measured
+/// from when it was entered, including the time it was preempted. The
+/// exit is synthesized at the start of the first chunk past the
+/// timeout, preceded by an [`api::AnomalyKind::MissingExit`] anomaly,
+/// and marked as estimated. Should the exit of the task be traced after
+/// all, it is dropped. On an [`api::EventType::Overflow`], all tasks
+/// are forgotten: the executing ones are exited by the
+/// [`ActionNormalizer`](crate::normalize::ActionNormalizer), and late
+/// exits lost to the overflow will not be traced.
+pub struct Watchdog {
+    /// Timeout of tasks without a timeout of their own, if any.
+    default: Option<Duration>,
+    timeouts: HashMap<String, Duration>,
+    /// Executing tasks and when they were entered, in the order they
+    /// were entered.
+    executing: Vec<(String, Duration)>,
+    /// Number of synthesized exits of each task that may yet be traced.
+    synthesized: HashMap<String, usize>,
+}
+
+impl Watchdog {
+    pub fn new(default: Option<Duration>, timeouts: HashMap<String, Duration>) -> Self {
+        Self {
+            default,
+            timeouts,
+            executing: vec![],
+            synthesized: HashMap::new(),
+        }
+    }
+
+    fn timeout(&self, task: &str) -> Option<Duration> {
+        self.timeouts.get(task).copied().or(self.default)
+    }
+
+    pub fn update(&mut self, chunk: &mut api::EventChunk) {
+        let now = offset(&chunk.timestamp);
+
+        // Exit the most recently entered tasks first.
+        let mut events = vec![];
+        for i in (0..self.executing.len()).rev() {
+            let (name, entered) = &self.executing[i];
+            match self.timeout(name) {
+                Some(timeout) if now.saturating_sub(*entered) > timeout => (),
+                _ => continue,
+            }
+
+            let (name, _) = self.executing.remove(i);
+            *self.synthesized.entry(name.clone()).or_default() += 1;
+            events.push(api::EventType::Anomaly {
+                kind: api::AnomalyKind::MissingExit,
+                name: name.clone(),
+            });
+            events.push(api::EventType::Task {
+                name,
+                action: api::TaskAction::Exited,
+                calibrated_offset: None,
+                args: None,
+                estimated: true,
+            });
+        }
+
+        for event in std::mem::take(&mut chunk.events) {
+            if let api::EventType::Overflow = event {
+                self.executing.clear();
+                self.synthesized.clear();
+            }
+            if let api::EventType::Task { name, action, .. } = &event {
+                match action {
+                    api::TaskAction::Entered => self.executing.push((name.to_owned(), now)),
+                    api::TaskAction::Exited => {
+                        if let Some(i) = self.executing.iter().rposition(|(n, _)| n == name) {
+                            self.executing.remove(i);
+                        } else if let Some(late) =
+                            self.synthesized.get_mut(name).filter(|late| **late > 0)
+                        {
+                            // NOTE(continue) already exited
+                            *late -= 1;
+                            continue;
+                        }
+                    }
+                    api::TaskAction::Returned => (),
+                }
+            }
+            events.push(event);
+        }
+
+        chunk.events = events;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{chunk, task};
+    use api::TaskAction::*;
+
+    fn events(chunk: &api::EventChunk) -> Vec<String> {
+        chunk
+            .events
+            .iter()
+            .map(|event| match event {
+                api::EventType::Task {
+                    name,
+                    action,
+                    estimated,
+                    ..
+                } => format!("{}:{:?}{}", name, action, if *estimated { "?" } else { "" }),
+                event => format!("{:?}", event),
+            })
+            .collect()
+    }
+
+    /// Ensure a task that does not exit within its timeout is exited,
+    /// and that its late exit is dropped.
+    #[test]
+    fn missing_exit() {
+        let ms = Duration::from_millis;
+        let mut watchdog = Watchdog::new(
+            Some(Duration::from_millis(10)),
+            HashMap::from([("slow".to_string(), Duration::from_millis(100))]),
+        );
+
+        let mut entered = chunk(ms(0), vec![task("fast", Entered), task("slow", Entered)]);
+        watchdog.update(&mut entered);
+        assert_eq!(events(&entered), ["fast:Entered", "slow:Entered"]);
+
+        let mut later = chunk(ms(20), vec![task("fast", Exited), task("slow", Exited)]);
+        watchdog.update(&mut later);
+        assert_eq!(
+            events(&later),
+            [
+                "Anomaly { kind: MissingExit, name: \"fast\" }",
+                "fast:Exited?",
+                "slow:Exited",
+            ]
+        );
+
+        let mut again = chunk(ms(30), vec![task("fast", Exited)]);
+        watchdog.update(&mut again);
+        assert!(again.events.is_empty());
+    }
+
+    /// Ensure tasks are forgotten on an overflow, such that those
+    /// executing during it are not exited again.
+    #[test]
+    fn overflow() {
+        let ms = Duration::from_millis;
+        let mut watchdog = Watchdog::new(Some(Duration::from_millis(10)), HashMap::new());
+
+        let mut entered = chunk(ms(0), vec![task("a", Entered), task("b", Entered)]);
+        watchdog.update(&mut entered);
+        let mut timeout = chunk(ms(20), vec![api::EventType::Overflow]);
+        watchdog.update(&mut timeout);
+        assert_eq!(
+            events(&timeout),
+            [
+                "Anomaly { kind: MissingExit, name: \"b\" }",
+                "b:Exited?",
+                "Anomaly { kind: MissingExit, name: \"a\" }",
+                "a:Exited?",
+                "Overflow",
+            ]
+        );
+
+        let mut reentered = chunk(ms(30), vec![task("a", Entered)]);
+        watchdog.update(&mut reentered);
+        let mut overflow = chunk(ms(35), vec![api::EventType::Overflow]);
+        watchdog.update(&mut overflow);
+        let mut later = chunk(ms(50), vec![task("a", Entered), task("a", Exited)]);
+        watchdog.update(&mut later);
+        assert_eq!(events(&later), ["a:Entered", "a:Exited"]);
+    }
+}
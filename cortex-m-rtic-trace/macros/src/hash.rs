@@ -0,0 +1,72 @@
+// Hashes of task names and application modules, as calculated by the
+// macros. Included by the tests of the host, which verify that its
+// hashes agree; hence no inner attributes.
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+
+/// The 24-bit FNV-1a hash of a task name, as reported by
+/// `cortex_m_rtic_trace::__check_id`. Must match the hash calculated by
+/// the host, which tests against this file.
+pub fn name_hash(name: &str) -> u32 {
+    let hash = name.bytes().fold(0x811c_9dc5u32, |hash, b| {
+        (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    });
+    hash & 0x00ff_ffff
+}
+
+/// Folds `tokens` into the 32-bit FNV-1a hash `hash`. Doc comments are
+/// skipped as whole `#[doc = ...]` attributes, as the form in which they
+/// reach a macro differs from how they are parsed from source. Must
+/// match the hash calculated by the host, which tests against this
+/// file.
+pub fn tokens_hash(hash: u32, tokens: TokenStream) -> u32 {
+    fn fnv(hash: u32, bytes: &[u8]) -> u32 {
+        bytes.iter().fold(hash, |hash, b| {
+            (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+        })
+    }
+
+    /// The number of tokens of the `#[doc = ...]` or `#![doc = ...]`
+    /// attribute `tokens` start with, if any.
+    fn doc_attribute(tokens: &[TokenTree]) -> Option<usize> {
+        let bang = matches!(tokens.get(1), Some(TokenTree::Punct(p)) if p.as_char() == '!');
+        let bang = usize::from(bang);
+        match (tokens.first(), tokens.get(1 + bang)) {
+            (Some(TokenTree::Punct(p)), Some(TokenTree::Group(g)))
+                if p.as_char() == '#'
+                    && g.delimiter() == Delimiter::Bracket
+                    && matches!(g.stream().into_iter().next(), Some(TokenTree::Ident(i)) if i == "doc") =>
+            {
+                Some(2 + bang)
+            }
+            _ => None,
+        }
+    }
+
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let (mut hash, mut i) = (hash, 0);
+    while i < tokens.len() {
+        if let Some(len) = doc_attribute(&tokens[i..]) {
+            i += len;
+            continue;
+        }
+        hash = match &tokens[i] {
+            TokenTree::Group(g) => {
+                let (open, close) = match g.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                fnv(
+                    tokens_hash(fnv(hash, open.as_bytes()), g.stream()),
+                    close.as_bytes(),
+                )
+            }
+            TokenTree::Ident(ident) => fnv(fnv(hash, ident.to_string().as_bytes()), b" "),
+            TokenTree::Punct(punct) => fnv(hash, &[punct.as_char() as u8]),
+            TokenTree::Literal(lit) => fnv(fnv(hash, lit.to_string().as_bytes()), b" "),
+        };
+        i += 1;
+    }
+    hash
+}
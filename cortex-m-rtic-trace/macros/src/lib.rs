@@ -1,13 +1,56 @@
 extern crate proc_macro;
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
-use syn::{self, parse_macro_input, ItemFn, LitInt, Stmt};
+use syn::{self, parse_macro_input, FnArg, Ident, ItemFn, ItemMod, LitInt, Pat, Stmt};
+
+mod hash;
+use hash::{name_hash, tokens_hash};
 
 static mut TRACE_ID: usize = 0;
 
+/// The arguments of a software task, following its context, whose
+/// value is traced with `#[trace(args)]`.
+fn task_args(fun: &ItemFn) -> Result<Vec<Ident>, syn::Error> {
+    fun.sig
+        .inputs
+        .iter()
+        .skip(1)
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => match &*arg.pat {
+                Pat::Ident(pat) => Ok(pat.ident.clone()),
+                pat => Err(syn::Error::new_spanned(
+                    pat,
+                    "#[trace(args)] requires arguments to be bound to identifiers",
+                )),
+            },
+            FnArg::Receiver(arg) => Err(syn::Error::new_spanned(
+                arg,
+                "#[trace] is applied to software tasks, which take no receiver",
+            )),
+        })
+        .collect()
+}
+
 #[proc_macro_attribute]
-pub fn trace(_attrs: TokenStream, item: TokenStream) -> TokenStream {
+pub fn trace(attrs: TokenStream, item: TokenStream) -> TokenStream {
     let mut fun = parse_macro_input!(item as ItemFn);
+
+    // Trace the value of the arguments of the task on #[trace(args)].
+    let args = if attrs.is_empty() {
+        None
+    } else {
+        let attr = parse_macro_input!(attrs as Ident);
+        if attr != "args" {
+            return syn::Error::new_spanned(attr, "expected `#[trace]` or `#[trace(args)]`")
+                .to_compile_error()
+                .into();
+        }
+        match task_args(&fun) {
+            Ok(args) => Some(args),
+            Err(e) => return e.to_compile_error().into(),
+        }
+    };
+
     fun.block.stmts = {
         // Generate a unique (software) task ID by strictly increasing a
         // variable that preserves state over multiple macro calls.
@@ -24,15 +67,39 @@ pub fn trace(_attrs: TokenStream, item: TokenStream) -> TokenStream {
         )
         .unwrap();
 
-        // Wrap the task body in a closure, write the enter UTID, call
-        // the closure and save the return value, write the exit UTID,
-        // and lastly return the value returned by the closure.
-        let prologue = syn::parse2::<Stmt>(quote!(
-            ::cortex_m_rtic_trace::__write_enter_id(#task_id);
+        // Wrap the task body in a closure, report the UTID (see the
+        // `id-check` feature), write the enter UTID (along with the
+        // value of the arguments, if requested), call the closure and
+        // save the return value, write the exit UTID, and lastly
+        // return the value returned by the closure. The exit UTID is
+        // only written if the enter UTID was (see the `throttle`
+        // feature). The value of the arguments is taken before the
+        // closure, which may move them.
+        let hash = name_hash(&fun.sig.ident.to_string());
+        let check = syn::parse2::<Stmt>(quote!(
+            ::cortex_m_rtic_trace::__check_id(#task_id, #hash);
         ))
         .unwrap();
+        let value = args.as_ref().map(|args| {
+            syn::parse2::<Stmt>(quote!(
+                let __rtic_scope_args = ::cortex_m_rtic_trace::__trace_args_value(&[
+                    #(::cortex_m_rtic_trace::TraceArg::trace_value(&#args)),*
+                ]);
+            ))
+            .unwrap()
+        });
+        let prologue = syn::parse2::<Stmt>(match &args {
+            Some(_) => quote!(
+                let __rtic_scope_traced =
+                    ::cortex_m_rtic_trace::__trace_enter_args(#task_id, __rtic_scope_args);
+            ),
+            None => quote!(
+                let __rtic_scope_traced = ::cortex_m_rtic_trace::__trace_enter(#task_id);
+            ),
+        })
+        .unwrap();
         let epilogue = syn::parse2::<Stmt>(quote!(
-            ::cortex_m_rtic_trace::__write_exit_id(#task_id);
+            ::cortex_m_rtic_trace::__trace_exit(#task_id, __rtic_scope_traced);
         ))
         .unwrap();
         let call = syn::parse2::<Stmt>(quote!(
@@ -54,8 +121,46 @@ pub fn trace(_attrs: TokenStream, item: TokenStream) -> TokenStream {
             .unwrap()
         };
 
-        vec![closure, prologue, call, epilogue, ret]
+        value
+            .into_iter()
+            .chain([closure, check, prologue, call, epilogue, ret])
+            .collect()
     };
 
     fun.into_token_stream().into()
 }
+
+/// Embeds the build ID of the RTIC application module it is applied to
+/// in the firmware, as the `RTIC_SCOPE_BUILD_ID` static. Apply above
+/// `#[rtic::app]`. The build ID is a hash of the content of the module,
+/// which also covers the order of the tasks traced with `#[trace]`,
+/// and thus their IDs. With the `build-id` feature,
+/// `cortex_m_rtic_trace::configure` reports it on
+/// `cortex_m_rtic_trace::BUILD_ID_PORT`, such that the host can tell
+/// whether the firmware was built from the sources the metadata is
+/// recovered from.
+#[proc_macro_attribute]
+pub fn build_id(_attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let module = parse_macro_input!(item as ItemMod);
+    let items = match &module.content {
+        Some((_, items)) => items,
+        None => {
+            return syn::Error::new_spanned(
+                module,
+                "#[build_id] is applied to the RTIC application module, which must be inline",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+    let id = tokens_hash(0x811c_9dc5, quote!(#(#items)*));
+
+    quote!(
+        #module
+
+        #[no_mangle]
+        #[used]
+        static RTIC_SCOPE_BUILD_ID: u32 = #id;
+    )
+    .into()
+}
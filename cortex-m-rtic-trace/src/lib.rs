@@ -13,9 +13,12 @@ pub use cortex_m::peripheral::{
     tpiu::TraceProtocol,
 };
 
-/// The tracing macro. Takes no arguments and should be placed on a
-/// function. Refer to crate example usage.
-pub use rtic_trace_macros::trace;
+/// The tracing macro. Should be placed on a function. Refer to crate
+/// example usage. With the `args-trace` feature, `#[trace(args)]` also
+/// traces the value of the arguments of the software task (following
+/// its context), which must implement [`TraceArg`]; see
+/// [`configure_args_tracing`].
+pub use rtic_trace_macros::{build_id, trace};
 
 /// Trace configuration to apply via [`configure`].
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -34,6 +37,162 @@ pub struct TraceConfiguration {
     pub protocol: TraceProtocol,
 }
 
+impl TraceConfiguration {
+    /// Checks the invariants of the configuration that do not depend on
+    /// the target: the TPIU clock frequency and baud rate are non-zero,
+    /// the baud rate is reachable by dividing the frequency with the
+    /// 16-bit SWO prescaler, and local timestamps are only prescaled
+    /// when clocked by the TPIU. Also checked by [`configure`]; see
+    /// [`static_assert_trace_config!`] to check a constant configuration
+    /// at compile time instead.
+    pub const fn validate(&self) -> Result<(), TraceConfigurationError> {
+        if self.tpiu_freq == 0
+            || self.tpiu_baud == 0
+            || self.tpiu_baud > self.tpiu_freq
+            || self.tpiu_freq / self.tpiu_baud - 1 > 0xffff
+        {
+            return Err(TraceConfigurationError::TPIUConfig);
+        }
+
+        if let (
+            TimestampClkSrc::SystemClock,
+            LocalTimestampOptions::EnabledDiv4
+            | LocalTimestampOptions::EnabledDiv16
+            | LocalTimestampOptions::EnabledDiv64,
+        ) = (self.timestamp_clk_src, self.delta_timestamps)
+        {
+            return Err(TraceConfigurationError::TimestampPrescaler);
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks a constant [`TraceConfiguration`] at compile time, such that
+/// an invalid configuration fails the build instead of [`configure`] on
+/// the target. See [`TraceConfiguration::validate`]. For example,
+///
+/// ```ignore
+/// const TRACE_CONFIG: TraceConfiguration = TraceConfiguration {
+///     delta_timestamps: LocalTimestampOptions::Enabled,
+///     absolute_timestamps: GlobalTimestampOptions::Disabled,
+///     timestamp_clk_src: TimestampClkSrc::AsyncTPIU,
+///     tpiu_freq: 16_000_000,
+///     tpiu_baud: 115_200,
+///     protocol: TraceProtocol::AsyncSWONRZ,
+/// };
+/// cortex_m_rtic_trace::static_assert_trace_config!(TRACE_CONFIG);
+/// ```
+#[macro_export]
+macro_rules! static_assert_trace_config {
+    ($config:expr) => {
+        const _: () = match $crate::TraceConfiguration::validate(&$config) {
+            Ok(()) => (),
+            Err($crate::TraceConfigurationError::TPIUConfig) => panic!(
+                "invalid trace configuration: the TPIU clock frequency and baud rate must be non-zero, and the frequency at most 65536 times the baud rate and no lower than it"
+            ),
+            Err($crate::TraceConfigurationError::TimestampPrescaler) => panic!(
+                "invalid trace configuration: local timestamps can only be prescaled when clocked by the TPIU (TimestampClkSrc::AsyncTPIU)"
+            ),
+            Err(_) => panic!("invalid trace configuration"),
+        };
+    };
+}
+
+/// Architecture of the target, as identified by the part number in the
+/// CPUID base register.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Architecture {
+    /// ARMv6-M (Cortex-M0, Cortex-M0+, Cortex-M1, SC000). Does not
+    /// implement the ITM and DWT units required for tracing.
+    ARMv6M,
+    /// ARMv7-M (Cortex-M3, Cortex-M4, Cortex-M7, SC300).
+    ARMv7M,
+    /// ARMv8-M Baseline (Cortex-M23). Does not implement the ITM and
+    /// DWT units required for tracing.
+    ARMv8MBaseline,
+    /// ARMv8-M Mainline (Cortex-M33, Cortex-M35P, Cortex-M55, Cortex-M85).
+    ARMv8MMainline,
+    /// An unknown implementer and part number. Assumed to be
+    /// ARMv7-M-compatible.
+    Unknown {
+        /// CPUID.IMPLEMENTER
+        implementer: u8,
+        /// CPUID.PARTNO
+        partno: u16,
+    },
+}
+
+impl Architecture {
+    /// Identifies the architecture of the executing target.
+    pub fn read() -> Self {
+        // NOTE(unsafe) atomic read with no side effects
+        let cpuid = unsafe { (*Core::CPUID::PTR).base.read() };
+        let implementer = (cpuid >> 24) as u8;
+        let partno = ((cpuid >> 4) & 0xfff) as u16;
+
+        const ARM: u8 = 0x41;
+        match (implementer, partno) {
+            (ARM, 0xc20 | 0xc60 | 0xc21 | 0xc30) => Self::ARMv6M,
+            (ARM, 0xc23 | 0xc24 | 0xc27 | 0xc33) => Self::ARMv7M,
+            (ARM, 0xd20) => Self::ARMv8MBaseline,
+            (ARM, 0xd21 | 0xd31 | 0xd22 | 0xd23) => Self::ARMv8MMainline,
+            (implementer, partno) => Self::Unknown {
+                implementer,
+                partno,
+            },
+        }
+    }
+}
+
+/// Raw addresses of registers accessed independently of the register
+/// layouts of the `cortex-m` crate, which are those of ARMv7-M.
+mod regs {
+    /// Debug Exception and Monitor Control Register.
+    pub const DEMCR: *mut u32 = 0xe000_edfc as *mut u32;
+    /// DEMCR.TRCENA: global enable for DWT and ITM features.
+    pub const DEMCR_TRCENA: u32 = 1 << 24;
+    /// Processor Feature Register 1.
+    pub const ID_PFR1: *const u32 = 0xe000_ed44 as *const u32;
+    /// ID_PFR1.Security: whether the Security Extension is implemented.
+    pub const ID_PFR1_SECURITY_MASK: u32 = 0xf << 4;
+
+    /// Interrupt Control and State Register.
+    pub const ICSR: *const u32 = 0xe000_ed04 as *const u32;
+    /// ICSR.VECTACTIVE: the exception number of the active exception,
+    /// or 0 in thread mode.
+    pub const ICSR_VECTACTIVE_MASK: u32 = 0x1ff;
+
+    /// Configurable Fault Status Register.
+    #[cfg(feature = "fault-trace")]
+    pub const CFSR: *const u32 = 0xe000_ed28 as *const u32;
+    /// CFSR.MMARVALID: MMFAR holds the faulting address.
+    #[cfg(feature = "fault-trace")]
+    pub const CFSR_MMARVALID: u32 = 1 << 7;
+    /// CFSR.BFARVALID: BFAR holds the faulting address.
+    #[cfg(feature = "fault-trace")]
+    pub const CFSR_BFARVALID: u32 = 1 << 15;
+    /// MemManage Fault Address Register.
+    #[cfg(feature = "fault-trace")]
+    pub const MMFAR: *const u32 = 0xe000_ed34 as *const u32;
+    /// BusFault Address Register.
+    #[cfg(feature = "fault-trace")]
+    pub const BFAR: *const u32 = 0xe000_ed38 as *const u32;
+
+    /// DWT_CTRL.SLEEPEVTENA: enable sleep counter overflow events.
+    pub const DWT_CTRL_SLEEPEVTENA: u32 = 1 << 19;
+
+    /// ARMv8-M DWT_FUNCTIONn.MATCH: data address, write access. See
+    /// the Armv8-M Architecture Reference Manual, DWT_FUNCTIONn.
+    pub const V8M_FUNCTION_MATCH_DADDR_WRITE: u32 = 0b0101;
+    /// ARMv8-M DWT_FUNCTIONn.ACTION: generate data trace packets.
+    pub const V8M_FUNCTION_ACTION_DATA_TRACE: u32 = 0b10 << 4;
+    /// ARMv8-M DWT_FUNCTIONn.DATAVSIZE: byte-sized watched accesses.
+    pub const V8M_FUNCTION_DATAVSIZE_BYTE: u32 = 0b00 << 10;
+    /// ARMv8-M DWT_FUNCTIONn.DATAVSIZE: word-sized watched accesses.
+    pub const V8M_FUNCTION_DATAVSIZE_WORD: u32 = 0b10 << 10;
+}
+
 /// Possible errors on [`configure`].
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum TraceConfigurationError {
@@ -45,6 +204,17 @@ pub enum TraceConfigurationError {
     GTS,
     /// The TPIU clock frequency or baud rate (or both) are invalid.
     TPIUConfig,
+    /// Local timestamps are prescaled, but not clocked by the TPIU. The
+    /// prescaler only applies to the TPIU clock.
+    TimestampPrescaler,
+    /// The target architecture does not implement the ITM and DWT
+    /// units required for tracing.
+    Architecture(Architecture),
+    /// Trace could not be globally enabled. On ARMv8-M targets
+    /// implementing the Security Extension, trace may be disabled for
+    /// the current security state by the secure firmware or debug
+    /// authentication.
+    TraceLocked,
     /// The ITM configuration failed to apply.
     ITMConfig(Core::itm::ITMConfigurationError),
 }
@@ -70,9 +240,38 @@ struct WatchVariable {
 static mut WATCH_VARIABLE_ENTER: WatchVariable = WatchVariable { id: 0 };
 /// Watch variable to which the just exited software task ID is written to. Aligned to 32-bit.
 static mut WATCH_VARIABLE_EXIT: WatchVariable = WatchVariable { id: 0 };
+/// Watch variable to which the ID and action of a just locked or
+/// unlocked resource is written to. Aligned to 32-bit.
+#[cfg(feature = "lock-trace")]
+static mut WATCH_VARIABLE_LOCK: WatchVariable = WatchVariable { id: 0 };
+
+/// Container of a variable in memory to which word-sized records are
+/// written. See [`trace_spawn`] and [`configure_args_tracing`].
+#[cfg(any(feature = "spawn-trace", feature = "args-trace"))]
+#[repr(align(4))]
+struct RecordWatchVariable {
+    pub record: u32,
+}
 
-/// Configures the ARMv7-M peripherals for RTIC hardware and software
-/// task tracing. Fails if the configuration cannot be applied.
+/// Watch variable to which a record of the just spawned software task
+/// and its spawner is written to.
+#[cfg(feature = "spawn-trace")]
+static mut WATCH_VARIABLE_SPAWN: RecordWatchVariable = RecordWatchVariable { record: 0 };
+/// Watch variable to which the ID of a just entered software task
+/// traced via `#[trace(args)]` is written along with the value of its
+/// arguments.
+#[cfg(feature = "args-trace")]
+static mut WATCH_VARIABLE_ARGS: RecordWatchVariable = RecordWatchVariable { record: 0 };
+/// The exception number of the context in which the currently
+/// executing software task was entered, and the ID of the task. Used to
+/// tell whether a spawn is made by that task or by a hardware task
+/// that preempted it.
+#[cfg(feature = "spawn-trace")]
+static mut CURRENT_TASK: Option<(u16, u8)> = None;
+
+/// Configures the ARMv7-M or ARMv8-M Mainline peripherals for RTIC
+/// hardware and software task tracing. Fails if the configuration
+/// cannot be applied.
 pub fn configure(
     dcb: &mut Core::DCB,
     tpiu: &mut Core::TPIU,
@@ -82,10 +281,16 @@ pub fn configure(
     exit_dwt_idx: usize,
     config: &TraceConfiguration,
 ) -> Result<(), TraceConfigurationError> {
+    let arch = Architecture::read();
+
     // Check hardware flags for tracing support, verify input.
     {
         use TraceConfigurationError as Error;
 
+        if let Architecture::ARMv6M | Architecture::ARMv8MBaseline = arch {
+            return Err(Error::Architecture(arch));
+        }
+
         let supports = tpiu.swo_supports();
         if !{
             match config.protocol {
@@ -97,9 +302,7 @@ pub fn configure(
             return Err(Error::SWOProtocol);
         }
 
-        if config.tpiu_freq == 0 || config.tpiu_baud == 0 {
-            return Err(Error::TPIUConfig);
-        }
+        config.validate()?;
 
         if !dwt.has_exception_trace() {
             return Err(Error::Trace);
@@ -109,6 +312,15 @@ pub fn configure(
     // Globally enable DWT and ITM features
     dcb.enable_trace();
 
+    // With the Security Extension, the enable may not take effect in
+    // the current security state.
+    // NOTE(unsafe) atomic reads with no side effects
+    if unsafe { regs::ID_PFR1.read_volatile() } & regs::ID_PFR1_SECURITY_MASK != 0
+        && unsafe { regs::DEMCR.read_volatile() } & regs::DEMCR_TRCENA == 0
+    {
+        return Err(TraceConfigurationError::TraceLocked);
+    }
+
     tpiu.set_swo_baud_rate(config.tpiu_freq, config.tpiu_baud);
     tpiu.set_trace_output_protocol(config.protocol);
     tpiu.enable_continuous_formatting(false); // drop ETM packets
@@ -122,29 +334,588 @@ pub fn configure(
         timestamp_clk_src: config.timestamp_clk_src,
     })?;
 
+    // Report the frequency of the timestamp clock; see FREQ_PORT.
+    #[cfg(feature = "freq-report")]
+    {
+        unsafe {
+            itm.ter[0].modify(|ter| ter | 1 << FREQ_PORT);
+        }
+        let stim = &mut itm.stim[FREQ_PORT];
+        while !stim.is_fifo_ready() {}
+        stim.write_u32(config.tpiu_freq);
+    }
+
     // Enable hardware task tracing
     dwt.enable_exception_tracing();
 
+    // Report the build ID of the firmware; see BUILD_ID_PORT.
+    #[cfg(feature = "build-id")]
+    {
+        extern "Rust" {
+            static RTIC_SCOPE_BUILD_ID: u32;
+        }
+
+        unsafe {
+            itm.ter[0].modify(|ter| ter | 1 << BUILD_ID_PORT);
+        }
+        let stim = &mut itm.stim[BUILD_ID_PORT];
+        while !stim.is_fifo_ready() {}
+        // NOTE(unsafe) defined by #[build_id] and never written to
+        stim.write_u32(unsafe { RTIC_SCOPE_BUILD_ID });
+    }
+
+    // Enable the stimulus port on which software tasks report their IDs.
+    #[cfg(feature = "id-check")]
+    unsafe {
+        itm.ter[0].modify(|ter| ter | 1 << ID_CHECK_PORT);
+    }
+
     // Configure DWT comparators for software task tracing.
     let enter_addr: u32 = unsafe { &WATCH_VARIABLE_ENTER.id as *const _ } as u32;
     let exit_addr: u32 = unsafe { &WATCH_VARIABLE_EXIT.id as *const _ } as u32;
-    for (dwt, addr) in [
-        (&dwt.c[enter_dwt_idx], enter_addr),
-        (&dwt.c[exit_dwt_idx], exit_addr),
-    ] {
-        // TODO do we need to clear the MATCHED, bit[24] after every match?
-        dwt.configure(ComparatorFunction::Address(ComparatorAddressSettings {
+    watch_address(
+        &dwt.c[enter_dwt_idx],
+        enter_addr,
+        arch,
+        regs::V8M_FUNCTION_DATAVSIZE_BYTE,
+    );
+    watch_address(
+        &dwt.c[exit_dwt_idx],
+        exit_addr,
+        arch,
+        regs::V8M_FUNCTION_DATAVSIZE_BYTE,
+    );
+
+    Ok(())
+}
+
+/// Configures a DWT comparator to emit data trace packets on writes to
+/// the given address. On ARMv8-M, `datavsize` is the size of the
+/// watched accesses.
+fn watch_address(
+    comparator: &Core::dwt::Comparator,
+    addr: u32,
+    arch: Architecture,
+    datavsize: u32,
+) {
+    if let Architecture::ARMv8MMainline = arch {
+        // The ARMv8-M DWT_FUNCTIONn layout differs from that of
+        // ARMv7-M, and DWT_MASKn is not implemented.
+        let function =
+            regs::V8M_FUNCTION_MATCH_DADDR_WRITE | regs::V8M_FUNCTION_ACTION_DATA_TRACE | datavsize;
+        unsafe {
+            (&comparator.comp as *const _ as *mut u32).write_volatile(addr);
+            (&comparator.function as *const _ as *mut u32).write_volatile(function);
+        }
+        return;
+    }
+
+    // TODO do we need to clear the MATCHED, bit[24] after every match?
+    comparator
+        .configure(ComparatorFunction::Address(ComparatorAddressSettings {
             address: addr,
             mask: 0,
             emit: EmitOption::Data,
             access_type: AccessType::WriteOnly,
         }))
         .unwrap(); // NOTE safe: valid (emit, access_type) used
+}
+
+/// Configures the DWT comparator `lock_dwt_idx` for resource lock
+/// tracing via [`trace_lock`]. Call after [`configure`]. The same index
+/// must be set as `dwt_lock_id` in
+/// `[package.metadata.rtic-scope]`.
+#[cfg(feature = "lock-trace")]
+pub fn configure_lock_tracing(dwt: &mut Core::DWT, lock_dwt_idx: usize) {
+    let lock_addr: u32 = unsafe { &WATCH_VARIABLE_LOCK.id as *const _ } as u32;
+    watch_address(
+        &dwt.c[lock_dwt_idx],
+        lock_addr,
+        Architecture::read(),
+        regs::V8M_FUNCTION_DATAVSIZE_BYTE,
+    );
+}
+
+/// Traces the execution of `f` as the critical section of the shared
+/// resource with the given ID. The ID of a resource is the position of
+/// its field in the `#[shared]` struct of the application, starting
+/// from zero. Up to 128 resources are supported.
+///
+/// Call from within the closure passed to `lock`:
+///
+/// ```ignore
+/// cx.shared.counter.lock(|counter| {
+///     cortex_m_rtic_trace::trace_lock(0, || *counter += 1)
+/// });
+/// ```
+#[cfg(feature = "lock-trace")]
+#[inline]
+pub fn trace_lock<R>(resource_id: u8, f: impl FnOnce() -> R) -> R {
+    debug_assert!(resource_id < 0x80, "128 resources are supported at maximum");
+
+    // NOTE bit 7 of the written ID denotes an unlock.
+    unsafe {
+        core::ptr::write_volatile(&mut WATCH_VARIABLE_LOCK.id, resource_id & 0x7f);
+    }
+    let retval = f();
+    unsafe {
+        core::ptr::write_volatile(&mut WATCH_VARIABLE_LOCK.id, resource_id | 0x80);
+    }
+
+    retval
+}
+
+/// Configures the DWT comparator `spawn_dwt_idx` for spawn tracing via
+/// [`trace_spawn`]. Call after [`configure`]. The same index must be
+/// set as `dwt_spawn_id` in `[package.metadata.rtic-scope]`.
+#[cfg(feature = "spawn-trace")]
+pub fn configure_spawn_tracing(dwt: &mut Core::DWT, spawn_dwt_idx: usize) {
+    let spawn_addr: u32 = unsafe { &WATCH_VARIABLE_SPAWN.record as *const _ } as u32;
+    watch_address(
+        &dwt.c[spawn_dwt_idx],
+        spawn_addr,
+        Architecture::read(),
+        regs::V8M_FUNCTION_DATAVSIZE_WORD,
+    );
+}
+
+/// Traces that the executing task spawned the software task with the
+/// given ID. The ID of a task is the order in which
+/// [`#[trace]`](trace) was applied, starting from zero. The spawner is
+/// the executing software task if it is traced via
+/// [`#[trace]`](trace), or else the executing hardware task (or
+/// `#[init]`/`#[idle]`).
+///
+/// Call next to the spawn:
+///
+/// ```ignore
+/// cortex_m_rtic_trace::trace_spawn(1);
+/// some_task::spawn().unwrap();
+/// ```
+#[cfg(feature = "spawn-trace")]
+#[inline]
+pub fn trace_spawn(task_id: u8) {
+    write_spawn_record(task_id, false);
+}
+
+/// Spawns the software task with the given ID via `spawn`, tracing the
+/// spawn as [`trace_spawn`] does, or else that it was rejected because
+/// the queue of the task was full. The spawned task is not dispatched
+/// before the spawn is traced, such that the host can follow the depth
+/// of the queue of each dispatcher.
+///
+/// Wrap the spawn:
+///
+/// ```ignore
+/// cortex_m_rtic_trace::trace_queued(1, || some_task::spawn(42))?;
+/// ```
+#[cfg(feature = "queue-trace")]
+#[inline]
+pub fn trace_queued<T, E>(task_id: u8, spawn: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    cortex_m::interrupt::free(|_| {
+        let result = spawn();
+        write_spawn_record(task_id, result.is_err());
+        result
+    })
+}
+
+#[cfg(feature = "spawn-trace")]
+#[inline]
+fn write_spawn_record(task_id: u8, rejected: bool) {
+    // NOTE(unsafe) atomic read with no side effects
+    let active = (unsafe { regs::ICSR.read_volatile() } & regs::ICSR_VECTACTIVE_MASK) as u16;
+
+    // NOTE the record is, from the least significant byte: the ID of
+    // the spawned task, the kind of spawner (bit 0: 0 for a software
+    // task, 1 for an exception) and whether the spawn was rejected (bit
+    // 1), and the software task ID or exception number of the spawner.
+    let spawner = match unsafe { CURRENT_TASK } {
+        Some((context, id)) if context == active => (id as u32) << 16,
+        _ => 1 << 8 | (active as u32) << 16,
+    };
+    let rejected = (rejected as u32) << 9;
+    unsafe {
+        core::ptr::write_volatile(
+            &mut WATCH_VARIABLE_SPAWN.record,
+            spawner | rejected | task_id as u32,
+        );
+    }
+}
+
+/// Configures the DWT comparator `args_dwt_idx` for the tracing of
+/// software task arguments via `#[trace(args)]`. Call after
+/// [`configure`]. The same index must be set as `dwt_args_id` in
+/// `[package.metadata.rtic-scope]`.
+///
+/// A software task traced via `#[trace(args)]` writes its ID along with
+/// the value of its arguments to this comparator when entered, instead
+/// of to the enter comparator. The value is the low 24 bits of
+/// [`TraceArg::trace_value`] of a single argument, or a 24-bit hash of
+/// those of several arguments.
+#[cfg(feature = "args-trace")]
+pub fn configure_args_tracing(dwt: &mut Core::DWT, args_dwt_idx: usize) {
+    let args_addr: u32 = unsafe { &WATCH_VARIABLE_ARGS.record as *const _ } as u32;
+    watch_address(
+        &dwt.c[args_dwt_idx],
+        args_addr,
+        Architecture::read(),
+        regs::V8M_FUNCTION_DATAVSIZE_WORD,
+    );
+}
+
+/// A software task argument whose value can be traced via
+/// `#[trace(args)]`. Only the low 24 bits of a single argument are
+/// traced; implement it for small, `Copy` types.
+#[cfg(feature = "args-trace")]
+pub trait TraceArg {
+    /// The value of the argument to trace.
+    fn trace_value(&self) -> u32;
+}
+
+#[cfg(feature = "args-trace")]
+macro_rules! impl_trace_arg {
+    ($($t:ty),+) => {
+        $(
+            impl TraceArg for $t {
+                #[inline]
+                fn trace_value(&self) -> u32 {
+                    *self as u32
+                }
+            }
+        )+
+    };
+}
+
+#[cfg(feature = "args-trace")]
+impl_trace_arg!(u8, u16, u32, usize, i8, i16, i32, isize, bool, char);
+
+#[cfg(feature = "args-trace")]
+impl TraceArg for u64 {
+    #[inline]
+    fn trace_value(&self) -> u32 {
+        (*self ^ *self >> 32) as u32
+    }
+}
+
+#[cfg(feature = "args-trace")]
+impl TraceArg for i64 {
+    #[inline]
+    fn trace_value(&self) -> u32 {
+        (*self as u64).trace_value()
+    }
+}
+
+/// Function utilized by [`#[trace(args)]`](trace) to reduce the values
+/// of the arguments of a software task to 24 bits: the value of a single
+/// argument as-is, or the FNV-1a hash of several. Only use this
+/// function via [`#[trace(args)]`](trace).
+#[cfg(feature = "args-trace")]
+#[doc(hidden)]
+#[inline]
+pub fn __trace_args_value(values: &[u32]) -> u32 {
+    let value = match values {
+        [] => 0,
+        [value] => *value,
+        values => values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .fold(0x811c_9dc5u32, |hash, b| {
+                (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+            }),
+    };
+    value & 0x00ff_ffff
+}
+
+/// Enables DWT sleep counter overflow events, from which the host
+/// estimates the CPU load of the target. Call after [`configure`].
+/// Increases the trace bandwidth used by a small event packet per 256
+/// sleep cycles.
+pub fn enable_cpu_load_events(dwt: &mut Core::DWT) {
+    let ctrl = &dwt.ctrl as *const _ as *mut u32;
+    unsafe {
+        ctrl.write_volatile(ctrl.read_volatile() | regs::DWT_CTRL_SLEEPEVTENA);
     }
+}
+
+/// Trace divider of each software task, by ID: 0 disables the tracing of
+/// the task, and n traces every nth invocation. Can be written to by
+/// the host via the debug probe while the target is running, e.g.
+/// `set var RTIC_SCOPE_TRACE_DIVIDER[3] = 10` in GDB.
+#[cfg(feature = "throttle")]
+#[no_mangle]
+static mut RTIC_SCOPE_TRACE_DIVIDER: [u8; 256] = [1; 256];
+/// Invocations of each software task since it was last traced.
+#[cfg(feature = "throttle")]
+static mut TRACE_SKIPPED: [u8; 256] = [0; 256];
+
+/// Only trace every `divider`th invocation of the software task with
+/// the given ID, or none at all if `divider` is 0. Trades completeness
+/// of the trace for fewer overflows on slow trace links. The ID of a
+/// task is the order in which [`#[trace]`](trace) was applied,
+/// starting from zero.
+#[cfg(feature = "throttle")]
+pub fn set_trace_divider(task_id: u8, divider: u8) {
+    unsafe {
+        core::ptr::write_volatile(&mut RTIC_SCOPE_TRACE_DIVIDER[task_id as usize], divider);
+    }
+}
+
+/// Possible errors on [`write_frame`].
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FrameError {
+    /// The ITM implements stimulus ports 0 through 31 only.
+    Port,
+    /// The frame is longer than 65535 bytes.
+    Length,
+}
+
+/// Writes `frame` to ITM stimulus port `port` as a frame of binary
+/// telemetry: its length as a 16-bit write, followed by its bytes. The
+/// host reassembles the frame if the port is configured via
+/// `ports.<port> = { type = "binary", name = "..." }` in
+/// `[package.metadata.rtic-scope]`. Frames are at most 65535 bytes;
+/// interrupts are disabled while a frame is written, such that frames
+/// are not interleaved. Ports 27 through 31 are used by this crate.
+pub fn write_frame(port: usize, frame: &[u8]) -> Result<(), FrameError> {
+    if port >= 32 {
+        return Err(FrameError::Port);
+    }
+    let len = u16::try_from(frame.len()).map_err(|_| FrameError::Length)?;
+
+    cortex_m::interrupt::free(|_| unsafe {
+        let itm = &mut *(Core::ITM::PTR as *mut Core::itm::RegisterBlock);
+        itm.ter[0].modify(|ter| ter | 1 << port);
+        let stim = &mut itm.stim[port];
+
+        while !stim.is_fifo_ready() {}
+        stim.write_u16(len);
+        let mut words = frame.chunks_exact(4);
+        for word in &mut words {
+            while !stim.is_fifo_ready() {}
+            stim.write_u32(u32::from_le_bytes(word.try_into().unwrap()));
+        }
+        for byte in words.remainder() {
+            while !stim.is_fifo_ready() {}
+            stim.write_u8(*byte);
+        }
+    });
 
     Ok(())
 }
 
+/// ITM stimulus port on which [`trace_fault`] writes the fault status
+/// registers.
+pub const FAULT_PORT: usize = 27;
+
+/// Writes the fault status of the target on [`FAULT_PORT`], such that
+/// the host reports the fault, its causes, and the faulting address, if
+/// valid, on the timeline. The record is the exception number of the
+/// fault in a 1-byte write, followed by CFSR and the faulting address
+/// (0 if not valid) in 4-byte writes. Call first thing in the handler
+/// of a fault exception (HardFault, MemManage, BusFault or UsageFault),
+/// e.g.
+///
+/// ```ignore
+/// #[exception]
+/// fn BusFault() {
+///     cortex_m_rtic_trace::trace_fault();
+///     // ...
+/// }
+/// ```
+///
+/// Requires the `fault-trace` feature, and `fault_trace = true` in
+/// `[package.metadata.rtic-scope]` for the host to decode the records.
+#[cfg(feature = "fault-trace")]
+pub fn trace_fault() {
+    cortex_m::interrupt::free(|_| unsafe {
+        // NOTE(unsafe) atomic reads with no side effects
+        let active = regs::ICSR.read_volatile() & regs::ICSR_VECTACTIVE_MASK;
+        let cfsr = regs::CFSR.read_volatile();
+        let address = if cfsr & regs::CFSR_MMARVALID != 0 {
+            regs::MMFAR.read_volatile()
+        } else if cfsr & regs::CFSR_BFARVALID != 0 {
+            regs::BFAR.read_volatile()
+        } else {
+            0
+        };
+
+        let itm = &mut *(Core::ITM::PTR as *mut Core::itm::RegisterBlock);
+        itm.ter[0].modify(|ter| ter | 1 << FAULT_PORT);
+        let stim = &mut itm.stim[FAULT_PORT];
+        while !stim.is_fifo_ready() {}
+        stim.write_u8(active as u8);
+        for word in [cfsr, address] {
+            while !stim.is_fifo_ready() {}
+            stim.write_u32(word);
+        }
+    });
+}
+
+/// ITM stimulus port on which [`configure`] reports the build ID
+/// embedded by [`#[build_id]`](build_id) once tracing is configured.
+/// The host compares it with the build ID of the sources it recovers
+/// the metadata from, and refuses to trace firmware built from other
+/// sources. Requires the `build-id` feature, and `build_id = true` in
+/// `[package.metadata.rtic-scope]` for the host to verify it.
+pub const BUILD_ID_PORT: usize = 28;
+
+/// ITM stimulus port on which [`sync_pulse`] writes its marker.
+pub const SYNC_PORT: usize = 29;
+
+/// Emits a synchronization marker on [`SYNC_PORT`] together with an
+/// external pulse, such that the trace can be aligned with the
+/// captures of external measurement equipment (e.g. an oscilloscope
+/// triggered on the pulse). `pulse` should toggle the GPIO the
+/// equipment is triggered on; the marker is written right after it
+/// returns, with interrupts disabled. Call once after [`configure`],
+/// e.g. at the end of `#[init]`. The host records the trace time of
+/// the first marker as the sync anchor of the trace, given
+/// `sync_pulse = true` in `[package.metadata.rtic-scope]`.
+pub fn sync_pulse(pulse: impl FnOnce()) {
+    cortex_m::interrupt::free(|_| unsafe {
+        let itm = &mut *(Core::ITM::PTR as *mut Core::itm::RegisterBlock);
+        itm.ter[0].modify(|ter| ter | 1 << SYNC_PORT);
+        let stim = &mut itm.stim[SYNC_PORT];
+        while !stim.is_fifo_ready() {}
+
+        pulse();
+        stim.write_u8(0);
+    });
+}
+
+/// ITM stimulus port on which [`configure`] reports the frequency of
+/// the TPIU source clock (that is, [`TraceConfiguration::tpiu_freq`])
+/// once tracing is configured, with the `freq-report` feature. The host
+/// reads it when replaying a raw trace file, which otherwise carries no
+/// frequency to decode timestamps with. Set `freq_report = true` in
+/// `[package.metadata.rtic-scope]` for the host to reserve the port.
+pub const FREQ_PORT: usize = 30;
+
+/// ITM stimulus port on which each software task reports its ID along
+/// with a hash of its name the first time it is entered. The host
+/// verifies these against the task IDs it recovered from the source of
+/// the application, and refuses to attribute trace data to the wrong
+/// tasks if they differ (e.g. if the firmware was built from other
+/// sources).
+pub const ID_CHECK_PORT: usize = 31;
+
+/// Software tasks, by ID, that have reported their ID.
+#[cfg(feature = "id-check")]
+static mut ID_CHECKED: [u32; 8] = [0; 8];
+
+/// Function utilized by [`#[trace]`](trace) to report the ID of the
+/// just entered software task on [`ID_CHECK_PORT`], if not already
+/// reported. `name_hash` is the 24-bit FNV-1a hash of the name of the
+/// task. Only use this function via [`#[trace]`](trace).
+#[doc(hidden)]
+#[inline]
+pub fn __check_id(id: u8, name_hash: u32) {
+    #[cfg(feature = "id-check")]
+    cortex_m::interrupt::free(|_| unsafe {
+        let (word, bit) = (id as usize / 32, 1 << (id % 32));
+        if ID_CHECKED[word] & bit != 0 {
+            return;
+        }
+        ID_CHECKED[word] |= bit;
+
+        let stim = &mut (*(Core::ITM::PTR as *mut Core::itm::RegisterBlock)).stim[ID_CHECK_PORT];
+        while !stim.is_fifo_ready() {}
+        stim.write_u32(u32::from(id) | name_hash << 8);
+    });
+
+    #[cfg(not(feature = "id-check"))]
+    let _ = (id, name_hash);
+}
+
+/// Returned by [`__trace_enter`] and passed on to [`__trace_exit`].
+/// Only use via [`#[trace]`](trace).
+#[doc(hidden)]
+pub struct __Entered {
+    traced: bool,
+    /// The software task that was executing before this one was
+    /// entered.
+    #[cfg(feature = "spawn-trace")]
+    previous: Option<(u16, u8)>,
+}
+
+/// Function utilized by [`#[trace]`](trace) to decide whether the
+/// just entered software task should be traced, in which case its
+/// unique ID is written to the enter watch address. Only use this
+/// function via [`#[trace]`](trace).
+#[inline]
+pub fn __trace_enter(id: u8) -> __Entered {
+    enter(id, || __write_enter_id(id))
+}
+
+/// Function utilized by [`#[trace(args)]`](trace) to decide whether
+/// the just entered software task should be traced, in which case its
+/// unique ID and the 24-bit value of its arguments are written to the
+/// arguments watch address. Only use this function via
+/// [`#[trace(args)]`](trace).
+#[cfg(feature = "args-trace")]
+#[doc(hidden)]
+#[inline]
+pub fn __trace_enter_args(id: u8, args: u32) -> __Entered {
+    enter(id, || unsafe {
+        core::ptr::write_volatile(&mut WATCH_VARIABLE_ARGS.record, id as u32 | args << 8);
+    })
+}
+
+/// Decides whether the just entered software task should be traced,
+/// in which case `write` records the entry.
+#[inline]
+fn enter(id: u8, write: impl FnOnce()) -> __Entered {
+    // NOTE(unsafe) software tasks preempt each other in a nested
+    // fashion, and the previous task is restored on exit.
+    #[cfg(feature = "spawn-trace")]
+    let previous = unsafe {
+        let active = (regs::ICSR.read_volatile() & regs::ICSR_VECTACTIVE_MASK) as u16;
+        core::mem::replace(&mut CURRENT_TASK, Some((active, id)))
+    };
+    let entered = |traced| __Entered {
+        traced,
+        #[cfg(feature = "spawn-trace")]
+        previous,
+    };
+
+    #[cfg(feature = "throttle")]
+    // NOTE(unsafe) the counter is not updated atomically: a traced
+    // function called from tasks of different priorities may preempt
+    // its own update. The counter is only ever accessed volatilely and
+    // a lost update merely traces or skips one invocation too many.
+    unsafe {
+        let divider = core::ptr::read_volatile(&RTIC_SCOPE_TRACE_DIVIDER[id as usize]);
+        let skipped = core::ptr::addr_of_mut!(TRACE_SKIPPED[id as usize]);
+        if divider == 0 {
+            return entered(false);
+        }
+        let count = skipped.read_volatile().wrapping_add(1);
+        if count < divider {
+            skipped.write_volatile(count);
+            return entered(false);
+        }
+        skipped.write_volatile(0);
+    }
+
+    write();
+    entered(true)
+}
+
+/// Function utilized by [`#[trace]`](trace) to write the unique ID of
+/// the software task about to exit, if its enter was traced. Only use
+/// this function via [`#[trace]`](trace).
+#[inline]
+pub fn __trace_exit(id: u8, entered: __Entered) {
+    #[cfg(feature = "spawn-trace")]
+    unsafe {
+        CURRENT_TASK = entered.previous;
+    }
+
+    if entered.traced {
+        __write_exit_id(id);
+    }
+}
+
 /// Function utilized by [`#[trace]`](trace) to write the unique ID of
 /// the just entered software task to its associated watch address. Only
 /// use this function via [`#[trace]`](trace).
@@ -2,16 +2,27 @@
 #![no_main]
 #![no_std]
 
+use cortex_m_rtic_trace::{
+    GlobalTimestampOptions, LocalTimestampOptions, TimestampClkSrc, TraceConfiguration,
+    TraceProtocol,
+};
 use panic_semihosting as _;
 use rtic;
 
+const TRACE_CONFIG: TraceConfiguration = TraceConfiguration {
+    delta_timestamps: LocalTimestampOptions::Enabled,
+    absolute_timestamps: GlobalTimestampOptions::Disabled,
+    timestamp_clk_src: TimestampClkSrc::AsyncTPIU,
+    tpiu_freq: 16_000_000, // Hz
+    tpiu_baud: 115_200,    // B/s
+    protocol: TraceProtocol::AsyncSWONRZ,
+};
+cortex_m_rtic_trace::static_assert_trace_config!(TRACE_CONFIG);
+
 #[rtic::app(device = stm32f4::stm32f401, dispatchers = [EXTI0, EXTI1])]
 mod app {
     use cortex_m::peripheral::syst::SystClkSource;
-    use cortex_m_rtic_trace::{
-        self, trace, GlobalTimestampOptions, LocalTimestampOptions, TimestampClkSrc,
-        TraceConfiguration, TraceProtocol,
-    };
+    use cortex_m_rtic_trace::{self, trace};
 
     #[shared]
     struct Shared {}
@@ -48,14 +59,7 @@ mod app {
             &mut ctx.core.ITM,
             1, // task enter DWT comparator ID
             2, // task exit DWT comparator ID
-            &TraceConfiguration {
-                delta_timestamps: LocalTimestampOptions::Enabled,
-                absolute_timestamps: GlobalTimestampOptions::Disabled,
-                timestamp_clk_src: TimestampClkSrc::AsyncTPIU,
-                tpiu_freq: 16_000_000, // Hz
-                tpiu_baud: 115_200,    // B/s
-                protocol: TraceProtocol::AsyncSWONRZ,
-            },
+            &crate::TRACE_CONFIG,
         )
         .unwrap();
 
@@ -5,6 +5,403 @@
 pub use itm::Timestamp;
 use itm::{ExceptionAction, MalformedPacket, TracePacket};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Version of this API. A frontend built against an API version is
+/// compatible with backends using the same major (or, before 1.0.0,
+/// minor) API version.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Description of a frontend, printed as a single JSON line to stdout
+/// when the frontend is started with `--describe`, instead of the
+/// socket path.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FrontendDescription {
+    /// Name of the frontend. For example, `"dummy"`.
+    pub name: String,
+
+    /// Version of the frontend itself.
+    pub version: String,
+
+    /// The [`VERSION`] of the API the frontend was built against.
+    pub api_version: String,
+}
+
+/// Which events besides task events a frontend wants to receive. By
+/// default, all events are received.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct Subscription {
+    /// Receive [`EventType::Unknown`] events.
+    pub unknown: bool,
+
+    /// Receive [`EventType::Unmappable`] events.
+    pub unmappable: bool,
+
+    /// Receive [`EventType::Invalid`] events.
+    pub invalid: bool,
+
+    /// Receive [`EventType::Overflow`] events.
+    pub overflow: bool,
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            unknown: true,
+            unmappable: true,
+            invalid: true,
+            overflow: true,
+        }
+    }
+}
+
+impl Subscription {
+    /// Whether the frontend wants to receive the given event.
+    pub fn wants(&self, event: &EventType) -> bool {
+        match event {
+            EventType::Unknown(_) => self.unknown,
+            EventType::Unmappable(_, _) => self.unmappable,
+            EventType::Invalid(_) => self.invalid,
+            EventType::Overflow => self.overflow,
+            _ => true,
+        }
+    }
+}
+
+/// Handshake printed as a single JSON line to stdout by a frontend
+/// once it listens on its socket. Frontends may instead print a
+/// `SOCKET <path>` line, in which case all events are received. Lines
+/// printed before the handshake are forwarded as frontend output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Handshake {
+    /// Absolute path to the socket the frontend listens on, or
+    /// `@<name>` for a socket named `<name>` in the Linux abstract
+    /// namespace, which is not tied to the lifetime of a file.
+    pub socket: String,
+
+    /// Events the frontend wants to receive.
+    #[serde(default)]
+    pub subscription: Subscription,
+
+    /// Receive events from the shared-memory ring buffer instead of
+    /// over the socket; see [`shm`]. Only honored if the frontend was
+    /// spawned with [`shm::FD_ENV`] set. The socket is still used for
+    /// [`ControlMessage`]s.
+    #[serde(default)]
+    pub shm: bool,
+
+    /// Receive a [`Greeting`] before any events.
+    #[serde(default)]
+    pub greeting: bool,
+
+    /// Receive [`Ping`]s, and answer each with a
+    /// [`ControlMessage::Pong`], such that the backend notices within
+    /// seconds if the frontend has died or hangs. A frontend that does
+    /// not answer pings for [`PING_TIMEOUT_SECS`] is dropped.
+    #[serde(default)]
+    pub ping: bool,
+}
+
+/// Seconds after which a frontend that has asked for [`Ping`]s, but
+/// not answered any, is considered dead.
+pub const PING_TIMEOUT_SECS: u64 = 5;
+
+/// Sent as a single JSON line to a frontend that asks for it in its
+/// [`Handshake`] about once a second, between [`EventChunk`]s. Told
+/// apart from a chunk by its `ping` field.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Ping {
+    /// Sequence number of the ping, echoed by the
+    /// [`ControlMessage::Pong`] answering it.
+    pub ping: u64,
+}
+
+/// How a task should be displayed, as configured via
+/// `[package.metadata.rtic-scope.tasks."app::foo"]` in the manifest of
+/// the traced application. Frontends should honor these such that all
+/// render the application the same.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct TaskHints {
+    /// Color of the task as `#rrggbb`. For example, `"#ff0000"`.
+    pub color: Option<String>,
+
+    /// Group the task belongs to. For example, `"comms"`.
+    pub group: Option<String>,
+
+    /// Position of the task relative to the other tasks, in ascending
+    /// order. Tasks without a row follow those with one.
+    pub row: Option<u32>,
+}
+
+/// Sent as a single JSON line to a frontend that asks for it in its
+/// [`Handshake`], before any [`EventChunk`]s.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Greeting {
+    /// Name of the traced application.
+    pub program: String,
+
+    /// Display hints of the tasks that have any, by full task name
+    /// (e.g. `app::foo`).
+    pub tasks: BTreeMap<String, TaskHints>,
+}
+
+/// Layout of the shared-memory ring buffer through which the backend
+/// may forward events to frontends, if enabled via `--shm-ring`. Each
+/// [`EventChunk`] is serialized once, and frontends read at their own
+/// pace.
+///
+/// The buffer is a memfd inherited by the frontend, whose descriptor
+/// is given in [`FD_ENV`], and which is mapped from the start. It
+/// begins with a header of native-endian fields at the given offsets,
+/// followed by the data area at [`data_offset`]. Positions are byte
+/// offsets that only increase; position `p` is at `p % capacity` in the
+/// data area. Each record is the length of a JSON-serialized
+/// [`EventChunk`] as a little-endian `u32`, followed by the mask of the
+/// cursors the record is for as a little-endian `u64` (bit `i` for
+/// cursor `i`), followed by the chunk. A chunk is written once for
+/// each distinct [`Subscription`] of the frontends that are not paused,
+/// filtered accordingly. Records may wrap around the end of the data
+/// area.
+///
+/// A frontend reads the records from its cursor, the index of which is
+/// given in [`CONSUMER_ENV`], up to the head, skipping those not for
+/// it, and then stores the position after the last record it has read
+/// in its cursor. The head, tail, and cursors are accessed atomically
+/// (acquire/release). The backend never waits for frontends: if the
+/// cursor is behind the tail, the records in between have been
+/// overwritten and the frontend continues from the tail. A record is
+/// only valid if the tail is still at or before its position after it
+/// has been copied. Once the closed field is non-zero, no more records
+/// are written. See [`Reader`](shm::Reader).
+pub mod shm {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Environment variable with the file descriptor of the buffer.
+    pub const FD_ENV: &str = "RTIC_SCOPE_SHM_FD";
+    /// Environment variable with the index of the read cursor of the
+    /// frontend.
+    pub const CONSUMER_ENV: &str = "RTIC_SCOPE_SHM_CONSUMER";
+
+    /// `u32` at the start of the buffer.
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"RSRB");
+    /// `u32` version of the layout.
+    pub const VERSION: u32 = 2;
+    /// Maximum number of read cursors: one per bit of a record mask.
+    pub const MAX_CONSUMERS: usize = 64;
+    /// Size of the length and mask preceding each record.
+    pub const RECORD_HEADER_SIZE: usize = 12;
+
+    pub const MAGIC_OFFSET: usize = 0;
+    pub const VERSION_OFFSET: usize = 4;
+    /// `u64` size of the data area in bytes.
+    pub const CAPACITY_OFFSET: usize = 8;
+    /// `u64` position after the last written record.
+    pub const HEAD_OFFSET: usize = 16;
+    /// `u64` position of the oldest record that has not been
+    /// overwritten.
+    pub const TAIL_OFFSET: usize = 24;
+    /// `u64` non-zero once the session has ended.
+    pub const CLOSED_OFFSET: usize = 32;
+    /// `u64` number of read cursors.
+    pub const CONSUMERS_OFFSET: usize = 40;
+    /// `u64` read cursor of each frontend.
+    pub const CURSORS_OFFSET: usize = 64;
+
+    /// Offset of the data area in a buffer with the given number of
+    /// read cursors.
+    pub fn data_offset(consumers: usize) -> usize {
+        (CURSORS_OFFSET + 8 * consumers + 63) / 64 * 64
+    }
+
+    /// Reads the records of a single cursor from a mapped buffer.
+    pub struct Reader {
+        ptr: *const u8,
+        consumer: usize,
+        capacity: u64,
+        data: usize,
+    }
+
+    // NOTE(unsafe) the buffer is only accessed atomically or by copy.
+    unsafe impl Send for Reader {}
+
+    impl Reader {
+        /// Reads the records of cursor `consumer` from the buffer
+        /// mapped at `ptr`.
+        ///
+        /// # Safety
+        ///
+        /// `ptr` must point to a mapping of the whole buffer, which
+        /// must outlive the reader.
+        pub unsafe fn new(ptr: *const u8, consumer: usize) -> Result<Self, String> {
+            let magic = (ptr.add(MAGIC_OFFSET) as *const u32).read();
+            let version = (ptr.add(VERSION_OFFSET) as *const u32).read();
+            if magic != MAGIC || version != VERSION {
+                return Err(format!(
+                    "not a version {} ring buffer (magic {:#x}, version {})",
+                    VERSION, magic, version
+                ));
+            }
+            let word = |offset| (*(ptr.add(offset) as *const AtomicU64)).load(Ordering::Acquire);
+            let consumers = word(CONSUMERS_OFFSET) as usize;
+            if consumer >= consumers {
+                return Err(format!(
+                    "cursor {} of a ring buffer with {} cursors",
+                    consumer, consumers
+                ));
+            }
+
+            Ok(Self {
+                ptr,
+                consumer,
+                capacity: word(CAPACITY_OFFSET),
+                data: data_offset(consumers),
+            })
+        }
+
+        fn word(&self, offset: usize) -> &AtomicU64 {
+            unsafe { &*(self.ptr.add(offset) as *const AtomicU64) }
+        }
+
+        /// Copies the bytes from position `pos` of the data area,
+        /// wrapping around its end.
+        fn copy(&self, pos: u64, out: &mut [u8]) {
+            let start = (pos % self.capacity) as usize;
+            let first = out.len().min(self.capacity as usize - start);
+            unsafe {
+                let data = self.ptr.add(self.data);
+                std::ptr::copy_nonoverlapping(data.add(start), out.as_mut_ptr(), first);
+                std::ptr::copy_nonoverlapping(data, out[first..].as_mut_ptr(), out.len() - first);
+            }
+        }
+
+        /// The next JSON-serialized [`EventChunk`](crate::EventChunk)
+        /// for this cursor, if any has been written since. Records that
+        /// have been overwritten before they were read are skipped.
+        pub fn next(&mut self) -> Option<Vec<u8>> {
+            let cursor = self.word(CURSORS_OFFSET + 8 * self.consumer);
+            loop {
+                let head = self.word(HEAD_OFFSET).load(Ordering::Acquire);
+                let tail = self.word(TAIL_OFFSET).load(Ordering::Acquire);
+                let pos = cursor.load(Ordering::Acquire).max(tail);
+                if pos >= head {
+                    return None;
+                }
+
+                let mut header = [0; RECORD_HEADER_SIZE];
+                self.copy(pos, &mut header);
+                let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+                let mask = u64::from_le_bytes(header[4..].try_into().unwrap());
+                let end = pos + RECORD_HEADER_SIZE as u64 + len;
+                let mut json = vec![];
+                if end <= head {
+                    json.resize(len as usize, 0);
+                    self.copy(pos + RECORD_HEADER_SIZE as u64, &mut json);
+                }
+
+                // NOTE(continue) lapped while copying: the record may
+                // be torn.
+                let tail = self.word(TAIL_OFFSET).load(Ordering::Acquire);
+                if tail > pos {
+                    cursor.store(tail, Ordering::Release);
+                    continue;
+                }
+                if end > head {
+                    // NOTE only a torn record can end past the head
+                    return None;
+                }
+
+                cursor.store(end, Ordering::Release);
+                if mask & 1 << self.consumer != 0 {
+                    return Some(json);
+                }
+            }
+        }
+
+        /// Whether the session has ended. Records written before may
+        /// still be unread.
+        pub fn closed(&self) -> bool {
+            self.word(CLOSED_OFFSET).load(Ordering::Acquire) != 0
+        }
+    }
+}
+
+/// Message sent by a frontend to the backend over its socket, as a
+/// single JSON line, to control the trace/replay session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ControlMessage {
+    /// End the session, as if interrupted with Ctrl+C.
+    Stop,
+
+    /// Stop sending events to this frontend until resumed. The trace
+    /// is still recorded.
+    Pause,
+
+    /// Resume sending events to this frontend.
+    Resume,
+
+    /// Note something at the current point of the session, e.g. a
+    /// marker set by the user. The note is logged by the backend.
+    Annotate { text: String },
+
+    /// Answer a [`Ping`], with its sequence number.
+    Pong { ping: u64 },
+}
+
+/// Location of a task definition in the source of the traced RTIC
+/// application.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// Path to the source file, relative to the workspace root of the
+    /// application if possible.
+    pub file: String,
+
+    /// Line of the task definition, starting at 1.
+    pub line: usize,
+
+    /// Column of the task identifier, starting at 1.
+    pub column: usize,
+}
+
+/// An interrupt from which software tasks of the traced RTIC
+/// application are dispatched, as recorded in the metadata of a trace.
+/// Several software tasks of the same priority share a dispatcher, so
+/// its entry and exit do not identify the task that is run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Dispatcher {
+    /// Name of the interrupt, as given in `#[app(dispatchers = [..])]`.
+    /// For example, `"EXTI0"`.
+    pub interrupt: String,
+
+    /// Priority of the software tasks dispatched from the interrupt.
+    pub priority: u8,
+
+    /// Names of the software tasks dispatched from the interrupt. For
+    /// example, `"app::some_task"`.
+    pub tasks: Vec<String>,
+}
+
+/// Whether two API versions are compatible with each other. See
+/// [`VERSION`].
+pub fn is_compatible(a: &str, b: &str) -> bool {
+    fn significant(version: &str) -> Vec<&str> {
+        let parts: Vec<&str> = version.split('.').collect();
+        match parts.first() {
+            Some(&"0") => parts.into_iter().take(2).collect(),
+            _ => parts.into_iter().take(1).collect(),
+        }
+    }
+
+    significant(a) == significant(b)
+}
 
 /// [RTIC](https://rtic.rs) nomenclature alias.
 pub type TaskAction = ExceptionAction;
@@ -12,17 +409,135 @@ pub type TaskAction = ExceptionAction;
 /// A set of events that occurred at a certain timepoint during target
 /// execution.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct EventChunk {
     /// Collective timestamp for the chunk of [`EventChunk::events`].
+    #[cfg_attr(feature = "schema", schemars(with = "schema::TimestampDef"))]
     pub timestamp: Timestamp,
 
     /// Set of events that occured during [`EventChunk::timestamp`].
     pub events: Vec<EventType>,
+
+    /// Host-side estimate of the offset from target reset when
+    /// [`EventChunk::timestamp`] is of unknown quality (i.e.
+    /// [`Timestamp::UnknownDelay`] or
+    /// [`Timestamp::UnknownAssocEventDelay`]), interpolated from the
+    /// surrounding chunks of known quality. `None` if the timestamp is
+    /// of known quality or if interpolation is disabled.
+    #[serde(default)]
+    pub estimated_timestamp: Option<std::time::Duration>,
+
+    /// Offset from target reset of [`EventChunk::timestamp`] in cycles
+    /// of the timestamp clock, as counted by the local timestamps: a
+    /// multiple of the local timestamp prescaler. Not rounded to
+    /// nanoseconds, and thus exact to a single tick on fast targets. For timestamps of unknown quality, the upper bound is
+    /// used. Not corrected for drift.
+    #[serde(default)]
+    pub cycles: Option<u64>,
+
+    /// Index of the core of a multi-core target the chunk was traced
+    /// from, if the trace was recorded with `--core`. `None` for
+    /// single-core targets.
+    #[serde(default)]
+    pub core: Option<u8>,
+
+    /// How the ITM packets the chunk was decoded from were accounted
+    /// for. `None` for chunks not decoded from packets, e.g. that of
+    /// [`EventType::SessionStart`].
+    #[serde(default)]
+    pub packets: Option<PacketAccounting>,
+}
+
+/// Accounting of the ITM packets an [`EventChunk`] was decoded from,
+/// such that the events of a chunk can be reconciled with the packets
+/// it was decoded from. Packets that neither are malformed nor ignored
+/// but produced no event carry data that is accounted for separately,
+/// e.g. global timestamps.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PacketAccounting {
+    /// Number of packets decoded, including malformed ones.
+    pub consumed: usize,
+
+    /// Number of malformed packets, each of which produced an
+    /// [`EventType::Invalid`].
+    pub malformed: usize,
+
+    /// Number of exception trace packets of ignored exceptions (see
+    /// `ignore_exceptions`), which produced no events.
+    pub ignored: usize,
+
+    /// Number of events produced from the packets. Events added on the
+    /// host afterwards, e.g. [`EventType::Queue`], are not included.
+    pub events: usize,
+}
+
+/// Action performed on an RTIC resource.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ResourceAction {
+    /// The critical section of the resource was entered.
+    Locked,
+    /// The critical section of the resource was exited.
+    Unlocked,
+}
+
+/// Action performed on the queue of a dispatcher.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum QueueAction {
+    /// A software task was spawned onto the queue.
+    Enqueued,
+    /// A spawned software task was dispatched from the queue.
+    Dequeued,
+    /// A spawn was rejected because the queue of the spawned task was
+    /// full.
+    Rejected,
+}
+
+/// Fault exception of an [`EventType::Fault`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum FaultKind {
+    HardFault,
+    MemManage,
+    BusFault,
+    UsageFault,
+}
+
+/// Kind of an [`EventType::Anomaly`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum AnomalyKind {
+    /// The task did not exit within its watchdog timeout (see
+    /// `--watchdog`), presumably because its exit was lost. An
+    /// estimated [`TaskAction::Exited`] of the task follows, closing
+    /// its execution.
+    MissingExit,
+}
+
+/// Why a session ended; see [`EventType::SessionEnd`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum SessionEndReason {
+    /// The source ran out of trace data, e.g. the end of a replayed
+    /// trace file was reached.
+    Exhausted,
+    /// The session was interrupted on the host (SIGINT).
+    Interrupted,
+    /// A frontend requested the session to stop via
+    /// [`ControlMessage::Stop`].
+    Stopped,
+    /// The target crashed; see [`EventType::Crash`].
+    Crashed,
+    /// Trace data could not be read from the source.
+    Failed,
 }
 
 /// Derivative of [`TracePacket`], where RTIC task information has
 /// been resolved.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum EventType {
     /// Equivalent to [`TracePacket::Overflow`].
     Overflow,
@@ -37,16 +552,541 @@ pub enum EventType {
         name: String,
 
         /// What did the task do?
+        #[cfg_attr(feature = "schema", schemars(with = "schema::TaskActionDef"))]
         action: TaskAction,
+
+        /// Offset from target reset of the action, with the constant
+        /// interrupt entry (or exit) latency configured for the target
+        /// subtracted from [`EventChunk::timestamp`]. Only set for
+        /// hardware tasks when a latency is configured and the
+        /// timestamp is of known quality.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        calibrated_offset: Option<std::time::Duration>,
+
+        /// Value of the arguments a software task was spawned with, if
+        /// traced via `#[trace(args)]`: the low 24 bits of a single
+        /// argument, or a 24-bit hash of several arguments, as three
+        /// little-endian bytes. Only set when the task is entered.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        args: Option<Vec<u8>>,
+
+        /// The action was not traced, but synthesized on the host, e.g.
+        /// the exit of a task whose exit was lost (see
+        /// [`EventType::Anomaly`]). Neither whether nor when it
+        /// occurred is known for certain.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        estimated: bool,
+    },
+
+    /// An RTIC resource was locked or unlocked. Only emitted for
+    /// resources traced on the target via
+    /// `cortex_m_rtic_trace::trace_lock`.
+    Resource {
+        /// Name of the resource, as declared in the `#[shared]` struct.
+        /// For example, `"counter"`.
+        name: String,
+
+        /// Was the resource locked or unlocked?
+        action: ResourceAction,
+    },
+
+    /// A software task was spawned. Only emitted for spawns traced on
+    /// the target via `cortex_m_rtic_trace::trace_spawn`.
+    Spawn {
+        /// Name of the task that spawned [`EventType::Spawn::to`]. For
+        /// example, `"app::some_task"`, or `"thread"` if spawned from
+        /// `#[init]` or `#[idle]`.
+        from: String,
+
+        /// Name of the spawned software task.
+        to: String,
+
+        /// The spawn was rejected because the queue of the spawned
+        /// task was full: the task was not spawned. Only traced via
+        /// `cortex_m_rtic_trace::trace_queued`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        rejected: bool,
+    },
+
+    /// The queue of spawned software tasks of a dispatcher changed.
+    /// Derived host-side from the spawns and entries of the tasks
+    /// dispatched from it, and thus only emitted for tasks whose spawns
+    /// are traced. Several tasks of the same priority share a queue.
+    Queue {
+        /// Name of the interrupt the tasks of the queue are dispatched
+        /// from; see [`Dispatcher::interrupt`]. For example, `"EXTI0"`.
+        name: String,
+
+        /// Number of spawned tasks in the queue after the action.
+        depth: usize,
+
+        /// What happened to the queue?
+        action: QueueAction,
+    },
+
+    /// Approximate CPU load of the target over the last sample window,
+    /// in percent. Only emitted if the target emits DWT sleep counter
+    /// overflow events.
+    CpuLoad {
+        /// Fraction of the sample window the target did not sleep.
+        percent: f32,
+    },
+
+    /// A phase of the RTIC application that is not a task was entered
+    /// or exited. Derived host-side from exception trace: `"init"` is
+    /// entered at target reset and exited before the first exception
+    /// trace packet, as interrupts are disabled while `#[init]` runs.
+    /// Only emitted if the trace starts within the phase.
+    Phase {
+        /// Name of the phase. For example, `"init"`.
+        name: String,
+
+        /// Was the phase entered or exited? An entered phase is
+        /// reported in the first chunk of the trace, which follows
+        /// target reset.
+        #[cfg_attr(feature = "schema", schemars(with = "schema::TaskActionDef"))]
+        action: TaskAction,
+    },
+
+    /// A line of text written to an ITM stimulus port configured as a
+    /// text port via `ports` in `[package.metadata.rtic-scope]`.
+    Log {
+        /// Name of the port, as configured. For example, `"log"`.
+        name: String,
+
+        /// The line, without its line ending.
+        message: String,
+    },
+
+    /// A frame of binary telemetry written to an ITM stimulus port
+    /// configured as a binary port via `ports` in
+    /// `[package.metadata.rtic-scope]`, or a line written to a text
+    /// port that is not valid UTF-8.
+    Telemetry {
+        /// Name of the port, as configured. For example, `"imu"`.
+        name: String,
+
+        /// The frame, reassembled from the writes to the port.
+        bytes: Vec<u8>,
+    },
+
+    /// A value written to an ITM stimulus port configured as a numeric
+    /// port (of type `u32`, `f32` or `bitfield`) via `ports` in
+    /// `[package.metadata.rtic-scope]`. Each 4-byte write is a value,
+    /// or a value of each field of a bitfield port.
+    Channel {
+        /// Name of the port, as configured, or `<port>.<field>` for a
+        /// field of a bitfield port. For example, `"adc"` or
+        /// `"status.mode"`.
+        name: String,
+
+        /// The value.
+        value: f64,
+    },
+
+    /// A sample of an instrument external to the target, e.g. of the
+    /// current drawn by the target as measured by a power analyzer,
+    /// read on the host via `--aux-csv`. Timestamped by aligning the
+    /// clock of the instrument on the sync pulse of the target; see
+    /// `cortex_m_rtic_trace::sync_pulse`.
+    AuxSample {
+        /// Name of the channel, as given in the header of the samples.
+        /// For example, `"current"`.
+        channel: String,
+
+        /// The sampled value, in the units of the instrument.
+        value: f64,
+    },
+
+    /// A fault handler reported the fault status of the target via
+    /// `cortex_m_rtic_trace::trace_fault`. Unlike [`EventType::Crash`],
+    /// the target need not be halted, and the trace continues if the
+    /// handler returns.
+    Fault {
+        /// The fault exception that was entered.
+        kind: FaultKind,
+
+        /// The Configurable Fault Status Register (CFSR) when the
+        /// fault was entered.
+        cfsr: u32,
+
+        /// The faulting address (MMFAR or BFAR), if valid.
+        address: Option<u32>,
+    },
+
+    /// The target crashed: it entered the HardFault exception. Always
+    /// the last traced event of a session; only [`EventType::SessionEnd`]
+    /// follows.
+    Crash {
+        /// Why the target crashed, as far as known. For example, the
+        /// fault causes read from the target and the last panic
+        /// message, if the firmware uses `panic-persist`.
+        details: String,
+    },
+
+    /// The trace stream was lost before this chunk, and recovered by
+    /// re-applying the SWO configuration; see `--swo-silence`. Events
+    /// that occurred during the outage are missing.
+    TraceGap {
+        /// How long the stream was lost for, as measured on the host.
+        duration: std::time::Duration,
+    },
+
+    /// The session started: the source of trace data was configured.
+    /// Always the first event of a session, in a chunk of its own
+    /// timestamped at target reset.
+    SessionStart {
+        /// Description of the source. For example, `"probe-rs (SWO)"`.
+        source: String,
+
+        /// Whether the target was flashed with the traced application
+        /// at the start of the session. Always `false` when replaying.
+        flashed: bool,
+    },
+
+    /// The target was reset, which [`EventChunk::timestamp`] is an
+    /// offset from. Follows [`EventType::SessionStart`] if the target
+    /// was reset at the start of the session, as recorded in the trace
+    /// when replaying.
+    TargetReset {
+        /// Approximate time of the reset on the host, since the UNIX
+        /// epoch.
+        unix_time: std::time::Duration,
+    },
+
+    /// The first trace packet of the session was received. The first
+    /// event of its chunk.
+    TraceStart,
+
+    /// The session ended. Always the last event of a session, in a
+    /// chunk of its own timestamped as the last chunk before it.
+    SessionEnd {
+        /// Why did the session end?
+        reason: SessionEndReason,
+    },
+
+    /// The timestamp of this chunk had diverged from the global
+    /// timestamps emitted by the target, e.g. because local timestamps
+    /// were lost to an overflow, and was re-anchored on them. The
+    /// timestamps of the following chunks are corrected likewise.
+    Reanchored {
+        /// Offset from target reset of the chunk as accumulated from
+        /// local timestamps, before the correction.
+        local: std::time::Duration,
+    },
+
+    /// A task behaved in a way that suggests that trace data was lost.
+    Anomaly {
+        /// What was anomalous?
+        kind: AnomalyKind,
+
+        /// Name of the RTIC task. For example, `"app::some_task"`.
+        name: String,
     },
 
     /// RTIC Scope does not know how to map this packet.
-    Unknown(TracePacket),
+    Unknown(#[cfg_attr(feature = "schema", schemars(with = "schema::TracePacketDef"))] TracePacket),
 
     /// RTIC Scope knows how to map this packet, but recovered
     /// translation maps does not contain the correct information.
-    Unmappable(TracePacket, String),
+    Unmappable(
+        #[cfg_attr(feature = "schema", schemars(with = "schema::TracePacketDef"))] TracePacket,
+        String,
+    ),
 
     /// Packet could not be decoded.
-    Invalid(MalformedPacket),
+    Invalid(
+        #[cfg_attr(feature = "schema", schemars(with = "schema::MalformedPacketDef"))]
+        MalformedPacket,
+    ),
+}
+
+/// [JSON Schemas](https://json-schema.org) of the API types, for
+/// frontends written in other languages than Rust. Printed by `cargo
+/// rtic-scope api-schema`. Typed bindings can be generated from them,
+/// e.g. for TypeScript via
+/// `quicktype --src-lang schema --lang typescript event-chunk.json`.
+///
+/// The schemas describe the serde serialization of the types, which
+/// is what is sent to frontends. Packets of the `itm` crate carried by
+/// [`EventType::Unknown`], [`EventType::Unmappable`] and
+/// [`EventType::Invalid`] are left unconstrained.
+#[cfg(feature = "schema")]
+pub mod schema {
+    use schemars::gen::SchemaGenerator;
+    use schemars::schema::{Metadata, RootSchema, Schema, SchemaObject};
+    use schemars::{schema_for, JsonSchema};
+
+    /// The API types a schema is generated for by [`of`].
+    pub const TYPES: &[&str] = &[
+        "EventChunk",
+        "Handshake",
+        "Greeting",
+        "ControlMessage",
+        "Ping",
+        "FrontendDescription",
+    ];
+
+    /// The JSON Schema of the API type named `name`, if any of
+    /// [`TYPES`].
+    pub fn of(name: &str) -> Option<RootSchema> {
+        Some(match name {
+            "EventChunk" => schema_for!(super::EventChunk),
+            "Handshake" => schema_for!(super::Handshake),
+            "Greeting" => schema_for!(super::Greeting),
+            "ControlMessage" => schema_for!(super::ControlMessage),
+            "Ping" => schema_for!(super::Ping),
+            "FrontendDescription" => schema_for!(super::FrontendDescription),
+            _ => return None,
+        })
+    }
+
+    /// Mirror of [`itm::Timestamp`], which does not implement
+    /// `JsonSchema`.
+    #[doc(hidden)]
+    #[derive(JsonSchema)]
+    #[schemars(rename = "Timestamp")]
+    pub enum TimestampDef {
+        /// The events occured exactly at this offset from target reset.
+        Sync(std::time::Duration),
+        /// The events occured at some point between these offsets from
+        /// target reset.
+        UnknownDelay {
+            prev: std::time::Duration,
+            curr: std::time::Duration,
+        },
+        /// The events occured at this offset from target reset, but
+        /// their trace packets were delayed relative to it.
+        AssocEventDelay(std::time::Duration),
+        /// Both of the above.
+        UnknownAssocEventDelay {
+            prev: std::time::Duration,
+            curr: std::time::Duration,
+        },
+    }
+
+    /// Mirror of [`TaskAction`](super::TaskAction).
+    #[doc(hidden)]
+    #[derive(JsonSchema)]
+    #[schemars(rename = "TaskAction")]
+    pub enum TaskActionDef {
+        /// The task was entered.
+        Entered,
+        /// The task was exited.
+        Exited,
+        /// The task was returned to after having been preempted.
+        Returned,
+    }
+
+    /// A schema that accepts any value, with the given description.
+    fn opaque(description: &str) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(description.to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    #[doc(hidden)]
+    pub struct TracePacketDef;
+
+    impl JsonSchema for TracePacketDef {
+        fn schema_name() -> String {
+            "TracePacket".to_string()
+        }
+
+        fn json_schema(_: &mut SchemaGenerator) -> Schema {
+            opaque("An ITM packet, as serialized by the itm crate.")
+        }
+    }
+
+    #[doc(hidden)]
+    pub struct MalformedPacketDef;
+
+    impl JsonSchema for MalformedPacketDef {
+        fn schema_name() -> String {
+            "MalformedPacket".to_string()
+        }
+
+        fn json_schema(_: &mut SchemaGenerator) -> Schema {
+            opaque("A malformed ITM packet, as serialized by the itm crate.")
+        }
+    }
+}
+
+#[cfg(all(test, feature = "schema"))]
+mod test {
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    /// The shape of a schema at `path`: the path of each property and
+    /// enumerated value, with references to definitions followed.
+    /// Descriptions are ignored.
+    fn shape(root: &Value, path: &str, schema: &Value, out: &mut BTreeSet<String>) {
+        if let Some(name) = schema["$ref"]
+            .as_str()
+            .and_then(|r| r.strip_prefix("#/definitions/"))
+        {
+            shape(root, path, &root["definitions"][name], out);
+        }
+        for (name, prop) in schema["properties"].as_object().into_iter().flatten() {
+            let path = format!("{}.{}", path, name);
+            out.insert(path.clone());
+            shape(root, &path, prop, out);
+        }
+        if schema["items"].is_object() {
+            shape(root, &format!("{}[]", path), &schema["items"], out);
+        }
+        for key in ["oneOf", "anyOf", "allOf"] {
+            for sub in schema[key].as_array().into_iter().flatten() {
+                shape(root, path, sub, out);
+            }
+        }
+        for value in schema["enum"].as_array().into_iter().flatten() {
+            out.insert(format!("{}={}", path, value.as_str().unwrap_or_default()));
+        }
+    }
+
+    /// Ensure the schema of the events sent to frontends does not
+    /// change by accident. Frontends written in other languages rely
+    /// on it; extend the expected shape deliberately, and never remove
+    /// from it without a breaking API version.
+    #[test]
+    fn event_chunk_shape() {
+        let root = serde_json::to_value(super::schema::of("EventChunk").unwrap()).unwrap();
+        let mut actual = BTreeSet::new();
+        shape(&root, "EventChunk", &root, &mut actual);
+        let expected: BTreeSet<String> = EXPECTED.iter().map(|s| s.to_string()).collect();
+        assert_eq!(actual, expected);
+
+        for name in super::schema::TYPES {
+            assert!(super::schema::of(name).is_some());
+        }
+    }
+
+    const EXPECTED: &[&str] = &[
+        "EventChunk.core",
+        "EventChunk.cycles",
+        "EventChunk.estimated_timestamp",
+        "EventChunk.estimated_timestamp.nanos",
+        "EventChunk.estimated_timestamp.secs",
+        "EventChunk.events",
+        "EventChunk.events[].Anomaly",
+        "EventChunk.events[].Anomaly.kind",
+        "EventChunk.events[].Anomaly.kind=MissingExit",
+        "EventChunk.events[].Anomaly.name",
+        "EventChunk.events[].AuxSample",
+        "EventChunk.events[].AuxSample.channel",
+        "EventChunk.events[].AuxSample.value",
+        "EventChunk.events[].Channel",
+        "EventChunk.events[].Channel.name",
+        "EventChunk.events[].Channel.value",
+        "EventChunk.events[].CpuLoad",
+        "EventChunk.events[].CpuLoad.percent",
+        "EventChunk.events[].Crash",
+        "EventChunk.events[].Crash.details",
+        "EventChunk.events[].Fault",
+        "EventChunk.events[].Fault.address",
+        "EventChunk.events[].Fault.cfsr",
+        "EventChunk.events[].Fault.kind",
+        "EventChunk.events[].Fault.kind=BusFault",
+        "EventChunk.events[].Fault.kind=HardFault",
+        "EventChunk.events[].Fault.kind=MemManage",
+        "EventChunk.events[].Fault.kind=UsageFault",
+        "EventChunk.events[].Invalid",
+        "EventChunk.events[].Log",
+        "EventChunk.events[].Log.message",
+        "EventChunk.events[].Log.name",
+        "EventChunk.events[].Phase",
+        "EventChunk.events[].Phase.action",
+        "EventChunk.events[].Phase.action=Entered",
+        "EventChunk.events[].Phase.action=Exited",
+        "EventChunk.events[].Phase.action=Returned",
+        "EventChunk.events[].Phase.name",
+        "EventChunk.events[].Queue",
+        "EventChunk.events[].Queue.action",
+        "EventChunk.events[].Queue.action=Dequeued",
+        "EventChunk.events[].Queue.action=Enqueued",
+        "EventChunk.events[].Queue.action=Rejected",
+        "EventChunk.events[].Queue.depth",
+        "EventChunk.events[].Queue.name",
+        "EventChunk.events[].Reanchored",
+        "EventChunk.events[].Reanchored.local",
+        "EventChunk.events[].Reanchored.local.nanos",
+        "EventChunk.events[].Reanchored.local.secs",
+        "EventChunk.events[].Resource",
+        "EventChunk.events[].Resource.action",
+        "EventChunk.events[].Resource.action=Locked",
+        "EventChunk.events[].Resource.action=Unlocked",
+        "EventChunk.events[].Resource.name",
+        "EventChunk.events[].SessionEnd",
+        "EventChunk.events[].SessionEnd.reason",
+        "EventChunk.events[].SessionEnd.reason=Crashed",
+        "EventChunk.events[].SessionEnd.reason=Exhausted",
+        "EventChunk.events[].SessionEnd.reason=Failed",
+        "EventChunk.events[].SessionEnd.reason=Interrupted",
+        "EventChunk.events[].SessionEnd.reason=Stopped",
+        "EventChunk.events[].SessionStart",
+        "EventChunk.events[].SessionStart.flashed",
+        "EventChunk.events[].SessionStart.source",
+        "EventChunk.events[].Spawn",
+        "EventChunk.events[].Spawn.from",
+        "EventChunk.events[].Spawn.rejected",
+        "EventChunk.events[].Spawn.to",
+        "EventChunk.events[].TargetReset",
+        "EventChunk.events[].TargetReset.unix_time",
+        "EventChunk.events[].TargetReset.unix_time.nanos",
+        "EventChunk.events[].TargetReset.unix_time.secs",
+        "EventChunk.events[].Task",
+        "EventChunk.events[].Task.action",
+        "EventChunk.events[].Task.action=Entered",
+        "EventChunk.events[].Task.action=Exited",
+        "EventChunk.events[].Task.action=Returned",
+        "EventChunk.events[].Task.args",
+        "EventChunk.events[].Task.calibrated_offset",
+        "EventChunk.events[].Task.calibrated_offset.nanos",
+        "EventChunk.events[].Task.calibrated_offset.secs",
+        "EventChunk.events[].Task.estimated",
+        "EventChunk.events[].Task.name",
+        "EventChunk.events[].Telemetry",
+        "EventChunk.events[].Telemetry.bytes",
+        "EventChunk.events[].Telemetry.name",
+        "EventChunk.events[].TraceGap",
+        "EventChunk.events[].TraceGap.duration",
+        "EventChunk.events[].TraceGap.duration.nanos",
+        "EventChunk.events[].TraceGap.duration.secs",
+        "EventChunk.events[].Unknown",
+        "EventChunk.events[].Unmappable",
+        "EventChunk.events[]=Overflow",
+        "EventChunk.events[]=TraceStart",
+        "EventChunk.packets",
+        "EventChunk.packets.consumed",
+        "EventChunk.packets.events",
+        "EventChunk.packets.ignored",
+        "EventChunk.packets.malformed",
+        "EventChunk.timestamp",
+        "EventChunk.timestamp.AssocEventDelay",
+        "EventChunk.timestamp.AssocEventDelay.nanos",
+        "EventChunk.timestamp.AssocEventDelay.secs",
+        "EventChunk.timestamp.Sync",
+        "EventChunk.timestamp.Sync.nanos",
+        "EventChunk.timestamp.Sync.secs",
+        "EventChunk.timestamp.UnknownAssocEventDelay",
+        "EventChunk.timestamp.UnknownAssocEventDelay.curr",
+        "EventChunk.timestamp.UnknownAssocEventDelay.curr.nanos",
+        "EventChunk.timestamp.UnknownAssocEventDelay.curr.secs",
+        "EventChunk.timestamp.UnknownAssocEventDelay.prev",
+        "EventChunk.timestamp.UnknownAssocEventDelay.prev.nanos",
+        "EventChunk.timestamp.UnknownAssocEventDelay.prev.secs",
+        "EventChunk.timestamp.UnknownDelay",
+        "EventChunk.timestamp.UnknownDelay.curr",
+        "EventChunk.timestamp.UnknownDelay.curr.nanos",
+        "EventChunk.timestamp.UnknownDelay.curr.secs",
+        "EventChunk.timestamp.UnknownDelay.prev",
+        "EventChunk.timestamp.UnknownDelay.prev.nanos",
+        "EventChunk.timestamp.UnknownDelay.prev.secs",
+    ];
 }
@@ -0,0 +1,109 @@
+//! A heads-up display of the rolling execution latencies of each task,
+//! shown instead of the raw event dump if asked for.
+use rtic_scope_api as api;
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How often the display is redrawn at most.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Rolling execution latencies of a single task.
+#[derive(Default)]
+struct TaskLatencies {
+    entered: Option<u128>,
+    window: VecDeque<u128>,
+}
+
+pub struct Hud {
+    /// Number of most recent executions statistics are calculated over.
+    executions: usize,
+    tasks: Vec<(String, TaskLatencies)>,
+    index: HashMap<String, usize>,
+    last_redraw: Option<Instant>,
+}
+
+/// The nearest-rank `p`th percentile of the sorted `samples`.
+fn percentile(sorted: &[u128], p: f64) -> u128 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.saturating_sub(1).min(sorted.len() - 1)]
+}
+
+impl Hud {
+    pub fn new(executions: usize) -> Self {
+        Self {
+            executions: executions.max(1),
+            tasks: vec![],
+            index: HashMap::new(),
+            last_redraw: None,
+        }
+    }
+
+    fn task(&mut self, name: &str) -> &mut TaskLatencies {
+        let idx = match self.index.get(name) {
+            Some(idx) => *idx,
+            None => {
+                self.tasks
+                    .push((name.to_string(), TaskLatencies::default()));
+                self.index.insert(name.to_string(), self.tasks.len() - 1);
+                self.tasks.len() - 1
+            }
+        };
+        &mut self.tasks[idx].1
+    }
+
+    /// Records the task actions of a chunk received at `nanos` and
+    /// redraws the display if due.
+    pub fn update(&mut self, nanos: u128, events: &[api::EventType]) {
+        let executions = self.executions;
+        for event in events {
+            if let api::EventType::Task { name, action, .. } = event {
+                let task = self.task(name);
+                match action {
+                    api::TaskAction::Entered => task.entered = Some(nanos),
+                    api::TaskAction::Exited => {
+                        if let Some(entered) = task.entered.take() {
+                            if task.window.len() == executions {
+                                task.window.pop_front();
+                            }
+                            task.window.push_back(nanos.saturating_sub(entered));
+                        }
+                    }
+                    api::TaskAction::Returned => (),
+                }
+            }
+        }
+
+        if matches!(self.last_redraw, Some(last) if last.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+        self.last_redraw = Some(Instant::now());
+        self.draw();
+    }
+
+    fn draw(&self) {
+        // Clear the terminal and move the cursor home.
+        eprint!("\x1b[H\x1b[J");
+        eprintln!(
+            "{:<32} {:>12} {:>12} {:>12} {:>6}",
+            "task", "p50 [ns]", "p95 [ns]", "max [ns]", "n"
+        );
+        for (name, task) in self.tasks.iter() {
+            if task.window.is_empty() {
+                eprintln!("{:<32} {:>12} {:>12} {:>12} {:>6}", name, "-", "-", "-", 0);
+                continue;
+            }
+
+            let mut sorted: Vec<u128> = task.window.iter().copied().collect();
+            sorted.sort_unstable();
+            eprintln!(
+                "{:<32} {:>12} {:>12} {:>12} {:>6}",
+                name,
+                percentile(&sorted, 0.50),
+                percentile(&sorted, 0.95),
+                sorted[sorted.len() - 1],
+                sorted.len()
+            );
+        }
+    }
+}
@@ -4,34 +4,219 @@
 
 use anyhow::{Context, Result};
 use rtic_scope_api as api;
+use serde::Deserialize;
 use serde_json::Deserializer;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+mod hud;
+
+/// A message from the backend: a ping to answer, or a chunk of events.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Ping(api::Ping),
+    Chunk(api::EventChunk),
+}
+
+/// Default number of most recent executions the latency display is
+/// calculated over.
+const DEFAULT_HUD_EXECUTIONS: usize = 100;
+
+/// Whether to display rolling task latencies instead of raw events,
+/// and over how many executions. Enabled via `--hud [N]` or by setting
+/// `RTIC_SCOPE_DUMMY_HUD` (optionally to N).
+fn hud_executions() -> Result<Option<usize>> {
+    let mut args = std::env::args().skip(1);
+    let arg = match args.position(|arg| arg == "--hud") {
+        Some(_) => Some(args.next().unwrap_or_default()),
+        None => std::env::var("RTIC_SCOPE_DUMMY_HUD").ok(),
+    };
+
+    match arg.as_deref() {
+        None => Ok(None),
+        Some("") => Ok(Some(DEFAULT_HUD_EXECUTIONS)),
+        Some(n) => n
+            .parse()
+            .map(Some)
+            .with_context(|| format!("Invalid number of HUD executions {:?}", n)),
+    }
+}
+
+/// Prints the events of a chunk to stderr along with its nanoseconds
+/// timestamp, or updates the HUD.
+struct Display {
+    hud: Option<hud::Hud>,
+    prev_nanos: u128,
+}
+
+impl Display {
+    fn show(&mut self, chunk: api::EventChunk) {
+        let api::EventChunk {
+            timestamp,
+            events,
+            estimated_timestamp,
+            cycles: _,
+            core: _,
+            packets: _,
+        } = chunk;
+        let (quality, nanos) = match (timestamp, estimated_timestamp) {
+            (api::Timestamp::Sync(offset), _) | (api::Timestamp::AssocEventDelay(offset), _) => {
+                ("good", offset.as_nanos())
+            }
+            (_, Some(estimate)) => ("estimated", estimate.as_nanos()),
+            (api::Timestamp::UnknownDelay { prev: _, curr }, None)
+            | (api::Timestamp::UnknownAssocEventDelay { prev: _, curr }, None) => {
+                ("bad!", curr.as_nanos())
+            }
+        };
+        if let Some(hud) = self.hud.as_mut() {
+            hud.update(nanos, &events);
+            return;
+        }
+        let diff = nanos - self.prev_nanos;
+        eprintln!("@{nanos} ns (+{diff} ns) [{quality}]: {events:?}");
+        self.prev_nanos = nanos;
+    }
+}
+
+/// Maps the shared-memory ring buffer, if the backend offers one.
+fn shm_reader() -> Result<Option<api::shm::Reader>> {
+    let (fd, consumer) = match (
+        std::env::var(api::shm::FD_ENV),
+        std::env::var(api::shm::CONSUMER_ENV),
+    ) {
+        (Ok(fd), Ok(consumer)) => (fd, consumer),
+        _ => return Ok(None),
+    };
+    let fd: libc::c_int = fd.parse().context("Invalid ring buffer descriptor")?;
+    let consumer: usize = consumer.parse().context("Invalid ring buffer cursor")?;
+
+    // NOTE(unsafe) the mapping is never unmapped, and thus outlives
+    // the reader.
+    unsafe {
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd, &mut stat) != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to stat ring buffer descriptor");
+        }
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            stat.st_size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error()).context("Failed to map ring buffer");
+        }
+        api::shm::Reader::new(ptr as *const u8, consumer)
+            .map(Some)
+            .map_err(anyhow::Error::msg)
+            .context("Failed to read ring buffer")
+    }
+}
+
+/// Reads chunks from the ring buffer until the session ends or the
+/// backend hangs up.
+fn read_shm(mut reader: api::shm::Reader, mut display: Display, hangup: Arc<AtomicBool>) {
+    loop {
+        // NOTE(ordering) checked before draining, so that no record
+        // written before the end is left unread.
+        let done = reader.closed() || hangup.load(Ordering::Acquire);
+        while let Some(json) = reader.next() {
+            match serde_json::from_slice(&json) {
+                Ok(chunk) => display.show(chunk),
+                Err(e) => eprintln!("Failed to deserialize chunk from ring buffer: {}", e),
+            }
+        }
+        if done {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
 
 fn main() -> Result<()> {
+    // Describe ourselves to the backend if asked to, instead of
+    // accepting trace data.
+    if std::env::args().nth(1).as_deref() == Some("--describe") {
+        println!(
+            "{}",
+            serde_json::to_string(&api::FrontendDescription {
+                name: "dummy".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                api_version: api::VERSION.to_string(),
+            })
+            .context("Failed to serialize frontend description")?
+        );
+        return Ok(());
+    }
+    let mut display = Display {
+        hud: hud_executions()?.map(hud::Hud::new),
+        prev_nanos: 0,
+    };
+    let shm = shm_reader()?;
+
     // Create frontend socket in a temporary directory, print it for the parent backend.
     let socket_dir = tempfile::TempDir::new()
         .context("Failed to create temporary directory for frontend socket")?;
     let socket_path = socket_dir.path().join("rtic-scope-frontend.socket");
     let listener = std::os::unix::net::UnixListener::bind(&socket_path)
         .context("Failed to bind frontend socket")?;
-    println!("{}", socket_path.display());
 
-    // Deserialize api::EventChunks from socket and print events to
-    // stderr along with nanoseconds timestamp.
+    // Unknown packets are of no interest unless asked for.
+    println!(
+        "{}",
+        serde_json::to_string(&api::Handshake {
+            socket: socket_path.display().to_string(),
+            subscription: api::Subscription {
+                unknown: std::env::var_os("RTIC_SCOPE_DUMMY_UNKNOWN").is_some(),
+                ..api::Subscription::default()
+            },
+            shm: shm.is_some(),
+            greeting: false,
+            ping: true,
+        })
+        .context("Failed to serialize handshake")?
+    );
+
+    // Deserialize api::EventChunks from socket, or read them from the
+    // ring buffer, and print events to stderr along with nanoseconds
+    // timestamp. Pings are answered in between.
     let (socket, _addr) = listener.accept().context("Failed to accept()")?;
-    let stream = Deserializer::from_reader(socket).into_iter::<api::EventChunk>();
-    let mut prev_nanos = 0;
-    for chunk in stream {
-        let api::EventChunk { timestamp, events } = chunk.context("Failed to deserialize chunk")?;
-        let (quality, nanos) = match timestamp {
-            api::Timestamp::Sync(offset) | api::Timestamp::AssocEventDelay(offset) => {
-                ("good", offset.as_nanos())
+    let hangup = Arc::new(AtomicBool::new(false));
+    let (mut display, shm) = match shm {
+        Some(reader) => {
+            let hangup = hangup.clone();
+            let thread = std::thread::spawn(move || read_shm(reader, display, hangup));
+            (None, Some(thread))
+        }
+        None => (Some(display), None),
+    };
+    let mut pongs = socket.try_clone().context("Failed to clone socket")?;
+    let stream = Deserializer::from_reader(socket).into_iter::<Message>();
+    for message in stream {
+        match message.context("Failed to deserialize message")? {
+            Message::Ping(api::Ping { ping }) => {
+                let pong = serde_json::to_string(&api::ControlMessage::Pong { ping })?;
+                writeln!(pongs, "{}", pong).context("Failed to answer ping")?;
             }
-            api::Timestamp::UnknownDelay { prev: _, curr }
-            | api::Timestamp::UnknownAssocEventDelay { prev: _, curr } => ("bad!", curr.as_nanos()),
-        };
-        let diff = nanos - prev_nanos;
-        eprintln!("@{nanos} ns (+{diff} ns) [{quality}]: {events:?}");
-        prev_nanos = nanos;
+            // NOTE no chunks are sent over the socket when the ring
+            // buffer is read
+            Message::Chunk(chunk) => {
+                if let Some(display) = display.as_mut() {
+                    display.show(chunk);
+                }
+            }
+        }
+    }
+
+    hangup.store(true, Ordering::Release);
+    if let Some(reader) = shm {
+        reader.join().expect("ring buffer reader panicked");
     }
 
     Ok(())